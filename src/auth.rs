@@ -0,0 +1,157 @@
+use inifile::IniFile;
+use std::collections::HashMap;
+
+// constants -------------------------------------------------------------------
+const INI_SECTION_TOKENS       : &'static str = "tokens";
+const INI_SECTION_ROLE_PREFIX  : &'static str = "role:";
+const INI_OPTION_PERMISSIONS   : &'static str = "permissions";
+const INI_OPTION_PREFIXES      : &'static str = "prefixes";
+
+const PERMISSION_READ          : &'static str = "read";
+const PERMISSION_WRITE         : &'static str = "write";
+const PERMISSION_ADMIN         : &'static str = "admin";
+// -----------------------------------------------------------------------------
+
+// The classes of commands a role can be granted
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permission {
+    Read,  // check, multi, info, list
+    Write, // set, bulk
+    Admin  // create, drop, clear, close, flush
+}
+
+impl Permission {
+    // Parses a permission name as used in the ACL ini file
+    pub fn from_name(name : &str) -> Option<Permission> {
+        return match name {
+            PERMISSION_READ  => Some(Permission::Read),
+            PERMISSION_WRITE => Some(Permission::Write),
+            PERMISSION_ADMIN => Some(Permission::Admin),
+            _ => None
+        };
+    }
+}
+
+// A named set of permissions, scoped to the filters whose names start with one of the prefixes
+#[derive(Clone)]
+pub struct Role {
+    pub name        : String,          // Role name
+    pub permissions : Vec<Permission>, // Command classes the role may use
+    pub prefixes    : Vec<String>      // Filter name prefixes; empty means every filter
+}
+
+impl Role {
+    // Returns a new Role with the given permissions and prefixes
+    pub fn new(name : String, permissions : Vec<Permission>, prefixes : Vec<String>) -> Self {
+        return Role { name: name, permissions: permissions, prefixes: prefixes };
+    }
+
+    // Returns true if the role is not restricted to any filter prefixes
+    pub fn is_unscoped(&self) -> bool {
+        return self.prefixes.is_empty();
+    }
+
+    // Returns true if the role may use the given command class on the given filter.
+    // A filter name of None stands for every filter, so only unscoped roles are allowed.
+    pub fn allows(&self, permission : Permission, filter_name : Option<&str>) -> bool {
+        if !self.permissions.contains(&permission) {
+            return false;
+        }
+
+        return match filter_name {
+            Some(name) => { self.is_unscoped() || self.prefixes.iter().any(|prefix| name.starts_with(prefix.as_slice())) },
+            None => { self.is_unscoped() }
+        };
+    }
+}
+
+// The tokens clients can authenticate with, and the roles they map to
+#[derive(Clone)]
+pub struct AccessControl {
+    tokens : HashMap<String, Role>
+}
+
+impl AccessControl {
+    // Returns an empty AccessControl, which leaves authentication disabled
+    pub fn new() -> Self {
+        return AccessControl { tokens: HashMap::new() };
+    }
+
+    // Pulls the roles and tokens out of an ini file.
+    // Roles are sections named 'role:<name>', tokens are options of the 'tokens' section.
+    pub fn from_ini(ini : &IniFile) -> Result<Self, String> {
+        let mut roles : HashMap<String, Role> = HashMap::new();
+
+        for section in ini.sections().iter() {
+            if !section.starts_with(INI_SECTION_ROLE_PREFIX) {
+                continue;
+            }
+
+            let role_name : String = String::from_str(&section[INI_SECTION_ROLE_PREFIX.len()..]);
+
+            let mut permissions : Vec<Permission> = Vec::new();
+            for name in split_list(ini.get_string(section.as_slice(), INI_OPTION_PERMISSIONS)).iter() {
+                match Permission::from_name(name.as_slice()) {
+                    Some(permission) => { permissions.push(permission) },
+                    None => { return Err(format!("unknown permission for role {}: {}", role_name, name)) }
+                }
+            }
+
+            let prefixes : Vec<String> = split_list(ini.get_string(section.as_slice(), INI_OPTION_PREFIXES));
+
+            roles.insert(role_name.clone(), Role::new(role_name, permissions, prefixes));
+        }
+
+        let mut access_control : AccessControl = AccessControl::new();
+
+        if ini.has_section(INI_SECTION_TOKENS) {
+            for token in ini.options(String::from_str(INI_SECTION_TOKENS)).iter() {
+                let role_name : String = ini.get_string(INI_SECTION_TOKENS, token.as_slice()).unwrap();
+
+                match roles.get(&role_name) {
+                    Some(role) => { access_control.tokens.insert(token.clone(), role.clone()); },
+                    None => { return Err(format!("token refers to unknown role: {}", role_name)) }
+                }
+            }
+        }
+
+        return Ok(access_control);
+    }
+
+    // Reads the roles and tokens from the ini file with the given name
+    pub fn from_filename(filename : &str) -> Result<Self, String> {
+        return match IniFile::from_filename(filename) {
+            Ok(ini) => AccessControl::from_ini(&ini),
+            Err(e) => Err(format!("could not read acl file {}: {}", filename, e))
+        };
+    }
+
+    // Adds all the tokens from the given AccessControl to this one
+    pub fn merge(&mut self, other : AccessControl) {
+        for (token, role) in other.tokens.into_iter() {
+            self.tokens.insert(token, role);
+        }
+    }
+
+    // Returns true if clients have to authenticate before issuing commands
+    pub fn is_enabled(&self) -> bool {
+        return !self.tokens.is_empty();
+    }
+
+    // Returns the role for the given token, or None if the token is unknown
+    pub fn authenticate(&self, token : &str) -> Option<Role> {
+        return self.tokens.get(&String::from_str(token)).map(|role| role.clone());
+    }
+}
+
+// splits a comma separated ini value, skipping empty pieces
+fn split_list(value : Option<String>) -> Vec<String> {
+    return match value {
+        Some(value) => value.as_slice().split_str(",")
+                                       .map(|piece| piece.trim())
+                                       .filter(|piece| !piece.is_empty())
+                                       .map(|piece| String::from_str(piece))
+                                       .collect(),
+        None => Vec::new()
+    };
+}
@@ -1,8 +1,8 @@
 
 extern crate libc;
 
-use util;
-use std::{ffi};
+use error::{BloomError, BloomResult};
+use std::{cmp, ffi, ptr};
 use std::ops::{BitAnd, BitOr, BitXor};
 
 #[repr(C)]
@@ -38,55 +38,144 @@ impl BitXor for bitmap_mode {
     }
 }
 
+// constants -------------------------------------------------------------------
+const IO_MODE_BUFFERED : &'static str = "buffered";
+const IO_MODE_MMAP     : &'static str = "mmap";
+
+// the C code marks changes in pages of this many bytes
+const PAGE_SIZE : u64 = 4096;
+// -----------------------------------------------------------------------------
+
+// How the bitmaps of a filter on disk are read in and written back
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IoMode {
+    Buffered, // Read into the heap; the pages set are tracked and written back with pwrite on flush
+    Mmap      // Mapped shared with the file; the kernel writes pages back, and flush syncs them
+}
+
+impl IoMode {
+    // Returns the mode the use_mmap setting selects
+    pub fn from_use_mmap(use_mmap : bool) -> IoMode {
+        return if use_mmap { IoMode::Mmap } else { IoMode::Buffered };
+    }
+
+    // Parses a mode name as used in filter ini files
+    pub fn from_name(name : &str) -> Option<IoMode> {
+        return match name {
+            IO_MODE_BUFFERED => Some(IoMode::Buffered),
+            IO_MODE_MMAP => Some(IoMode::Mmap),
+            _ => None
+        };
+    }
+
+    // Returns the name of the mode, as used in filter ini files
+    pub fn name(&self) -> &'static str {
+        return match *self {
+            IoMode::Buffered => IO_MODE_BUFFERED,
+            IoMode::Mmap => IO_MODE_MMAP
+        };
+    }
+
+    // Returns the bitmap mode to open a bitmap in; bitmaps of in-memory filters have no file to read or write
+    pub fn bitmap_mode(&self, in_memory : bool) -> bitmap_mode {
+        if in_memory {
+            return bitmap_mode::ANONYMOUS;
+        }
+
+        return match *self {
+            IoMode::Buffered => bitmap_mode::PERSISTENT,
+            IoMode::Mmap => bitmap_mode::SHARED
+        };
+    }
+}
+
 #[repr(C)]
+#[allow(dead_code)]
 pub struct bloom_bitmap {
     mode        : u32,
     fileno      : i32,
     size        : u64,
-    mmap        : Vec<i8>,
-    dirty_pages : Vec<i8>
+    mmap        : *mut u8, // The bitmap, allocated and freed by the C code
+    dirty_pages : *mut u8  // A bit for each page of a buffered bitmap, set when the page changes and cleared by flush
 }
 
 impl bloom_bitmap {
     // Returns a new bitmap 
     fn new(mode : u32, fileno : i32, size : u64) -> Self {
-        return bloom_bitmap { mode: mode, fileno: fileno, size: size, mmap: Vec::with_capacity(size as usize), dirty_pages: Vec::with_capacity(size as usize) };
+        return bloom_bitmap { mode: mode, fileno: fileno, size: size, mmap: ptr::null_mut(), dirty_pages: ptr::null_mut() };
     }
 
     // Returns the bitmap from an opened file
-    pub fn from_file(fileno : i32, len : u64, mode : u32) -> Result<Self, String> {
+    pub fn from_file(fileno : i32, len : u64, mode : u32) -> BloomResult<Self> {
         let mut map : bloom_bitmap = bloom_bitmap::new(mode, fileno, len);
 
         let value : i32 = unsafe { externals::bitmap_from_file(fileno, len, mode, &mut map as *mut bloom_bitmap) };
 
         if value < 0 {
-            return util::strerror(value);
+            return Err(BloomError::from_header_code(value, format!("mapping a bitmap of {} bytes from an open file", len).as_slice()));
         }
 
         return Ok(map);
     }
 
     // Opens the file with the name given and loads the bitmap in it
-    pub fn from_filename(filename : &str, len : u64, create : bool, mode : u32) -> Result<Self, String> {
+    pub fn from_filename(filename : &str, len : u64, create : bool, mode : u32) -> BloomResult<Self> {
         let mut map : bloom_bitmap  = bloom_bitmap::new(mode, 0, len);
 
-        let filename : ffi::CString = ffi::CString::from_slice(filename.as_bytes());
+        let c_filename : ffi::CString = ffi::CString::from_slice(filename.as_bytes());
 
-        let value : i32 = unsafe { externals::bitmap_from_filename(filename.as_ptr(), len, create as i32, mode, &mut map as *mut bloom_bitmap) };
+        let value : i32 = unsafe { externals::bitmap_from_filename(c_filename.as_ptr(), len, create as i32, mode, &mut map as *mut bloom_bitmap) };
 
         if value < 0 {
-            return util::strerror(value);
+            return Err(BloomError::from_header_code(value, format!("opening bitmap {} of {} bytes", filename, len).as_slice()));
         }
 
         return Ok(map);
     }
     
+    // Returns the number of bytes the next flush writes from a buffered bitmap: the pages changed since the
+    // last flush, and the first page, holding the header, which is always written
+    pub fn dirty_bytes(&self) -> u64 {
+        if self.mode != bitmap_mode::PERSISTENT as u32 || self.dirty_pages.is_null() {
+            return 0;
+        }
+
+        let pages : u64 = (self.size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut bytes : u64 = 0;
+
+        // the bits are set most significant first, as in bitmap_setbit
+        for page in (0..pages) {
+            let byte : u8 = unsafe { *self.dirty_pages.offset((page / 8) as isize) };
+
+            if page == 0 || byte & (0x80 >> (page % 8) as usize) != 0 {
+                bytes += cmp::min(PAGE_SIZE, self.size - page * PAGE_SIZE);
+            }
+        }
+
+        return bytes;
+    }
+
+    // Returns the number of bytes the next flush syncs from a mapped bitmap, which is all of it;
+    // the kernel doesn't say which pages changed, so this is an upper bound on what it writes
+    pub fn mapped_bytes(&self) -> u64 {
+        if self.mode != bitmap_mode::SHARED as u32 {
+            return 0;
+        }
+
+        return self.size;
+    }
+
+    // Returns the bitmap_mode the bitmap was opened with
+    pub fn mode(&self) -> u32 {
+        return self.mode;
+    }
+
     // Flushes the changes to the bitmap to the disk
-    pub fn flush(&mut self) -> Result<(), String> {
+    pub fn flush(&mut self) -> BloomResult<()> {
         let value : i32 = unsafe { externals::bitmap_flush(self as *mut bloom_bitmap) };
 
         if value < 0 {
-            return util::strerror(value);
+            return Err(BloomError::from_code(value, format!("flushing a bitmap of {} bytes", self.size).as_slice()));
         }
 
         return Ok(());
@@ -101,6 +190,12 @@ impl Drop for bloom_bitmap {
     }
 }
 
+// The C code only touches the bitmap while its filter is locked
+unsafe impl Send for bloom_bitmap { }
+unsafe impl Sync for bloom_bitmap { }
+
+// the whole of the C interface, not all of which is called from Rust
+#[allow(dead_code)]
 mod externals {
     use super::libc::{c_char, c_int, c_ulong};
     use super::{bloom_bitmap};
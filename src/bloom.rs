@@ -2,29 +2,51 @@
 
 extern crate libc;
 
-use self::libc::c_char;
+use self::libc::{c_char, ENOMEM};
 use std::ffi;
-use bitmap::{bitmap_mode, bloom_bitmap};
+use std::io::File;
+use bitmap::{bitmap_mode, bloom_bitmap, IoMode};
+use error::{BloomError, BloomResult};
 use filter::IBloomFilter;
-use util;
 
 // Struct representing the header of a bloom filter
 #[repr(C, packed)]
+#[allow(dead_code)]
 pub struct bloom_filter_header {
-    magic : u32,
-    k_num : u32,
-    count : u64,
-    __buf : [i8; 496]
+    pub magic : u32,
+    pub k_num : u32,
+    pub count : u64,
+    __buf     : [i8; 496]
 }
 
 impl bloom_filter_header {
     pub fn new(magic : u32, k_num : u32, count : u64) -> Self {
         return bloom_filter_header { magic: magic, k_num: k_num, count: count, __buf: [0; 496] };
     }
+
+    // Reads the header at the start of a bitmap file, without mapping in the bitmap
+    pub fn from_filename(bitmap_filename : &str) -> BloomResult<Self> {
+        let mut file : File = try!(File::open(&Path::new(bitmap_filename)));
+
+        // the C code writes the header in host byte order
+        let (magic, k_num, count) : (u32, u32, u64) = if cfg!(target_endian = "big") {
+            (try!(file.read_be_u32()), try!(file.read_be_u32()), try!(file.read_be_u64()))
+        } else {
+            (try!(file.read_le_u32()), try!(file.read_le_u32()), try!(file.read_le_u64()))
+        };
+
+        return Ok(bloom_filter_header::new(magic, k_num, count));
+    }
+
+    // Returns true if the header starts with the magic number every bloom filter is written with
+    pub fn is_valid(&self) -> bool {
+        return self.magic == externals::MAGIC_HEADER;
+    }
 }
 
 // Struct representing a bloom filter
 #[repr(C)]
+#[allow(dead_code)]
 pub struct bloom_bloomfilter {
     header      : Box<bloom_filter_header>,
     map         : Box<bloom_bitmap>,
@@ -34,7 +56,7 @@ pub struct bloom_bloomfilter {
 
 impl bloom_bloomfilter {
     // Creates a new bloom filter from the given bitmap
-    pub fn new(k_num : u32, count : u64, map : bloom_bitmap, new_filter : bool) -> Result<Self, String> {
+    pub fn new(k_num : u32, count : u64, map : bloom_bitmap, new_filter : bool) -> BloomResult<Self> {
         let mut filter : bloom_bloomfilter = bloom_bloomfilter {
             header: Box::new(bloom_filter_header::new(if new_filter { 0 } else { externals::MAGIC_HEADER }, k_num, count)),
             map: Box::new(map),
@@ -44,35 +66,55 @@ impl bloom_bloomfilter {
 
         let value : i32 = unsafe { externals::bf_from_bitmap(&mut *filter.map, k_num, new_filter as i32, &mut filter as *mut bloom_bloomfilter) };
 
-        if value < 0 {
-            return util::strerror(value);
+        // -1 on an existing filter means its magic header didn't match
+        if value == -1 && !new_filter {
+            return Err(BloomError::Corrupt(String::from_str("bad magic header in bitmap")));
+        } else if value == -ENOMEM {
+            return Err(BloomError::Capacity(String::from_str("bitmap is smaller than the filter header")));
+        } else if value < 0 {
+            return Err(BloomError::from_header_code(value, format!("loading a filter with k_num {}", k_num).as_slice()));
         }
 
         return Ok(filter);
     }
+
+    // Returns the number of bytes of the bitmap the next flush writes
+    pub fn dirty_bytes(&self) -> u64 {
+        return self.map.dirty_bytes();
+    }
+
+    // Returns the number of bytes of the mapped bitmap the next flush syncs
+    pub fn mapped_bytes(&self) -> u64 {
+        return self.map.mapped_bytes();
+    }
+
+    // Returns the bitmap_mode of the filter's bitmap
+    pub fn bitmap_mode(&self) -> u32 {
+        return self.map.mode();
+    }
 }
 
 impl IBloomFilter<bool> for bloom_bloomfilter {
     // Adds a key to the filter
-    fn add(&mut self, key : String) -> Result<bool, String> {
+    fn add(&mut self, key : String) -> BloomResult<bool> {
         let key : ffi::CString = ffi::CString::from_slice(key.as_slice().as_bytes());
         let result : i32 = unsafe { externals::bf_add(self as *mut bloom_bloomfilter, key.as_ptr()) };
 
         if result < 0 {
-            return util::strerror(result);
+            return Err(BloomError::from_code(result, "adding a key to the filter"));
         }
 
         return Ok(result > 0);
     }
 
     // Returns true if the key is probably in the filter, and false if it definitely isn't
-    fn contains(&self, key : &String) -> Result<bool, String> {
+    fn contains(&self, key : &String) -> BloomResult<bool> {
         let key : ffi::CString = ffi::CString::from_slice(key.as_slice().as_bytes());
 
         let result : i32 = unsafe { externals::bf_contains(self as *const bloom_bloomfilter, key.as_ptr()) };
 
         if result < 0 {
-            return util::strerror(result);
+            return Err(BloomError::from_code(result, "checking a key against the filter"));
         }
 
         return Ok(result > 0);
@@ -84,11 +126,11 @@ impl IBloomFilter<bool> for bloom_bloomfilter {
     }
 
     // Flushes the filter to disk
-    fn flush(&mut self) -> Result<(), String> {
+    fn flush(&mut self) -> BloomResult<()> {
         let value : i32 = unsafe { externals::bf_flush(self as *mut bloom_bloomfilter) };
 
         if value < 0 {
-            return util::strerror(value);
+            return Err(BloomError::from_code(value, "flushing the filter, which has no bitmap"));
         }
 
         return Ok(());
@@ -121,6 +163,11 @@ impl bloom_filter_params {
     pub fn new(bytes : u64, k_num : u32, capacity : u64, fp_probability : f64) -> Self {
         return bloom_filter_params { bytes: bytes, k_num: k_num, capacity: capacity, fp_probability: fp_probability };
     }
+
+    // returns the error for parameters the C code rejected
+    fn invalid(&self) -> BloomError {
+        return BloomError::InvalidParams(format!("capacity {}, probability {}, bytes {}, k_num {}", self.capacity, self.fp_probability, self.bytes, self.k_num));
+    }
 }
 
 // Computes the hashes for a bloom filter
@@ -135,105 +182,92 @@ pub fn compute_hashes(k_num : u32, key : &str) -> u64 {
 }
 
 // Creates the bloom filter parameters, including the given capacity and probability
-pub fn create_bloom_filter_params(capacity : u64, probability : f64) -> Result<bloom_filter_params, String> {
+pub fn create_bloom_filter_params(capacity : u64, probability : f64) -> BloomResult<bloom_filter_params> {
     let mut params : bloom_filter_params = bloom_filter_params::empty();
     params.capacity = capacity;
     params.fp_probability = probability;
 
-    match size_for_capacity_prob(&mut params) {
-        Err(e) => { return Err(e) },
-        Ok(_) => { }
-    }
-
-    match ideal_k_num(&mut params) {
-        Err(e) => { return Err(e) },
-        Ok(_) => { }
-    }
+    try!(size_for_capacity_prob(&mut params));
+    try!(ideal_k_num(&mut params));
 
     return Ok(params);
 }
 
-// Creates a fresh bloom filter
-pub fn create_bloom_filter(params : &bloom_filter_params, bitmap_filename : &str, in_memory : bool) -> Result<bloom_bloomfilter, String> {
-    let mode : u32 = bitmap_mode::NEW_BITMAP | if in_memory { bitmap_mode::ANONYMOUS } else { bitmap_mode::PERSISTENT };
+// Creates a fresh bloom filter, whose bitmap is kept in the given I/O mode unless it is in memory only
+pub fn create_bloom_filter(params : &bloom_filter_params, bitmap_filename : &str, in_memory : bool, io_mode : IoMode) -> BloomResult<bloom_bloomfilter> {
+    let mode : u32 = bitmap_mode::NEW_BITMAP | io_mode.bitmap_mode(in_memory);
 
-    let map : bloom_bitmap;
-    match if in_memory { bloom_bitmap::from_file(0, params.bytes, mode) } else { bloom_bitmap::from_filename(bitmap_filename, params.bytes, true, mode) } {
-        Ok(_map) => { map = _map },
-        Err(e) => { return Err(e) }
-    }
+    let map : bloom_bitmap = try!(if in_memory { bloom_bitmap::from_file(0, params.bytes, mode) } else { bloom_bitmap::from_filename(bitmap_filename, params.bytes, true, mode) });
 
     return bloom_bloomfilter::new(params.k_num, 0, map, true);
 }
 
-// Loads the bloom filter with the bitmap from the given filename
-pub fn load_bloom_filter(params : &bloom_filter_params, count : u64, bitmap_filename : &str, in_memory : bool) -> Result<bloom_bloomfilter, String> {
-    let mode : u32 = if in_memory { bitmap_mode::ANONYMOUS } else { bitmap_mode::PERSISTENT } as u32;
+// Loads the bloom filter with the bitmap from the given filename, keeping it in the given I/O mode
+pub fn load_bloom_filter(params : &bloom_filter_params, count : u64, bitmap_filename : &str, in_memory : bool, io_mode : IoMode) -> BloomResult<bloom_bloomfilter> {
+    let mode : u32 = io_mode.bitmap_mode(in_memory) as u32;
 
-    let map : bloom_bitmap;
-    match bloom_bitmap::from_filename(bitmap_filename, params.bytes, false, mode) {
-        Ok(_map) => { map = _map },
-        Err(e) => { return Err(e) }
-    }
+    let map : bloom_bitmap = try!(bloom_bitmap::from_filename(bitmap_filename, params.bytes, false, mode));
 
     return bloom_bloomfilter::new(params.k_num, count, map, false);
 }
 
 // Expects capacity and probability to be set, and sets the bytes and k_num that should be used.
-pub fn params_for_capacity(params : &mut bloom_filter_params) -> Result<(), String> {
+pub fn params_for_capacity(params : &mut bloom_filter_params) -> BloomResult<()> {
     let value : i32 = unsafe { externals::bf_params_for_capacity(params as *mut bloom_filter_params) };
 
     if value < 0 {
-        return util::strerror(value);
+        return Err(params.invalid());
     }
 
     return Ok(());
 }
 
 // Expects capacity and probability to be set, computes the minimum byte size required.
-pub fn size_for_capacity_prob(params : &mut bloom_filter_params) -> Result<(), String> {
+pub fn size_for_capacity_prob(params : &mut bloom_filter_params) -> BloomResult<()> {
     let value : i32 = unsafe { externals::bf_size_for_capacity_prob(params as *mut bloom_filter_params) };
 
     if value < 0 {
-        return util::strerror(value);
+        return Err(params.invalid());
     }
 
     return Ok(());
 }
 
 //  Expects capacity and size to be set, computes the best false positive probability given an ideal k.
-pub fn fp_probability_for_capacity_size(params : &mut bloom_filter_params) -> Result<(), String> {
+pub fn fp_probability_for_capacity_size(params : &mut bloom_filter_params) -> BloomResult<()> {
     let value : i32 = unsafe { externals::bf_fp_probability_for_capacity_size(params as *mut bloom_filter_params) };
 
     if value < 0 {
-        return util::strerror(value);
+        return Err(params.invalid());
     }
 
     return Ok(());
 }
 
 //Expects bytes and probability to be set, computes the expected capacity.
-pub fn capacity_for_size_prob(params : &mut bloom_filter_params) -> Result<(), String> {
+pub fn capacity_for_size_prob(params : &mut bloom_filter_params) -> BloomResult<()> {
     let value : i32 = unsafe { externals::bf_capacity_for_size_prob(params as *mut bloom_filter_params) };
 
     if value < 0 {
-        return util::strerror(value);
+        return Err(params.invalid());
     }
 
     return Ok(());
 }
 
 // Expects bytes and capacity to be set, computes the ideal k num.
-pub fn ideal_k_num(params : &mut bloom_filter_params) -> Result<(), String> {
+pub fn ideal_k_num(params : &mut bloom_filter_params) -> BloomResult<()> {
     let value : i32 = unsafe { externals::bf_ideal_k_num(params as *mut bloom_filter_params) };
 
     if value < 0 {
-        return util::strerror(value);
+        return Err(params.invalid());
     }
 
     return Ok(());
 }
 
+// the whole of the C interface, not all of which is called from Rust
+#[allow(dead_code)]
 mod externals {
     use super::libc::{c_char, c_int, c_uint, c_ulong};
     use super::{bloom_bloomfilter, bloom_filter_params};
@@ -273,7 +307,9 @@ mod externals {
 
 #[cfg(test)]
 mod tests {
-    use super::{bloom_bloomfilter, bloom_filter_params, create_bloom_filter};
+    use super::{bloom_bloomfilter, bloom_filter_params, create_bloom_filter, create_bloom_filter_params};
+    use bitmap::IoMode;
+    use error::BloomError;
     use filter;
 
     static BITMAP_FILE : &'static str = "/tmp/map.bmp";
@@ -281,10 +317,19 @@ mod tests {
     #[test]
     fn test() {
         let params : bloom_filter_params = filter::test::create_bloom_filter_params();
-        let filter : bloom_bloomfilter = create_bloom_filter(&params, BITMAP_FILE);
+        let filter : bloom_bloomfilter = create_bloom_filter(&params, BITMAP_FILE, false, IoMode::Buffered).unwrap();
 
         filter::test::test_filter(Box::new(filter),
             &[[true, false, false], [false, true, false], [false, false, true]],
             &[[true, false, false], [true, true, false], [true, true, true]]);
     }
+
+    #[test]
+    fn test_invalid_params() {
+        match create_bloom_filter_params(1000, 1.5) {
+            Err(BloomError::InvalidParams(_)) => { },
+            Err(e) => { panic!("expected InvalidParams, got {}", e) },
+            Ok(_) => { panic!("expected a probability of 1.5 to be rejected") }
+        }
+    }
 }
@@ -0,0 +1,575 @@
+#![allow(unstable)]
+
+extern crate bloomd;
+
+use bloomd::{bloom_filter_header, bloom_filter_params, capacity_for_size_prob, IniFile, IoMode};
+use bloomd::config::{BloomConfig, BloomFilterConfig, ConfigOverride};
+use bloomd::error::{BloomError, BloomResult};
+use bloomd::loader::{KeyFormat, KeyReader};
+use bloomd::wrappers::{BloomFilter, BloomFilterCounters, FILTER_FOLDER_PREFIX};
+use std::os;
+use std::io;
+use std::io::fs;
+use std::io::fs::PathExtensions;
+use std::str::FromStr;
+
+// constants -------------------------------------------------------------------
+const USAGE : &'static str = "bloomctl [-f config_file] [--<option>=<value> ...] <command> <filter> [<args>]\r\n\r\n\
+Works on the filters in the data_dir of the config while the server is not running. Commands:\r\n\
+  inspect <filter>                          print the filter's parameters, counters and how full each layer is\r\n\
+  check <filter> <key_file>                 print how many layers contain each key in the file, one key per line\r\n\
+  add <filter> <key_file>                   add each key in the file to the filter\r\n\
+  verify <filter>                           check the size, header and key count of each bitmap against the metadata;\r\n\
+                                            the bits themselves aren't checksummed\r\n\
+  compact <filter>                          remove empty layers from the end of the filter\r\n\
+  export <filter> <data_dir>                copy the filter into another data_dir\r\n\
+  rebuild-metadata <filter> [<probability>] rewrite a lost ini file from the filter's bitmaps\r\n\
+  build <filter> <key_file> <directory>     build a new filter from a key file, outside the data_dir, for 'swap'";
+
+const BITMAP_EXTENSION : &'static str = "bmp";
+const KEY_BATCH_SIZE   : usize        = 10000;
+// -----------------------------------------------------------------------------
+
+fn main() {
+    // get command line arguments
+    let args = os::args();
+    let mut args = args.iter();
+    args.next();
+
+    let mut config_filename : Option<&str> = None;
+    let mut overrides : Vec<ConfigOverride> = ConfigOverride::from_env();
+    let mut command : Vec<&str> = Vec::new();
+
+    // read command line arguments
+    loop {
+        match args.next() {
+            Some(arg) => {
+                match arg.as_slice() {
+                    // config filename
+                    "-f" => {
+                        match args.next() {
+                            Some(value) => config_filename = Some(value.as_slice()),
+                            None => { return fail(format!("missing value for flag \"-f\"\r\n\r\n{}", USAGE)) }
+                        }
+                    },
+                    // --<option>=<value> overrides
+                    _ if arg.as_slice().starts_with("--") => {
+                        match ConfigOverride::from_arg(arg.as_slice()) {
+                            Some(config_override) => { overrides.push(config_override) },
+                            None => { return fail(format!("invalid argument: {}\r\n\r\n{}", arg, USAGE)) }
+                        }
+                    },
+                    // the command and its arguments
+                    _ => { command.push(arg.as_slice()) }
+                };
+            },
+            None => { break }
+        };
+    }
+
+    let config : BloomConfig = match BloomConfig::load(config_filename, overrides.as_slice()) {
+        Ok(config) => config,
+        Err(e) => { return fail(format!("{}", e)) }
+    };
+
+    if command.len() < 2 {
+        return fail(String::from_str(USAGE));
+    }
+
+    let mut directory : Path = Path::new(config.data_dir.as_slice());
+    directory.push(format!("{}{}", FILTER_FOLDER_PREFIX, command[1]).as_slice());
+
+    let filter_name : String = String::from_str(command[1]);
+
+    let result : BloomResult<bool> = match (command[0], command.len()) {
+        ("inspect", 2) => inspect(&directory, &filter_name),
+        ("check", 3) => check(&directory, &filter_name, command[2]),
+        ("add", 3) => add(&directory, &filter_name, command[2]),
+        ("verify", 2) => verify(&directory, &filter_name),
+        ("compact", 2) => compact(&directory, &filter_name),
+        ("export", 3) => export(&directory, &filter_name, command[2]),
+        ("build", 4) => build(&filter_name, command[2], command[3], &config),
+        ("rebuild-metadata", 2) => rebuild_metadata(&directory, &filter_name, config.default_probability),
+        ("rebuild-metadata", 3) => {
+            match FromStr::from_str(command[2]) {
+                Some(probability) => rebuild_metadata(&directory, &filter_name, probability),
+                None => { return fail(format!("invalid probability: {}", command[2])) }
+            }
+        },
+        _ => { return fail(String::from_str(USAGE)) }
+    };
+
+    match result {
+        Ok(true) => { },
+        Ok(false) => { os::set_exit_status(1) },
+        Err(e) => { fail(format!("{}: {}", filter_name, e)) }
+    }
+}
+
+// prints an error and sets a failing exit status
+fn fail(message : String) {
+    let _ = writeln!(&mut io::stderr(), "{}", message);
+    os::set_exit_status(1);
+}
+
+// prints the filter's parameters, counters, and the number of keys in each layer
+fn inspect(directory : &Path, filter_name : &String) -> BloomResult<bool> {
+    let filter : BloomFilter = try!(BloomFilter::from_directory(directory, filter_name, false));
+
+    println!("name {}", filter.config.filter_name);
+    println!("type {}", filter.config.filter_type);
+    println!("io_mode {}", filter.config.io_mode.name());
+    println!("capacity {}", filter.config.capacity);
+    println!("probability {}", filter.config.probability);
+    println!("k_num {}", filter.config.k_num);
+    println!("bytes {}", filter.config.bytes);
+    println!("in_memory {}", filter.config.in_memory);
+    println!("size {}", filter.config.size);
+    println!("expires_at {}", filter.config.expires_at);
+    println!("check_hits {}", filter.counters.check_hits);
+    println!("check_misses {}", filter.counters.check_misses);
+    println!("set_hits {}", filter.counters.set_hits);
+    println!("set_misses {}", filter.counters.set_misses);
+    println!("page_ins {}", filter.counters.page_ins);
+    println!("page_outs {}", filter.counters.page_outs);
+    println!("partitions {}", filter.partitions.len());
+    println!("layers {}", filter.layer_count());
+
+    print_layers(&filter, "");
+
+    for (index, partition) in filter.partitions.iter().enumerate() {
+        print_layers(partition, format!("partition {} ", index).as_slice());
+    }
+
+    return Ok(true);
+}
+
+// prints how full each layer of a layered filter, or of a partition, is; every layer is sized for its capacity
+fn print_layers(filter : &BloomFilter, label : &str) {
+    for (index, bitmap_filename) in filter.config.bitmap_filenames.iter().enumerate() {
+        let size : u64 = filter.config.filter_sizes[index];
+
+        println!("{}layer {} size {} fill {:.2}% {}", label, index + 1, size, size as f64 * 100.0 / filter.config.capacity as f64, bitmap_filename);
+    }
+}
+
+// prints how many layers contain each key in the key file, without changing the filter on disk
+fn check(directory : &Path, filter_name : &String, key_filename : &str) -> BloomResult<bool> {
+    let mut filter : BloomFilter = try!(BloomFilter::from_directory(directory, filter_name, true));
+    let mut reader : KeyReader = try!(KeyReader::open(key_filename, KeyFormat::Lines));
+
+    loop {
+        let batch : Vec<String> = try!(reader.next_batch(KEY_BATCH_SIZE));
+
+        if batch.is_empty() {
+            break;
+        }
+
+        for key in batch.into_iter() {
+            let value : u32 = try!(filter.check(key.clone()));
+
+            println!("{} {}", value, key);
+        }
+    }
+
+    return Ok(true);
+}
+
+// adds each key in the key file to the filter, and writes it back to disk
+fn add(directory : &Path, filter_name : &String, key_filename : &str) -> BloomResult<bool> {
+    let mut filter : BloomFilter = try!(BloomFilter::from_directory(directory, filter_name, true));
+    let mut reader : KeyReader = try!(KeyReader::open(key_filename, KeyFormat::Lines));
+    let mut keys : usize = 0;
+    let mut new_keys : usize = 0;
+
+    loop {
+        let batch : Vec<String> = try!(reader.next_batch(KEY_BATCH_SIZE));
+
+        if batch.is_empty() {
+            break;
+        }
+
+        keys += batch.len();
+
+        for key in batch.into_iter() {
+            // a key that lands in the first layer wasn't in the filter before
+            if try!(filter.set(key)) == 1 {
+                new_keys += 1;
+            }
+        }
+    }
+
+    try!(filter.flush());
+
+    println!("Added {} keys, {} new", keys, new_keys);
+
+    return Ok(true);
+}
+
+// checks that each bitmap exists, has the size and header the metadata says it should,
+// and that the layers don't hold more keys than the layers below them; a partitioned filter's
+// partitions are each checked in turn. Only the metadata is compared; a bit flipped inside
+// a bitmap goes unnoticed
+fn verify(directory : &Path, filter_name : &String) -> BloomResult<bool> {
+    let filter : BloomFilter = try!(BloomFilter::from_directory(directory, filter_name, false));
+    let mut problems : Vec<String> = Vec::new();
+
+    try!(verify_layers(&filter, "", &mut problems));
+
+    for (index, partition) in filter.partitions.iter().enumerate() {
+        try!(verify_layers(partition, format!("partition {} ", index).as_slice(), &mut problems));
+    }
+
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+
+    if problems.is_empty() {
+        println!("OK: every bitmap matches the metadata");
+    }
+
+    return Ok(problems.is_empty());
+}
+
+// checks the bitmaps of a layered filter, or of a partition, against its metadata, adding what doesn't match to the problems
+fn verify_layers(filter : &BloomFilter, label : &str, problems : &mut Vec<String>) -> BloomResult<()> {
+    for (index, bitmap_filename) in filter.config.bitmap_filenames.iter().enumerate() {
+        let layer : usize = index + 1;
+        let path : Path = Path::new(bitmap_filename.as_slice());
+
+        if !path.exists() {
+            problems.push(format!("{}layer {}: {} is missing", label, layer, bitmap_filename));
+            continue;
+        }
+
+        let bytes : u64 = try!(fs::stat(&path)).size;
+        if bytes != filter.config.bytes {
+            problems.push(format!("{}layer {}: {} is {} bytes, expected {}", label, layer, bitmap_filename, bytes, filter.config.bytes));
+        }
+
+        let header : bloom_filter_header = try!(bloom_filter_header::from_filename(bitmap_filename.as_slice()));
+        if !header.is_valid() {
+            problems.push(format!("{}layer {}: {} has a bad magic header", label, layer, bitmap_filename));
+            continue;
+        }
+
+        if header.k_num != filter.config.k_num {
+            problems.push(format!("{}layer {}: k_num is {}, expected {}", label, layer, header.k_num, filter.config.k_num));
+        }
+
+        if header.count != filter.config.filter_sizes[index] {
+            problems.push(format!("{}layer {}: holds {} keys, metadata says {}", label, layer, header.count, filter.config.filter_sizes[index]));
+        }
+
+        if index > 0 && filter.config.filter_sizes[index] > filter.config.filter_sizes[index - 1] {
+            problems.push(format!("{}layer {}: holds more keys than layer {}", label, layer, layer - 1));
+        }
+    }
+
+    return Ok(());
+}
+
+// removes the empty layers at the end of the filter, or of each of its partitions, along with their bitmaps
+fn compact(directory : &Path, filter_name : &String) -> BloomResult<bool> {
+    let mut filter : BloomFilter = try!(BloomFilter::from_directory(directory, filter_name, false));
+    let mut removed : usize = try!(compact_layers(&mut filter));
+
+    for partition in filter.partitions.iter_mut() {
+        removed += try!(compact_layers(partition));
+    }
+
+    // the filter isn't loaded, so only its metadata is written
+    try!(filter.flush());
+
+    println!("Removed {} empty layers", removed);
+
+    return Ok(true);
+}
+
+// removes the empty layers at the end of a layered filter, or of a partition; returns the number removed
+fn compact_layers(filter : &mut BloomFilter) -> BloomResult<usize> {
+    let mut removed : usize = 0;
+
+    while filter.config.filter_sizes.last() == Some(&0) {
+        filter.config.filter_sizes.pop();
+
+        let bitmap_filename : String = filter.config.bitmap_filenames.pop().unwrap();
+        let path : Path = Path::new(bitmap_filename);
+
+        if path.exists() {
+            try!(fs::unlink(&path));
+        }
+
+        removed += 1;
+    }
+
+    filter.mark_dirty();
+
+    return Ok(removed);
+}
+
+// copies the filter into another data_dir, partitions and all, pointing its metadata at the copied bitmaps
+fn export(directory : &Path, filter_name : &String, data_dir : &str) -> BloomResult<bool> {
+    let mut filter : BloomFilter = try!(BloomFilter::from_directory(directory, filter_name, false));
+
+    let mut export_directory : Path = Path::new(data_dir);
+    export_directory.push(format!("{}{}", FILTER_FOLDER_PREFIX, filter_name).as_slice());
+
+    if export_directory.exists() {
+        return Err(BloomError::InvalidParams(format!("{} already exists", export_directory.display())));
+    }
+
+    let sources : Vec<Path> = filter.files();
+
+    // the data_dir may not exist yet; the filter's own directory is made by init
+    try!(fs::mkdir_recursive(&export_directory.dir_path(), io::USER_RWX));
+
+    filter.relocate(export_directory.clone());
+    try!(filter.init());
+
+    // the bitmaps are copied as they are, and the ini files written out afresh by the flush
+    for (source, destination) in sources.iter().zip(filter.files().iter()) {
+        if source.extension_str() == Some(BITMAP_EXTENSION) {
+            try!(fs::copy(source, destination));
+        }
+    }
+
+    try!(filter.flush());
+
+    println!("Exported to {}", export_directory.display());
+
+    return Ok(true);
+}
+
+// builds a new filter from the keys in a key file, in a directory of its own, so it can be
+// swapped into a running server; its capacity and probability come from the config
+fn build(filter_name : &String, key_filename : &str, directory : &str, config : &BloomConfig) -> BloomResult<bool> {
+    let directory : Path = Path::new(directory);
+
+    if directory.exists() {
+        return Err(BloomError::InvalidParams(format!("{} already exists", directory.display())));
+    }
+
+    let io_mode : IoMode = IoMode::from_use_mmap(config.use_mmap);
+    let mut filter : BloomFilter = try!(BloomFilter::create(filter_name.as_slice(), config.initial_capacity, config.default_probability, io_mode, directory.clone()));
+    let mut reader : KeyReader = try!(KeyReader::open(key_filename, KeyFormat::Lines));
+    let mut keys : u64 = 0;
+
+    loop {
+        let batch : Vec<String> = try!(reader.next_batch(KEY_BATCH_SIZE));
+
+        if batch.is_empty() {
+            break;
+        }
+
+        keys += try!(filter.load_keys(batch.as_slice()));
+    }
+
+    try!(filter.flush());
+
+    println!("Built {} with {} keys in {} layers", directory.display(), keys, filter.config.bitmap_filenames.len());
+
+    return Ok(true);
+}
+
+// rewrites the filter's ini file from the headers of its bitmaps; the counters start over,
+// and the capacity is worked out from the bitmap size and the given probability
+fn rebuild_metadata(directory : &Path, filter_name : &String, probability : f64) -> BloomResult<bool> {
+    if !directory.exists() {
+        return Err(BloomError::NotFound(format!("directory {} does not exist", directory.display())));
+    }
+
+    // layers are named after their index, starting from 0
+    let mut layers : Vec<(u32, Path)> = Vec::new();
+    for path in try!(fs::readdir(directory)).into_iter() {
+        if path.extension_str() == Some(BITMAP_EXTENSION) {
+            match path.filestem_str().and_then(|stem| FromStr::from_str(stem)) {
+                Some(index) => { layers.push((index, path.clone())) },
+                None => { }
+            }
+        }
+    }
+    layers.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+
+    if layers.is_empty() {
+        return Err(BloomError::NotFound(format!("no bitmaps in {}", directory.display())));
+    }
+
+    let mut params : bloom_filter_params = bloom_filter_params::empty();
+    let mut bitmap_filenames : Vec<String> = Vec::new();
+    let mut filter_sizes : Vec<u64> = Vec::new();
+
+    for &(index, ref path) in layers.iter() {
+        if index as usize != bitmap_filenames.len() {
+            return Err(BloomError::Corrupt(format!("layer {} is missing", bitmap_filenames.len())));
+        }
+
+        let bitmap_filename : String = String::from_str(path.as_str().unwrap());
+        let header : bloom_filter_header = try!(bloom_filter_header::from_filename(bitmap_filename.as_slice()));
+        let bytes : u64 = try!(fs::stat(path)).size;
+
+        if !header.is_valid() {
+            return Err(BloomError::Corrupt(format!("{} has a bad magic header", bitmap_filename)));
+        }
+
+        if index == 0 {
+            params.k_num = header.k_num;
+            params.bytes = bytes;
+        } else if header.k_num != params.k_num || bytes != params.bytes {
+            return Err(BloomError::Corrupt(format!("{} doesn't match the size or k_num of the first layer", bitmap_filename)));
+        }
+
+        bitmap_filenames.push(bitmap_filename);
+        filter_sizes.push(header.count);
+    }
+
+    params.fp_probability = probability;
+    try!(capacity_for_size_prob(&mut params));
+
+    let mut config : BloomFilterConfig = BloomFilterConfig::new(filter_name.clone(), params.capacity, probability, params.k_num, false, params.bytes);
+    config.size = filter_sizes[0];
+    config.bitmap_filenames = bitmap_filenames;
+    config.filter_sizes = filter_sizes;
+
+    let mut config_file : Path = directory.clone();
+    config_file.push(filter_name.as_slice());
+    config_file.set_extension("ini");
+
+    let mut ini : IniFile = IniFile::new();
+    config.add_to_ini(&mut ini);
+    BloomFilterCounters::new().add_to_ini(&mut ini);
+    try!(ini.write_to_path(&config_file));
+
+    println!("Rebuilt {} with {} layers, capacity {}", config_file.display(), config.bitmap_filenames.len(), config.capacity);
+
+    return Ok(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add, check, compact, export, inspect, rebuild_metadata, verify};
+    use bloomd::IoMode;
+    use bloomd::config::BloomFilterConfig;
+    use bloomd::wrappers::BloomFilter;
+    use std::io::{fs, File, TempDir};
+    use std::io::fs::PathExtensions;
+
+    #[test]
+    fn test_rebuild_metadata() {
+        let data_dir : TempDir = TempDir::new("bloomctl-test-rebuild").unwrap();
+        let directory : Path = data_dir.path().join("filter.offline");
+
+        let filter_name : String = String::from_str("offline");
+        let mut filter : BloomFilter = BloomFilter::create(filter_name.as_slice(), 10000, 0.001, IoMode::Buffered, directory.clone()).unwrap();
+
+        filter.set(String::from_str("first")).unwrap();
+        filter.set(String::from_str("first")).unwrap();
+        filter.set(String::from_str("second")).unwrap();
+        filter.flush().unwrap();
+        drop(filter);
+
+        assert!(verify(&directory, &filter_name).unwrap());
+
+        // The metadata comes back from the bitmaps alone
+        let mut config_file : Path = directory.clone();
+        config_file.push("offline.ini");
+        fs::unlink(&config_file).unwrap();
+
+        assert!(rebuild_metadata(&directory, &filter_name, 0.001).unwrap());
+        assert!(verify(&directory, &filter_name).unwrap());
+
+        let filter : BloomFilter = BloomFilter::from_directory(&directory, &filter_name, false).unwrap();
+        assert_eq!(filter.config.filter_sizes, vec![2, 1]);
+        assert_eq!(filter.config.size, 2);
+
+        // Nothing to compact while every layer holds keys
+        assert!(compact(&directory, &filter_name).unwrap());
+        assert_eq!(BloomFilter::from_directory(&directory, &filter_name, false).unwrap().config.bitmap_filenames.len(), 2);
+    }
+
+    #[test]
+    fn test_add_check_export() {
+        let data_dir : TempDir = TempDir::new("bloomctl-test-export").unwrap();
+        let filter_name : String = String::from_str("keys");
+        let directory : Path = data_dir.path().join("filter.keys");
+
+        BloomFilter::create(filter_name.as_slice(), 10000, 0.001, IoMode::Mmap, directory.clone()).unwrap().flush().unwrap();
+
+        // Blank lines in the key file are skipped
+        let key_file : Path = data_dir.path().join("keys.txt");
+        File::create(&key_file).unwrap().write_str("first\n\nsecond\n").unwrap();
+
+        assert!(add(&directory, &filter_name, key_file.as_str().unwrap()).unwrap());
+        assert!(check(&directory, &filter_name, key_file.as_str().unwrap()).unwrap());
+        assert!(inspect(&directory, &filter_name).unwrap());
+        assert_eq!(BloomFilter::from_directory(&directory, &filter_name, false).unwrap().config.size, 2);
+
+        // The export goes into a data_dir that doesn't exist yet, and loads from there
+        let export_dir : Path = data_dir.path().join("exported").join("data");
+        assert!(export(&directory, &filter_name, export_dir.as_str().unwrap()).unwrap());
+
+        let exported_directory : Path = export_dir.join("filter.keys");
+        assert!(verify(&exported_directory, &filter_name).unwrap());
+
+        let mut exported : BloomFilter = BloomFilter::from_directory(&exported_directory, &filter_name, true).unwrap();
+        assert_eq!(exported.check(String::from_str("second")).unwrap(), 1);
+        assert_eq!(exported.check(String::from_str("third")).unwrap(), 0);
+
+        // An existing export is left alone
+        assert!(export(&directory, &filter_name, export_dir.as_str().unwrap()).is_err());
+
+        // Missing key files are errors
+        assert!(add(&directory, &filter_name, data_dir.path().join("missing.txt").as_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_partitioned() {
+        let data_dir : TempDir = TempDir::new("bloomctl-test-partitioned").unwrap();
+        let filter_name : String = String::from_str("parts");
+        let directory : Path = data_dir.path().join("filter.parts");
+
+        let mut config : BloomFilterConfig = BloomFilterConfig::new(filter_name.clone(), 10000, 0.001, 0, false, 0);
+        config.partitions = 4;
+
+        {
+            let mut filter : BloomFilter = BloomFilter::new_partitioned(config, directory.clone()).unwrap();
+            filter.init().unwrap();
+
+            for i in (0..100) {
+                filter.set(format!("key{}", i)).unwrap();
+            }
+
+            filter.flush().unwrap();
+        }
+
+        // Each partition's layers are inspected, verified and compacted in turn
+        assert!(inspect(&directory, &filter_name).unwrap());
+        assert!(verify(&directory, &filter_name).unwrap());
+        assert!(compact(&directory, &filter_name).unwrap());
+        assert!(verify(&directory, &filter_name).unwrap());
+
+        // A missing partition bitmap is caught
+        let bitmap : Path = directory.join("partition.2.0.bmp");
+        fs::rename(&bitmap, &data_dir.path().join("moved.bmp")).unwrap();
+        assert!(!verify(&directory, &filter_name).unwrap());
+        fs::rename(&data_dir.path().join("moved.bmp"), &bitmap).unwrap();
+
+        // The export carries every partition along
+        let export_dir : Path = data_dir.path().join("exported");
+        assert!(export(&directory, &filter_name, export_dir.as_str().unwrap()).unwrap());
+
+        let exported_directory : Path = export_dir.join("filter.parts");
+        assert!(verify(&exported_directory, &filter_name).unwrap());
+
+        for index in (0..4) {
+            assert!(exported_directory.join(format!("partition.{}.0.bmp", index)).exists());
+        }
+
+        let mut exported : BloomFilter = BloomFilter::from_directory(&exported_directory, &filter_name, true).unwrap();
+        assert_eq!(exported.partitions.len(), 4);
+
+        for i in (0..100) {
+            assert_eq!(exported.check(format!("key{}", i)).unwrap(), 1);
+        }
+    }
+}
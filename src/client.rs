@@ -0,0 +1,560 @@
+use snapshot::FilterSnapshot;
+use wrappers::BloomFilterCounters;
+use std::collections::HashMap;
+use std::error::{Error, FromError};
+use std::fmt;
+use std::io::{BufferedStream, IoError, TcpStream};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+// constants -------------------------------------------------------------------
+const RESPONSE_START        : &'static str = "START";
+const RESPONSE_END          : &'static str = "END";
+const RESPONSE_DONE         : &'static str = "Done";
+const RESPONSE_EXISTS       : &'static str = "Exists";
+const RESPONSE_NO_EXIST     : &'static str = "Filter does not exist";
+const RESPONSE_CLIENT_ERROR : &'static str = "Client Error:";
+const RESPONSE_SERVER_ERROR : &'static str = "Server Error:";
+
+const DEFAULT_POOL_SIZE     : usize = 4;
+const DEFAULT_BATCH_SIZE    : usize = 128;
+
+// commands that change nothing if the server already ran them, so they can be sent again
+// when a pooled connection turns out to be closed
+const RETRYABLE_COMMANDS    : [&'static str; 5] = ["check", "multi", "info", "list", "flush"];
+// -----------------------------------------------------------------------------
+
+// The ways a request to a bloomd server can fail
+#[derive(Clone, Debug)]
+pub enum ClientError {
+    Io(IoError),        // talking to the server failed; the connection is not reused
+    NoFilter(String),   // the named filter does not exist
+    InvalidKey(String), // the key is empty or contains whitespace, so it can't be sent
+    Client(String),     // the server rejected the request with a Client Error
+    Server(String),     // the server failed to serve the request with a Server Error
+    Protocol(String),   // the server sent a response the client doesn't understand
+    NoServers           // a sharded client was given no servers to send requests to
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+impl fmt::String for ClientError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            ClientError::Io(ref e) => write!(f, "I/O error: {}", e),
+            ClientError::NoFilter(ref name) => write!(f, "filter {} does not exist", name),
+            ClientError::InvalidKey(ref key) => write!(f, "invalid key \"{}\"", key),
+            ClientError::Client(ref message) => write!(f, "{} {}", RESPONSE_CLIENT_ERROR, message),
+            ClientError::Server(ref message) => write!(f, "{} {}", RESPONSE_SERVER_ERROR, message),
+            ClientError::Protocol(ref response) => write!(f, "unexpected response \"{}\"", response),
+            ClientError::NoServers => write!(f, "no servers given")
+        };
+    }
+}
+
+impl Error for ClientError {
+    fn description(&self) -> &str {
+        return match *self {
+            ClientError::Io(ref e) => e.description(),
+            ClientError::NoFilter(_) => "filter does not exist",
+            ClientError::InvalidKey(_) => "invalid key",
+            ClientError::Client(_) => "client error",
+            ClientError::Server(_) => "server error",
+            ClientError::Protocol(_) => "unexpected response",
+            ClientError::NoServers => "no servers given"
+        };
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        return match *self {
+            ClientError::Io(ref e) => Some(e as &Error),
+            _ => None
+        };
+    }
+}
+
+impl FromError<IoError> for ClientError {
+    fn from_error(error : IoError) -> Self {
+        return ClientError::Io(error);
+    }
+}
+
+// A filter as reported by 'list'
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterListing {
+    pub name        : String,
+    pub probability : f64,
+    pub bytes       : u64,
+    pub capacity    : u64,
+    pub size        : u64,
+    pub ttl         : Option<u64> // Seconds left to live, if the filter has a ttl
+}
+
+// A filter as reported by 'info': its settings and its counters
+#[derive(Clone, Debug)]
+pub struct FilterInfo {
+    pub capacity    : u64,
+    pub probability : f64,
+    pub k_num       : u32,
+    pub in_memory   : bool,                // The filter is kept in memory only, never written to disk
+    pub layers      : u32,                 // Layers in the filter, across every partition
+    pub size        : u64,
+    pub bytes       : u64,                 // Reported as 'storage'
+    pub ttl         : Option<u64>,         // Seconds left to live, if the filter has a ttl
+    pub partitions  : Option<u32>,         // Partitions the keys are spread across, if the filter is partitioned
+    pub counters    : BloomFilterCounters
+}
+
+// A single connection to a bloomd server
+pub struct Connection {
+    stream : BufferedStream<TcpStream>
+}
+
+impl Connection {
+    // Connects to the server at the given <host>:<port>
+    pub fn connect(address : &str) -> ClientResult<Self> {
+        let stream : TcpStream = try!(TcpStream::connect(address));
+
+        return Ok(Connection { stream: BufferedStream::new(stream) });
+    }
+
+    // Sends a command and returns the response; a START ... END block is returned as its lines
+    pub fn request(&mut self, command : &str) -> ClientResult<Vec<String>> {
+        try!(self.stream.write_str(command));
+        try!(self.stream.write_str("\r\n"));
+        try!(self.stream.flush());
+
+        let line : String = try!(self.read_line());
+
+        if line.as_slice() != RESPONSE_START {
+            return Ok(vec![line]);
+        }
+
+        let mut lines : Vec<String> = Vec::new();
+
+        loop {
+            let line : String = try!(self.read_line());
+
+            if line.as_slice() == RESPONSE_END {
+                return Ok(lines);
+            }
+
+            lines.push(line);
+        }
+    }
+
+    // Sends a command expecting a single line response, and returns it
+    pub fn request_line(&mut self, command : &str) -> ClientResult<String> {
+        let mut lines : Vec<String> = try!(self.request(command));
+
+        if lines.len() != 1 {
+            return Err(ClientError::Protocol(lines.connect("\r\n")));
+        }
+
+        return Ok(lines.pop().unwrap());
+    }
+
+    // Asks for a copy of a filter's files; returns them, if the server sent any, along with the response line
+    pub fn export(&mut self, filter : &str) -> ClientResult<(Option<FilterSnapshot>, String)> {
+        try!(self.stream.write_str(format!("export {}\r\n", filter).as_slice()));
+        try!(self.stream.flush());
+
+        let line : String = try!(self.read_line());
+
+        if !FilterSnapshot::is_header(line.as_slice()) {
+            return Ok((None, line));
+        }
+
+        let snapshot : FilterSnapshot = try!(FilterSnapshot::read_from(&mut self.stream, line.as_slice()));
+
+        return Ok((Some(snapshot), try!(self.read_line())));
+    }
+
+    // Sends a copy of a filter's files to be imported, and returns the response line
+    pub fn import(&mut self, snapshot : &FilterSnapshot) -> ClientResult<String> {
+        try!(self.stream.write_str(format!("import {}\r\n", snapshot.name).as_slice()));
+        try!(snapshot.write_to(&mut self.stream));
+        try!(self.stream.flush());
+
+        return self.read_line();
+    }
+
+    // reads a line, without its line ending
+    fn read_line(&mut self) -> ClientResult<String> {
+        let line : String = try!(self.stream.read_line());
+        let chars_to_trim : &[char] = &['\n', '\r'];
+
+        return Ok(String::from_str(line.as_slice().trim_right_matches(chars_to_trim)));
+    }
+}
+
+// A client for a bloomd server, keeping a pool of idle connections that
+// requests are sent over; it can be shared between threads
+pub struct Client {
+    address        : String,
+    password       : Option<String>,
+    pool           : Mutex<Vec<Connection>>,
+    pub pool_size  : usize, // Most idle connections kept open
+    pub batch_size : usize  // Most keys sent in a single 'bulk' or 'multi'
+}
+
+impl Client {
+    // Returns a client for the server at the given <host>:<port>; connections are opened as needed
+    pub fn new(address : &str) -> Self {
+        return Client {
+            address: String::from_str(address),
+            password: None,
+            pool: Mutex::new(Vec::new()),
+            pool_size: DEFAULT_POOL_SIZE,
+            batch_size: DEFAULT_BATCH_SIZE
+        };
+    }
+
+    // Returns a client that authenticates every connection it opens with the given password
+    pub fn with_password(address : &str, password : &str) -> Self {
+        let mut client : Client = Client::new(address);
+        client.password = Some(String::from_str(password));
+
+        return client;
+    }
+
+    // Creates a filter, with options such as "capacity=100000"; returns false if it already exists
+    pub fn create(&self, filter : &str, options : &[&str]) -> ClientResult<bool> {
+        let mut command : String = format!("create {}", filter);
+
+        for option in options.iter() {
+            command.push_str(" ");
+            command.push_str(*option);
+        }
+
+        let response : String = try!(self.request_line(filter, command.as_slice()));
+
+        return match response.as_slice() {
+            RESPONSE_DONE => Ok(true),
+            RESPONSE_EXISTS => Ok(false),
+            _ => Err(ClientError::Protocol(response))
+        };
+    }
+
+    // Drops a filter, deleting it from the server's disk
+    pub fn drop_filter(&self, filter : &str) -> ClientResult<()> {
+        return self.request_done(filter, format!("drop {}", filter).as_slice());
+    }
+
+    // Closes a filter, unloading it from the server's memory
+    pub fn close(&self, filter : &str) -> ClientResult<()> {
+        return self.request_done(filter, format!("close {}", filter).as_slice());
+    }
+
+    // Clears a closed filter from the server's list, leaving it on disk
+    pub fn clear(&self, filter : &str) -> ClientResult<()> {
+        return self.request_done(filter, format!("clear {}", filter).as_slice());
+    }
+
+    // Flushes a filter to disk, or every filter if none is given
+    pub fn flush(&self, filter : Option<&str>) -> ClientResult<()> {
+        return match filter {
+            Some(filter) => self.request_done(filter, format!("flush {}", filter).as_slice()),
+            None => self.request_done("", "flush")
+        };
+    }
+
+    // Adds a key to a filter, returning the layer it was added to, or 0 if every layer had it
+    pub fn set(&self, filter : &str, key : &str) -> ClientResult<u32> {
+        try!(check_key(key));
+
+        let response : String = try!(self.request_line(filter, format!("set {} {}", filter, key).as_slice()));
+
+        return parse_count(response);
+    }
+
+    // Returns the number of layers of a filter that contain the key
+    pub fn check(&self, filter : &str, key : &str) -> ClientResult<u32> {
+        try!(check_key(key));
+
+        let response : String = try!(self.request_line(filter, format!("check {} {}", filter, key).as_slice()));
+
+        return parse_count(response);
+    }
+
+    // Adds the keys to a filter, in 'bulk' commands of at most batch_size keys; returns the result of each set
+    pub fn bulk(&self, filter : &str, keys : &[&str]) -> ClientResult<Vec<u32>> {
+        return self.request_batched("bulk", filter, keys);
+    }
+
+    // Checks the keys against a filter, in 'multi' commands of at most batch_size keys; returns the result of each check
+    pub fn multi(&self, filter : &str, keys : &[&str]) -> ClientResult<Vec<u32>> {
+        return self.request_batched("multi", filter, keys);
+    }
+
+    // Lists the filters whose names start with the prefix, or every filter if none is given
+    pub fn list(&self, prefix : Option<&str>) -> ClientResult<Vec<FilterListing>> {
+        let command : String = match prefix {
+            Some(prefix) => format!("list {}", prefix),
+            None => String::from_str("list")
+        };
+
+        let lines : Vec<String> = try!(self.request("", command.as_slice()));
+        let mut filters : Vec<FilterListing> = Vec::new();
+
+        for line in lines.iter() {
+            let fields : Vec<&str> = line.as_slice().split(' ').collect();
+
+            if fields.len() != 6 {
+                return Err(ClientError::Protocol(line.clone()));
+            }
+
+            filters.push(FilterListing {
+                name: String::from_str(fields[0]),
+                probability: try!(parse_field(line, fields[1])),
+                bytes: try!(parse_field(line, fields[2])),
+                capacity: try!(parse_field(line, fields[3])),
+                size: try!(parse_field(line, fields[4])),
+                ttl: if fields[5] == "-" { None } else { Some(try!(parse_field(line, fields[5]))) }
+            });
+        }
+
+        return Ok(filters);
+    }
+
+    // Returns the settings and counters of a filter
+    pub fn info(&self, filter : &str) -> ClientResult<FilterInfo> {
+        let lines : Vec<String> = try!(self.request(filter, format!("info {}", filter).as_slice()));
+
+        let mut values : HashMap<String, String> = HashMap::new();
+        for line in lines.iter() {
+            let fields : Vec<&str> = line.as_slice().splitn(1, ' ').collect();
+
+            if fields.len() != 2 {
+                return Err(ClientError::Protocol(line.clone()));
+            }
+
+            values.insert(String::from_str(fields[0]), String::from_str(fields[1]));
+        }
+
+        return Ok(FilterInfo {
+            capacity: try!(info_value(&values, "capacity")),
+            probability: try!(info_value(&values, "probability")),
+            k_num: try!(info_value(&values, "k_num")),
+            in_memory: try!(info_value::<u8>(&values, "in_memory")) != 0,
+            layers: try!(info_value(&values, "layers")),
+            size: try!(info_value(&values, "size")),
+            bytes: try!(info_value(&values, "storage")),
+            ttl: match values.get("ttl") {
+                Some(_) => Some(try!(info_value(&values, "ttl"))),
+                None => None
+            },
+            partitions: match values.get("partitions") {
+                Some(_) => Some(try!(info_value(&values, "partitions"))),
+                None => None
+            },
+            counters: BloomFilterCounters {
+                check_hits: try!(info_value(&values, "check_hits")),
+                check_misses: try!(info_value(&values, "check_misses")),
+                set_hits: try!(info_value(&values, "set_hits")),
+                set_misses: try!(info_value(&values, "set_misses")),
+                page_ins: try!(info_value(&values, "page_ins")),
+                page_outs: try!(info_value(&values, "page_outs"))
+            }
+        });
+    }
+
+    // Returns a copy of a filter's files, e.g. to import into another server; in-memory filters can't be exported
+    pub fn export(&self, filter : &str) -> ClientResult<FilterSnapshot> {
+        let (snapshot, response) : (Option<FilterSnapshot>, String) = try!(self.with_connection(true, |connection| connection.export(filter)));
+
+        try!(check_response(filter, response.as_slice()));
+
+        return match snapshot {
+            Some(snapshot) if response.as_slice() == RESPONSE_DONE => Ok(snapshot),
+            _ => Err(ClientError::Protocol(response))
+        };
+    }
+
+    // Puts an exported filter in place of the server's filter with the same name, if any, which is deleted
+    pub fn import(&self, snapshot : &FilterSnapshot) -> ClientResult<()> {
+        let response : String = try!(self.with_connection(false, |connection| connection.import(snapshot)));
+
+        try!(check_response(snapshot.name.as_slice(), response.as_slice()));
+
+        if response.as_slice() != RESPONSE_DONE {
+            return Err(ClientError::Protocol(response));
+        }
+
+        return Ok(());
+    }
+
+    // Returns the number of idle connections in the pool
+    pub fn idle_connections(&self) -> usize {
+        return self.pool.lock().unwrap().len();
+    }
+
+    // sends the keys in batches of the given command, and collects the counts from each response;
+    // every key is checked first, so an invalid key doesn't leave the earlier batches applied
+    fn request_batched(&self, command : &str, filter : &str, keys : &[&str]) -> ClientResult<Vec<u32>> {
+        for key in keys.iter() {
+            try!(check_key(*key));
+        }
+
+        let mut counts : Vec<u32> = Vec::with_capacity(keys.len());
+
+        for batch in keys.chunks(if self.batch_size > 0 { self.batch_size } else { DEFAULT_BATCH_SIZE }) {
+            let mut request : String = format!("{} {}", command, filter);
+
+            for key in batch.iter() {
+                request.push_str(" ");
+                request.push_str(*key);
+            }
+
+            let response : String = try!(self.request_line(filter, request.as_slice()));
+
+            for count in response.as_slice().split(' ') {
+                counts.push(try!(parse_count(String::from_str(count))));
+            }
+        }
+
+        if counts.len() != keys.len() {
+            return Err(ClientError::Protocol(format!("expected {} results, got {}", keys.len(), counts.len())));
+        }
+
+        return Ok(counts);
+    }
+
+    // sends a command expecting 'Done'
+    fn request_done(&self, filter : &str, command : &str) -> ClientResult<()> {
+        let response : String = try!(self.request_line(filter, command));
+
+        if response.as_slice() != RESPONSE_DONE {
+            return Err(ClientError::Protocol(response));
+        }
+
+        return Ok(());
+    }
+
+    // sends a command expecting a single line, turning error responses into errors
+    fn request_line(&self, filter : &str, command : &str) -> ClientResult<String> {
+        let mut lines : Vec<String> = try!(self.request(filter, command));
+
+        if lines.len() != 1 {
+            return Err(ClientError::Protocol(lines.connect("\r\n")));
+        }
+
+        return Ok(lines.pop().unwrap());
+    }
+
+    // sends a command over a pooled connection, turning error responses into errors
+    fn request(&self, filter : &str, command : &str) -> ClientResult<Vec<String>> {
+        let retryable : bool = RETRYABLE_COMMANDS.contains(&command.split(' ').next().unwrap_or(""));
+        let lines : Vec<String> = try!(self.with_connection(retryable, |connection| connection.request(command)));
+
+        if lines.len() == 1 {
+            try!(check_response(filter, lines[0].as_slice()));
+        }
+
+        return Ok(lines);
+    }
+
+    // runs an exchange over a pooled connection, returning the connection to the pool afterwards
+    // only a retryable exchange is sent again after an I/O error, as the server may have applied it
+    // before the connection dropped
+    fn with_connection<T, F : Fn(&mut Connection) -> ClientResult<T>>(&self, retryable : bool, exchange : F) -> ClientResult<T> {
+        let pooled : Option<Connection> = self.pool.lock().unwrap().pop();
+
+        let (connection, result) : (Connection, ClientResult<T>) = match pooled {
+            Some(mut connection) => {
+                match exchange(&mut connection) {
+                    // the server may have closed the connection while it sat idle, so the exchange
+                    // is tried once more on a new one
+                    Err(ClientError::Io(_)) if retryable => try!(self.exchange_on_new_connection(&exchange)),
+                    result => (connection, result)
+                }
+            },
+            None => try!(self.exchange_on_new_connection(&exchange))
+        };
+
+        // a connection that failed mid-request is in an unknown state, so it is dropped
+        let result : T = try!(result);
+
+        {
+            let mut pool = self.pool.lock().unwrap();
+
+            if pool.len() < self.pool_size {
+                pool.push(connection);
+            }
+        }
+
+        return Ok(result);
+    }
+
+    // runs an exchange over a newly opened connection, returning the connection along with the result
+    fn exchange_on_new_connection<T, F : Fn(&mut Connection) -> ClientResult<T>>(&self, exchange : &F) -> ClientResult<(Connection, ClientResult<T>)> {
+        let mut connection : Connection = try!(self.open_connection());
+        let result : ClientResult<T> = exchange(&mut connection);
+
+        return Ok((connection, result));
+    }
+
+    // opens a new connection, authenticating it if the client has a password
+    fn open_connection(&self) -> ClientResult<Connection> {
+        let mut connection : Connection = try!(Connection::connect(self.address.as_slice()));
+
+        match self.password {
+            Some(ref password) => {
+                let response : String = try!(connection.request_line(format!("auth {}", password).as_slice()));
+
+                if response.as_slice() != RESPONSE_DONE {
+                    return Err(ClientError::Client(response));
+                }
+            },
+            None => { }
+        }
+
+        return Ok(connection);
+    }
+}
+
+// returns an error if the key can't be sent as a single argument
+fn check_key(key : &str) -> ClientResult<()> {
+    if key.is_empty() || key.chars().any(|c| c.is_whitespace()) {
+        return Err(ClientError::InvalidKey(String::from_str(key)));
+    }
+
+    return Ok(());
+}
+
+// turns an error response about the filter into an error
+fn check_response(filter : &str, line : &str) -> ClientResult<()> {
+    if line == RESPONSE_NO_EXIST {
+        return Err(ClientError::NoFilter(String::from_str(filter)));
+    } else if line.starts_with(RESPONSE_CLIENT_ERROR) {
+        return Err(ClientError::Client(String::from_str(line[RESPONSE_CLIENT_ERROR.len()..].trim())));
+    } else if line.starts_with(RESPONSE_SERVER_ERROR) {
+        return Err(ClientError::Server(String::from_str(line[RESPONSE_SERVER_ERROR.len()..].trim())));
+    }
+
+    return Ok(());
+}
+
+// parses the layer count returned by set, check, bulk and multi
+fn parse_count(response : String) -> ClientResult<u32> {
+    return match FromStr::from_str(response.as_slice()) {
+        Some(count) => Ok(count),
+        None => Err(ClientError::Protocol(response))
+    };
+}
+
+// parses a field of a response line
+fn parse_field<T : FromStr>(line : &String, field : &str) -> ClientResult<T> {
+    return match FromStr::from_str(field) {
+        Some(value) => Ok(value),
+        None => Err(ClientError::Protocol(line.clone()))
+    };
+}
+
+// parses a value of an 'info' response
+fn info_value<T : FromStr>(values : &HashMap<String, String>, name : &str) -> ClientResult<T> {
+    return match values.get(name).and_then(|value| FromStr::from_str(value.as_slice())) {
+        Some(value) => Ok(value),
+        None => Err(ClientError::Protocol(format!("missing or invalid info value {}", name)))
+    };
+}
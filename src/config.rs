@@ -1,4 +1,12 @@
+use auth::AccessControl;
+use bitmap::IoMode;
+use error::{BloomError, BloomResult};
 use inifile::IniFile;
+use logging;
+use util;
+use std::{cmp, fmt, num, os};
+use std::ascii::AsciiExt;
+use std::error::Error;
 use std::str::FromStr;
 
 // The general settings for the bloom server as a whole
@@ -16,7 +24,27 @@ pub struct BloomConfig {
     pub cold_interval         : i32,
     pub in_memory             : bool,
     pub worker_threads        : i32,
-    pub use_mmap              : bool
+    pub use_mmap              : bool,
+    pub acl_file              : String,
+    pub access_control        : AccessControl,
+    pub tls_port              : i32,
+    pub tls_cert_file         : String,
+    pub tls_key_file          : String,
+    pub tls_ca_file           : String,
+    pub unix_socket           : String,
+    pub unix_socket_mode      : u32,
+    pub unix_socket_uid       : isize,
+    pub unix_socket_gid       : isize,
+    pub shutdown_timeout      : i32,
+    pub metrics_port          : i32,
+    pub log_level             : logging::Level,
+    pub log_format            : logging::Format,
+    pub log_destination       : logging::Destination,
+    pub slowlog_threshold     : i32,
+    pub slowlog_max_len       : i32,
+    pub replicate_from        : String,
+    pub replication_token     : String,
+    pub profiles              : Vec<FilterProfile>
 }
 
 // constants -------------------------------------------------------------------
@@ -35,6 +63,42 @@ const INI_OPTION_DEFAULT_PROBABILITY   : &'static str = "default_probability";
 const INI_OPTION_PROBABILITY_REDUCTION : &'static str = "probability_reduction";
 const INI_OPTION_DATA_DIR              : &'static str = "data_dir";
 const INI_OPTION_BIND_ADDRESS          : &'static str = "bind_address";
+const INI_OPTION_ACL_FILE              : &'static str = "acl_file";
+const INI_OPTION_TLS_PORT              : &'static str = "tls_port";
+const INI_OPTION_TLS_CERT_FILE         : &'static str = "tls_cert_file";
+const INI_OPTION_TLS_KEY_FILE          : &'static str = "tls_key_file";
+const INI_OPTION_TLS_CA_FILE           : &'static str = "tls_ca_file";
+const INI_OPTION_UNIX_SOCKET           : &'static str = "unix_socket";
+const INI_OPTION_UNIX_SOCKET_MODE      : &'static str = "unix_socket_mode";
+const INI_OPTION_UNIX_SOCKET_UID       : &'static str = "unix_socket_uid";
+const INI_OPTION_UNIX_SOCKET_GID       : &'static str = "unix_socket_gid";
+const INI_OPTION_SHUTDOWN_TIMEOUT      : &'static str = "shutdown_timeout";
+const INI_OPTION_METRICS_PORT          : &'static str = "metrics_port";
+const INI_OPTION_LOG_LEVEL             : &'static str = "log_level";
+const INI_OPTION_LOG_FORMAT            : &'static str = "log_format";
+const INI_OPTION_LOG_DESTINATION       : &'static str = "log_destination";
+const INI_OPTION_SLOWLOG_THRESHOLD     : &'static str = "slowlog_threshold";
+const INI_OPTION_SLOWLOG_MAX_LEN       : &'static str = "slowlog_max_len";
+const INI_OPTION_REPLICATE_FROM        : &'static str = "replicate_from";
+const INI_OPTION_REPLICATION_TOKEN     : &'static str = "replication_token";
+
+const INI_SECTION_PROFILE_PREFIX       : &'static str = "profile:";
+const INI_OPTION_PATTERNS              : &'static str = "patterns";
+const INI_OPTION_TTL                   : &'static str = "ttl";
+
+const ENV_PREFIX                       : &'static str = "BLOOMD_";
+const ARG_PREFIX                       : &'static str = "--";
+
+static INI_OPTIONS : [&'static str; 32] = [
+    INI_OPTION_PORT, INI_OPTION_TCP_PORT, INI_OPTION_UDP_PORT, INI_OPTION_SCALE_SIZE, INI_OPTION_FLUSH_INTERVAL,
+    INI_OPTION_COLD_INTERVAL, INI_OPTION_IN_MEMORY, INI_OPTION_WORKERS, INI_OPTION_USE_MMAP, INI_OPTION_INITIAL_CAPACITY,
+    INI_OPTION_DEFAULT_PROBABILITY, INI_OPTION_PROBABILITY_REDUCTION, INI_OPTION_DATA_DIR, INI_OPTION_BIND_ADDRESS,
+    INI_OPTION_ACL_FILE, INI_OPTION_TLS_PORT, INI_OPTION_TLS_CERT_FILE, INI_OPTION_TLS_KEY_FILE, INI_OPTION_TLS_CA_FILE,
+    INI_OPTION_UNIX_SOCKET, INI_OPTION_UNIX_SOCKET_MODE, INI_OPTION_UNIX_SOCKET_UID, INI_OPTION_UNIX_SOCKET_GID,
+    INI_OPTION_SHUTDOWN_TIMEOUT, INI_OPTION_METRICS_PORT, INI_OPTION_LOG_LEVEL, INI_OPTION_LOG_FORMAT,
+    INI_OPTION_LOG_DESTINATION, INI_OPTION_SLOWLOG_THRESHOLD, INI_OPTION_SLOWLOG_MAX_LEN, INI_OPTION_REPLICATE_FROM,
+    INI_OPTION_REPLICATION_TOKEN
+];
 // -----------------------------------------------------------------------------
 
 impl BloomConfig {
@@ -65,7 +129,27 @@ impl BloomConfig {
             cold_interval: cold_interval,
             in_memory: in_memory,
             worker_threads: worker_threads,
-            use_mmap: use_mmap
+            use_mmap: use_mmap,
+            acl_file: String::new(),
+            access_control: AccessControl::new(),
+            tls_port: 8675,
+            tls_cert_file: String::new(),
+            tls_key_file: String::new(),
+            tls_ca_file: String::new(),
+            unix_socket: String::new(),
+            unix_socket_mode: 0o770,
+            unix_socket_uid: -1,
+            unix_socket_gid: -1,
+            shutdown_timeout: 30,
+            metrics_port: 0,
+            log_level: logging::Level::Info,
+            log_format: logging::Format::Text,
+            log_destination: logging::Destination::Stderr,
+            slowlog_threshold: 10,
+            slowlog_max_len: 128,
+            replicate_from: String::new(),
+            replication_token: String::new(),
+            profiles: Vec::new()
         };
     }
 
@@ -88,49 +172,621 @@ impl BloomConfig {
         );
     }
 
+    // Pulls the config settings out of an ini file, validating every option
+    // Returns all the problems found if the file can't be read or is invalid
+    pub fn from_filename(filename : &str) -> Result<Self, ConfigError> {
+        return BloomConfig::load(Some(filename), &[]);
+    }
+
+    // Builds the effective config, in order of precedence: the defaults, the config file if any,
+    // then the overrides, which are expected to hold the environment variables before the command line flags
+    // Returns all the problems found if the file can't be read or the result is invalid
+    pub fn load(filename : Option<&str>, overrides : &[ConfigOverride]) -> Result<Self, ConfigError> {
+        let ini : IniFile = match filename {
+            Some(filename) => {
+                match IniFile::from_filename(filename) {
+                    Ok(ini) => ini,
+                    Err(e) => {
+                        let problem : ConfigProblem = ConfigProblem::new(None, "", format!("could not read config file: {}", e));
+                        return Err(ConfigError::new(filename, vec![problem]));
+                    }
+                }
+            },
+            None => IniFile::new()
+        };
+
+        return match BloomConfig::from_ini_with_overrides(&ini, overrides) {
+            Ok(config) => Ok(config),
+            Err(problems) => Err(ConfigError::new(filename.unwrap_or(""), problems))
+        };
+    }
+
     // Pulls the config settings out of an ini file
-    pub fn from_filename(filename : &str) -> Self {
+    // Returns every problem found, with the line it was found on when known
+    pub fn from_ini(ini : &IniFile) -> Result<Self, Vec<ConfigProblem>> {
+        return BloomConfig::from_ini_with_overrides(ini, &[]);
+    }
+
+    // Pulls the config settings out of an ini file, then applies the overrides on top
+    // Returns every problem found, with the line or override it was found in
+    pub fn from_ini_with_overrides(ini : &IniFile, overrides : &[ConfigOverride]) -> Result<Self, Vec<ConfigProblem>> {
         let mut config : BloomConfig = BloomConfig::default();
+        let mut problems : Vec<ConfigProblem> = Vec::new();
 
-        let ini_opt : Result<IniFile, _> = IniFile::from_filename(filename);
-
-        if ini_opt.is_ok() {
-            let ini : IniFile = ini_opt.unwrap();
-
-            if ini.has_section(INI_SECTION_BLOOMD.as_slice()) {
-                for option in ini.options(String::from_str(INI_SECTION_BLOOMD)).iter() {
-                    match option.as_slice() {
-                        INI_OPTION_PORT                  => { config.tcp_port              = ini.get::<i32>(INI_SECTION_BLOOMD, INI_OPTION_PORT).unwrap() },
-                        INI_OPTION_TCP_PORT              => { config.tcp_port              = ini.get::<i32>(INI_SECTION_BLOOMD, INI_OPTION_TCP_PORT).unwrap() },
-                        INI_OPTION_UDP_PORT              => { config.udp_port              = ini.get::<i32>(INI_SECTION_BLOOMD, INI_OPTION_UDP_PORT).unwrap() },
-                        INI_OPTION_SCALE_SIZE            => { config.scale_size            = ini.get::<i32>(INI_SECTION_BLOOMD, INI_OPTION_SCALE_SIZE).unwrap() },
-                        INI_OPTION_FLUSH_INTERVAL        => { config.flush_interval        = ini.get::<i32>(INI_SECTION_BLOOMD, INI_OPTION_FLUSH_INTERVAL).unwrap() },
-                        INI_OPTION_COLD_INTERVAL         => { config.cold_interval         = ini.get::<i32>(INI_SECTION_BLOOMD, INI_OPTION_COLD_INTERVAL).unwrap() },
-                        INI_OPTION_WORKERS               => { config.worker_threads        = ini.get::<i32>(INI_SECTION_BLOOMD, INI_OPTION_WORKERS).unwrap() }
-                        INI_OPTION_INITIAL_CAPACITY      => { config.initial_capacity      = ini.get::<u64>(INI_SECTION_BLOOMD, INI_OPTION_INITIAL_CAPACITY).unwrap() },
-                        INI_OPTION_USE_MMAP              => { config.use_mmap              = ini.get_bool  (INI_SECTION_BLOOMD, INI_OPTION_USE_MMAP).unwrap() },
-                        INI_OPTION_IN_MEMORY             => { config.in_memory             = ini.get_bool  (INI_SECTION_BLOOMD, INI_OPTION_IN_MEMORY).unwrap() },
-                        INI_OPTION_DEFAULT_PROBABILITY   => { config.default_probability   = ini.get::<f64>(INI_SECTION_BLOOMD, INI_OPTION_DEFAULT_PROBABILITY).unwrap() },
-                        INI_OPTION_PROBABILITY_REDUCTION => { config.probability_reduction = ini.get::<f64>(INI_SECTION_BLOOMD, INI_OPTION_PROBABILITY_REDUCTION).unwrap() },
-                        INI_OPTION_DATA_DIR              => { config.data_dir              = ini.get_string(INI_SECTION_BLOOMD, INI_OPTION_DATA_DIR).unwrap() },
-                        INI_OPTION_BIND_ADDRESS          => { config.bind_host             = ini.get_string(INI_SECTION_BLOOMD, INI_OPTION_BIND_ADDRESS).unwrap() },
-                        _ => { panic!("Unknown option: {}", option) }
+        for line in ini.malformed_lines().iter() {
+            problems.push(ConfigProblem::new(Some(*line), "", String::from_str("expected option=value")));
+        }
+
+        if ini.has_section(INI_SECTION_BLOOMD.as_slice()) {
+            for option in ini.options(String::from_str(INI_SECTION_BLOOMD)).iter() {
+                let value : String = ini.get_string(INI_SECTION_BLOOMD, option.as_slice()).unwrap();
+
+                match config.set_option(option.as_slice(), value.as_slice()) {
+                    Err(message) => { problems.push(ConfigProblem::new(ini.line_number(INI_SECTION_BLOOMD, option.as_slice()), option.as_slice(), message)) },
+                    Ok(_) => { }
+                }
+            }
+        }
+
+        // filter profiles live in their own sections
+        for section in ini.sections().iter() {
+            if section.starts_with(INI_SECTION_PROFILE_PREFIX) {
+                let mut profile : FilterProfile = FilterProfile::new(&section[INI_SECTION_PROFILE_PREFIX.len()..]);
+
+                for option in ini.options(section.clone()).iter() {
+                    let value : String = ini.get_string(section.as_slice(), option.as_slice()).unwrap();
+
+                    match profile.set_option(option.as_slice(), value.as_slice()) {
+                        Err(message) => { problems.push(ConfigProblem::new(ini.line_number(section.as_slice(), option.as_slice()), format!("{}.{}", section, option).as_slice(), message)) },
+                        Ok(_) => { }
                     }
                 }
+
+                config.profiles.push(profile);
+            }
+        }
+
+        for config_override in overrides.iter() {
+            match config.set_option(config_override.option.as_slice(), config_override.value.as_slice()) {
+                Err(message) => { problems.push(ConfigProblem::new(None, config_override.source.as_slice(), message)) },
+                Ok(_) => { }
+            }
+        }
+
+        // roles and tokens can live in the config file itself
+        match AccessControl::from_ini(ini) {
+            Ok(access_control) => { config.access_control = access_control },
+            Err(message) => { problems.push(ConfigProblem::new(None, "", message)) }
+        }
+
+        // or in a separate acl file
+        if !config.acl_file.is_empty() {
+            match AccessControl::from_filename(config.acl_file.as_slice()) {
+                Ok(access_control) => { config.access_control.merge(access_control) },
+                Err(message) => { problems.push(ConfigProblem::new(ini.line_number(INI_SECTION_BLOOMD, INI_OPTION_ACL_FILE), INI_OPTION_ACL_FILE, message)) }
             }
         }
 
-        return config;
+        // blame the override that set the value last, if any, otherwise the line in the file
+        for (option, message) in config.validate().into_iter() {
+            match overrides.iter().filter(|config_override| config_override.option.as_slice() == option).last() {
+                Some(config_override) => { problems.push(ConfigProblem::new(None, config_override.source.as_slice(), message)) },
+                None => { problems.push(ConfigProblem::new(ini.line_number(INI_SECTION_BLOOMD, option), option, message)) }
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        return Ok(config);
+    }
+
+    // Sets the option with the given name from its string value
+    // Returns a description of the problem if the option is unknown or the value has the wrong type
+    pub fn set_option(&mut self, option : &str, value : &str) -> Result<(), String> {
+        match option {
+            INI_OPTION_PORT                  => { self.tcp_port              = try!(parse_value(value, "an integer")) },
+            INI_OPTION_TCP_PORT              => { self.tcp_port              = try!(parse_value(value, "an integer")) },
+            INI_OPTION_UDP_PORT              => { self.udp_port              = try!(parse_value(value, "an integer")) },
+            INI_OPTION_SCALE_SIZE            => { self.scale_size            = try!(parse_value(value, "an integer")) },
+            INI_OPTION_FLUSH_INTERVAL        => { self.flush_interval        = try!(parse_value(value, "an integer")) },
+            INI_OPTION_COLD_INTERVAL         => { self.cold_interval         = try!(parse_value(value, "an integer")) },
+            INI_OPTION_WORKERS               => { self.worker_threads        = try!(parse_value(value, "an integer")) },
+            INI_OPTION_INITIAL_CAPACITY      => { self.initial_capacity      = try!(parse_value(value, "a positive integer")) },
+            INI_OPTION_USE_MMAP              => { self.use_mmap              = try!(parse_bool(value)) },
+            INI_OPTION_IN_MEMORY             => { self.in_memory             = try!(parse_bool(value)) },
+            INI_OPTION_DEFAULT_PROBABILITY   => { self.default_probability   = try!(parse_value(value, "a number")) },
+            INI_OPTION_PROBABILITY_REDUCTION => { self.probability_reduction = try!(parse_value(value, "a number")) },
+            INI_OPTION_DATA_DIR              => { self.data_dir              = String::from_str(value) },
+            INI_OPTION_BIND_ADDRESS          => { self.bind_host             = String::from_str(value) },
+            INI_OPTION_ACL_FILE              => { self.acl_file              = String::from_str(value) },
+            INI_OPTION_TLS_PORT              => { self.tls_port              = try!(parse_value(value, "an integer")) },
+            INI_OPTION_TLS_CERT_FILE         => { self.tls_cert_file         = String::from_str(value) },
+            INI_OPTION_TLS_KEY_FILE          => { self.tls_key_file          = String::from_str(value) },
+            INI_OPTION_TLS_CA_FILE           => { self.tls_ca_file           = String::from_str(value) },
+            INI_OPTION_UNIX_SOCKET           => { self.unix_socket           = String::from_str(value) },
+            INI_OPTION_UNIX_SOCKET_MODE      => { self.unix_socket_mode      = try!(parse_octal(value)) },
+            INI_OPTION_UNIX_SOCKET_UID       => { self.unix_socket_uid       = try!(parse_value(value, "an integer")) },
+            INI_OPTION_UNIX_SOCKET_GID       => { self.unix_socket_gid       = try!(parse_value(value, "an integer")) },
+            INI_OPTION_SHUTDOWN_TIMEOUT      => { self.shutdown_timeout      = try!(parse_value(value, "an integer")) },
+            INI_OPTION_METRICS_PORT          => { self.metrics_port          = try!(parse_value(value, "an integer")) },
+            INI_OPTION_SLOWLOG_THRESHOLD     => { self.slowlog_threshold     = try!(parse_value(value, "an integer")) },
+            INI_OPTION_SLOWLOG_MAX_LEN       => { self.slowlog_max_len       = try!(parse_value(value, "an integer")) },
+            INI_OPTION_REPLICATE_FROM        => { self.replicate_from        = String::from_str(value) },
+            INI_OPTION_REPLICATION_TOKEN     => { self.replication_token     = String::from_str(value) },
+            INI_OPTION_LOG_LEVEL             => { self.log_level             = try!(parse_named(logging::Level::from_name(value), "debug, info, warn or error", value)) },
+            INI_OPTION_LOG_FORMAT            => { self.log_format            = try!(parse_named(logging::Format::from_name(value), "text or json", value)) },
+            INI_OPTION_LOG_DESTINATION       => { self.log_destination       = try!(parse_named(logging::Destination::from_name(value), "stderr, file:<path>, syslog or syslog:<path>", value)) },
+            _ => {
+                return match suggest_option(option) {
+                    Some(suggestion) => Err(format!("unknown option, did you mean {}?", suggestion)),
+                    None => Err(String::from_str("unknown option"))
+                };
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Returns an ini file holding the settings, as they would be written in a config file
+    pub fn to_ini(&self) -> IniFile {
+        let mut ini : IniFile = IniFile::new();
+        ini.add_section(INI_SECTION_BLOOMD);
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_TCP_PORT,              self.tcp_port.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_UDP_PORT,              self.udp_port.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_BIND_ADDRESS,          self.bind_host.clone());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_DATA_DIR,              self.data_dir.clone());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_INITIAL_CAPACITY,      self.initial_capacity.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_DEFAULT_PROBABILITY,   self.default_probability.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_SCALE_SIZE,            self.scale_size.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_PROBABILITY_REDUCTION, self.probability_reduction.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_FLUSH_INTERVAL,        self.flush_interval.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_COLD_INTERVAL,         self.cold_interval.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_IN_MEMORY,             self.in_memory.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_WORKERS,               self.worker_threads.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_USE_MMAP,              self.use_mmap.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_ACL_FILE,              self.acl_file.clone());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_TLS_PORT,              self.tls_port.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_TLS_CERT_FILE,         self.tls_cert_file.clone());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_TLS_KEY_FILE,          self.tls_key_file.clone());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_TLS_CA_FILE,           self.tls_ca_file.clone());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_UNIX_SOCKET,           self.unix_socket.clone());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_UNIX_SOCKET_MODE,      format!("{:o}", self.unix_socket_mode));
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_UNIX_SOCKET_UID,       self.unix_socket_uid.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_UNIX_SOCKET_GID,       self.unix_socket_gid.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_SHUTDOWN_TIMEOUT,      self.shutdown_timeout.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_METRICS_PORT,          self.metrics_port.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_LOG_LEVEL,             String::from_str(self.log_level.name()));
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_LOG_FORMAT,            String::from_str(self.log_format.name()));
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_LOG_DESTINATION,       self.log_destination.name());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_SLOWLOG_THRESHOLD,     self.slowlog_threshold.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_SLOWLOG_MAX_LEN,       self.slowlog_max_len.to_string());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_REPLICATE_FROM,        self.replicate_from.clone());
+        ini.set(INI_SECTION_BLOOMD, INI_OPTION_REPLICATION_TOKEN,     self.replication_token.clone());
+
+        for profile in self.profiles.iter() {
+            profile.add_to_ini(&mut ini);
+        }
+
+        return ini;
+    }
+
+    // Checks that the settings are within their allowed ranges
+    // Returns the name of each offending option along with what is wrong with it
+    pub fn validate(&self) -> Vec<(&'static str, String)> {
+        let mut problems : Vec<(&'static str, String)> = Vec::new();
+
+        if self.tcp_port <= 0 || self.tcp_port > 65535 {
+            problems.push((INI_OPTION_TCP_PORT, format!("must be between 1 and 65535, got {}", self.tcp_port)));
+        }
+        if self.udp_port < 0 || self.udp_port > 65535 {
+            problems.push((INI_OPTION_UDP_PORT, format!("must be between 0 and 65535, got {}", self.udp_port)));
+        }
+        if self.tls_enabled() && (self.tls_port <= 0 || self.tls_port > 65535) {
+            problems.push((INI_OPTION_TLS_PORT, format!("must be between 1 and 65535, got {}", self.tls_port)));
+        }
+        if self.metrics_port < 0 || self.metrics_port > 65535 {
+            problems.push((INI_OPTION_METRICS_PORT, format!("must be between 0 and 65535, got {}", self.metrics_port)));
+        }
+        if self.tls_cert_file.is_empty() != self.tls_key_file.is_empty() {
+            problems.push((if self.tls_cert_file.is_empty() { INI_OPTION_TLS_CERT_FILE } else { INI_OPTION_TLS_KEY_FILE }, String::from_str("tls_cert_file and tls_key_file must be set together")));
+        }
+        if self.initial_capacity <= 10000 {
+            problems.push((INI_OPTION_INITIAL_CAPACITY, format!("must be greater than 10000, got {}", self.initial_capacity)));
+        }
+        if self.default_probability <= 0.0 || self.default_probability >= 0.1 {
+            problems.push((INI_OPTION_DEFAULT_PROBABILITY, format!("must be greater than 0 and less than 0.1, got {}", self.default_probability)));
+        }
+        if self.scale_size != 2 && self.scale_size != 4 {
+            problems.push((INI_OPTION_SCALE_SIZE, format!("must be 2 or 4, got {}", self.scale_size)));
+        }
+        if self.probability_reduction <= 0.1 || self.probability_reduction >= 1.0 {
+            problems.push((INI_OPTION_PROBABILITY_REDUCTION, format!("must be greater than 0.1 and less than 1, got {}", self.probability_reduction)));
+        }
+        if self.flush_interval < 0 {
+            problems.push((INI_OPTION_FLUSH_INTERVAL, format!("cannot be negative, got {}", self.flush_interval)));
+        }
+        if self.cold_interval < 0 {
+            problems.push((INI_OPTION_COLD_INTERVAL, format!("cannot be negative, got {}", self.cold_interval)));
+        }
+        if self.worker_threads < 1 {
+            problems.push((INI_OPTION_WORKERS, format!("must be at least 1, got {}", self.worker_threads)));
+        }
+        if self.shutdown_timeout < 0 {
+            problems.push((INI_OPTION_SHUTDOWN_TIMEOUT, format!("cannot be negative, got {}", self.shutdown_timeout)));
+        }
+        if self.slowlog_threshold < 0 {
+            problems.push((INI_OPTION_SLOWLOG_THRESHOLD, format!("cannot be negative, got {}", self.slowlog_threshold)));
+        }
+        if self.slowlog_max_len < 0 {
+            problems.push((INI_OPTION_SLOWLOG_MAX_LEN, format!("cannot be negative, got {}", self.slowlog_max_len)));
+        }
+        if !self.replicate_from.is_empty() && !self.replicate_from.contains(":") {
+            problems.push((INI_OPTION_REPLICATE_FROM, format!("must be <host>:<port>, got \"{}\"", self.replicate_from)));
+        }
+
+        return problems;
+    }
+
+    // Takes over the settings from the given config that can change while the server is running.
+    // Returns the names of the options that differ but only take effect after a restart.
+    pub fn apply_live_settings(&mut self, other : &BloomConfig) -> Vec<&'static str> {
+        self.initial_capacity      = other.initial_capacity;
+        self.default_probability   = other.default_probability;
+        self.scale_size            = other.scale_size;
+        self.probability_reduction = other.probability_reduction;
+        self.flush_interval        = other.flush_interval;
+        self.cold_interval         = other.cold_interval;
+        self.in_memory             = other.in_memory;
+        self.use_mmap              = other.use_mmap;
+        self.shutdown_timeout      = other.shutdown_timeout;
+        self.acl_file              = other.acl_file.clone();
+        self.access_control        = other.access_control.clone();
+        self.profiles              = other.profiles.clone();
+        self.log_level             = other.log_level;
+        self.log_format            = other.log_format;
+        self.log_destination       = other.log_destination.clone();
+        self.slowlog_threshold     = other.slowlog_threshold;
+        self.slowlog_max_len       = other.slowlog_max_len;
+
+        let mut restart_required : Vec<&'static str> = Vec::new();
+
+        if self.tcp_port         != other.tcp_port         { restart_required.push(INI_OPTION_TCP_PORT) }
+        if self.udp_port         != other.udp_port         { restart_required.push(INI_OPTION_UDP_PORT) }
+        if self.bind_host        != other.bind_host        { restart_required.push(INI_OPTION_BIND_ADDRESS) }
+        if self.data_dir         != other.data_dir         { restart_required.push(INI_OPTION_DATA_DIR) }
+        if self.worker_threads   != other.worker_threads   { restart_required.push(INI_OPTION_WORKERS) }
+        if self.tls_port         != other.tls_port         { restart_required.push(INI_OPTION_TLS_PORT) }
+        if self.tls_cert_file    != other.tls_cert_file    { restart_required.push(INI_OPTION_TLS_CERT_FILE) }
+        if self.tls_key_file     != other.tls_key_file     { restart_required.push(INI_OPTION_TLS_KEY_FILE) }
+        if self.tls_ca_file      != other.tls_ca_file      { restart_required.push(INI_OPTION_TLS_CA_FILE) }
+        if self.unix_socket      != other.unix_socket      { restart_required.push(INI_OPTION_UNIX_SOCKET) }
+        if self.unix_socket_mode != other.unix_socket_mode { restart_required.push(INI_OPTION_UNIX_SOCKET_MODE) }
+        if self.unix_socket_uid  != other.unix_socket_uid  { restart_required.push(INI_OPTION_UNIX_SOCKET_UID) }
+        if self.unix_socket_gid  != other.unix_socket_gid  { restart_required.push(INI_OPTION_UNIX_SOCKET_GID) }
+        if self.metrics_port     != other.metrics_port     { restart_required.push(INI_OPTION_METRICS_PORT) }
+        if self.replicate_from   != other.replicate_from   { restart_required.push(INI_OPTION_REPLICATE_FROM) }
+        if self.replication_token != other.replication_token { restart_required.push(INI_OPTION_REPLICATION_TOKEN) }
+
+        return restart_required;
+    }
+
+    // Returns the profile with the given name, if there is one
+    pub fn profile_named(&self, name : &str) -> Option<&FilterProfile> {
+        return self.profiles.iter().find(|profile| profile.name.as_slice() == name);
+    }
+
+    // Returns the first profile with a pattern matching the given filter name, if there is one
+    pub fn profile_for_filter(&self, filter_name : &str) -> Option<&FilterProfile> {
+        return self.profiles.iter().find(|profile| profile.matches(filter_name));
     }
 
     // Returns the bind address
     pub fn get_bind_address(&self) -> String {
         return format!("{}:{}", self.bind_host, self.tcp_port);
     }
+
+    // Returns true if a certificate and key are configured for the TLS listener
+    pub fn tls_enabled(&self) -> bool {
+        return !self.tls_cert_file.is_empty() && !self.tls_key_file.is_empty();
+    }
+
+    // Returns the bind address of the TLS listener
+    pub fn get_tls_bind_address(&self) -> String {
+        return format!("{}:{}", self.bind_host, self.tls_port);
+    }
+
+    // Returns true if a port is configured for the metrics listener
+    pub fn metrics_enabled(&self) -> bool {
+        return self.metrics_port > 0;
+    }
+
+    // Returns the bind address of the metrics listener
+    pub fn get_metrics_bind_address(&self) -> String {
+        return format!("{}:{}", self.bind_host, self.metrics_port);
+    }
+
+    // Returns true if the server follows a leader, serving only reads
+    pub fn is_follower(&self) -> bool {
+        return !self.replicate_from.is_empty();
+    }
 }
 
 unsafe impl Send for BloomConfig { }
 
+// A setting given outside of the config file, in an environment variable or on the command line
+#[derive(Clone, Debug)]
+pub struct ConfigOverride {
+    pub source : String, // Where the setting came from, e.g. BLOOMD_DATA_DIR or --data_dir
+    pub option : String, // Option name, as used in the config file
+    pub value  : String  // Option value
+}
+
+impl ConfigOverride {
+    // Returns a new ConfigOverride
+    pub fn new(source : &str, option : &str, value : &str) -> Self {
+        return ConfigOverride { source: String::from_str(source), option: String::from_str(option), value: String::from_str(value) };
+    }
+
+    // Returns the overrides set through BLOOMD_<OPTION> environment variables
+    pub fn from_env() -> Vec<ConfigOverride> {
+        let mut overrides : Vec<ConfigOverride> = Vec::new();
+
+        for option in INI_OPTIONS.iter() {
+            let variable : String = format!("{}{}", ENV_PREFIX, option.to_ascii_uppercase());
+
+            match os::getenv(variable.as_slice()) {
+                Some(value) => { overrides.push(ConfigOverride::new(variable.as_slice(), *option, value.as_slice())) },
+                None => { }
+            }
+        }
+
+        return overrides;
+    }
+
+    // Parses a --<option>=<value> command line argument, returning None if the argument has another form
+    pub fn from_arg(arg : &str) -> Option<ConfigOverride> {
+        if !arg.starts_with(ARG_PREFIX) {
+            return None;
+        }
+
+        return match arg.find('=') {
+            Some(index) => Some(ConfigOverride::new(&arg[..index], &arg[ARG_PREFIX.len()..index], &arg[index + 1..])),
+            None => None
+        };
+    }
+}
+
+// Settings shared by a family of filters, read from a [profile:<name>] section.
+// Settings the profile leaves out fall back to the server defaults.
+#[derive(Clone, Debug)]
+pub struct FilterProfile {
+    pub name        : String,         // Profile name
+    pub patterns    : Vec<String>,    // Glob patterns of the filter names the profile applies to
+    pub capacity    : Option<u64>,    // Initial capacity
+    pub probability : Option<f64>,    // False positive probability
+    pub in_memory   : Option<bool>,   // Filter is only contained in memory
+    pub use_mmap    : Option<bool>,   // Bitmaps are mapped shared with their files, rather than buffered
+    pub filter_type : Option<String>, // Filter type
+    pub ttl         : Option<u64>     // Seconds until filters expire
+}
+
+impl FilterProfile {
+    // Returns a profile that leaves every setting to the server defaults
+    pub fn new(name : &str) -> Self {
+        return FilterProfile {
+            name: String::from_str(name),
+            patterns: Vec::new(),
+            capacity: None,
+            probability: None,
+            in_memory: None,
+            use_mmap: None,
+            filter_type: None,
+            ttl: None
+        };
+    }
+
+    // Sets the option with the given name from its string value
+    // Returns a description of the problem if the option is unknown or the value is invalid
+    pub fn set_option(&mut self, option : &str, value : &str) -> Result<(), String> {
+        match option {
+            INI_OPTION_PATTERNS    => { self.patterns    = value.split(',').map(|pattern| pattern.trim()).filter(|pattern| !pattern.is_empty()).map(|pattern| String::from_str(pattern)).collect() },
+            INI_OPTION_CAPACITY    => {
+                let capacity : u64 = try!(parse_value(value, "a positive integer"));
+
+                if capacity == 0 {
+                    return Err(String::from_str("must be greater than 0"));
+                }
+
+                self.capacity = Some(capacity);
+            },
+            INI_OPTION_PROBABILITY => {
+                let probability : f64 = try!(parse_value(value, "a number"));
+
+                if probability <= 0.0 || probability >= 1.0 {
+                    return Err(format!("must be greater than 0 and less than 1, got {}", probability));
+                }
+
+                self.probability = Some(probability);
+            },
+            INI_OPTION_IN_MEMORY   => { self.in_memory   = Some(try!(parse_bool(value))) },
+            INI_OPTION_USE_MMAP    => { self.use_mmap    = Some(try!(parse_bool(value))) },
+            INI_OPTION_FILTER_TYPE => { self.filter_type = Some(try!(parse_filter_type(value))) },
+            INI_OPTION_TTL         => { self.ttl         = Some(try!(parse_value(value, "a number of seconds"))) },
+            _ => { return Err(String::from_str("unknown profile option")) }
+        }
+
+        return Ok(());
+    }
+
+    // Returns true if one of the profile's patterns matches the given filter name
+    pub fn matches(&self, filter_name : &str) -> bool {
+        return self.patterns.iter().any(|pattern| util::glob_matches(pattern.as_slice(), filter_name));
+    }
+
+    // Adds the profile to the given ini file, as its own section
+    pub fn add_to_ini(&self, ini : &mut IniFile) {
+        let section : String = format!("{}{}", INI_SECTION_PROFILE_PREFIX, self.name);
+        ini.add_section(section.as_slice());
+        ini.set(section.as_slice(), INI_OPTION_PATTERNS, self.patterns.connect(","));
+
+        match self.capacity    { Some(value) => { ini.set(section.as_slice(), INI_OPTION_CAPACITY,    value.to_string()) }, None => { } }
+        match self.probability { Some(value) => { ini.set(section.as_slice(), INI_OPTION_PROBABILITY, value.to_string()) }, None => { } }
+        match self.in_memory   { Some(value) => { ini.set(section.as_slice(), INI_OPTION_IN_MEMORY,   value.to_string()) }, None => { } }
+        match self.use_mmap    { Some(value) => { ini.set(section.as_slice(), INI_OPTION_USE_MMAP,    value.to_string()) }, None => { } }
+        match self.filter_type { Some(ref value) => { ini.set(section.as_slice(), INI_OPTION_FILTER_TYPE, value.clone()) }, None => { } }
+        match self.ttl         { Some(value) => { ini.set(section.as_slice(), INI_OPTION_TTL,         value.to_string()) }, None => { } }
+    }
+}
+
+// A single problem found while reading a config file
+#[derive(Clone, Debug)]
+pub struct ConfigProblem {
+    pub line    : Option<usize>, // Line the problem was found on, if known
+    pub option  : String,        // Option the problem is about, empty if none
+    pub message : String         // What is wrong
+}
+
+impl ConfigProblem {
+    // Returns a new ConfigProblem
+    pub fn new(line : Option<usize>, option : &str, message : String) -> Self {
+        return ConfigProblem { line: line, option: String::from_str(option), message: message };
+    }
+}
+
+impl fmt::String for ConfigProblem {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => { try!(write!(f, "line {}: ", line)) },
+            None => { }
+        }
+
+        if !self.option.is_empty() {
+            try!(write!(f, "{}: ", self.option));
+        }
+
+        return write!(f, "{}", self.message);
+    }
+}
+
+// The problems that kept a config file from being loaded
+#[derive(Clone, Debug)]
+pub struct ConfigError {
+    pub filename : String,             // Config file name
+    pub problems : Vec<ConfigProblem>  // Every problem found in the file
+}
+
+impl ConfigError {
+    // Returns a new ConfigError
+    pub fn new(filename : &str, problems : Vec<ConfigProblem>) -> Self {
+        return ConfigError { filename: String::from_str(filename), problems: problems };
+    }
+
+    // Returns all the problems on a single line
+    pub fn summary(&self) -> String {
+        return self.problems.iter().map(|problem| problem.to_string()).collect::<Vec<String>>().connect("; ");
+    }
+}
+
+impl fmt::String for ConfigError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        if self.filename.is_empty() {
+            try!(write!(f, "Invalid config:"));
+        } else {
+            try!(write!(f, "Invalid config file {}:", self.filename));
+        }
+
+        for problem in self.problems.iter() {
+            try!(write!(f, "\n  {}", problem));
+        }
+
+        return Ok(());
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        return "invalid config file";
+    }
+}
+
+// parses a typed value, describing the expected type if the value doesn't parse
+fn parse_value<T : FromStr>(value : &str, expected : &str) -> Result<T, String> {
+    return match FromStr::from_str(value) {
+        Some(parsed) => Ok(parsed),
+        None => Err(format!("expected {}, got \"{}\"", expected, value))
+    };
+}
+
+// parses a boolean value, accepting the same values as IniFile::get_bool
+fn parse_bool(value : &str) -> Result<bool, String> {
+    return match IniFile::parse_bool(value) {
+        Some(parsed) => Ok(parsed),
+        None => Err(format!("expected true or false, got \"{}\"", value))
+    };
+}
+
+// parses an octal value, as used for file modes
+fn parse_octal(value : &str) -> Result<u32, String> {
+    return match num::from_str_radix::<u32>(value, 8) {
+        Some(parsed) => Ok(parsed),
+        None => Err(format!("expected an octal file mode, got \"{}\"", value))
+    };
+}
+
+// unwraps a value parsed from one of a fixed set of names
+fn parse_named<T>(parsed : Option<T>, expected : &str, value : &str) -> Result<T, String> {
+    return match parsed {
+        Some(parsed) => Ok(parsed),
+        None => Err(format!("expected {}, got \"{}\"", expected, value))
+    };
+}
+
+// checks that the value names a known filter type
+fn parse_filter_type(value : &str) -> Result<String, String> {
+    return match value {
+        FILTER_TYPE_LAYERED | FILTER_TYPE_PARTITIONED => Ok(String::from_str(value)),
+        _ => Err(format!("unknown filter type \"{}\"", value))
+    };
+}
+
+// returns the known option closest to the given unknown one, if any is close enough to be a typo
+fn suggest_option(option : &str) -> Option<&'static str> {
+    let mut best : Option<(&'static str, usize)> = None;
+
+    for known in INI_OPTIONS.iter() {
+        let distance : usize = edit_distance(option, *known);
+
+        if distance <= cmp::max(1, known.len() / 3) && best.map_or(true, |(_, best_distance)| distance < best_distance) {
+            best = Some((*known, distance));
+        }
+    }
+
+    return best.map(|(known, _)| known);
+}
+
+// returns the Levenshtein distance between two strings
+fn edit_distance(a : &str, b : &str) -> usize {
+    let a : Vec<char> = a.chars().collect();
+    let b : Vec<char> = b.chars().collect();
+
+    let mut previous : Vec<usize> = (0..b.len() + 1).collect();
+
+    for i in (0..a.len()) {
+        let mut current : Vec<usize> = vec![i + 1];
+
+        for j in (0..b.len()) {
+            let substitution : usize = previous[j] + if a[i] == b[j] { 0 } else { 1 };
+            current.push(cmp::min(substitution, cmp::min(previous[j + 1], current[j]) + 1));
+        }
+
+        previous = current;
+    }
+
+    return previous[b.len()];
+}
+
 // constants -------------------------------------------------------------------
 const INI_SECTION_CONFIG          : &'static str = "config";
 const INI_OPTION_FILTER_NAME      : &'static str = "filter_name";
@@ -141,6 +797,13 @@ const INI_OPTION_BYTES            : &'static str = "bytes";
 const INI_OPTION_SIZE             : &'static str = "size";
 const INI_OPTION_BITMAP_FILENAMES : &'static str = "bitmap_filenames";
 const INI_OPTION_FILTER_SIZES     : &'static str = "filter_sizes";
+const INI_OPTION_EXPIRES_AT       : &'static str = "expires_at";
+const INI_OPTION_FILTER_TYPE      : &'static str = "type";
+const INI_OPTION_PARTITIONS       : &'static str = "partitions";
+const INI_OPTION_IO_MODE          : &'static str = "io_mode";
+
+pub const FILTER_TYPE_LAYERED     : &'static str = "layered";
+pub const FILTER_TYPE_PARTITIONED : &'static str = "partitioned";
 // -----------------------------------------------------------------------------
 
 /**
@@ -156,7 +819,11 @@ pub struct BloomFilterConfig {
     pub bytes                 : u64,         // Total byte size
     pub size                  : u64,         // Total size
     pub bitmap_filenames      : Vec<String>, // bitmap filenames
-    pub filter_sizes          : Vec<u64>     // filter sizes
+    pub filter_sizes          : Vec<u64>,    // filter sizes
+    pub expires_at            : u64,         // Unix time the filter expires at, 0 if never
+    pub filter_type           : String,      // Filter type
+    pub partitions            : u32,         // Number of partitions keys are spread across, 0 if not partitioned
+    pub io_mode               : IoMode       // How the bitmaps are read in and written back
 }
 
 impl BloomFilterConfig {
@@ -171,58 +838,62 @@ impl BloomFilterConfig {
             bytes: bytes,
             size: 0,
             bitmap_filenames: Vec::new(),
-            filter_sizes: Vec::new() };
+            filter_sizes: Vec::new(),
+            expires_at: 0,
+            filter_type: String::from_str(FILTER_TYPE_LAYERED),
+            partitions: 0,
+            io_mode: IoMode::Buffered };
     }
 
     // Pulls the values from an ini file and returns a BloomFilterConfig instance
-    // Throws an error if the ini file is missing any values
-    pub fn from_ini(ini : &IniFile) -> Result<Self, String> {
+    // Returns a Corrupt error if the ini file is missing any values
+    pub fn from_ini(ini : &IniFile) -> BloomResult<Self> {
         let filter_name : String;
         match ini.get_string(INI_SECTION_CONFIG, INI_OPTION_FILTER_NAME) {
             Some(value) => {
                 if !value.is_empty() {
                     filter_name = value;
                 } else {
-                    return Err(String::from_str("filter_name is empty"));
+                    return Err(BloomError::Corrupt(String::from_str("filter_name is empty")));
                 }
             },
-            None => { return Err(String::from_str("missing config:filter_name")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing config:filter_name"))) }
         };
 
         let capacity : u64;
         match ini.get::<u64>(INI_SECTION_CONFIG, INI_OPTION_CAPACITY) {
             Some(value) => { capacity = value },
-            None => { return Err(String::from_str("missing config:capacity")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing config:capacity"))) }
         };
 
         let probability : f64;
         match ini.get::<f64>(INI_SECTION_CONFIG, INI_OPTION_PROBABILITY) {
             Some(value) => { probability = value },
-            None => { return Err(String::from_str("missing config:probability")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing config:probability"))) }
         };
 
         let k_num : u32;
         match ini.get::<u32>(INI_SECTION_CONFIG, INI_OPTION_K_NUM) {
             Some(value) => { k_num = value },
-            None => { return Err(String::from_str("missing config:k_num")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing config:k_num"))) }
         };
 
         let in_memory : bool;
         match ini.get_bool(INI_SECTION_CONFIG, INI_OPTION_IN_MEMORY) {
             Some(value) => { in_memory = value },
-            None => { return Err(String::from_str("missing config:in_memory")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing config:in_memory"))) }
         };
 
         let bytes : u64;
         match ini.get::<u64>(INI_SECTION_CONFIG, INI_OPTION_BYTES) {
             Some(value) => { bytes = value },
-            None => { return Err(String::from_str("missing config:bytes")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing config:bytes"))) }
         };
 
         let size : u64;
         match ini.get::<u64>(INI_SECTION_CONFIG, INI_OPTION_SIZE) {
             Some(value) => { size = value },
-            None => { return Err(String::from_str("missing config:size")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing config:size"))) }
         };
 
         let bitmap_filenames : Vec<String>;
@@ -241,7 +912,10 @@ impl BloomFilterConfig {
         match ini.get_string(INI_SECTION_CONFIG, INI_OPTION_FILTER_SIZES) {
             Some(value) => {
                 if !value.is_empty() {
-                    filter_sizes = value.split_str(",").map(|piece| FromStr::from_str(piece).unwrap() ).collect::<Vec<u64>>()
+                    match value.split_str(",").map(|piece| FromStr::from_str(piece)).collect::<Option<Vec<u64>>>() {
+                        Some(sizes) => { filter_sizes = sizes },
+                        None => { return Err(BloomError::Corrupt(format!("invalid config:filter_sizes \"{}\"", value))) }
+                    }
                 } else {
                     filter_sizes = Vec::new()
                 }
@@ -249,7 +923,33 @@ impl BloomFilterConfig {
             None => { filter_sizes = Vec::new(); }
         };
 
-        assert!(bitmap_filenames.len() == filter_sizes.len());
+        if bitmap_filenames.len() != filter_sizes.len() {
+            return Err(BloomError::Corrupt(format!("{} bitmap filenames but {} filter sizes", bitmap_filenames.len(), filter_sizes.len())));
+        }
+
+        // expires_at is optional, so filters written before it existed never expire
+        let expires_at : u64 = ini.get::<u64>(INI_SECTION_CONFIG, INI_OPTION_EXPIRES_AT).unwrap_or(0);
+
+        // filters written before types existed are layered filters
+        let filter_type : String = ini.get_string(INI_SECTION_CONFIG, INI_OPTION_FILTER_TYPE).unwrap_or(String::from_str(FILTER_TYPE_LAYERED));
+
+        let partitions : u32 = ini.get::<u32>(INI_SECTION_CONFIG, INI_OPTION_PARTITIONS).unwrap_or(0);
+
+        if (filter_type.as_slice() == FILTER_TYPE_PARTITIONED) != (partitions > 0) {
+            return Err(BloomError::Corrupt(format!("{} filter with {} partitions", filter_type, partitions)));
+        }
+
+        // filters written before io_mode existed were always buffered
+        let io_mode : IoMode;
+        match ini.get_string(INI_SECTION_CONFIG, INI_OPTION_IO_MODE) {
+            Some(value) => {
+                match IoMode::from_name(value.as_slice()) {
+                    Some(mode) => { io_mode = mode },
+                    None => { return Err(BloomError::Corrupt(format!("invalid config:io_mode \"{}\"", value))) }
+                }
+            },
+            None => { io_mode = IoMode::Buffered }
+        };
 
         return Ok(BloomFilterConfig {
             filter_name: filter_name,
@@ -260,10 +960,33 @@ impl BloomFilterConfig {
             bytes: bytes,
             size: size,
             bitmap_filenames: bitmap_filenames,
-            filter_sizes: filter_sizes
+            filter_sizes: filter_sizes,
+            expires_at: expires_at,
+            filter_type: filter_type,
+            partitions: partitions,
+            io_mode: io_mode
         });
     }
 
+    // Returns true if the filter spreads its keys across partitions
+    pub fn is_partitioned(&self) -> bool {
+        return self.partitions > 0;
+    }
+
+    // Returns true if the filter has an expiry time that has passed
+    pub fn is_expired(&self, now : u64) -> bool {
+        return self.expires_at > 0 && self.expires_at <= now;
+    }
+
+    // Returns the number of seconds until the filter expires, or None if it never expires
+    pub fn time_to_live(&self, now : u64) -> Option<u64> {
+        if self.expires_at == 0 {
+            return None;
+        }
+
+        return Some(if self.expires_at > now { self.expires_at - now } else { 0 });
+    }
+
     pub fn add_to_ini(&self, ini : &mut IniFile) {
         ini.add_section(INI_SECTION_CONFIG);
         ini.set(INI_SECTION_CONFIG, INI_OPTION_FILTER_NAME,      self.filter_name.clone());
@@ -275,5 +998,122 @@ impl BloomFilterConfig {
         ini.set(INI_SECTION_CONFIG, INI_OPTION_SIZE,             self.size.to_string());
         ini.set(INI_SECTION_CONFIG, INI_OPTION_BITMAP_FILENAMES, self.bitmap_filenames.connect(","));
         ini.set(INI_SECTION_CONFIG, INI_OPTION_FILTER_SIZES,     self.filter_sizes.iter().map(|value| value.to_string() ).collect::<Vec<String>>().connect(","));
+        ini.set(INI_SECTION_CONFIG, INI_OPTION_EXPIRES_AT,       self.expires_at.to_string());
+        ini.set(INI_SECTION_CONFIG, INI_OPTION_FILTER_TYPE,      self.filter_type.clone());
+        ini.set(INI_SECTION_CONFIG, INI_OPTION_PARTITIONS,       self.partitions.to_string());
+        ini.set(INI_SECTION_CONFIG, INI_OPTION_IO_MODE,          String::from_str(self.io_mode.name()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BloomConfig, ConfigOverride, ConfigProblem};
+    use inifile::IniFile;
+    use logging;
+
+    fn read_ini(lines : &[&str]) -> IniFile {
+        let mut ini : IniFile = IniFile::new();
+        ini.read_string(lines.iter().map(|line| String::from_str(*line)).collect());
+
+        return ini;
+    }
+
+    #[test]
+    fn test_valid() {
+        let ini : IniFile = read_ini(&["[bloomd]", "tcp_port=9000", "flush_interval=30", "in_memory=1", "log_format=json", "log_destination=file:/var/log/bloomd.log"]);
+        let config : BloomConfig = BloomConfig::from_ini(&ini).ok().unwrap();
+
+        assert_eq!(config.tcp_port, 9000);
+        assert_eq!(config.flush_interval, 30);
+        assert!(config.in_memory);
+        assert_eq!(config.log_format, logging::Format::Json);
+        assert_eq!(config.log_destination, logging::Destination::File(String::from_str("/var/log/bloomd.log")));
+    }
+
+    #[test]
+    fn test_problems() {
+        let ini : IniFile = read_ini(&[
+            "[bloomd]",
+            "flush_interval=6o",
+            "flush_intreval=60",
+            "default_probability=1.5",
+            "cold_interval=-1",
+            "no value here",
+            "completely_unknown=1"]);
+
+        let problems : Vec<ConfigProblem> = BloomConfig::from_ini(&ini).err().unwrap();
+        let messages : Vec<String> = problems.iter().map(|problem| problem.to_string()).collect();
+
+        assert_eq!(messages, vec![
+            String::from_str("line 6: expected option=value"),
+            String::from_str("line 2: flush_interval: expected an integer, got \"6o\""),
+            String::from_str("line 3: flush_intreval: unknown option, did you mean flush_interval?"),
+            String::from_str("line 7: completely_unknown: unknown option"),
+            String::from_str("line 4: default_probability: must be greater than 0 and less than 0.1, got 1.5"),
+            String::from_str("line 5: cold_interval: cannot be negative, got -1")]);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let ini : IniFile = read_ini(&["[bloomd]", "tcp_port=9000", "flush_interval=30", "cold_interval=600"]);
+        let overrides : Vec<ConfigOverride> = vec![
+            ConfigOverride::new("BLOOMD_TCP_PORT", "tcp_port", "9001"),
+            ConfigOverride::new("BLOOMD_FLUSH_INTERVAL", "flush_interval", "20"),
+            ConfigOverride::from_arg("--tcp_port=9002").unwrap()];
+
+        // Later overrides win, and the file wins over the defaults
+        let config : BloomConfig = BloomConfig::from_ini_with_overrides(&ini, overrides.as_slice()).ok().unwrap();
+        assert_eq!(config.tcp_port, 9002);
+        assert_eq!(config.flush_interval, 20);
+        assert_eq!(config.cold_interval, 600);
+
+        // Problems are reported against the override
+        let overrides : Vec<ConfigOverride> = vec![
+            ConfigOverride::from_arg("--flush_interval=-5").unwrap(),
+            ConfigOverride::from_arg("--data_dri=/tmp").unwrap()];
+        let problems : Vec<ConfigProblem> = BloomConfig::from_ini_with_overrides(&ini, overrides.as_slice()).err().unwrap();
+        let messages : Vec<String> = problems.iter().map(|problem| problem.to_string()).collect();
+
+        assert_eq!(messages, vec![
+            String::from_str("--data_dri: unknown option, did you mean data_dir?"),
+            String::from_str("--flush_interval: cannot be negative, got -5")]);
+
+        assert!(ConfigOverride::from_arg("-f").is_none());
+        assert!(ConfigOverride::from_arg("--check-config").is_none());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let ini : IniFile = read_ini(&[
+            "[profile:sessions]",
+            "patterns=session_*,sess?",
+            "capacity=5000000",
+            "ttl=86400",
+            "[profile:campaigns]",
+            "probability=0.01",
+            "in_memory=yes"]);
+
+        let config : BloomConfig = BloomConfig::from_ini(&ini).ok().unwrap();
+
+        assert_eq!(config.profile_for_filter("session_eu").unwrap().capacity, Some(5000000));
+        assert_eq!(config.profile_for_filter("sess1").unwrap().ttl, Some(86400));
+        assert!(config.profile_for_filter("session_eu_2").unwrap().probability.is_none());
+        assert!(config.profile_for_filter("sessions_eu_2").is_none());
+        assert!(config.profile_for_filter("campaign_1").is_none());
+        assert_eq!(config.profile_named("campaigns").unwrap().in_memory, Some(true));
+
+        // Bad profile values are reported with their section
+        let ini : IniFile = read_ini(&["[profile:broken]", "probability=2", "type=cuckoo"]);
+        let problems : Vec<ConfigProblem> = BloomConfig::from_ini(&ini).err().unwrap();
+        let messages : Vec<String> = problems.iter().map(|problem| problem.to_string()).collect();
+
+        assert_eq!(messages, vec![
+            String::from_str("line 2: profile:broken.probability: must be greater than 0 and less than 1, got 2"),
+            String::from_str("line 3: profile:broken.type: unknown filter type \"cuckoo\"")]);
+    }
+
+    #[test]
+    fn test_missing_file() {
+        assert!(BloomConfig::from_filename("/tmp/bloomd-test-missing.ini").is_err());
     }
 }
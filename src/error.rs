@@ -0,0 +1,129 @@
+extern crate libc;
+
+use self::libc::{EINVAL, ENOENT};
+use std::error::{Error, FromError};
+use std::fmt;
+use std::io::{IoError, IoErrorKind};
+use std::os;
+
+// The ways working with a bloom filter can fail
+#[derive(Clone, Debug)]
+pub enum BloomError {
+    Io(IoError),           // reading or writing the filter's files failed
+    Os(i32),               // the underlying C filter failed with the given errno
+    InvalidParams(String), // the filter can't be built with the given parameters
+    NotFound(String),      // the filter, or one of its files, does not exist
+    Capacity(String),      // the bitmap is too small to hold the filter
+    Corrupt(String)        // the filter's files on disk are missing information or damaged
+}
+
+pub type BloomResult<T> = Result<T, BloomError>;
+
+impl BloomError {
+    // Converts the negative return code of a C filter function into an error, with a context
+    // describing the call that failed. The C code returns -errno for system failures, and -1
+    // for failures of its own, such as running out of memory partway through a flush
+    pub fn from_code(code : i32, context : &str) -> Self {
+        if code == -1 {
+            return BloomError::Io(IoError { kind: IoErrorKind::OtherIoError, desc: "the filter's C code failed", detail: Some(String::from_str(context)) });
+        }
+
+        return match -code {
+            EINVAL => BloomError::InvalidParams(String::from_str(context)),
+            ENOENT => BloomError::NotFound(String::from_str(context)),
+            errno => BloomError::Os(errno)
+        };
+    }
+
+    // Converts the return code of a C function that checks a bitmap's header or size as it opens it,
+    // where -1 means what it found on disk isn't what it expected
+    pub fn from_header_code(code : i32, context : &str) -> Self {
+        if code == -1 {
+            return BloomError::Corrupt(String::from_str(context));
+        }
+
+        return BloomError::from_code(code, context);
+    }
+
+    // Returns the errno the error was caused by, if it came from a system call
+    pub fn errno(&self) -> Option<i32> {
+        return match *self {
+            BloomError::Os(errno) => Some(errno),
+            _ => None
+        };
+    }
+}
+
+impl fmt::String for BloomError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            BloomError::Io(ref e) => write!(f, "I/O error: {}", e),
+            BloomError::Os(errno) => write!(f, "{} (errno {})", os::error_string(errno as usize), errno),
+            BloomError::InvalidParams(ref message) => write!(f, "invalid parameters: {}", message),
+            BloomError::NotFound(ref message) => write!(f, "not found: {}", message),
+            BloomError::Capacity(ref message) => write!(f, "capacity exceeded: {}", message),
+            BloomError::Corrupt(ref message) => write!(f, "corrupt filter: {}", message)
+        };
+    }
+}
+
+impl Error for BloomError {
+    fn description(&self) -> &str {
+        return match *self {
+            BloomError::Io(ref e) => e.description(),
+            BloomError::Os(_) => "system error",
+            BloomError::InvalidParams(_) => "invalid parameters",
+            BloomError::NotFound(_) => "not found",
+            BloomError::Capacity(_) => "capacity exceeded",
+            BloomError::Corrupt(_) => "corrupt filter"
+        };
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        return match *self {
+            BloomError::Io(ref e) => Some(e as &Error),
+            _ => None
+        };
+    }
+}
+
+impl FromError<IoError> for BloomError {
+    fn from_error(error : IoError) -> Self {
+        return BloomError::Io(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomError;
+
+    #[test]
+    fn test_from_code() {
+        match BloomError::from_code(-22, "k_num") {
+            BloomError::InvalidParams(message) => { assert_eq!(message.as_slice(), "k_num") },
+            e => { panic!("expected InvalidParams, got {:?}", e) }
+        }
+
+        // -1 is the C code's own failure, not EPERM; opening a bitmap, it means the file is damaged
+        match BloomError::from_header_code(-1, "opening bitmap /tmp/truncated.bmp") {
+            BloomError::Corrupt(message) => { assert_eq!(message.as_slice(), "opening bitmap /tmp/truncated.bmp") },
+            e => { panic!("expected Corrupt, got {:?}", e) }
+        }
+
+        // anywhere else, such as a flush that couldn't allocate, it's a failure to write
+        match BloomError::from_code(-1, "flushing a bitmap of 4096 bytes") {
+            BloomError::Io(e) => { assert_eq!(e.detail, Some(String::from_str("flushing a bitmap of 4096 bytes"))) },
+            e => { panic!("expected Io, got {:?}", e) }
+        }
+
+        match BloomError::from_code(-2, "/tmp/missing.bmp") {
+            BloomError::NotFound(_) => { },
+            e => { panic!("expected NotFound, got {:?}", e) }
+        }
+
+        // errno is kept for anything else
+        let error : BloomError = BloomError::from_code(-28, "/tmp/full.bmp");
+        assert_eq!(error.errno(), Some(28));
+        assert!(format!("{}", error).ends_with("(errno 28)"));
+    }
+}
@@ -1,12 +1,14 @@
+use error::BloomResult;
 
 // A trait representing the methods common to all bloom filters
 pub trait IBloomFilter<T> {
-    fn add(&mut self, key : String) -> Result<T, String>;
-    fn contains(&self, key : &String) -> Result<T, String>;
+    fn add(&mut self, key : String) -> BloomResult<T>;
+    fn contains(&self, key : &String) -> BloomResult<T>;
     fn size(&self) -> u64;
-    fn flush(&mut self) -> Result<(), String>;
+    fn flush(&mut self) -> BloomResult<()>;
 }
 
+#[cfg(test)]
 pub mod test {
     use super::IBloomFilter;
     use bloom;
@@ -28,7 +28,11 @@ pub struct IniFile {
 	/// INI structure: sections contain options (name=>value)
 	opts: HashMap<String, HashMap<String, String>>,
 	/// Section names, used to keep order (as HashMap doesn't).
-	sections: Vec<String>
+	sections: Vec<String>,
+	/// Line numbers options were read from, for error messages.
+	line_numbers: HashMap<String, HashMap<String, usize>>,
+	/// Line numbers of lines that could not be parsed.
+	malformed_lines: Vec<usize>
 }
 
 /**
@@ -68,16 +72,20 @@ impl IniFile {
 	 */
 	pub fn get_bool(&self, section: &str, option: &str) -> Option<bool> {
 		return match self.get_string(section, option) {
-            Some(value) => {
-                match value.as_slice() {
-                    "1" | "yes" | "true" | "T" | "on" => Some(true),
-                    "0" | "no" | "false" | "F" | "off" => Some(false),
-                    _ => None
-                }
-            },
+            Some(value) => IniFile::parse_bool(value.as_slice()),
             None => None
         };
 	}
+	/**
+	 * Coerces a value to a boolean, accepting the values get_bool does.
+	 */
+	pub fn parse_bool(value: &str) -> Option<bool> {
+		return match value {
+            "1" | "yes" | "true" | "T" | "on" => Some(true),
+            "0" | "no" | "false" | "F" | "off" => Some(false),
+            _ => None
+        };
+	}
 	/**
 	 * A convenience method which coerces the option in the specified section to an integer.
 	 */
@@ -101,7 +109,7 @@ impl IniFile {
 		self.opts.contains_key(&section.to_string())
 	}
 	pub fn new() -> IniFile {
-		IniFile { comments: HashMap::new(), options: Vec::new(), opts: HashMap::new(), sections: Vec::new() }
+		IniFile { comments: HashMap::new(), options: Vec::new(), opts: HashMap::new(), sections: Vec::new(), line_numbers: HashMap::new(), malformed_lines: Vec::new() }
 	}
 
     pub fn from_filename(filename : &str) -> IoResult<Self> {
@@ -150,7 +158,9 @@ impl IniFile {
 	pub fn read_string(&mut self, lines: Vec<String>) {
 		let mut section: String = "Default".to_string();
 		let mut comment_lines = String::new();
+		let mut line_number = 0us;
 		for line in lines.iter() {
+			line_number += 1;
 			let mut line_len = line.len();
 			let line_slice = line.as_slice();
 			if line_len > 0 && line_slice.slice_chars(line_len - 1, line_len) == "\n" {
@@ -174,7 +184,13 @@ impl IniFile {
 				}
 				continue;
 			}
-			let index = line_slice.find_str("=").unwrap();
+			let index = match line_slice.find_str("=") {
+				Some(index) => index,
+				None => {
+					self.malformed_lines.push(line_number);
+					continue;
+				}
+			};
 			let optkey = line_slice.slice_chars(0, index).to_string();
 			let optval = line_slice.slice_chars(index + 1, line_len).to_string();
 			self.comments.get_mut(&section).unwrap().insert(optkey.clone(), comment_lines.clone());
@@ -182,8 +198,27 @@ impl IniFile {
 			self.opts.get_mut(&section).unwrap().insert(optkey.clone(), optval);
 			let section_index = self.sections.as_slice().position_elem(&section).unwrap();
 			self.options.get_mut(section_index).unwrap().push(optkey.clone());
+			if !self.line_numbers.contains_key(&section) {
+				self.line_numbers.insert(section.clone(), HashMap::new());
+			}
+			self.line_numbers.get_mut(&section).unwrap().insert(optkey.clone(), line_number);
 		}
 	}
+	/**
+	 * Return the line number the option was read from, if it was read from a file.
+	 */
+	pub fn line_number(&self, section: &str, option: &str) -> Option<usize> {
+		return match self.line_numbers.get(&section.to_string()) {
+			Some(options) => options.get(&option.to_string()).map(|line| *line),
+			None => None
+		};
+	}
+	/**
+	 * Return the line numbers of the lines that were neither sections, comments nor options.
+	 */
+	pub fn malformed_lines(&self) -> Vec<usize> {
+		self.malformed_lines.clone()
+	}
 	/**
 	 * Remove the specified option from the specified section. If the section does not exist, fails.
 	 * If the option existed to be removed, return True; otherwise return False.
@@ -1,5 +1,6 @@
 extern crate libc;
 
+use error::BloomResult;
 use filter::IBloomFilter;
 use bloom::{bloom_bloomfilter, bloom_filter_params};
 
@@ -42,11 +43,26 @@ impl bloom_lbf {
     pub fn get_filter_size(&self, index : usize) -> u64 {
         return self.filters[index].size();
     }
+
+    // Returns the number of bytes the next flush writes, across every layer
+    pub fn dirty_bytes(&self) -> u64 {
+        return self.filters.iter().fold(0, |total, filter| total + filter.dirty_bytes());
+    }
+
+    // Returns the number of bytes of mapped bitmaps the next flush syncs, across every layer
+    pub fn mapped_bytes(&self) -> u64 {
+        return self.filters.iter().fold(0, |total, filter| total + filter.mapped_bytes());
+    }
+
+    // Returns the bitmap_mode of each layer's bitmap
+    pub fn bitmap_modes(&self) -> Vec<u32> {
+        return self.filters.iter().map(|filter| filter.bitmap_mode()).collect();
+    }
 }
 
 impl IBloomFilter<u32> for bloom_lbf {
     // Adds the given key to the first layer that does not already contain that key.
-    fn add(&mut self, key : String) -> Result<u32, String> {
+    fn add(&mut self, key : String) -> BloomResult<u32> {
         let mut index : u32 = 0;
 
         // Check each filter in the lbf
@@ -72,7 +88,7 @@ impl IBloomFilter<u32> for bloom_lbf {
     }
 
     // Returns the last layer that contains the given key
-    fn contains(&self, key : &String) -> Result<u32, String> {
+    fn contains(&self, key : &String) -> BloomResult<u32> {
         let mut index : u32 = 0;
         
         // Check each layer, break when we find one that doesn't contain the key
@@ -98,8 +114,8 @@ impl IBloomFilter<u32> for bloom_lbf {
     }
 
     // Saves the layered bloom filter to the disk
-    fn flush(&mut self) -> Result<(), String> {
-        let mut result : Result<(), String> = Ok(());
+    fn flush(&mut self) -> BloomResult<()> {
+        let mut result : BloomResult<()> = Ok(());
         
         // Flush each layer of the filter
         for ref mut filter in self.filters.iter_mut() {
@@ -170,6 +186,7 @@ pub extern "C" fn lbf_close(lbf : *mut bloom_lbf) -> i32 {
 #[cfg(test)]
 mod tests {
     use filter;
+    use bitmap::IoMode;
     use bloom::{bloom_filter_params, bloom_bloomfilter, create_bloom_filter};
     use lbf::bloom_lbf;
 
@@ -179,7 +196,7 @@ mod tests {
         let mut lbf : bloom_lbf = bloom_lbf::new(params, String::from_str("test"), Vec::new());
         
         for i in (0..3) {
-            let bloom_filter = create_bloom_filter(&lbf.params, format!("/tmp/lbf-{}.bmp", i).as_slice());
+            let bloom_filter = create_bloom_filter(&lbf.params, format!("/tmp/lbf-{}.bmp", i).as_slice(), false, IoMode::Buffered).unwrap();
 
             lbf.add_filter(bloom_filter);
         }
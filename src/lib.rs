@@ -0,0 +1,53 @@
+#![crate_name = "bloomd"]
+#![crate_type = "lib"]
+#![allow(unstable)]
+#![allow(unstable_features)]
+#![feature(unsafe_destructor)]
+#![allow(improper_ctypes)]
+#![feature(unboxed_closures)]
+
+// The bloom filter engine behind the bloomd server, and a client for the server, usable without running it.
+// The public modules and the re-exports below are the stable API. The server's access control, snapshot
+// stream and time helpers stay private; the last few re-exports are the parts of them the bloomd binary uses.
+
+#[macro_use]
+extern crate lazy_static;
+
+// logging comes first, so its macros are available to the other modules
+#[macro_use]
+pub mod logging;
+
+mod bitmap;
+mod bloom;
+mod filter;
+mod inifile;
+mod lbf;
+mod sbf;
+
+pub mod client;
+pub mod config;
+pub mod error;
+pub mod loader;
+pub mod shard;
+pub mod wrappers;
+
+mod auth;
+mod snapshot;
+mod util;
+
+pub use error::{BloomError, BloomResult};
+pub use filter::IBloomFilter;
+pub use bitmap::{bitmap_mode, bloom_bitmap, IoMode};
+pub use bloom::{bloom_bloomfilter, bloom_filter_header, bloom_filter_params};
+pub use bloom::{create_bloom_filter_params, create_bloom_filter, load_bloom_filter};
+pub use bloom::{params_for_capacity, size_for_capacity_prob, fp_probability_for_capacity_size, capacity_for_size_prob, ideal_k_num};
+pub use inifile::IniFile;
+pub use lbf::bloom_lbf;
+pub use sbf::{bloom_sbf, bloom_sbf_params};
+pub use config::BloomFilterConfig;
+pub use wrappers::{BloomFilter, BloomFilterCounters};
+pub use client::{Client, ClientError, ClientResult};
+pub use shard::{HashRing, ShardedClient};
+pub use auth::{AccessControl, Permission, Role};
+pub use snapshot::{FilterSnapshot, SnapshotSource, protocol_error, read_line};
+pub use util::{now, precise_time_ns, seconds_since};
@@ -0,0 +1,142 @@
+use error::{BloomError, BloomResult};
+use std::io::{BufferedReader, File, IoErrorKind};
+use std::io::fs;
+
+// constants -------------------------------------------------------------------
+const FORMAT_LINES    : &'static str = "lines";
+const FORMAT_PREFIXED : &'static str = "prefixed";
+// -----------------------------------------------------------------------------
+
+// How the keys in a key file are laid out
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyFormat {
+    Lines,         // one key per line; blank lines are skipped
+    LengthPrefixed // each key preceded by its length in bytes, as a big endian u32
+}
+
+impl KeyFormat {
+    // Parses a format name, as given to 'load'
+    pub fn from_name(name : &str) -> Option<KeyFormat> {
+        return match name {
+            FORMAT_LINES => Some(KeyFormat::Lines),
+            FORMAT_PREFIXED => Some(KeyFormat::LengthPrefixed),
+            _ => None
+        };
+    }
+}
+
+// Reads the keys in a key file in batches, keeping track of how far through the file it is
+pub struct KeyReader {
+    reader      : BufferedReader<File>,
+    format      : KeyFormat,
+    bytes_read  : u64,
+    total_bytes : u64
+}
+
+impl KeyReader {
+    // Opens the key file with the given path
+    pub fn open(path : &str, format : KeyFormat) -> BloomResult<Self> {
+        let path : Path = Path::new(path);
+        let total_bytes : u64 = try!(fs::stat(&path)).size;
+        let file : File = try!(File::open(&path));
+
+        return Ok(KeyReader { reader: BufferedReader::new(file), format: format, bytes_read: 0, total_bytes: total_bytes });
+    }
+
+    // Returns up to max_keys of the next keys in the file; an empty batch means the file is done
+    pub fn next_batch(&mut self, max_keys : usize) -> BloomResult<Vec<String>> {
+        let mut keys : Vec<String> = Vec::with_capacity(max_keys);
+
+        while keys.len() < max_keys {
+            match try!(self.next_key()) {
+                Some(key) => { keys.push(key) },
+                None => { break }
+            }
+        }
+
+        return Ok(keys);
+    }
+
+    // Returns the number of bytes of the file read so far
+    pub fn bytes_read(&self) -> u64 {
+        return self.bytes_read;
+    }
+
+    // Returns the size of the file
+    pub fn total_bytes(&self) -> u64 {
+        return self.total_bytes;
+    }
+
+    // returns the next key in the file, or None at the end of the file
+    fn next_key(&mut self) -> BloomResult<Option<String>> {
+        return match self.format {
+            KeyFormat::Lines => self.next_line(),
+            KeyFormat::LengthPrefixed => self.next_prefixed()
+        };
+    }
+
+    fn next_line(&mut self) -> BloomResult<Option<String>> {
+        loop {
+            let line : String = match self.reader.read_line() {
+                Ok(line) => line,
+                Err(ref e) if e.kind == IoErrorKind::EndOfFile => { return Ok(None) },
+                Err(e) => { return Err(BloomError::Io(e)) }
+            };
+
+            self.bytes_read += line.len() as u64;
+
+            let key : &str = line.as_slice().trim();
+            if !key.is_empty() {
+                return Ok(Some(String::from_str(key)));
+            }
+        }
+    }
+
+    fn next_prefixed(&mut self) -> BloomResult<Option<String>> {
+        let length : u32 = match self.reader.read_be_u32() {
+            Ok(length) => length,
+            Err(ref e) if e.kind == IoErrorKind::EndOfFile => { return Ok(None) },
+            Err(e) => { return Err(BloomError::Io(e)) }
+        };
+
+        let bytes : Vec<u8> = try!(self.reader.read_exact(length as usize));
+        self.bytes_read += 4 + length as u64;
+
+        return match String::from_utf8(bytes) {
+            Ok(key) => Ok(Some(key)),
+            Err(_) => Err(BloomError::Corrupt(format!("key ending at byte {} is not UTF-8", self.bytes_read)))
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyFormat, KeyReader};
+    use std::io::{File, TempDir};
+
+    #[test]
+    fn test_key_reader() {
+        let directory : TempDir = TempDir::new("bloomd-test-keys").unwrap();
+
+        let lines : Path = directory.path().join("keys.txt");
+        File::create(&lines).write_str("first\n\nsecond\r\nthird\n").unwrap();
+
+        let mut reader : KeyReader = KeyReader::open(lines.as_str().unwrap(), KeyFormat::Lines).unwrap();
+        assert_eq!(reader.next_batch(2).unwrap(), vec![String::from_str("first"), String::from_str("second")]);
+        assert_eq!(reader.next_batch(2).unwrap(), vec![String::from_str("third")]);
+        assert!(reader.next_batch(2).unwrap().is_empty());
+        assert_eq!(reader.bytes_read(), reader.total_bytes());
+
+        let prefixed : Path = directory.path().join("keys.bin");
+        {
+            let mut file : File = File::create(&prefixed).unwrap();
+            file.write_be_u32(9).unwrap();
+            file.write_str("two words").unwrap();
+            file.write_be_u32(1).unwrap();
+            file.write_str("x").unwrap();
+        }
+
+        let mut reader : KeyReader = KeyReader::open(prefixed.as_str().unwrap(), KeyFormat::LengthPrefixed).unwrap();
+        assert_eq!(reader.next_batch(10).unwrap(), vec![String::from_str("two words"), String::from_str("x")]);
+    }
+}
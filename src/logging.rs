@@ -0,0 +1,407 @@
+extern crate libc;
+extern crate time;
+
+use self::libc::{c_char, c_int, sa_family_t, size_t, sockaddr_un, AF_UNIX, SOCK_DGRAM};
+use std::ascii::AsciiExt;
+use std::fmt;
+use std::io;
+use std::io::{File, FileMode, FileAccess, IoError, IoErrorKind, IoResult, LineBufferedWriter};
+use std::mem;
+use std::sync::Mutex;
+
+// constants -------------------------------------------------------------------
+const DESTINATION_STDERR        : &'static str = "stderr";
+const DESTINATION_FILE_PREFIX   : &'static str = "file:";
+const DESTINATION_SYSLOG        : &'static str = "syslog";
+const DESTINATION_SYSLOG_PREFIX : &'static str = "syslog:";
+const DEFAULT_SYSLOG_SOCKET     : &'static str = "/dev/log";
+
+const FORMAT_TEXT               : &'static str = "text";
+const FORMAT_JSON               : &'static str = "json";
+
+const SYSLOG_FACILITY_DAEMON    : u32 = 3;
+const SYSLOG_TAG                : &'static str = "bloomd";
+// -----------------------------------------------------------------------------
+
+lazy_static! {
+    // The logger every message goes through; it writes info and above to stderr until configure is called
+    static ref LOGGER : Mutex<Logger> = Mutex::new(Logger {
+        level: Level::Info,
+        format: Format::Text,
+        destination: Destination::Stderr,
+        writer: Box::new(io::stderr()) as Box<Writer + Send>
+    });
+}
+
+// How severe a message is; messages below the configured level are dropped
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error
+}
+
+impl Level {
+    // Parses a level name as used in the config file
+    pub fn from_name(name : &str) -> Option<Level> {
+        return match name {
+            "debug" => Some(Level::Debug),
+            "info"  => Some(Level::Info),
+            "warn"  => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            _ => None
+        };
+    }
+
+    // Returns the name of the level, as used in the config file
+    pub fn name(&self) -> &'static str {
+        return match *self {
+            Level::Debug => "debug",
+            Level::Info  => "info",
+            Level::Warn  => "warn",
+            Level::Error => "error"
+        };
+    }
+
+    // returns the syslog severity matching the level
+    fn syslog_severity(&self) -> u32 {
+        return match *self {
+            Level::Debug => 7,
+            Level::Info  => 6,
+            Level::Warn  => 4,
+            Level::Error => 3
+        };
+    }
+}
+
+impl fmt::String for Level {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.name());
+    }
+}
+
+// How each message is written out
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Text, // <time> <LEVEL> <message>
+    Json  // {"time":"<time>","level":"<level>","message":"<message>"}
+}
+
+impl Format {
+    // Parses a format name as used in the config file
+    pub fn from_name(name : &str) -> Option<Format> {
+        return match name {
+            FORMAT_TEXT => Some(Format::Text),
+            FORMAT_JSON => Some(Format::Json),
+            _ => None
+        };
+    }
+
+    // Returns the name of the format, as used in the config file
+    pub fn name(&self) -> &'static str {
+        return match *self {
+            Format::Text => FORMAT_TEXT,
+            Format::Json => FORMAT_JSON
+        };
+    }
+}
+
+// Where messages are written to
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Destination {
+    Stderr,         // standard error
+    File(String),   // appended to the file with the given path
+    Syslog(String)  // sent to the syslog daemon listening on the local socket with the given path
+}
+
+impl Destination {
+    // Parses a destination as used in the config file: 'stderr', 'file:<path>', 'syslog' or 'syslog:<socket path>'
+    pub fn from_name(name : &str) -> Option<Destination> {
+        if name == DESTINATION_STDERR {
+            return Some(Destination::Stderr);
+        } else if name == DESTINATION_SYSLOG {
+            return Some(Destination::Syslog(String::from_str(DEFAULT_SYSLOG_SOCKET)));
+        } else if name.starts_with(DESTINATION_FILE_PREFIX) && name.len() > DESTINATION_FILE_PREFIX.len() {
+            return Some(Destination::File(String::from_str(&name[DESTINATION_FILE_PREFIX.len()..])));
+        } else if name.starts_with(DESTINATION_SYSLOG_PREFIX) && name.len() > DESTINATION_SYSLOG_PREFIX.len() {
+            return Some(Destination::Syslog(String::from_str(&name[DESTINATION_SYSLOG_PREFIX.len()..])));
+        }
+
+        return None;
+    }
+
+    // Returns the destination, as written in the config file
+    pub fn name(&self) -> String {
+        return match *self {
+            Destination::Stderr => String::from_str(DESTINATION_STDERR),
+            Destination::File(ref path) => format!("{}{}", DESTINATION_FILE_PREFIX, path),
+            Destination::Syslog(ref path) => format!("{}{}", DESTINATION_SYSLOG_PREFIX, path)
+        };
+    }
+
+    // opens a writer for the destination
+    fn open(&self) -> Result<Box<Writer + Send>, String> {
+        return match *self {
+            Destination::Stderr => Ok(Box::new(io::stderr()) as Box<Writer + Send>),
+            Destination::File(ref path) => {
+                match File::open_mode(&Path::new(path.as_slice()), FileMode::Append, FileAccess::Write) {
+                    Ok(file) => Ok(Box::new(LineBufferedWriter::new(file)) as Box<Writer + Send>),
+                    Err(e) => Err(format!("could not open log file {}: {}", path, e))
+                }
+            },
+            Destination::Syslog(ref path) => {
+                match SyslogSocket::connect(path.as_slice()) {
+                    Ok(socket) => Ok(Box::new(socket) as Box<Writer + Send>),
+                    Err(e) => Err(format!("could not connect to syslog socket {}: {}", path, e))
+                }
+            }
+        };
+    }
+}
+
+// The configured level, format and destination, along with the open destination
+struct Logger {
+    level       : Level,
+    format      : Format,
+    destination : Destination,
+    writer      : Box<Writer + Send>
+}
+
+// Sets the level, format and destination of the log, reopening the destination if it changed
+pub fn configure(level : Level, format : Format, destination : &Destination) -> Result<(), String> {
+    let mut logger = LOGGER.lock().unwrap();
+
+    if logger.destination != *destination {
+        logger.writer = try!(destination.open());
+        logger.destination = destination.clone();
+    }
+
+    logger.level = level;
+    logger.format = format;
+
+    return Ok(());
+}
+
+// Writes a message to the log, if its level is at or above the configured one
+pub fn log(level : Level, message : String) {
+    let mut logger = LOGGER.lock().unwrap();
+
+    if level < logger.level {
+        return;
+    }
+
+    // there is nowhere left to report a failing log destination
+    let _ = match logger.destination {
+        // syslog wants a priority up front, adds its own timestamps, and takes one datagram per message
+        Destination::Syslog(_) => {
+            let line : String = format!("<{}>{}: {}", SYSLOG_FACILITY_DAEMON * 8 + level.syslog_severity(), SYSLOG_TAG,
+                                        format_line(logger.format, "", level, message.as_slice()));
+            logger.writer.write_str(line.as_slice())
+        },
+        _ => {
+            let line : String = format_line(logger.format, time::now_utc().rfc3339().to_string().as_slice(), level, message.as_slice());
+            logger.writer.write_line(line.as_slice())
+        }
+    };
+}
+
+// A connection to the datagram socket the syslog daemon listens on; each write is sent as one message
+struct SyslogSocket {
+    path : String, // the path of the socket, kept to reconnect after the daemon restarts
+    fd   : c_int   // the connected socket, or -1 if it is closed
+}
+
+impl SyslogSocket {
+    // connects to the syslog socket at the given path
+    fn connect(path : &str) -> IoResult<SyslogSocket> {
+        let mut socket : SyslogSocket = SyslogSocket { path: String::from_str(path), fd: -1 };
+        try!(socket.reconnect());
+
+        return Ok(socket);
+    }
+
+    // opens a new socket and connects it to the path, closing the old one first
+    fn reconnect(&mut self) -> IoResult<()> {
+        self.close();
+
+        let mut address : sockaddr_un = unsafe { mem::zeroed() };
+
+        // the path has to fit with its terminating zero
+        if self.path.len() >= address.sun_path.len() {
+            return Err(IoError { kind: IoErrorKind::InvalidInput, desc: "syslog socket path is too long", detail: Some(self.path.clone()) });
+        }
+
+        address.sun_family = AF_UNIX as sa_family_t;
+
+        for (i, byte) in self.path.as_bytes().iter().enumerate() {
+            address.sun_path[i] = *byte as c_char;
+        }
+
+        unsafe {
+            self.fd = externals::socket(AF_UNIX, SOCK_DGRAM, 0);
+
+            if self.fd < 0 {
+                return Err(IoError::last_error());
+            }
+
+            if externals::connect(self.fd, &address, mem::size_of::<sockaddr_un>() as u32) < 0 {
+                let error : IoError = IoError::last_error();
+                self.close();
+
+                return Err(error);
+            }
+        }
+
+        return Ok(());
+    }
+
+    // sends a single datagram, returning false if it could not be sent
+    fn send(&self, buf : &[u8]) -> bool {
+        return self.fd >= 0 && unsafe { externals::send(self.fd, buf.as_ptr(), buf.len() as size_t, 0) } >= 0;
+    }
+
+    // closes the socket, if it is open
+    fn close(&mut self) {
+        if self.fd >= 0 {
+            unsafe { externals::close(self.fd) };
+            self.fd = -1;
+        }
+    }
+}
+
+impl Writer for SyslogSocket {
+    fn write(&mut self, buf : &[u8]) -> IoResult<()> {
+        if self.send(buf) {
+            return Ok(());
+        }
+
+        // the daemon may have been restarted, which leaves the old socket unconnected
+        try!(self.reconnect());
+
+        if !self.send(buf) {
+            return Err(IoError::last_error());
+        }
+
+        return Ok(());
+    }
+}
+
+impl Drop for SyslogSocket {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Formats a single log line; an empty time is left out
+pub fn format_line(format : Format, time : &str, level : Level, message : &str) -> String {
+    return match format {
+        Format::Text => {
+            if time.is_empty() {
+                format!("{} {}", level.name().to_ascii_uppercase(), message)
+            } else {
+                format!("{} {} {}", time, level.name().to_ascii_uppercase(), message)
+            }
+        },
+        Format::Json => {
+            if time.is_empty() {
+                format!("{{\"level\":\"{}\",\"message\":\"{}\"}}", level, escape_json(message))
+            } else {
+                format!("{{\"time\":\"{}\",\"level\":\"{}\",\"message\":\"{}\"}}", time, level, escape_json(message))
+            }
+        }
+    };
+}
+
+// escapes a string for use inside a JSON string literal
+fn escape_json(value : &str) -> String {
+    let mut escaped : String = String::new();
+
+    for c in value.chars() {
+        match c {
+            '"'  => { escaped.push_str("\\\"") },
+            '\\' => { escaped.push_str("\\\\") },
+            '\n' => { escaped.push_str("\\n") },
+            '\r' => { escaped.push_str("\\r") },
+            '\t' => { escaped.push_str("\\t") },
+            c if (c as u32) < 0x20 => { escaped.push_str(format!("\\u{:04x}", c as u32).as_slice()) },
+            c => { escaped.push(c) }
+        }
+    }
+
+    return escaped;
+}
+
+// Logs a message at the debug level
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::Level::Debug, format!($($arg)*)))
+}
+
+// Logs a message at the info level
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::Level::Info, format!($($arg)*)))
+}
+
+// Logs a message at the warn level
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::Level::Warn, format!($($arg)*)))
+}
+
+// Logs a message at the error level
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::Level::Error, format!($($arg)*)))
+}
+
+mod externals {
+    use super::libc::{c_int, size_t, sockaddr_un, ssize_t};
+
+    extern {
+        pub fn socket(domain : c_int, socket_type : c_int, protocol : c_int) -> c_int;
+
+        pub fn connect(fd : c_int, address : *const sockaddr_un, length : u32) -> c_int;
+
+        pub fn send(fd : c_int, buf : *const u8, length : size_t, flags : c_int) -> ssize_t;
+
+        pub fn close(fd : c_int) -> c_int;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Destination, Format, Level, format_line};
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(format_line(Format::Text, "2015-01-20T10:00:00Z", Level::Warn, "Could not load filter"),
+                   "2015-01-20T10:00:00Z WARN Could not load filter");
+        assert_eq!(format_line(Format::Json, "2015-01-20T10:00:00Z", Level::Info, "Dropped filter \"a\"\n"),
+                   "{\"time\":\"2015-01-20T10:00:00Z\",\"level\":\"info\",\"message\":\"Dropped filter \\\"a\\\"\\n\"}");
+        assert_eq!(format_line(Format::Text, "", Level::Error, "failed"), "ERROR failed");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Level::from_name("warn"), Some(Level::Warn));
+        assert_eq!(Level::from_name("verbose"), None);
+        assert!(Level::Debug < Level::Error);
+
+        assert_eq!(Format::from_name("json"), Some(Format::Json));
+        assert_eq!(Format::from_name("xml"), None);
+
+        assert_eq!(Destination::from_name("stderr"), Some(Destination::Stderr));
+        assert_eq!(Destination::from_name("file:/var/log/bloomd.log"), Some(Destination::File(String::from_str("/var/log/bloomd.log"))));
+        assert_eq!(Destination::from_name("syslog"), Some(Destination::Syslog(String::from_str("/dev/log"))));
+        assert_eq!(Destination::from_name("syslog:/run/log.sock"), Some(Destination::Syslog(String::from_str("/run/log.sock"))));
+        assert_eq!(Destination::from_name("file:"), None);
+    }
+
+    #[test]
+    fn test_open_syslog() {
+        assert!(Destination::Syslog(String::from_str("/nonexistent/bloomd-test-log")).open().is_err());
+        assert!(Destination::Syslog("/tmp".repeat(40)).open().is_err());
+    }
+}
@@ -5,37 +5,55 @@
 #![allow(improper_ctypes)]
 #![feature(unboxed_closures)]
 
-use config::{BloomConfig, BloomFilterConfig};
-use filter::IBloomFilter;
-use bloom::{bloom_filter_params, create_bloom_filter_params};
-use lbf::bloom_lbf;
-use wrappers::BloomFilter;
+extern crate openssl;
+
+// the filter engine; its logging macros are available to the server's modules
+#[macro_use]
+extern crate bloomd;
+
+use bloomd::{logging, now, precise_time_ns, seconds_since};
+use bloomd::{Permission, Role};
+use bloomd::error::{BloomError, BloomResult};
+use bloomd::config::{BloomConfig, BloomFilterConfig, ConfigOverride, FilterProfile, FILTER_TYPE_LAYERED, FILTER_TYPE_PARTITIONED};
+use bloomd::{IBloomFilter, IoMode};
+use bloomd::{bloom_filter_params, create_bloom_filter_params};
+use bloomd::bloom_lbf;
+use bloomd::loader::{KeyFormat, KeyReader};
+use bloomd::{FilterSnapshot, SnapshotSource, protocol_error};
+use bloomd::wrappers::{BloomFilter, LoadProgress, FILTER_FOLDER_PREFIX};
+use metrics::{FilterSample, ServerMetrics};
+use replication::{LeaderConnection, LeaderEvent, ReplicationEvent, Replicas};
+use slowlog::{SlowLog, SlowLogEntry};
+use unix_socket::UnixSocketGuard;
 use std::os;
 use std::io;
-use std::io::{fs, TcpListener, Listener, Acceptor, Stream, BufferedStream};
+use std::io::{fs, TcpListener, TcpStream, Listener, Acceptor, Stream, BufferedStream, IoResult};
+use std::io::net::tcp::TcpAcceptor;
+use std::io::net::pipe::{UnixAcceptor, UnixStream};
 use std::io::fs::PathExtensions;
+use std::io::timer;
 use std::io::timer::Timer;
 use std::time::Duration;
 use std::path::Path;
 use std::thread::Thread;
-use std::sync::{Arc, RwLock};
-use std::sync::mpsc::Receiver;
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
+use openssl::ssl::{SslContext, SslStream};
 
-mod bitmap;
-mod bloom;
-mod config;
-mod filter;
-mod inifile;
-mod lbf;
-mod util;
-mod wrappers;
+mod metrics;
+mod replication;
+mod signals;
+mod slowlog;
+mod tls;
+mod unix_socket;
 
 // constants -------------------------------------------------------------------
-const USAGE                   : &'static str = "bloomd [-f config_file]";
-
-const FILTER_FOLDER_PREFIX    : &'static str = "filter.";
+const USAGE                   : &'static str = "bloomd [-f config_file] [--<option>=<value> ...] [--check-config] [--print-config]\r\n\r\n\
+Settings are taken from, in increasing order of precedence: the defaults, the config file,\r\n\
+BLOOMD_<OPTION> environment variables (e.g. BLOOMD_DATA_DIR), and --<option>=<value> flags.";
 
 const MESSAGE_START           : &'static str = "START\r\n";
 const MESSAGE_END             : &'static str = "END";
@@ -46,6 +64,15 @@ const MESSAGE_YES             : &'static str = "Yes";
 const MESSAGE_NO              : &'static str = "No";
 const MESSAGE_NOT_IMPLEMENTED : &'static str = "Client Error: Command not supported";
 const MESSAGE_BAD_ARGS        : &'static str = "Client Error: Bad arguments";
+const MESSAGE_UNAUTHORIZED    : &'static str = "Client Error: Unauthorized";
+const MESSAGE_NO_CONFIG_FILE  : &'static str = "Client Error: Server was started without a config file";
+const MESSAGE_RESTART_NEEDED  : &'static str = "Done, restart required for:";
+const MESSAGE_INVALID_CONFIG  : &'static str = "Client Error: Invalid config:";
+const MESSAGE_SERVER_ERROR    : &'static str = "Server Error:";
+const MESSAGE_LOADING         : &'static str = "Client Error: Filter is already loading";
+const MESSAGE_READ_ONLY       : &'static str = "Client Error: Server is a read-only follower";
+
+const COMMAND_AUTH            : &'static str = "auth";
 
 const COMMAND_BULK_AB         : &'static str = "b";
 const COMMAND_BULK            : &'static str = "bulk";
@@ -55,30 +82,168 @@ const COMMAND_CREATE          : &'static str = "create";
 const COMMAND_CLOSE           : &'static str = "close";
 const COMMAND_CLEAR           : &'static str = "clear";
 const COMMAND_DROP            : &'static str = "drop";
+const COMMAND_EXPORT          : &'static str = "export";
+const COMMAND_IMPORT          : &'static str = "import";
 const COMMAND_INFO            : &'static str = "info";
 const COMMAND_LIST            : &'static str = "list";
+const COMMAND_LOAD            : &'static str = "load";
 const COMMAND_MULTI_AB        : &'static str = "m";
 const COMMAND_MULTI           : &'static str = "multi";
 const COMMAND_FLUSH           : &'static str = "flush";
 const COMMAND_SET_AB          : &'static str = "s";
 const COMMAND_SET             : &'static str = "set";
+const COMMAND_RELOAD          : &'static str = "reload";
+const COMMAND_REPLICATE       : &'static str = "replicate";
+const COMMAND_STATS           : &'static str = "stats";
+const COMMAND_SLOWLOG         : &'static str = "slowlog";
+const COMMAND_SWAP            : &'static str = "swap";
+
+const SLOWLOG_RESET           : &'static str = "reset";
+const SLOWLOG_DEFAULT_COUNT   : usize        = 10;
+
+const LOAD_BATCH_SIZE         : usize        = 10000;
+const LOAD_POLL_MS            : i64          = 100;
+const LOAD_CANCEL             : &'static str = "cancel";
+
+const DEFAULT_PARTITIONS      : u32          = 16;
+
+const SWAP_FOLDER_PREFIX      : &'static str = "swap.";
+const RETIRED_FOLDER_PREFIX   : &'static str = "retired.";
+
+const NO_TTL                  : &'static str = "-";
+
+const REPLICATION_RETRY_MS    : i64          = 1000;
+const REPLICATION_QUEUE_SIZE  : usize        = 100000;
+
+const METRICS_READ_TIMEOUT_MS : u64          = 5000;
+
+// the kinds of error responses counted by 'stats', and the responses they stand for
+// 'Exists' only says a create had nothing to do, so it isn't counted
+static ERROR_KINDS : [(&'static str, &'static str); 9] = [
+    ("bad_args",        MESSAGE_BAD_ARGS),
+    ("invalid_config",  MESSAGE_INVALID_CONFIG),
+    ("loading",         MESSAGE_LOADING),
+    ("no_config_file",  MESSAGE_NO_CONFIG_FILE),
+    ("no_exist",        MESSAGE_NO_EXIST),
+    ("not_implemented", MESSAGE_NOT_IMPLEMENTED),
+    ("read_only",       MESSAGE_READ_ONLY),
+    ("server_error",    MESSAGE_SERVER_ERROR),
+    ("unauthorized",    MESSAGE_UNAUTHORIZED)
+];
 // -----------------------------------------------------------------------------
 
+// the state kept for each client connection
+struct ClientSession {
+    role    : Option<Role>, // the role the client authenticated as, if any
+    address : String        // the address the client connected from, empty if unknown
+}
+
+impl ClientSession {
+    // returns a session that has not authenticated yet
+    fn new() -> Self {
+        return ClientSession::from_address(String::new());
+    }
+
+    // returns a session for a client connected from the given address, that has not authenticated yet
+    fn from_address(address : String) -> Self {
+        return ClientSession { role: None, address: address };
+    }
+}
+
+// a key file waiting to be loaded into a filter by the load thread
+struct PendingLoad {
+    filter_name : String,
+    key_file    : String,
+    reader      : KeyReader
+}
+
+// a stream that can tell where the client on the other end connected from
+trait ClientAddress {
+    fn client_address(&mut self) -> String;
+}
+
+impl ClientAddress for TcpStream {
+    fn client_address(&mut self) -> String {
+        return match self.peer_name() {
+            Ok(address) => address.to_string(),
+            Err(_) => String::new()
+        };
+    }
+}
+
+impl ClientAddress for UnixStream {
+    fn client_address(&mut self) -> String {
+        return String::from_str("unix");
+    }
+}
+
 // represents a bloom filter server
 struct BloomServer {
-    pub config  : BloomConfig,
-    filters     : RwLock<HashMap<String, RwLock<BloomFilter>>>
+    config           : RwLock<BloomConfig>,
+    config_filename  : Option<String>,       // the file the config was read from, if any
+    config_overrides : Vec<ConfigOverride>,  // environment and command line settings, reapplied on reload
+    filters          : RwLock<HashMap<String, RwLock<BloomFilter>>>,
+    shutting_down    : AtomicBool,           // set once the server stops serving requests
+    flushes_aborted  : AtomicBool,           // set when a shutdown runs out of time, to stop flushes between filters
+    active_requests  : AtomicUsize,          // number of requests currently being interpreted
+    metrics          : ServerMetrics,        // connection, latency and flush measurements
+    slowlog          : Mutex<SlowLog>,       // the most recent commands that exceeded slowlog_threshold
+    replicas         : Replicas,             // the followers changes to the filters are streamed to
+    pending_loads    : Mutex<Vec<PendingLoad>> // loads started by clients, not yet picked up by the load thread
 }
 
 impl BloomServer {
     // create a new BloomServer with the given configuration, reading in pre-existing filters
     fn new(config : BloomConfig) -> Self {
-        return BloomServer { config: config, filters: RwLock::new(HashMap::new()) };
+        return BloomServer {
+            config: RwLock::new(config),
+            config_filename: None,
+            config_overrides: Vec::new(),
+            filters: RwLock::new(HashMap::new()),
+            shutting_down: AtomicBool::new(false),
+            flushes_aborted: AtomicBool::new(false),
+            active_requests: AtomicUsize::new(0),
+            metrics: ServerMetrics::new(),
+            slowlog: Mutex::new(SlowLog::new()),
+            replicas: Replicas::new(),
+            pending_loads: Mutex::new(Vec::new())
+        };
+    }
+
+    // returns a copy of the current configuration
+    fn config(&self) -> BloomConfig {
+        return self.config.read().unwrap().clone();
+    }
+
+    // obtains a read lock on the configuration and passes the reference to the given function
+    // returns the result of calling the given function
+    fn use_config<T, F : Fn(&BloomConfig) -> T>(&self, user : F) -> T {
+        return user(&*self.config.read().unwrap());
+    }
+
+    // re-reads the config file, applying the settings that can change at runtime
+    // returns the names of the changed settings that need a restart
+    fn reload_config(&self) -> Result<Vec<&'static str>, String> {
+        let filename : &str = match self.config_filename {
+            Some(ref filename) => filename.as_slice(),
+            None => { return Err(String::from_str(MESSAGE_NO_CONFIG_FILE)) }
+        };
+
+        let new_config : BloomConfig = match BloomConfig::load(Some(filename), self.config_overrides.as_slice()) {
+            Ok(new_config) => new_config,
+            Err(e) => { return Err(format!("{} {}", MESSAGE_INVALID_CONFIG, e.summary())) }
+        };
+
+        let restart_required : Vec<&'static str> = self.config.write().unwrap().apply_live_settings(&new_config);
+
+        try!(configure_logging(&new_config));
+
+        return Ok(restart_required);
     }
 
     // read existing filters from disk
     fn read_in_filters(&self) {
-        let paths = fs::readdir(&Path::new(self.config.data_dir.clone())).unwrap();
+        let paths = fs::readdir(&Path::new(self.use_config(|config| config.data_dir.clone()))).unwrap();
 
         // Check each directory in the data directory
         for path in paths.iter() {
@@ -93,8 +258,12 @@ impl BloomServer {
                         
                         // If it is a valid filter, add it to the filters list
                         match BloomFilter::from_directory(path, &filter_name, false) {
-                            Ok(filter) => { filters.insert(filter_name, RwLock::new(filter)); return (); },
-                            Err(e) => { println!("Could not read filter from directory {}: {}", path.display(), e) }
+                            Ok(filter) => {
+                                log_info!("Loaded filter {} from {}", filter_name, path.display());
+                                filters.insert(filter_name, RwLock::new(filter));
+                                return ();
+                            },
+                            Err(e) => { log_error!("Could not read filter from directory {}: {}", path.display(), e) }
                         };
                     });
                 }
@@ -103,8 +272,11 @@ impl BloomServer {
     }
     
     // handle a client connection
-    fn handle_client<S : Stream>(&self, stream: S) {
+    fn handle_client<S : Stream>(&self, stream: S, address : String) {
         let mut buf_stream : BufferedStream<S> = BufferedStream::new(stream);
+        let mut session : ClientSession = ClientSession::from_address(address);
+
+        self.metrics.connection_opened();
 
         loop {
             // try to read input from client
@@ -115,24 +287,318 @@ impl BloomServer {
                 }
             }; 
 
+            // stop serving requests once the server is shutting down
+            if !self.begin_request() {
+                break;
+            }
+
             // clean up input
             let chars_to_trim: &[char] = &[' ', '\n', '\r'];
             let trim_line : &str = line.as_slice().trim_matches(chars_to_trim);
 
-            // respond to input
-            let response : String = self.interpret_request(trim_line);
-            buf_stream.write_str(response.as_slice()).unwrap();
-            buf_stream.write_str("\r\n").unwrap();
+            // a follower's connection becomes the stream of changes sent to it, until either side goes away
+            if command_name(trim_line) == COMMAND_REPLICATE && self.is_authorized(&session, split_args(trim_line).as_slice()) {
+                self.end_request();
+
+                match self.serve_follower(&mut buf_stream) {
+                    Ok(_) => { log_info!("Stopped replicating to follower {}", session.address) },
+                    Err(e) => { log_warn!("Stopped replicating to follower {}: {}", session.address, e) }
+                }
+
+                break;
+            }
+
+            // respond to input; export and import carry a filter's files along, so they use the stream themselves
+            let response : IoResult<String> = match command_name(trim_line) {
+                COMMAND_EXPORT => self.handle_export(&session, trim_line, &mut buf_stream),
+                COMMAND_IMPORT => self.handle_import(&session, trim_line, &mut buf_stream),
+                _ => Ok(self.handle_request(&mut session, trim_line))
+            };
+            self.end_request();
+
+            // a transfer cut off halfway leaves the stream out of step with the client
+            let response : String = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    log_warn!("Dropped connection from {} during {}: {}", session.address, command_name(trim_line), e);
+                    break;
+                }
+            };
+
+            // a client that went away before its response is dropped like one that sent nothing more
+            match write_response(&mut buf_stream, response.as_slice()) {
+                Err(e) => {
+                    log_debug!("Could not respond to {}: {}", session.address, e);
+                    break;
+                },
+                Ok(_) => { }
+            }
+        };
+
+        self.metrics.connection_closed();
+    }
+
+    // registers an in-flight request; returns false if the server is shutting down
+    fn begin_request(&self) -> bool {
+        self.active_requests.fetch_add(1, Ordering::SeqCst);
+
+        if self.shutting_down.load(Ordering::SeqCst) {
+            self.end_request();
+            return false;
+        }
+
+        return true;
+    }
+
+    // unregisters an in-flight request
+    fn end_request(&self) {
+        self.active_requests.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // returns true once the server has stopped serving requests
+    fn is_shutting_down(&self) -> bool {
+        return self.shutting_down.load(Ordering::SeqCst);
+    }
+
+    // stops serving new requests; set before the listeners are closed, so accept loops know to stop
+    fn stop_serving(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    // stops serving new requests, and waits until the in-flight ones are done or the deadline passes
+    // the deadline is in nanoseconds, as returned by precise_time_ns; returns false if it passed first
+    fn drain_requests(&self, deadline : u64) -> bool {
+        self.stop_serving();
+
+        while self.active_requests.load(Ordering::SeqCst) > 0 {
+            if precise_time_ns() >= deadline {
+                return false;
+            }
+
+            timer::sleep(Duration::milliseconds(10));
+        }
+
+        return true;
+    }
+
+    // stops flush_filters from starting on any more filters, and waits for a flush in progress to finish
+    // the filter it's writing; flushes hold the filter map, so once it's free none is running
+    fn abort_flushes(&self) {
+        self.flushes_aborted.store(true, Ordering::SeqCst);
+        self.use_filters_mut(|_| { });
+    }
+
+    // flushes every filter that changed to disk, carrying on past filters that fail
+    // returns the last error, if any filter failed
+    fn flush_filters(&self) -> Result<(), BloomError> {
+        let start : u64 = precise_time_ns();
+
+        let (count, written, synced, result) : (usize, u64, u64, Result<(), BloomError>) = self.use_filters_mut(|filters| {
+            let mut result : Result<(), BloomError> = Ok(());
+            let mut count : usize = 0;
+            let mut written : u64 = 0;
+            let mut synced : u64 = 0;
+
+            for (name, filter_lock) in filters.iter() {
+                // a shutdown out of time stops here, leaving no filter written halfway
+                if self.flushes_aborted.load(Ordering::SeqCst) {
+                    log_warn!("Flush aborted before filter {}", name);
+                    break;
+                }
+
+                let mut filter = filter_lock.write().unwrap();
+
+                // clean filters are skipped without touching the disk
+                if !(*filter).is_dirty() {
+                    continue;
+                }
+
+                match (*filter).flush() {
+                    Ok((filter_written, filter_synced)) => {
+                        log_debug!("Flushed filter {} ({} bytes written, {} mapped bytes synced)", name, filter_written, filter_synced);
+                        count += 1;
+                        written += filter_written;
+                        synced += filter_synced;
+                    },
+                    Err(e) => {
+                        log_error!("Could not flush filter {}: {}", name, e);
+                        result = Err(e);
+                    }
+                }
+            }
+
+            return (count, written, synced, result);
+        });
+
+        let seconds : f64 = seconds_since(start);
+        self.metrics.observe_flush(seconds, written, synced);
+
+        log_info!("Flushed {} filters, {} bytes written, {} mapped bytes synced, in {:.3}s", count, written, synced, seconds);
+
+        return result;
+    }
+
+    // returns true if a metrics scrape with the given bearer token may see the metrics of every filter
+    fn metrics_authorized(&self, token : Option<&str>) -> bool {
+        return self.use_config(|config| {
+            if !config.access_control.is_enabled() {
+                return true;
+            }
+
+            return match token.and_then(|token| config.access_control.authenticate(token)) {
+                Some(role) => role.allows(Permission::Read, None),
+                None => false
+            };
+        });
+    }
+
+    // renders the server-wide and per-filter metrics in Prometheus exposition format
+    fn render_metrics(&self) -> String {
+        let mut result : String = String::new();
+
+        self.metrics.write(&mut result);
+
+        let samples : Vec<FilterSample> = self.use_filters(|filters| {
+            let mut names : Vec<&String> = filters.keys().collect();
+            names.sort();
+
+            return names.iter().map(|name| FilterSample::new(name.as_slice(), &*filters.get(*name).unwrap().read().unwrap())).collect();
+        });
+
+        metrics::write_filter_samples(&mut result, samples.as_slice());
+
+        return result;
+    }
+
+    // handles a client request, recording how long it took and whether it failed
+    fn handle_request(&self, session : &mut ClientSession, input : &str) -> String {
+        let start : u64 = precise_time_ns();
+        let response : String = self.authorize_request(session, input);
+
+        self.observe_request(session, input, start, response.as_slice());
+
+        return response;
+    }
+
+    // serves an 'export' request, writing the filter's files to the stream ahead of the response
+    // returns the response, or an error if writing the files failed
+    fn handle_export<S : Stream>(&self, session : &ClientSession, input : &str, stream : &mut BufferedStream<S>) -> IoResult<String> {
+        let start : u64 = precise_time_ns();
+        let args : Vec<&str> = split_args(input);
+
+        let response : String = if self.is_authorized(session, args.as_slice()) {
+            try!(self.process_export(args[1..].to_vec(), stream))
+        } else {
+            String::from_str(MESSAGE_UNAUTHORIZED)
+        };
+
+        self.observe_request(session, input, start, response.as_slice());
+
+        return Ok(response);
+    }
+
+    // serves an 'import' request, reading the filter's files that follow it from the stream
+    // returns the response, or an error if the files could not be read or the request was refused
+    fn handle_import<S : Stream>(&self, session : &ClientSession, input : &str, stream : &mut BufferedStream<S>) -> IoResult<String> {
+        let start : u64 = precise_time_ns();
+        let args : Vec<&str> = split_args(input);
+
+        // a refused request is answered and the connection closed, rather than reading in files only to throw them away
+        if !self.is_authorized(session, args.as_slice()) {
+            self.observe_request(session, input, start, MESSAGE_UNAUTHORIZED);
+
+            try!(stream.write_str(MESSAGE_UNAUTHORIZED));
+            try!(stream.write_str("\r\n"));
+            try!(stream.flush());
+
+            return Err(protocol_error("import refused, so the files sent with it were not read", input));
+        }
+
+        let snapshot : FilterSnapshot = try!(FilterSnapshot::read(stream));
+        let response : String = self.process_import(args[1..].to_vec(), &snapshot);
+
+        self.observe_request(session, input, start, response.as_slice());
+
+        return Ok(response);
+    }
+
+    // records a served request in the metrics, and in the slow log if it took long enough
+    fn observe_request(&self, session : &ClientSession, input : &str, start : u64, response : &str) {
+        let seconds : f64 = seconds_since(start);
 
-            buf_stream.flush().unwrap();
+        self.metrics.observe_command(command_name(input), seconds);
+        self.record_if_slow(session, input, seconds);
+
+        match error_kind(response) {
+            Some(kind) => { self.metrics.record_error(kind) },
+            None => { }
+        }
+    }
+
+    // adds a request to the slow log if it took at least slowlog_threshold milliseconds
+    fn record_if_slow(&self, session : &ClientSession, input : &str, seconds : f64) {
+        let (threshold, max_len) : (i32, i32) = self.use_config(|config| (config.slowlog_threshold, config.slowlog_max_len));
+
+        if max_len == 0 || seconds * 1000.0 < threshold as f64 {
+            return;
+        }
+
+        let args : Vec<&str> = split_args(input);
+
+        let entry : SlowLogEntry = SlowLogEntry {
+            id: 0,
+            timestamp: now(),
+            duration_us: (seconds * 1e6) as u64,
+            command: String::from_str(command_name(input)),
+            filter: request_filter(args.as_slice()).map(|filter| String::from_str(filter)),
+            arg_count: if args.is_empty() { 0 } else { args.len() - 1 },
+            client: session.address.clone()
+        };
+
+        self.slowlog.lock().unwrap().record(entry, max_len as usize);
+    }
+
+    // checks a client request against the session's permissions before interpreting it
+    fn authorize_request(&self, session : &mut ClientSession, input : &str) -> String {
+        let args : Vec<&str> = split_args(input);
+
+        // authentication is always allowed
+        if !args.is_empty() && args[0] == COMMAND_AUTH {
+            return self.process_auth(session, args[1..].to_vec());
+        }
+
+        if !self.is_authorized(session, args.as_slice()) {
+            return String::from_str(MESSAGE_UNAUTHORIZED);
+        }
+
+        return self.interpret_request(input);
+    }
+
+    // returns true if the session's role allows the request; everything is allowed while authentication is disabled
+    fn is_authorized(&self, session : &ClientSession, args : &[&str]) -> bool {
+        if !self.use_config(|config| config.access_control.is_enabled()) || args.is_empty() {
+            return true;
+        }
+
+        // unknown commands fall through and are reported as such
+        return match command_permission(args[0]) {
+            Some(permission) => {
+                // the first argument is the filter name, or the prefix for 'list'
+                let filter_name : Option<&str> = if args.len() > 1 { Some(args[1]) } else { None };
+
+                match session.role {
+                    Some(ref role) => role.allows(permission, filter_name),
+                    None => false
+                }
+            },
+            None => true
         };
     }
 
     // interpret a client request
     fn interpret_request(&self, input : &str) -> String {
         // split input into valid arguments
-        let mut args : Vec<&str> = input.split(|&:c : char| c.is_whitespace())
-                                         .filter(|&s| s.len() > 0).collect();
+        let mut args : Vec<&str> = split_args(input);
 
         // handle empty input
         if args.is_empty() {
@@ -142,6 +608,11 @@ impl BloomServer {
         // get main command
         let command : &str = args.remove(0);
 
+        // a follower's filters only change as its leader tells it to
+        if modifies_filters(command) && self.use_config(|config| config.is_follower()) {
+            return String::from_str(MESSAGE_READ_ONLY);
+        }
+
         // process command arguments and return response 
         return match command {
             COMMAND_BULK     => { self.process_bulk  (args) },
@@ -154,11 +625,16 @@ impl BloomServer {
             COMMAND_DROP     => { self.process_drop  (args) },
             COMMAND_INFO     => { self.process_info  (args) },
             COMMAND_LIST     => { self.process_list  (args) },
+            COMMAND_LOAD     => { self.process_load  (args) },
             COMMAND_MULTI    => { self.process_multi (args) },
             COMMAND_MULTI_AB => { self.process_multi (args) },
             COMMAND_FLUSH    => { self.process_flush (args) },
             COMMAND_SET      => { self.process_set   (args) },
             COMMAND_SET_AB   => { self.process_set   (args) },
+            COMMAND_RELOAD   => { self.process_reload(args) },
+            COMMAND_STATS    => { self.process_stats (args) },
+            COMMAND_SLOWLOG  => { self.process_slowlog(args) },
+            COMMAND_SWAP     => { self.process_swap  (args) },
             _ => { String::from_str(MESSAGE_NOT_IMPLEMENTED) },
         }
     }
@@ -202,6 +678,25 @@ impl BloomServer {
         });
     }
 
+    // process an 'auth' command (auth <token>)
+    // returns a response String
+    fn process_auth(&self, session : &mut ClientSession, args : Vec<&str>) -> String {
+        // handle invalid arguments
+        if args.len() != 1 {
+            return String::from_str(MESSAGE_BAD_ARGS);
+        }
+
+        // nothing to check if authentication is disabled
+        if !self.use_config(|config| config.access_control.is_enabled()) {
+            return String::from_str(MESSAGE_DONE);
+        }
+
+        // a failed attempt also drops any earlier authentication
+        session.role = self.use_config(|config| config.access_control.authenticate(args[0]));
+
+        return String::from_str(if session.role.is_some() { MESSAGE_DONE } else { MESSAGE_UNAUTHORIZED });
+    }
+
     // process a 'bulk' command (bulk <filter> <key> ...)
     // returns a response String
     fn process_bulk(&self, args : Vec<&str>) -> String {
@@ -221,6 +716,8 @@ impl BloomServer {
         // form response
         return self.use_filter_mut(&filter_name, |filter| {
             let mut result : String = String::new();
+            let mut error : Option<BloomError> = None;
+            let mut keys_set : usize = 0;
 
             // iterate through remaining arguments
             for arg in args[1..].iter() {
@@ -230,13 +727,28 @@ impl BloomServer {
 
                 // get key and corresponding 'set' value
                 let key : String = String::from_str(*arg);
-                let value : u32 = self.set(filter, key);
+                let value : u32 = match filter.set(key) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        error = Some(e);
+                        break;
+                    }
+                };
 
                 result.push_str(format!("{}", value).as_slice());
+                keys_set += 1;
             }
 
-            return result;
-        }).unwrap();
+            // followers get the keys that made it in
+            if keys_set > 0 {
+                self.replicate_command(format!("{} {}", COMMAND_BULK, args[..keys_set + 1].connect(" ")));
+            }
+
+            return match error {
+                Some(e) => server_error(&e),
+                None => result
+            };
+        }).unwrap_or(String::from_str(MESSAGE_NO_EXIST));
     }
 
     // process a 'check' command (check <filter> <key>)
@@ -258,13 +770,19 @@ impl BloomServer {
         // form response
         return self.use_filter_mut(&filter_name, |filter| {
             let key : String = String::from_str(args[1]);
-            let value : u32 = self.check(filter, key);
 
-            return format!("{}", value);
-        }).unwrap();
+            return match filter.check(key) {
+                Ok(value) => format!("{}", value),
+                Err(e) => server_error(&e)
+            };
+        }).unwrap_or(String::from_str(MESSAGE_NO_EXIST));
     }
 
-    // process a 'create' command (create <filter> [capacity=<capacity>] [probability=<probability>] [in_memory=<in_memory>])
+    // process a 'create' command (create <filter> [capacity=<capacity>] [probability=<probability>] [in_memory=<in_memory>] [ttl=<seconds>] [expires_at=<unix_time>] [profile=<profile>] [partitions=<count>] [use_mmap=<use_mmap>] [load=<key_file>])
+    // settings not given fall back to the named profile, then the first profile matching the filter name, then the server defaults
+    // a filter with partitions spreads its keys and capacity across that many layered filters, paged in and out on their own
+    // a filter with use_mmap maps its bitmaps shared with their files, rather than reading them in and writing back the pages set
+    // a key file given with load= is loaded into the new filter, one key per line, as with 'load'
     // returns a response String
     fn process_create(&self, args : Vec<&str>) -> String {
         // handle invalid arguments
@@ -280,9 +798,16 @@ impl BloomServer {
             return String::from_str(MESSAGE_EXISTS);
         }
         
-        let mut capacity    : u64  = self.config.initial_capacity;
-        let mut probability : f64  = self.config.default_probability;
-        let mut in_memory   : bool = self.config.in_memory;
+        let config : BloomConfig = self.config();
+
+        let mut capacity    : Option<u64>  = None;
+        let mut probability : Option<f64>  = None;
+        let mut in_memory   : Option<bool> = None;
+        let mut use_mmap    : Option<bool> = None;
+        let mut expires_at  : Option<u64>  = None;
+        let mut profile     : Option<FilterProfile> = None;
+        let mut key_file    : Option<&str> = None;
+        let mut partitions  : Option<u32>  = None;
 
         // Check for manual parameters
         for arg in args[1..].iter() {
@@ -290,63 +815,183 @@ impl BloomServer {
                 let pieces : Vec<&str> = arg.split_str("=").collect();
                 let value_opt : Option<u64> = FromStr::from_str(pieces[1]);
                 if value_opt.is_some() {
-                    capacity = value_opt.unwrap();
+                    capacity = value_opt;
                 }
             } else if arg.starts_with("prob=") {
                 let pieces : Vec<&str> = arg.split_str("=").collect();
                 let value_opt : Option<f64> = FromStr::from_str(pieces[1]);
                 if value_opt.is_some() {
-                    probability = value_opt.unwrap();
+                    probability = value_opt;
                 }
             } else if arg.starts_with("in_memory=") {
                 let pieces : Vec<&str> = arg.split_str("=").collect();
                 let value_opt : Option<u8> = FromStr::from_str(pieces[1]);
                 if value_opt.is_some() {
-                    in_memory = value_opt.unwrap() > 0;
+                    in_memory = Some(value_opt.unwrap() > 0);
+                }
+            } else if arg.starts_with("use_mmap=") {
+                let pieces : Vec<&str> = arg.split_str("=").collect();
+                let value_opt : Option<u8> = FromStr::from_str(pieces[1]);
+                if value_opt.is_some() {
+                    use_mmap = Some(value_opt.unwrap() > 0);
+                }
+            } else if arg.starts_with("ttl=") {
+                let pieces : Vec<&str> = arg.split_str("=").collect();
+                let value_opt : Option<u64> = FromStr::from_str(pieces[1]);
+                if value_opt.is_some() {
+                    expires_at = Some(now() + value_opt.unwrap());
+                }
+            } else if arg.starts_with("expires_at=") {
+                let pieces : Vec<&str> = arg.split_str("=").collect();
+                let value_opt : Option<u64> = FromStr::from_str(pieces[1]);
+                if value_opt.is_some() {
+                    expires_at = value_opt;
+                }
+            } else if arg.starts_with("profile=") {
+                let pieces : Vec<&str> = arg.split_str("=").collect();
+                match config.profile_named(pieces[1]) {
+                    Some(named) => { profile = Some(named.clone()) },
+                    None => { return String::from_str(MESSAGE_BAD_ARGS) }
+                }
+            } else if arg.starts_with("partitions=") {
+                let pieces : Vec<&str> = arg.split_str("=").collect();
+                match FromStr::from_str(pieces[1]) {
+                    Some(count) if count > 0 => { partitions = Some(count) },
+                    _ => { return String::from_str(MESSAGE_BAD_ARGS) }
                 }
+            } else if arg.starts_with("load=") && arg.len() > "load=".len() {
+                key_file = Some(&arg["load=".len()..]);
             } else {
                 return String::from_str(MESSAGE_BAD_ARGS);
             }
         }
-        
-        // form response
-        self.use_filters_mut(|filters| {
-            let mut directory : Path = Path::new(self.config.data_dir.clone());
+
+        // without an explicit profile, use the first one whose patterns match the filter name
+        if profile.is_none() {
+            profile = config.profile_for_filter(filter_name.as_slice()).map(|matched| matched.clone());
+        }
+
+        // explicit parameters win over the profile, which wins over the server defaults
+        let profile : FilterProfile = profile.unwrap_or(FilterProfile::new(""));
+
+        let capacity    : u64    = capacity.or(profile.capacity).unwrap_or(config.initial_capacity);
+        let probability : f64    = probability.or(profile.probability).unwrap_or(config.default_probability);
+        let in_memory   : bool   = in_memory.or(profile.in_memory).unwrap_or(config.in_memory);
+        let io_mode     : IoMode = IoMode::from_use_mmap(use_mmap.or(profile.use_mmap).unwrap_or(config.use_mmap));
+        let expires_at  : u64    = expires_at.or(profile.ttl.map(|ttl| now() + ttl)).unwrap_or(0);
+        let filter_type : String = profile.filter_type.clone().unwrap_or(String::from_str(FILTER_TYPE_LAYERED));
+
+        // profiles can make filters partitioned, but leave the number of partitions to the default
+        let partitions  : u32    = partitions.unwrap_or(if filter_type.as_slice() == FILTER_TYPE_PARTITIONED { DEFAULT_PARTITIONS } else { 0 });
+
+        // a key file that can't be opened fails the create, rather than leaving an empty filter behind
+        let reader : Option<KeyReader> = match key_file {
+            Some(key_file) => {
+                match KeyReader::open(key_file, KeyFormat::Lines) {
+                    Ok(reader) => Some(reader),
+                    Err(e) => { return server_error(&e) }
+                }
+            },
+            None => None
+        };
+
+        // form response; an Err holds the response for a failure
+        let created : Result<(), String> = self.use_filters_mut(|filters| {
+            let mut directory : Path = Path::new(config.data_dir.clone());
             directory.push(format!("{}{}", FILTER_FOLDER_PREFIX, &filter_name).as_slice());
 
             let mut bloom_filter : BloomFilter;
-            if directory.exists() {
+            let revived : bool = directory.exists();
+
+            if revived {
                 // load filter from directory if it exists
                 bloom_filter = match BloomFilter::from_directory(&directory, &filter_name, true) {
                     Ok(filter) => filter,
-                    Err(e) => panic!("{}", e)
+                    Err(e) => { return Err(server_error(&e)) }
                 };
 
                 // if in_memory, delete filter from disk
                 if in_memory {
                     bloom_filter.config.in_memory = true;
-                    bloom_filter.delete().unwrap();
+
+                    match bloom_filter.delete() {
+                        Err(e) => { return Err(server_error(&e)) },
+                        Ok(_) => { }
+                    }
+                }
+
+                // a new ttl replaces the one the filter was persisted with
+                if expires_at > 0 {
+                    bloom_filter.config.expires_at = expires_at;
+                    bloom_filter.mark_dirty();
                 }
             } else {
                 // create new filter if directory does not exist
                 let params : bloom_filter_params;
                 match create_bloom_filter_params(capacity, probability) {
                     Ok(_params) => { params = _params },
-                    Err(e) => { panic!("{}", e) }
+                    Err(_) => { return Err(String::from_str(MESSAGE_BAD_ARGS)) }
                 };
 
-                let filter_config : BloomFilterConfig = BloomFilterConfig::new(filter_name.clone(), capacity, probability, params.k_num, in_memory, params.bytes);
-                let lbf : bloom_lbf = bloom_lbf::new(params, filter_name.clone(), Vec::new());
+                let mut filter_config : BloomFilterConfig = BloomFilterConfig::new(filter_name.clone(), capacity, probability, params.k_num, in_memory, params.bytes);
+                filter_config.expires_at = expires_at;
+                filter_config.filter_type = filter_type.clone();
+                filter_config.partitions = partitions;
+                filter_config.io_mode = io_mode;
+
+                if partitions > 0 {
+                    bloom_filter = match BloomFilter::new_partitioned(filter_config, directory) {
+                        Ok(filter) => filter,
+                        Err(_) => { return Err(String::from_str(MESSAGE_BAD_ARGS)) }
+                    };
+                } else {
+                    let lbf : bloom_lbf = bloom_lbf::new(params, filter_name.clone(), Vec::new());
+
+                    bloom_filter = BloomFilter::new(filter_config, lbf, directory);
+                }
 
-                bloom_filter = BloomFilter::new(filter_config, lbf, directory);
-                bloom_filter.init().unwrap();
-                bloom_filter.flush().unwrap();
+                match bloom_filter.init().and_then(|_| bloom_filter.flush()) {
+                    Err(e) => { return Err(server_error(&e)) },
+                    Ok(_) => { }
+                }
             }
 
-            filters.insert(filter_name.clone(), RwLock::new(bloom_filter))
+            // followers create a new filter themselves, but need a copy of one brought back from disk
+            if revived {
+                self.replicate_filter(&mut bloom_filter);
+            } else {
+                let mut command : String = format!("{} {} capacity={} prob={} in_memory={} use_mmap={} expires_at={}",
+                                                   COMMAND_CREATE,
+                                                   filter_name,
+                                                   bloom_filter.config.capacity,
+                                                   bloom_filter.config.probability,
+                                                   bloom_filter.config.in_memory as u8,
+                                                   (bloom_filter.config.io_mode == IoMode::Mmap) as u8,
+                                                   bloom_filter.config.expires_at);
+
+                if bloom_filter.config.is_partitioned() {
+                    command.push_str(format!(" partitions={}", bloom_filter.config.partitions).as_slice());
+                }
+
+                self.replicate_command(command);
+            }
+
+            filters.insert(filter_name.clone(), RwLock::new(bloom_filter));
+
+            return Ok(());
         });
 
-        return String::from_str(MESSAGE_DONE);
+        match created {
+            Err(response) => { return response },
+            Ok(_) => { }
+        }
+
+        log_info!("Created filter {}", filter_name);
+
+        return match (key_file, reader) {
+            (Some(key_file), Some(reader)) => self.queue_load(&filter_name, key_file, reader),
+            _ => String::from_str(MESSAGE_DONE)
+        };
     }
 
     // process a 'close' command (close <filter>)
@@ -370,6 +1015,8 @@ impl BloomServer {
             filter.unload_filter();
         });
 
+        log_info!("Closed filter {}", filter_name);
+
         return String::from_str(MESSAGE_DONE);
     }
 
@@ -389,11 +1036,15 @@ impl BloomServer {
             return String::from_str(MESSAGE_NO_EXIST);
         }
 
-        // remove the filter
+        // remove the filter, telling the followers while the map is still locked
         self.use_filters_mut(|filters| {
-            filters.remove(&filter_name);
+            if filters.remove(&filter_name).is_some() {
+                self.replicate_command(format!("{} {}", COMMAND_CLEAR, filter_name));
+            }
         });
 
+        log_info!("Cleared filter {}", filter_name);
+
         return String::from_str(MESSAGE_DONE);
     }
 
@@ -413,12 +1064,30 @@ impl BloomServer {
             return String::from_str(MESSAGE_NO_EXIST);
         }
 
-        // remove the filter
-        self.use_filters_mut(|filters| {
-            let filter : Option<RwLock<BloomFilter>> = filters.remove(&filter_name);
-            filter.unwrap().write().unwrap().delete().unwrap();
+        // delete the filter, and only once its files are gone remove it and tell the followers;
+        // a filter that could not be deleted stays in place
+        let deleted : Option<Result<(), BloomError>> = self.use_filters_mut(|filters| {
+            let result : Option<Result<(), BloomError>> = filters.get(&filter_name).map(|filter| filter.write().unwrap().delete());
+
+            match result {
+                Some(Ok(_)) => {
+                    filters.remove(&filter_name);
+                    self.replicate_command(format!("{} {}", COMMAND_DROP, filter_name));
+                },
+                _ => { }
+            }
+
+            return result;
         });
 
+        match deleted {
+            Some(Err(e)) => { return server_error(&e) },
+            Some(Ok(_)) => { },
+            None => { return String::from_str(MESSAGE_NO_EXIST) }
+        }
+
+        log_info!("Dropped filter {}", filter_name);
+
         return String::from_str(MESSAGE_DONE);
     }
 
@@ -439,17 +1108,20 @@ impl BloomServer {
             }
 
             // flush the filter
-            self.use_filter_mut(&filter_name, |filter| {
-                filter.flush().unwrap();
-            }).unwrap();
+            match self.use_filter_mut(&filter_name, |filter| filter.flush()) {
+                Some(Err(e)) => { return server_error(&e) },
+                Some(Ok(_)) => { },
+                None => { return String::from_str(MESSAGE_NO_EXIST) }
+            }
+
+            log_info!("Flushed filter {}", filter_name);
         // handle all filters flush
         } else {
             // flush all filters
-            self.use_filters_mut(|filters| {
-                for filter in filters.values() {
-                    (*filter.write().unwrap()).flush().unwrap();
-                }
-            });
+            match self.flush_filters() {
+                Err(e) => { return server_error(&e) },
+                Ok(_) => { }
+            }
         }        
 
         return String::from_str(MESSAGE_DONE);
@@ -475,11 +1147,14 @@ impl BloomServer {
         return self.use_filter(&filter_name, |filter| {
             let mut result : String = String::new();
             result.push_str(MESSAGE_START);
-            result.push_str(format!("capacity {}\r\nchecks {}\r\ncheck_hits {}\r\ncheck_misses {}\r\npage_ins {}\r\npage_outs {}\r\nprobability {}\r\nsets {}\r\nset_hits {}\r\nset_misses {}\r\nsize {}\r\nstorage {}\r\n",
+            result.push_str(format!("capacity {}\r\nchecks {}\r\ncheck_hits {}\r\ncheck_misses {}\r\nin_memory {}\r\nk_num {}\r\nlayers {}\r\npage_ins {}\r\npage_outs {}\r\nprobability {}\r\nsets {}\r\nset_hits {}\r\nset_misses {}\r\nsize {}\r\nstorage {}\r\n",
                            filter.config.capacity,
                            filter.counters.checks(),
                            filter.counters.check_hits,
                            filter.counters.check_misses,
+                           filter.config.in_memory as u8,
+                           filter.config.k_num,
+                           filter.layer_count(),
                            filter.counters.page_ins,
                            filter.counters.page_outs,
                            filter.config.probability,
@@ -488,10 +1163,43 @@ impl BloomServer {
                            filter.counters.set_misses,
                            filter.config.size,
                            filter.config.bytes).as_slice());
+
+            // only filters with a ttl report their remaining lifetime
+            match filter.config.time_to_live(now()) {
+                Some(ttl) => { result.push_str(format!("ttl {}\r\n", ttl).as_slice()) },
+                None => { }
+            }
+
+            // only partitioned filters report how many of their partitions are in memory
+            if filter.config.is_partitioned() {
+                result.push_str(format!("partitions {}\r\nresident_partitions {}\r\n",
+                                        filter.config.partitions,
+                                        filter.resident_partitions()).as_slice());
+            }
+
+            // only filters that have been loaded into report how far the load got
+            match filter.load_progress {
+                Some(ref progress) => {
+                    result.push_str(format!("load_done {}\r\n", progress.done as u8).as_slice());
+
+                    // only loads that stopped before the end of the key file say why
+                    match progress.error {
+                        Some(ref error) => { result.push_str(format!("load_failed {}\r\n", error).as_slice()) },
+                        None => { }
+                    }
+
+                    result.push_str(format!("load_file {}\r\nload_keys {}\r\nload_percent {:.1}\r\n",
+                                            progress.path,
+                                            progress.keys,
+                                            progress.percent()).as_slice());
+                },
+                None => { }
+            }
+
             result.push_str(MESSAGE_END);
 
             return result;
-        }).unwrap();
+        }).unwrap_or(String::from_str(MESSAGE_NO_EXIST));
     }
 
     // process a 'list' command (list [<filter_prefix>])
@@ -510,6 +1218,8 @@ impl BloomServer {
             prefix = "";
         }
 
+        let current_time : u64 = now();
+
         // form response
         return self.use_filters(|filters| {
             let mut result : String = String::new();
@@ -519,12 +1229,19 @@ impl BloomServer {
                 if name.starts_with(prefix) {
                     let ref filter = *filter_lock.read().unwrap();
 
-                    result.push_str(format!("{} {} {} {} {}\r\n", 
+                    // the last column is the remaining lifetime, or - for filters without a ttl
+                    let ttl : String = match filter.config.time_to_live(current_time) {
+                        Some(ttl) => ttl.to_string(),
+                        None => String::from_str(NO_TTL)
+                    };
+
+                    result.push_str(format!("{} {} {} {} {} {}\r\n", 
                                             name, 
                                             filter.config.probability, 
                                             filter.config.bytes, 
                                             filter.config.capacity, 
-                                            filter.config.size).as_slice());
+                                            filter.config.size,
+                                            ttl).as_slice());
                 }
             }
 
@@ -534,39 +1251,97 @@ impl BloomServer {
         });
     }
 
-    // process a 'multi' command (multi <filter> <key> ...)
+    // process a 'load' command (load <filter> <key_file> [format=lines|prefixed] | load <filter> cancel)
+    // the key file is opened straight away, and its keys added by the load thread; the progress shows
+    // up in 'info', and a running load can be cancelled before its next batch
     // returns a response String
-    fn process_multi(&self, args : Vec<&str>) -> String {
+    fn process_load(&self, args : Vec<&str>) -> String {
         // handle invalid arguments
-        if args.len() < 2 {
+        if args.len() < 2 || args.len() > 3 {
             return String::from_str(MESSAGE_BAD_ARGS);
         }
 
         // get filter name
         let filter_name : String = String::from_str(args[0]);
-        
+
         // check that filter exists
         if !self.contains_filter_named(&filter_name) {
             return String::from_str(MESSAGE_NO_EXIST);
         }
 
-        // form response
-        return self.use_filter_mut(&filter_name, |filter| {
-            let mut result : String = String::new();
+        if args.len() == 2 && args[1] == LOAD_CANCEL {
+            return self.cancel_load(&filter_name);
+        }
 
-            for arg in args[1..].iter() {
-                if !result.is_empty() {
+        let format : KeyFormat;
+        if args.len() == 3 && args[2].starts_with("format=") {
+            match KeyFormat::from_name(&args[2]["format=".len()..]) {
+                Some(_format) => { format = _format },
+                None => { return String::from_str(MESSAGE_BAD_ARGS) }
+            }
+        } else if args.len() == 3 {
+            return String::from_str(MESSAGE_BAD_ARGS);
+        } else {
+            format = KeyFormat::Lines;
+        }
+
+        return match KeyReader::open(args[1], format) {
+            Ok(reader) => self.queue_load(&filter_name, args[1], reader),
+            Err(e) => server_error(&e)
+        };
+    }
+
+    // process a 'multi' command (multi <filter> <key> ...)
+    // returns a response String
+    fn process_multi(&self, args : Vec<&str>) -> String {
+        // handle invalid arguments
+        if args.len() < 2 {
+            return String::from_str(MESSAGE_BAD_ARGS);
+        }
+
+        // get filter name
+        let filter_name : String = String::from_str(args[0]);
+        
+        // check that filter exists
+        if !self.contains_filter_named(&filter_name) {
+            return String::from_str(MESSAGE_NO_EXIST);
+        }
+
+        // form response
+        return self.use_filter_mut(&filter_name, |filter| {
+            let mut result : String = String::new();
+
+            for arg in args[1..].iter() {
+                if !result.is_empty() {
                     result.push_str(" ");
                 }
 
                 let key : String = String::from_str(*arg);
-                let value : u32 = self.check(filter, key);
+                let value : u32 = match filter.check(key) {
+                    Ok(value) => value,
+                    Err(e) => { return server_error(&e) }
+                };
 
                 result.push_str(format!("{}", value).as_slice());
             }
 
             return result;
-        }).unwrap();
+        }).unwrap_or(String::from_str(MESSAGE_NO_EXIST));
+    }
+
+    // process a 'reload' command (reload)
+    // returns a response String
+    fn process_reload(&self, args : Vec<&str>) -> String {
+        // handle invalid arguments
+        if !args.is_empty() {
+            return String::from_str(MESSAGE_BAD_ARGS);
+        }
+
+        return match self.reload_config() {
+            Ok(ref restart_required) if restart_required.is_empty() => String::from_str(MESSAGE_DONE),
+            Ok(restart_required) => format!("{} {}", MESSAGE_RESTART_NEEDED, restart_required.connect(" ")),
+            Err(e) => e
+        };
     }
 
     // process a 'set' command (set <filter> <key>)
@@ -588,341 +1363,2508 @@ impl BloomServer {
         // form response
         return self.use_filter_mut(&filter_name, |filter| {
             let key : String = String::from_str(args[1]);
-            let value : u32 = self.set(filter, key);
 
-            return format!("{}", value);
-        }).unwrap();
+            return match filter.set(key) {
+                Ok(value) => {
+                    self.replicate_command(format!("{} {} {}", COMMAND_SET, filter_name, args[1]));
+                    format!("{}", value)
+                },
+                Err(e) => server_error(&e)
+            };
+        }).unwrap_or(String::from_str(MESSAGE_NO_EXIST));
     }
 
-    // do a check for the given key in the given BloomFilter and return the corresponding value
-    fn check(&self, filter : &mut BloomFilter, key : String) -> u32 {
-        filter.touch();
+    // process a 'stats' command (stats)
+    // returns a response String
+    fn process_stats(&self, args : Vec<&str>) -> String {
+        // handle invalid arguments
+        if !args.is_empty() {
+            return String::from_str(MESSAGE_BAD_ARGS);
+        }
+
+        // count the filters, and the bytes mapped by the ones that are loaded
+        let (filter_count, resident_count, mapped_bytes) : (usize, usize, u64) = self.use_filters(|filters| {
+            let mut resident_count : usize = 0;
+            let mut mapped_bytes : u64 = 0;
 
-        let value : u32 = filter.contains(&key).unwrap();
+            for filter_lock in filters.values() {
+                let ref filter = *filter_lock.read().unwrap();
 
-        if value > 0 {
-            filter.counters.check_hits += 1;
-        } else {
-            filter.counters.check_misses += 1;
+                if filter.is_resident() {
+                    resident_count += 1;
+                    mapped_bytes += filter.resident_bytes();
+                }
+            }
+
+            return (filters.len(), resident_count, mapped_bytes);
+        });
+
+        let (last_flush, last_flush_duration, last_flush_bytes, last_flush_synced_bytes) : (u64, f64, u64, u64) = self.metrics.last_flush().unwrap_or((0, 0.0, 0, 0));
+
+        // form response
+        let mut result : String = String::new();
+        result.push_str(MESSAGE_START);
+        result.push_str(format!("uptime {}\r\nconnections {}\r\nconnections_total {}\r\nfilters {}\r\nresident_filters {}\r\nmapped_bytes {}\r\nlast_flush {}\r\nlast_flush_duration {}\r\nlast_flush_bytes {}\r\nlast_flush_synced_bytes {}\r\nfollowers {}\r\n",
+                                self.metrics.uptime(),
+                                self.metrics.connections_current(),
+                                self.metrics.connections_total(),
+                                filter_count,
+                                resident_count,
+                                mapped_bytes,
+                                last_flush,
+                                last_flush_duration,
+                                last_flush_bytes,
+                                last_flush_synced_bytes,
+                                self.replicas.count()).as_slice());
+
+        for (command, count) in self.metrics.command_counts().into_iter() {
+            result.push_str(format!("cmd_{} {}\r\n", command, count).as_slice());
+        }
+
+        for (kind, count) in self.metrics.error_counts().into_iter() {
+            result.push_str(format!("errors_{} {}\r\n", kind, count).as_slice());
         }
 
-        return value;
+        result.push_str(MESSAGE_END);
+
+        return result;
     }
 
-    // do a set for the given key in the given BloomFilter, creating new bloom filters if necessary, and return the corresponding value
-    fn set(&self, filter : &mut BloomFilter, key : String) -> u32 {
-        filter.touch();
+    // process a 'slowlog' command (slowlog [<count>] | slowlog reset)
+    // returns a response String
+    fn process_slowlog(&self, args : Vec<&str>) -> String {
+        // handle invalid arguments
+        if args.len() > 1 {
+            return String::from_str(MESSAGE_BAD_ARGS);
+        }
+
+        // handle reset
+        if !args.is_empty() && args[0] == SLOWLOG_RESET {
+            self.slowlog.lock().unwrap().reset();
 
-        // Check and make sure that there is a layer that doesn't contain the key,
-        // creating a new layer if necessary
-        let value : u32 = filter.contains(&key).unwrap();
-        if value == filter.num_filters {
-            match filter.add_filter(value) {
-                Err(e) => { println!("Could not add new filter: {}", e) },
-                Ok(_) => { }
-            }
+            return String::from_str(MESSAGE_DONE);
         }
-        
-        // Increment the counters for the filter
-        if value > 0 {
-            filter.counters.set_hits += 1;
+
+        // get number of entries
+        let count : usize = if args.is_empty() {
+            SLOWLOG_DEFAULT_COUNT
         } else {
-            filter.counters.set_misses += 1;
-        }
+            match FromStr::from_str(args[0]) {
+                Some(count) => count,
+                None => { return String::from_str(MESSAGE_BAD_ARGS) }
+            }
+        };
 
-        // Add the key to the filter, and increment the size of the filter
-        let value = filter.add(key).unwrap();
-        filter.config.size = filter.size();
+        // form response
+        let mut result : String = String::new();
+        result.push_str(MESSAGE_START);
 
-        if value > 0 {
-            let index : usize = (value - 1) as usize;
-            filter.config.filter_sizes[index] = filter.get_filter_size(index);
+        for entry in self.slowlog.lock().unwrap().latest(count).iter() {
+            result.push_str(entry.to_line().as_slice());
+            result.push_str("\r\n");
         }
 
-        return value;
+        result.push_str(MESSAGE_END);
+
+        return result;
     }
-}
 
-unsafe impl Send for BloomServer { }
+    // hands an opened key file to the load thread; only one load into a filter runs at a time
+    // returns a response String
+    fn queue_load(&self, filter_name : &String, key_file : &str, reader : KeyReader) -> String {
+        let started : Option<bool> = self.use_filter_mut(filter_name, |filter| {
+            if filter.load_progress.as_ref().map_or(false, |progress| progress.is_running()) {
+                return false;
+            }
 
-// represents a task that executes subtasks periodically
-struct Worker {
-    timer    : Timer,
-    receiver : Receiver<()>,
-    tasks    : Vec<Box<FnMut<(u64,), ()> + 'static>>
-}
+            filter.load_progress = Some(LoadProgress::new(key_file, reader.total_bytes()));
 
-impl Worker {
-    fn new(duration : Duration) -> Self {
-        let mut timer : Timer = Timer::new().unwrap();
-        let receiver : Receiver<()> = timer.periodic(duration);
+            return true;
+        });
 
-        return Worker {
-            timer: timer,
-            receiver: receiver,
-            tasks: Vec::new()
+        match started {
+            Some(true) => { },
+            Some(false) => { return String::from_str(MESSAGE_LOADING) },
+            None => { return String::from_str(MESSAGE_NO_EXIST) }
+        }
+
+        self.pending_loads.lock().unwrap().push(PendingLoad {
+            filter_name: filter_name.clone(),
+            key_file: String::from_str(key_file),
+            reader: reader
+        });
+
+        return String::from_str(MESSAGE_DONE);
+    }
+
+    // asks the running load into a filter to stop before its next batch
+    // returns a response String
+    fn cancel_load(&self, filter_name : &String) -> String {
+        let cancelled : Option<bool> = self.use_filter_mut(filter_name, |filter| {
+            return match filter.load_progress {
+                Some(ref mut progress) if progress.is_running() => {
+                    progress.cancelled = true;
+                    true
+                },
+                _ => false
+            };
+        });
+
+        return match cancelled {
+            Some(true) => String::from_str(MESSAGE_DONE),
+            Some(false) => String::from_str(MESSAGE_BAD_ARGS),
+            None => String::from_str(MESSAGE_NO_EXIST)
         };
     }
 
-    fn add_task<T : FnMut<(u64,), ()> + 'static>(&mut self, task : T) {
-        self.tasks.push(Box::new(task));
+    // runs the loads queued since the last call, one after the other; called by the load thread
+    fn run_pending_loads(&self) {
+        let loads : Vec<PendingLoad> = self.pending_loads.lock().unwrap().drain().collect();
+
+        for load in loads.into_iter() {
+            self.load_keys(load);
+        }
     }
-}
 
-impl FnMut<(), ()> for Worker {
-    #[allow(unused_variables)]
-    extern "rust-call" fn call_mut(&mut self, args : ()) {
-        let mut count : u64 = 0;
+    // streams the keys in a key file into a filter a batch at a time, so other requests can get
+    // at the filter in between batches, and flushes the filter once at the end. A load that stops
+    // early, because it failed, was cancelled or the server is shutting down, says why in its progress
+    fn load_keys(&self, load : PendingLoad) {
+        let PendingLoad { filter_name, key_file, mut reader } = load;
+        let filter_name : &String = &filter_name;
+
+        let start : u64 = precise_time_ns();
+
+        let mut result : Option<BloomResult<u64>> = Some(Ok(0));
+        let mut stopped : Option<&'static str> = None;
 
         loop {
-            // activate the tasks
-            for task in self.tasks.iter_mut() {
-                task(count,);
+            // each batch counts as a request, so a shutdown waits for it rather than flushing halfway through
+            if !self.begin_request() {
+                stopped = Some("server is shutting down");
+                break;
             }
 
-            count += 1;
+            let cancelled : bool = self.use_filter(filter_name, |filter| {
+                return filter.load_progress.as_ref().map_or(false, |progress| progress.cancelled);
+            }).unwrap_or(false);
 
-            // sleep for the rest of the minute
-            self.receiver.recv().unwrap();
-        }
-    }
-}
+            if cancelled {
+                self.end_request();
+                stopped = Some("cancelled");
+                break;
+            }
 
-unsafe impl Send for Worker { }
+            let batch : Vec<String> = match reader.next_batch(LOAD_BATCH_SIZE) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    self.end_request();
+                    result = Some(Err(e));
+                    break;
+                }
+            };
 
-// task for flushing filters
-struct FlushTask {
-    server     : Arc<BloomServer>,
-    last_flush : u64
-}
+            let bytes_read : u64 = reader.bytes_read();
 
-impl FlushTask {
-    fn new(server : Arc<BloomServer>) -> Self {
-        return FlushTask { server: server, last_flush: 0 };
-    }
-}
+            result = self.use_filter_mut(filter_name, |filter| {
+                let keys : u64 = if batch.is_empty() { 0 } else { try!(filter.load_keys(batch.as_slice())) };
 
-impl FnMut<(u64,), ()> for FlushTask {
-    extern "rust-call" fn call_mut(&mut self, args : (u64,)) {
-        let (time,) = args;
+                let total : u64 = match filter.load_progress {
+                    Some(ref mut progress) => {
+                        progress.keys += keys;
+                        progress.bytes_read = bytes_read;
+                        progress.keys
+                    },
+                    None => keys
+                };
 
-        if (time - self.last_flush) > self.server.config.flush_interval as u64 {
-            self.server.use_filters_mut(|filters| {
-                for filter in filters.values() {
-                    (*filter.write().unwrap()).flush().unwrap();
+                // the filter is written out once, after the last batch, and sent to followers as a whole
+                if batch.is_empty() {
+                    try!(filter.flush());
+                    self.replicate_filter(filter);
                 }
+
+                return Ok(total);
             });
 
-            self.last_flush = time;
+            self.end_request();
+
+            // stop after the last batch, or once a batch fails or the filter is gone
+            match result {
+                Some(Ok(_)) if !batch.is_empty() => { },
+                _ => { break }
+            }
         }
-    }
-}
 
-unsafe impl Send for FlushTask { }
+        let error : Option<String> = match (stopped, &result) {
+            (Some(reason), _) => Some(String::from_str(reason)),
+            (None, &Some(Err(ref e))) => Some(e.to_string()),
+            _ => None
+        };
 
-// task for clearing cold filters
-struct CloseTask {
-    server : Arc<BloomServer>
-}
+        // a load that stopped early is over too, so the filter can be loaded into again
+        self.use_filter_mut(filter_name, |filter| {
+            match filter.load_progress {
+                Some(ref mut progress) => {
+                    progress.done = true;
+                    progress.error = error.clone();
+                },
+                None => { }
+            }
+        });
 
-impl CloseTask {
-    fn new(server : Arc<BloomServer>) -> Self {
-        return CloseTask { server: server };
+        match (error, result) {
+            (Some(error), _) => { log_warn!("Stopped loading {} into filter {}: {}", key_file, filter_name, error) },
+            (None, Some(Ok(keys))) => { log_info!("Loaded {} keys from {} into filter {} in {:.3}s", keys, key_file, filter_name, seconds_since(start)) },
+            (None, _) => { log_warn!("Stopped loading {} into filter {}: the filter is gone", key_file, filter_name) }
+        }
     }
-}
 
-impl FnMut<(u64,), ()> for CloseTask {
-    #[allow(unused_variables)]
-    extern "rust-call" fn call_mut(&mut self, args : (u64,)) {
-        self.server.use_filters_mut(|filters| {
-            for filter_lock in filters.values() {
-                let mut guard = filter_lock.write().unwrap();
+    // process a 'swap' command (swap <filter> <directory>)
+    // replaces the filter with the one built in the directory, e.g. by 'bloomctl build'; the directory is
+    // moved into the data_dir, and the filter it replaces is flushed and deleted
+    // returns a response String
+    fn process_swap(&self, args : Vec<&str>) -> String {
+        // handle invalid arguments
+        if args.len() != 2 {
+            return String::from_str(MESSAGE_BAD_ARGS);
+        }
 
-                if (*guard).cold_index > self.server.config.cold_interval as u64 {
-                    (*guard).unload_filter();
-                }
+        // get filter name
+        let filter_name : String = String::from_str(args[0]);
 
-                (*guard).cold_index += 1;
+        let staged_directory : Path = Path::new(args[1]);
+        let data_dir : Path = Path::new(self.use_config(|config| config.data_dir.clone()));
+
+        let mut swap_directory : Path = data_dir.clone();
+        swap_directory.push(format!("{}{}", SWAP_FOLDER_PREFIX, filter_name).as_slice());
+
+        if !staged_directory.is_dir() {
+            return String::from_str(MESSAGE_BAD_ARGS);
+        }
+
+        // left over from a swap that was cut off
+        if swap_directory.exists() {
+            match fs::rmdir_recursive(&swap_directory) {
+                Err(e) => { return server_error(&BloomError::Io(e)) },
+                Ok(_) => { }
             }
-        });
+        }
+
+        // move the new filter next to the live one first, so the swap itself can't fail on a move across file systems
+        match fs::rename(&staged_directory, &swap_directory) {
+            Err(e) => { return server_error(&BloomError::Io(e)) },
+            Ok(_) => { }
+        }
+
+        // page the new filter in before taking any locks
+        // a directory that doesn't hold the filter is put back where it came from
+        let bloom_filter : BloomFilter = match self.load_staged_filter(&filter_name, &swap_directory) {
+            Ok(filter) => filter,
+            Err(e) => {
+                let _ = fs::rename(&swap_directory, &staged_directory);
+
+                return match e {
+                    BloomError::InvalidParams(_) => String::from_str(MESSAGE_BAD_ARGS),
+                    e => server_error(&e)
+                };
+            }
+        };
+
+        match self.replace_filter(&filter_name, bloom_filter, &swap_directory) {
+            Err(e) => { return server_error(&e) },
+            Ok(_) => { }
+        }
+
+        log_info!("Swapped in filter {} from {}", filter_name, staged_directory.display());
+
+        return String::from_str(MESSAGE_DONE);
     }
-}
 
-unsafe impl Send for CloseTask { }
+    // process an 'export' command (export <filter>)
+    // writes a copy of the filter's files to the stream, for 'import' into another server
+    // returns a response String
+    fn process_export<S : Stream>(&self, args : Vec<&str>, stream : &mut BufferedStream<S>) -> IoResult<String> {
+        // handle invalid arguments
+        if args.len() != 1 {
+            return Ok(String::from_str(MESSAGE_BAD_ARGS));
+        }
 
-fn main() {
-    // get command line arguments
-    let args = os::args();
-    let mut args = args.iter();
-    args.next();
+        // get filter name
+        let filter_name : String = String::from_str(args[0]);
 
-    let mut config_filename : Option<&str> = None;
+        // the filter stays locked while its bitmaps are read in and sent, so they match its ini file
+        let filters = self.filters.read().unwrap();
+        let mut filter = match filters.get(&filter_name) {
+            Some(filter_lock) => filter_lock.write().unwrap(),
+            None => { return Ok(String::from_str(MESSAGE_NO_EXIST)) }
+        };
 
-    // read command line arguments
-    loop {
-        match args.next() {
-            Some(arg) => {
-                match arg.as_slice() {
-                    // config filename
-                    "-f" => {
-                        match args.next() {
-                            Some(value) => config_filename = Some(value.as_slice()),
-                            None => panic!("missing value for flag \"-f\"\r\n\r\n{}", USAGE)
-                        }
-                    },
-                    _ => panic!("invalid argument: {}\r\n\r\n{}", arg, USAGE)
-                };
+        return match SnapshotSource::from_filter(&mut *filter) {
+            Ok(Some(snapshot)) => {
+                try!(snapshot.write_to(stream));
+
+                Ok(String::from_str(MESSAGE_DONE))
             },
-            None => { break }
+            // in-memory filters have nothing on disk to export
+            Ok(None) => Ok(String::from_str(MESSAGE_BAD_ARGS)),
+            Err(e) => Ok(server_error(&e))
         };
     }
 
-    // create config
-    let config : BloomConfig = match config_filename {
-        Some(filename) => BloomConfig::from_filename(filename),
-        None => BloomConfig::default()
-    };
+    // process an 'import' command (import <filter>, followed by the files 'export' wrote)
+    // puts the exported filter in place of the filter with the same name, if any, which is deleted
+    // returns a response String
+    fn process_import(&self, args : Vec<&str>, snapshot : &FilterSnapshot) -> String {
+        // handle invalid arguments
+        if args.len() != 1 || args[0] != snapshot.name.as_slice() {
+            return String::from_str(MESSAGE_BAD_ARGS);
+        }
 
-    // create server
-    let server : BloomServer = BloomServer::new(config);
+        // a follower's filters only change as its leader tells it to
+        if self.use_config(|config| config.is_follower()) {
+            return String::from_str(MESSAGE_READ_ONLY);
+        }
 
-    // start server
-    start(server);
-}
+        return match self.install_snapshot(snapshot) {
+            Ok(_) => {
+                log_info!("Imported filter {}", snapshot.name);
 
-fn start(server: BloomServer) {
-    // make sure data_dir exists and is accessible
-    let data_dir : Path = Path::new(server.config.data_dir.clone());
-    if !data_dir.exists() {
-        fs::mkdir(&data_dir, io::USER_RWX).unwrap();
-    } else if !data_dir.is_dir() {
-        panic!("Invalid data_dir: {} is not a directory", data_dir.as_str().unwrap());
-    } else {
-        server.read_in_filters();
+                String::from_str(MESSAGE_DONE)
+            },
+            Err(BloomError::InvalidParams(_)) => String::from_str(MESSAGE_BAD_ARGS),
+            Err(e) => server_error(&e)
+        };
     }
 
-    // listen at <bind_host>:<tcp_port>
-    let listener = TcpListener::bind(server.config.get_bind_address().as_slice()).unwrap();
+    // reads in the filter in a swap directory in the data_dir, and pages it in
+    fn load_staged_filter(&self, filter_name : &String, swap_directory : &Path) -> BloomResult<BloomFilter> {
+        let mut bloom_filter : BloomFilter = try!(BloomFilter::from_directory(swap_directory, filter_name, false));
 
-    let mut acceptor = listener.listen().unwrap();
+        // in-memory filters have nothing on disk to swap in
+        if bloom_filter.config.in_memory {
+            return Err(BloomError::InvalidParams(format!("filter in {} is in memory only", swap_directory.display())));
+        }
 
-    // share state
-    let server : Arc<BloomServer> = Arc::new(server);
+        bloom_filter.config.filter_name = filter_name.clone();
+        bloom_filter.relocate(swap_directory.clone());
+        bloom_filter.touch();
 
-    // setup background tasks
-    let flush_task : FlushTask = FlushTask::new(server.clone());
+        return Ok(bloom_filter);
+    }
+
+    // puts a filter read in from a swap directory in place of the filter with the same name, if any
+    // while the map is locked the live directory is renamed aside, the swap directory renamed into its place and
+    // the map entry swapped; a step that fails puts everything back, and the filter that was replaced is only
+    // deleted once the lock is released
+    fn replace_filter(&self, filter_name : &String, mut bloom_filter : BloomFilter, swap_directory : &Path) -> BloomResult<()> {
+        let data_dir : Path = Path::new(self.use_config(|config| config.data_dir.clone()));
+        let directory : Path = data_dir.join(format!("{}{}", FILTER_FOLDER_PREFIX, filter_name).as_slice());
+        let retired_directory : Path = data_dir.join(format!("{}{}", RETIRED_FOLDER_PREFIX, filter_name).as_slice());
+
+        // left over from a swap that was cut off before it could delete the filter it replaced
+        if retired_directory.exists() {
+            try!(fs::rmdir_recursive(&retired_directory));
+        }
+
+        // nothing can see the filter missing while the map is locked
+        let old_filter : Option<RwLock<BloomFilter>> = {
+            let mut filters = self.filters.write().unwrap();
+
+            match filters.get(filter_name) {
+                Some(old_filter_lock) => {
+                    match old_filter_lock.write().unwrap().flush() {
+                        Err(e) => { log_warn!("Could not flush filter {} before swapping it out: {}", filter_name, e) },
+                        Ok(_) => { }
+                    }
+                },
+                None => { }
+            }
+
+            let retired : bool = directory.exists();
+
+            if retired {
+                try!(fs::rename(&directory, &retired_directory));
+            }
+
+            match fs::rename(swap_directory, &directory) {
+                Err(e) => {
+                    if retired {
+                        restore_directory(&retired_directory, &directory);
+                    }
+
+                    return Err(BloomError::Io(e));
+                },
+                Ok(_) => { }
+            }
+
+            // the open bitmaps follow the rename, only the paths written to the ini change
+            bloom_filter.relocate(directory.clone());
+
+            match bloom_filter.flush() {
+                Err(e) => {
+                    restore_directory(&directory, swap_directory);
+
+                    if retired {
+                        restore_directory(&retired_directory, &directory);
+                    }
+
+                    return Err(e);
+                },
+                Ok(_) => { }
+            }
+
+            self.replicate_filter(&mut bloom_filter);
+
+            filters.insert(filter_name.clone(), RwLock::new(bloom_filter))
+        };
+
+        // out of the map, so nothing else can be using it; its bitmaps are closed before its files go
+        drop(old_filter);
+
+        if retired_directory.exists() {
+            match fs::rmdir_recursive(&retired_directory) {
+                Err(e) => { log_warn!("Could not delete {} after swapping in filter {}: {}", retired_directory.display(), filter_name, e) },
+                Ok(_) => { }
+            }
+        }
+
+        return Ok(());
+    }
+
+    // sends a change to the followers; called with the filter still locked, so they see changes in the order they were made
+    fn replicate_command(&self, command : String) {
+        self.replicas.broadcast(ReplicationEvent::Command(command));
+    }
+
+    // sends a copy of the filter to the followers, for changes that aren't replayed key by key
+    // only the ini files are read in here; each follower's stream reads the bitmaps from disk as it sends them
+    fn replicate_filter(&self, filter : &mut BloomFilter) {
+        if !self.replicas.is_active() {
+            return;
+        }
+
+        // the copy is taken while the caller holds the filter, as the changes made after it are streamed too
+        match FilterSnapshot::from_filter(filter) {
+            Ok(Some(snapshot)) => { self.replicas.broadcast(ReplicationEvent::Snapshot(snapshot)) },
+            Ok(None) => { log_warn!("Filter {} is only kept in memory, so followers can't be sent a copy", filter.config.filter_name) },
+            Err(e) => { log_error!("Could not send filter {} to followers: {}", filter.config.filter_name, e) }
+        }
+    }
+
+    // streams a copy of every filter on disk, then every change made after it was copied, to a follower
+    // returns once the follower goes away or the server shuts down
+    fn serve_follower<S : Stream>(&self, stream : &mut BufferedStream<S>) -> BloomResult<()> {
+        let (sender, receiver) : (SyncSender<ReplicationEvent>, Receiver<ReplicationEvent>) = sync_channel(REPLICATION_QUEUE_SIZE);
+
+        // the filters can't change while the follower is registered, so every change after this is streamed to it
+        let filter_names : Vec<String> = self.use_filters_mut(|filters| {
+            self.replicas.add(sender.clone());
+
+            return filters.keys().map(|name| name.clone()).collect();
+        });
+
+        try!(stream.write_str(MESSAGE_START));
+
+        // changes streamed before a filter was copied are already in the copy, and are skipped
+        let mut pending : HashSet<String> = HashSet::new();
+
+        for filter_name in filter_names.iter() {
+            // the filter stays locked until its files are written, so the copy holds exactly the changes
+            // queued before its marker; setting a key again would put it in another layer
+            let filters = self.filters.read().unwrap();
+            let mut filter = match filters.get(filter_name) {
+                Some(filter_lock) => filter_lock.write().unwrap(),
+                None => { continue }
+            };
+
+            let snapshot : Option<SnapshotSource> = try!(SnapshotSource::from_filter(&mut *filter));
+
+            // this thread is the only one emptying the channel, so it can't wait for room
+            match sender.try_send(ReplicationEvent::Snapshotted(filter_name.clone())) {
+                Err(TrySendError::Full(_)) => {
+                    return Err(BloomError::Io(io::IoError { kind: io::IoErrorKind::ResourceUnavailable, desc: "follower fell behind while it was being sent every filter", detail: None }));
+                },
+                _ => { }
+            }
+
+            match snapshot {
+                Some(snapshot) => {
+                    try!(snapshot.write_to(stream));
+                    pending.insert(filter_name.clone());
+                },
+                None => { log_warn!("Filter {} is only kept in memory, so followers can't be sent a copy", filter_name) }
+            }
+        }
+
+        try!(stream.write_str(MESSAGE_END));
+        try!(stream.write_str("\r\n"));
+        try!(stream.flush());
+
+        // only the followers list keeps the channel open from here, until the server shuts down
+        drop(sender);
+
+        for event in receiver.iter() {
+            match event {
+                ReplicationEvent::Snapshotted(ref filter_name) => { pending.remove(filter_name); },
+                _ => {
+                    if !event.filter_name().map_or(false, |filter_name| pending.contains(&String::from_str(filter_name))) {
+                        try!(event.write_to(stream));
+                        try!(stream.flush());
+                    }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    // follows the leader at the given address: puts the leader's copy of every filter in place, drops the
+    // filters the leader doesn't have, then replays the changes it streams
+    // returns once the connection to the leader drops or the server shuts down
+    fn follow(&self, address : &str) -> BloomResult<()> {
+        let token : String = self.use_config(|config| config.replication_token.clone());
+        let mut leader : LeaderConnection = try!(LeaderConnection::connect(address, token.as_slice()));
+
+        let mut synced : Vec<String> = Vec::new();
+
+        loop {
+            match try!(leader.next_snapshot()) {
+                Some(snapshot) => {
+                    try!(self.install_snapshot(&snapshot));
+                    synced.push(snapshot.name);
+                },
+                None => { break }
+            }
+        }
+
+        // filters dropped on the leader while this server wasn't following it
+        // they're taken out of the map with it locked, and deleted once it's unlocked again
+        let stale : Vec<(String, RwLock<BloomFilter>)> = self.use_filters_mut(|filters| {
+            let stale_names : Vec<String> = filters.keys().filter(|name| !synced.contains(*name)).map(|name| name.clone()).collect();
+
+            return stale_names.into_iter().map(|filter_name| {
+                let filter_lock : RwLock<BloomFilter> = filters.remove(&filter_name).unwrap();
+
+                return (filter_name, filter_lock);
+            }).collect();
+        });
+
+        for &(ref filter_name, ref filter_lock) in stale.iter() {
+            match filter_lock.write().unwrap().delete() {
+                Err(e) => { log_warn!("Could not delete filter {}, which the leader doesn't have: {}", filter_name, e) },
+                Ok(_) => { }
+            }
+        }
+
+        log_info!("Synced {} filters from leader {}, dropping {} it doesn't have", synced.len(), address, stale.len());
+
+        loop {
+            let event : LeaderEvent = try!(leader.next_event());
+
+            // stop once shutting down, without leaving a change half made
+            if !self.begin_request() {
+                return Ok(());
+            }
+
+            let applied : BloomResult<()> = match event {
+                LeaderEvent::Snapshot(ref snapshot) => self.install_snapshot(snapshot),
+                LeaderEvent::Command(ref command) => {
+                    let response : String = self.replay_request(command.as_slice());
+
+                    match error_kind(response.as_slice()) {
+                        // changes to filters the follower wasn't sent a copy of, like in-memory ones
+                        None | Some("no_exist") => { },
+                        Some(_) => { log_warn!("Could not replay \"{}\" from leader {}: {}", command, address, response) }
+                    }
+
+                    Ok(())
+                }
+            };
+
+            self.end_request();
+
+            try!(applied);
+        }
+    }
+
+    // puts a copy of a filter, sent by the leader or imported, in place of this server's copy
+    fn install_snapshot(&self, snapshot : &FilterSnapshot) -> BloomResult<()> {
+        let mut swap_directory : Path = Path::new(self.use_config(|config| config.data_dir.clone()));
+        swap_directory.push(format!("{}{}", SWAP_FOLDER_PREFIX, snapshot.name).as_slice());
+
+        // left over from a copy that was cut off
+        if swap_directory.exists() {
+            try!(fs::rmdir_recursive(&swap_directory));
+        }
+
+        try!(snapshot.write_to_directory(&swap_directory));
+
+        let bloom_filter : BloomFilter = try!(self.load_staged_filter(&snapshot.name, &swap_directory));
+
+        return self.replace_filter(&snapshot.name, bloom_filter, &swap_directory);
+    }
+
+    // replays a change streamed by the leader, which a follower's clients can't make themselves
+    fn replay_request(&self, input : &str) -> String {
+        let mut args : Vec<&str> = split_args(input);
+
+        if args.is_empty() {
+            return String::from_str(MESSAGE_NOT_IMPLEMENTED);
+        }
+
+        let command : &str = args.remove(0);
+
+        return match command {
+            COMMAND_BULK   => { self.process_bulk  (args) },
+            COMMAND_CLEAR  => { self.process_clear (args) },
+            COMMAND_CREATE => { self.process_create(args) },
+            COMMAND_DROP   => { self.process_drop  (args) },
+            COMMAND_SET    => { self.process_set   (args) },
+            _ => { String::from_str(MESSAGE_NOT_IMPLEMENTED) }
+        };
+    }
+
+    // drops every filter whose ttl has run out, deleting its directory from disk
+    // returns the names of the dropped filters
+    fn drop_expired_filters(&self, now : u64) -> Vec<String> {
+        let expired : Vec<(String, RwLock<BloomFilter>)> = self.use_filters_mut(|filters| {
+            let names : Vec<String> = filters.iter()
+                                             .filter(|&(_, filter_lock)| filter_lock.read().unwrap().config.is_expired(now))
+                                             .map(|(name, _)| name.clone())
+                                             .collect();
+
+            let mut removed : Vec<(String, RwLock<BloomFilter>)> = Vec::with_capacity(names.len());
+
+            for name in names.into_iter() {
+                let filter : RwLock<BloomFilter> = filters.remove(&name).unwrap();
+                removed.push((name, filter));
+            }
+
+            return removed;
+        });
+
+        // the filters are out of the map, so deleting them doesn't hold up other requests
+        let mut dropped : Vec<String> = Vec::with_capacity(expired.len());
+
+        for (filter_name, filter) in expired.into_iter() {
+            match filter.write().unwrap().delete() {
+                Err(e) => { log_error!("Could not delete expired filter {}: {}", filter_name, e) },
+                Ok(_) => { log_info!("Dropped expired filter {}", filter_name) }
+            }
+
+            dropped.push(filter_name);
+        }
+
+        return dropped;
+    }
+}
+
+unsafe impl Send for BloomServer { }
+
+// splits a request into its whitespace separated arguments
+fn split_args(input : &str) -> Vec<&str> {
+    return input.split(|&:c : char| c.is_whitespace())
+                .filter(|&s| s.len() > 0).collect();
+}
+
+// returns the full name of the command in a request, or "unknown" for commands that aren't supported
+fn command_name(input : &str) -> &'static str {
+    let args : Vec<&str> = split_args(input);
+
+    if args.is_empty() {
+        return "unknown";
+    }
+
+    return match args[0] {
+        COMMAND_AUTH                       => COMMAND_AUTH,
+        COMMAND_BULK   | COMMAND_BULK_AB   => COMMAND_BULK,
+        COMMAND_CHECK  | COMMAND_CHECK_AB  => COMMAND_CHECK,
+        COMMAND_CREATE                     => COMMAND_CREATE,
+        COMMAND_CLOSE                      => COMMAND_CLOSE,
+        COMMAND_CLEAR                      => COMMAND_CLEAR,
+        COMMAND_DROP                       => COMMAND_DROP,
+        COMMAND_EXPORT                     => COMMAND_EXPORT,
+        COMMAND_IMPORT                     => COMMAND_IMPORT,
+        COMMAND_INFO                       => COMMAND_INFO,
+        COMMAND_LIST                       => COMMAND_LIST,
+        COMMAND_LOAD                       => COMMAND_LOAD,
+        COMMAND_MULTI  | COMMAND_MULTI_AB  => COMMAND_MULTI,
+        COMMAND_FLUSH                      => COMMAND_FLUSH,
+        COMMAND_SET    | COMMAND_SET_AB    => COMMAND_SET,
+        COMMAND_RELOAD                     => COMMAND_RELOAD,
+        COMMAND_REPLICATE                  => COMMAND_REPLICATE,
+        COMMAND_STATS                      => COMMAND_STATS,
+        COMMAND_SLOWLOG                    => COMMAND_SLOWLOG,
+        COMMAND_SWAP                       => COMMAND_SWAP,
+        _ => "unknown"
+    };
+}
+
+// returns the filter a request operates on, or None for server-wide commands
+fn request_filter<'a>(args : &[&'a str]) -> Option<&'a str> {
+    if args.len() < 2 {
+        return None;
+    }
+
+    return match command_name(args[0]) {
+        COMMAND_AUTH | COMMAND_LIST | COMMAND_STATS | COMMAND_RELOAD | COMMAND_SLOWLOG | COMMAND_REPLICATE | "unknown" => None,
+        _ => Some(args[1])
+    };
+}
+
+// writes a response and the line ending after it, and sends them off
+fn write_response<S : Stream>(stream : &mut BufferedStream<S>, response : &str) -> IoResult<()> {
+    try!(stream.write_str(response));
+    try!(stream.write_str("\r\n"));
+
+    return stream.flush();
+}
+
+// moves a directory back where it was after a swap failed partway; if that fails too, all that's left is to say so
+fn restore_directory(from : &Path, to : &Path) {
+    match fs::rename(from, to) {
+        Err(e) => { log_error!("Could not move {} back to {}: {}", from.display(), to.display(), e) },
+        Ok(_) => { }
+    }
+}
+
+// logs an error that kept a request from being served, and returns the response reporting it
+fn server_error(error : &BloomError) -> String {
+    log_error!("{}", error);
+
+    return format!("{} {}", MESSAGE_SERVER_ERROR, error);
+}
+
+// returns the kind of error a response reports, or None if it isn't an error
+fn error_kind(response : &str) -> Option<&'static str> {
+    return ERROR_KINDS.iter().find(|&&(_, message)| response.starts_with(message)).map(|&(kind, _)| kind);
+}
+
+// returns the permission needed to run the given command, or None if the command is unknown
+fn command_permission(command : &str) -> Option<Permission> {
+    return match command {
+        COMMAND_CHECK | COMMAND_CHECK_AB | COMMAND_MULTI | COMMAND_MULTI_AB | COMMAND_INFO | COMMAND_LIST | COMMAND_STATS => Some(Permission::Read),
+        COMMAND_SET | COMMAND_SET_AB | COMMAND_BULK | COMMAND_BULK_AB => Some(Permission::Write),
+        COMMAND_CREATE | COMMAND_DROP | COMMAND_CLEAR | COMMAND_CLOSE | COMMAND_FLUSH | COMMAND_RELOAD | COMMAND_SLOWLOG | COMMAND_LOAD | COMMAND_SWAP | COMMAND_REPLICATE | COMMAND_EXPORT | COMMAND_IMPORT => Some(Permission::Admin),
+        _ => None
+    };
+}
+
+// returns true if the command changes which filters there are or what they hold, which only the leader does
+fn modifies_filters(command : &str) -> bool {
+    return match command {
+        COMMAND_SET | COMMAND_SET_AB | COMMAND_BULK | COMMAND_BULK_AB | COMMAND_CREATE | COMMAND_DROP | COMMAND_CLEAR | COMMAND_LOAD | COMMAND_SWAP | COMMAND_IMPORT => true,
+        _ => false
+    };
+}
+
+// represents a task that executes subtasks periodically
+struct Worker {
+    timer    : Timer,
+    receiver : Receiver<()>,
+    tasks    : Vec<Box<FnMut<(u64,), ()> + 'static>>
+}
+
+impl Worker {
+    fn new(duration : Duration) -> Self {
+        let mut timer : Timer = Timer::new().unwrap();
+        let receiver : Receiver<()> = timer.periodic(duration);
+
+        return Worker {
+            timer: timer,
+            receiver: receiver,
+            tasks: Vec::new()
+        };
+    }
+
+    fn add_task<T : FnMut<(u64,), ()> + 'static>(&mut self, task : T) {
+        self.tasks.push(Box::new(task));
+    }
+}
+
+impl FnMut<(), ()> for Worker {
+    #[allow(unused_variables)]
+    extern "rust-call" fn call_mut(&mut self, args : ()) {
+        let mut count : u64 = 0;
+
+        loop {
+            // activate the tasks
+            for task in self.tasks.iter_mut() {
+                task(count,);
+            }
+
+            count += 1;
+
+            // sleep for the rest of the minute
+            self.receiver.recv().unwrap();
+        }
+    }
+}
+
+unsafe impl Send for Worker { }
+
+// task for flushing filters
+struct FlushTask {
+    server     : Arc<BloomServer>,
+    last_flush : u64
+}
+
+impl FlushTask {
+    fn new(server : Arc<BloomServer>) -> Self {
+        return FlushTask { server: server, last_flush: 0 };
+    }
+}
+
+impl FnMut<(u64,), ()> for FlushTask {
+    extern "rust-call" fn call_mut(&mut self, args : (u64,)) {
+        let (time,) = args;
+
+        if (time - self.last_flush) > self.server.use_config(|config| config.flush_interval) as u64 {
+            // failures are logged, and the next flush tries again
+            let _ = self.server.flush_filters();
+
+            self.last_flush = time;
+        }
+    }
+}
+
+unsafe impl Send for FlushTask { }
+
+// task for clearing cold filters
+struct CloseTask {
+    server : Arc<BloomServer>
+}
+
+impl CloseTask {
+    fn new(server : Arc<BloomServer>) -> Self {
+        return CloseTask { server: server };
+    }
+}
+
+impl FnMut<(u64,), ()> for CloseTask {
+    #[allow(unused_variables)]
+    extern "rust-call" fn call_mut(&mut self, args : (u64,)) {
+        let cold_interval : u64 = self.server.use_config(|config| config.cold_interval) as u64;
+
+        self.server.use_filters_mut(|filters| {
+            for filter_lock in filters.values() {
+                let mut guard = filter_lock.write().unwrap();
+
+                if (*guard).cold_index > cold_interval && (*guard).is_resident() {
+                    (*guard).unload_filter();
+                    log_info!("Closed cold filter {}", (*guard).config.filter_name);
+                }
+
+                (*guard).cold_index += 1;
+
+                // partitions go cold on their own, so only the ones in use stay in memory
+                let filter_name : String = (*guard).config.filter_name.clone();
+
+                for (index, partition) in (*guard).partitions.iter_mut().enumerate() {
+                    if partition.cold_index > cold_interval && partition.is_resident() {
+                        partition.unload_filter();
+                        log_info!("Closed cold partition {} of filter {}", index, filter_name);
+                    }
+
+                    partition.cold_index += 1;
+                }
+            }
+        });
+    }
+}
+
+unsafe impl Send for CloseTask { }
+
+// task for dropping filters whose ttl has run out
+struct ExpireTask {
+    server : Arc<BloomServer>
+}
+
+impl ExpireTask {
+    fn new(server : Arc<BloomServer>) -> Self {
+        return ExpireTask { server: server };
+    }
+}
+
+impl FnMut<(u64,), ()> for ExpireTask {
+    #[allow(unused_variables)]
+    extern "rust-call" fn call_mut(&mut self, args : (u64,)) {
+        self.server.drop_expired_filters(now());
+    }
+}
+
+unsafe impl Send for ExpireTask { }
+
+fn main() {
+    // get command line arguments
+    let args = os::args();
+    let mut args = args.iter();
+    args.next();
+
+    let mut config_filename : Option<&str> = None;
+    let mut check_config : bool = false;
+    let mut print_config : bool = false;
+
+    // environment variables come before command line flags, so the flags win
+    let mut overrides : Vec<ConfigOverride> = ConfigOverride::from_env();
+
+    // read command line arguments
+    loop {
+        match args.next() {
+            Some(arg) => {
+                match arg.as_slice() {
+                    // config filename
+                    "-f" => {
+                        match args.next() {
+                            Some(value) => config_filename = Some(value.as_slice()),
+                            None => panic!("missing value for flag \"-f\"\r\n\r\n{}", USAGE)
+                        }
+                    },
+                    // only validate the config file
+                    "--check-config" => { check_config = true },
+                    // only print the effective config
+                    "--print-config" => { print_config = true },
+                    // --<option>=<value> overrides
+                    _ => {
+                        match ConfigOverride::from_arg(arg.as_slice()) {
+                            Some(config_override) => { overrides.push(config_override) },
+                            None => panic!("invalid argument: {}\r\n\r\n{}", arg, USAGE)
+                        }
+                    }
+                };
+            },
+            None => { break }
+        };
+    }
+
+    // create config
+    let config : BloomConfig = match BloomConfig::load(config_filename, overrides.as_slice()) {
+        Ok(config) => config,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "{}", e);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+
+    if print_config {
+        print!("{}", config.to_ini());
+        return;
+    }
+
+    if check_config {
+        println!("Config OK");
+        return;
+    }
+
+    // create server, remembering the config file for reloads
+    let mut server : BloomServer = BloomServer::new(config);
+    server.config_filename = config_filename.map(|filename| String::from_str(filename));
+    server.config_overrides = overrides;
+
+    // start server
+    start(server);
+}
+
+fn start(server: BloomServer) {
+    // the settings listeners and workers are set up with; these don't change on reload
+    let config : BloomConfig = server.config();
+
+    match configure_logging(&config) {
+        Ok(_) => { },
+        Err(e) => panic!("Could not set up logging: {}", e)
+    }
+
+    // catch signals before anything is listening, so a SIGTERM during startup still ends in the final flush
+    signals::install();
+
+    // make sure data_dir exists and is accessible
+    let data_dir : Path = Path::new(config.data_dir.clone());
+    if !data_dir.exists() {
+        fs::mkdir(&data_dir, io::USER_RWX).unwrap();
+    } else if !data_dir.is_dir() {
+        panic!("Invalid data_dir: {} is not a directory", data_dir.as_str().unwrap());
+    } else {
+        server.read_in_filters();
+    }
+
+    // listen at <bind_host>:<tcp_port>
+    let listener = TcpListener::bind(config.get_bind_address().as_slice()).unwrap();
+
+    let acceptor : TcpAcceptor = listener.listen().unwrap();
+
+    // keep clones of the acceptors, so they can be closed on shutdown
+    let mut tcp_acceptors : Vec<TcpAcceptor> = vec![acceptor.clone()];
+    let mut unix_acceptors : Vec<UnixAcceptor> = Vec::new();
+
+    // share state
+    let server : Arc<BloomServer> = Arc::new(server);
+
+    // listen for TLS clients at <bind_host>:<tls_port>
+    if config.tls_enabled() {
+        let context : SslContext = match tls::create_context(&config) {
+            Ok(context) => context,
+            Err(e) => panic!("Could not set up TLS: {}", e)
+        };
+
+        let tls_listener = TcpListener::bind(config.get_tls_bind_address().as_slice()).unwrap();
+        let tls_acceptor : TcpAcceptor = tls_listener.listen().unwrap();
+        tcp_acceptors.push(tls_acceptor.clone());
+
+        let server = server.clone();
+
+        Thread::spawn(move || {
+            accept_tls_clients(server, tls_acceptor, Arc::new(context));
+        });
+    }
+
+    // listen for local clients at <unix_socket>; the guard removes the socket file again on shutdown
+    let _unix_socket_guard : Option<UnixSocketGuard> = if !config.unix_socket.is_empty() {
+        let unix_acceptor : UnixAcceptor = match unix_socket::bind(&config) {
+            Ok(acceptor) => acceptor,
+            Err(e) => panic!("Could not listen on unix socket {}: {}", config.unix_socket, e)
+        };
+        unix_acceptors.push(unix_acceptor.clone());
+
+        let guard : UnixSocketGuard = UnixSocketGuard::new(Path::new(config.unix_socket.clone()));
+
+        let server = server.clone();
+
+        Thread::spawn(move || {
+            accept_clients(server, unix_acceptor);
+        });
+
+        Some(guard)
+    } else {
+        None
+    };
+
+    // serve metrics over HTTP at <bind_host>:<metrics_port>
+    if config.metrics_enabled() {
+        let metrics_listener = TcpListener::bind(config.get_metrics_bind_address().as_slice()).unwrap();
+        let metrics_acceptor : TcpAcceptor = metrics_listener.listen().unwrap();
+        tcp_acceptors.push(metrics_acceptor.clone());
+
+        let server = server.clone();
+
+        Thread::spawn(move || {
+            accept_metrics_clients(server, metrics_acceptor);
+        });
+    }
+
+    // follow the leader, if there is one; clients can only read from a follower
+    if config.is_follower() {
+        let server = server.clone();
+        let address : String = config.replicate_from.clone();
+
+        Thread::spawn(move || {
+            follow_leader(server, address);
+        });
+    }
+
+    // run loads on a thread of their own, so the clients that start them aren't held up
+    {
+        let server = server.clone();
+
+        Thread::spawn(move || {
+            while !server.is_shutting_down() {
+                server.run_pending_loads();
+                timer::sleep(Duration::milliseconds(LOAD_POLL_MS));
+            }
+        });
+    }
+
+    // setup background tasks
+    let flush_task : FlushTask = FlushTask::new(server.clone());
     let close_task : CloseTask = CloseTask::new(server.clone());
+    let expire_task : ExpireTask = ExpireTask::new(server.clone());
+
+    let duration : Duration = Duration::minutes(1);
+
+    // create worker threads
+    if config.worker_threads <= 1 {
+        let mut worker : Worker = Worker::new(duration);
+        worker.add_task(flush_task);
+        worker.add_task(close_task);
+        worker.add_task(expire_task);
+
+        Thread::spawn(worker);
+    } else {
+        let mut worker1 : Worker = Worker::new(duration);
+        worker1.add_task(flush_task);
+
+        let mut worker2 : Worker = Worker::new(duration);
+        worker2.add_task(close_task);
+        worker2.add_task(expire_task);
+
+        Thread::spawn(worker1);
+        Thread::spawn(worker2);
+    }
+
+    // handle connection
+    {
+        let server = server.clone();
+
+        Thread::spawn(move || {
+            accept_clients(server, acceptor);
+        });
+    }
+
+    // wait for SIGTERM or SIGINT, reloading the config and flushing whenever SIGHUP comes in
+    while !signals::shutdown_requested() {
+        if signals::take_hangup() {
+            match server.reload_config() {
+                Ok(ref restart_required) if restart_required.is_empty() => { },
+                Ok(restart_required) => { log_warn!("Reloaded config; restart required for: {}", restart_required.connect(" ")) },
+                Err(e) => { log_error!("Could not reload config: {}", e) }
+            }
+
+            // failures are logged by flush_filters
+            let _ = server.flush_filters();
+        }
+
+        timer::sleep(Duration::milliseconds(100));
+    }
+
+    // stop accepting new clients; the accept loops see the flag rather than an error once their listener closes
+    server.stop_serving();
+
+    for acceptor in tcp_acceptors.iter_mut() {
+        acceptor.close_accept().unwrap();
+    }
+
+    for acceptor in unix_acceptors.iter_mut() {
+        acceptor.close_accept().unwrap();
+    }
+
+    let shutdown_timeout : i32 = server.use_config(|config| config.shutdown_timeout);
+
+    if !shutdown(server.clone(), Duration::seconds(shutdown_timeout as i64)) {
+        log_error!("Shutdown timed out after {} seconds; some filters may not have been flushed", shutdown_timeout);
+    }
+}
+
+// points the log at the level, format and destination from the config
+fn configure_logging(config : &BloomConfig) -> Result<(), String> {
+    return logging::configure(config.log_level, config.log_format, &config.log_destination);
+}
+
+// drains in-flight requests and flushes every filter, giving up once the timeout runs out
+// returns true if everything was flushed in time
+fn shutdown(server : Arc<BloomServer>, timeout : Duration) -> bool {
+    let deadline : u64 = precise_time_ns() + timeout.num_milliseconds() as u64 * 1000000;
+
+    // followers' streams end once the changes already sent to them are written
+    server.replicas.disconnect_all();
+
+    if !server.drain_requests(deadline) {
+        return false;
+    }
+
+    // flush on a separate thread, so the deadline can be watched while it runs
+    let (sender, receiver) : (Sender<bool>, Receiver<bool>) = channel();
+
+    {
+        let server = server.clone();
+
+        Thread::spawn(move || {
+            sender.send(server.flush_filters().is_ok()).unwrap();
+        });
+    }
+
+    loop {
+        match receiver.try_recv() {
+            Ok(flushed) => { return flushed },
+            Err(_) => { }
+        }
+
+        // out of time, the flush stops once the filter it's writing is done, rather than the process
+        // exiting halfway through writing it
+        if precise_time_ns() >= deadline {
+            server.abort_flushes();
+            return false;
+        }
+
+        timer::sleep(Duration::milliseconds(10));
+    }
+}
+
+// follows the leader at the given address, reconnecting whenever the connection drops, until the server shuts down
+fn follow_leader(server : Arc<BloomServer>, address : String) {
+    while !server.is_shutting_down() {
+        log_info!("Following leader {}", address);
+
+        match server.follow(address.as_slice()) {
+            Err(e) => { log_error!("Lost connection to leader {}: {}", address, e) },
+            Ok(_) => { }
+        }
+
+        timer::sleep(Duration::milliseconds(REPLICATION_RETRY_MS));
+    }
+}
+
+// handles client connections from any kind of listener, each on its own thread
+fn accept_clients<S : Stream + Send + ClientAddress, A : Acceptor<S>>(server : Arc<BloomServer>, mut acceptor : A) {
+    for stream in acceptor.incoming() {
+        // the acceptor has been closed
+        if server.is_shutting_down() {
+            break;
+        }
+
+        match stream {
+            Ok(mut stream) => {
+                let server = server.clone();
+                let address : String = stream.client_address();
+
+                Thread::spawn(move || {
+                    server.handle_client(stream, address);
+                });
+            },
+            Err(e) => { log_warn!("Failed to connect to incoming client: {}", e) }
+        };
+    }
+}
+
+// answers HTTP scrapes of the metrics page, each on its own thread
+fn accept_metrics_clients(server : Arc<BloomServer>, mut acceptor : TcpAcceptor) {
+    for stream in acceptor.incoming() {
+        // the acceptor has been closed
+        if server.is_shutting_down() {
+            break;
+        }
+
+        match stream {
+            Ok(mut stream) => {
+                let server = server.clone();
+
+                // a scraper that connects and sends nothing gives up its thread after the timeout
+                stream.set_read_timeout(Some(METRICS_READ_TIMEOUT_MS));
+
+                Thread::spawn(move || {
+                    match metrics::serve_http(stream, |token| server.metrics_authorized(token), || server.render_metrics()) {
+                        Err(e) => { log_warn!("Failed to serve metrics: {}", e) },
+                        Ok(_) => { }
+                    }
+                });
+            },
+            Err(e) => { log_warn!("Failed to connect to incoming metrics client: {}", e) }
+        };
+    }
+}
+
+// handles TLS client connections; the handshake is done on each client's own thread
+fn accept_tls_clients(server : Arc<BloomServer>, mut acceptor : TcpAcceptor, context : Arc<SslContext>) {
+    for stream in acceptor.incoming() {
+        // the acceptor has been closed
+        if server.is_shutting_down() {
+            break;
+        }
+
+        match stream {
+            Ok(mut stream) => {
+                let server = server.clone();
+                let context = context.clone();
+                let address : String = stream.client_address();
+
+                Thread::spawn(move || {
+                    match SslStream::new_server(&*context, stream) {
+                        Ok(stream) => { server.handle_client(stream, address) },
+                        Err(e) => { log_warn!("TLS handshake with incoming client failed: {}", e) }
+                    };
+                });
+            },
+            Err(e) => { log_warn!("Failed to connect to incoming client: {}", e) }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BloomServer, ClientSession, MESSAGE_NO_CONFIG_FILE, MESSAGE_INVALID_CONFIG, MESSAGE_RESTART_NEEDED, accept_clients, accept_metrics_clients, accept_tls_clients, follow_leader, shutdown, MESSAGE_READ_ONLY, MESSAGE_NO_EXIST, MESSAGE_EXISTS, MESSAGE_DONE, MESSAGE_BAD_ARGS, MESSAGE_NOT_IMPLEMENTED, MESSAGE_UNAUTHORIZED, MESSAGE_START, MESSAGE_SERVER_ERROR};
+    use bloomd::AccessControl;
+    use bloomd::{bitmap_mode, IoMode};
+    use bloomd::client::{Client, ClientError, FilterInfo, FilterListing};
+    use bloomd::config::{BloomConfig, FilterProfile};
+    use bloomd::IniFile;
+    use bloomd::shard::ShardedClient;
+    use bloomd::wrappers::BloomFilter;
+    use tls;
+    use unix_socket;
+    use unix_socket::UnixSocketGuard;
+    use openssl::ssl::{SslContext, SslStream};
+    use std::io;
+    use std::io::{TcpListener, TcpStream, TcpAcceptor, Listener, BufferedStream, TempDir};
+    use std::io::fs::{File, PathExtensions};
+    use std::io::fs;
+    use std::io::net::pipe::UnixStream;
+    use std::io::timer;
+    use std::sync::Arc;
+    use std::thread::Thread;
+    use std::time::Duration;
+    use bloomd::now;
+    use replication::{ReplicationEvent, Replicas};
+    use std::os;
+    use std::io::process::{Command, Process, StdioContainer};
+    use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+    #[test]
+    fn test_server () {
+        // Start by making a default server instance
+        let server : BloomServer = BloomServer::new(BloomConfig::default());
+        
+        // Test create, including when filter already exists
+        test_command(&server, "create filter", MESSAGE_DONE);
+        test_command(&server, "create filter", MESSAGE_EXISTS);
+        
+        // Test set (and check)
+        test_command(&server, "check filter first", "0");
+        test_command(&server, "set filter first", "1");
+        test_command(&server, "c filter first", "1");
+        test_command(&server, "s filter first", "2");
+        test_command(&server, "c filter first", "2");
+        test_command(&server, "s filter first", "3");
+        test_command(&server, "c filter first", "3");
+        
+        // Test bad entries for set and check
+        test_command(&server, "set filetr first", MESSAGE_NO_EXIST);
+        test_command(&server, "check filetr first", MESSAGE_NO_EXIST);
+        test_command(&server, "set filter first second", MESSAGE_BAD_ARGS);
+        test_command(&server, "set filter first second", MESSAGE_BAD_ARGS);
+        test_command(&server, "check filter", MESSAGE_BAD_ARGS);
+        test_command(&server, "set filter", MESSAGE_BAD_ARGS);
+        
+        // Test multi and bulk
+        test_command(&server, "multi filter first second third", "3 0 0");
+        test_command(&server, "bulk filter first second third", "4 1 1");
+        test_command(&server, "b filter first second third", "5 2 2");
+        test_command(&server, "m filter first second third", "5 2 2");
+        
+        // Test bad entries for multi and bulk
+        test_command(&server, "bulk filetr first second third", MESSAGE_NO_EXIST);
+        test_command(&server, "multi filetr first second third", MESSAGE_NO_EXIST);
+        test_command(&server, "check filter", MESSAGE_BAD_ARGS);
+        test_command(&server, "set filter", MESSAGE_BAD_ARGS);
+        
+        // Test list
+        test_command(&server, "list fake_prefix", "START\r\nEND");
+        test_command(&server, "list", "START\r\nfilter 0.0001 239627 100000 3 -\r\nEND");
+        
+        // Test info
+        let info_results : &str = "START\r\ncapacity 100000\r\nchecks 10\r\ncheck_hits 7\r\ncheck_misses 3\r\nin_memory 0\r\nk_num 13\r\nlayers 1\r\npage_ins 0\r\npage_outs 0\r\nprobability 0.0001\r\nsets 9\r\nset_hits 6\r\nset_misses 3\r\nsize 3\r\nstorage 239627\r\nEND";
+        test_command(&server, "info", MESSAGE_BAD_ARGS);
+        test_command(&server, "info filetr", MESSAGE_NO_EXIST);
+        test_command(&server, "info filter", info_results);
+        
+        // Test nonexistent commands
+        test_command(&server, "infor filter", MESSAGE_NOT_IMPLEMENTED);
+        test_command(&server, "sette filter first", MESSAGE_NOT_IMPLEMENTED);
+        
+        // Test flush, close, and clear
+        test_command(&server, "flush", MESSAGE_DONE);
+        test_command(&server, "flush filter", MESSAGE_DONE);
+        
+        test_command(&server, "close", MESSAGE_BAD_ARGS);
+        test_command(&server, "close filter", MESSAGE_DONE);
+        test_command(&server, "create filter", MESSAGE_EXISTS);
+        
+        test_command(&server, "clear filter", MESSAGE_DONE);
+        test_command(&server, "create filter", MESSAGE_DONE);
+        test_command(&server, "m filter first second third", "5 2 2");
+        
+        // Clean up to prevent persistence affecting future tests, and test drop
+        test_command(&server, "drop", MESSAGE_BAD_ARGS);
+        test_command(&server, "drop filter", MESSAGE_DONE);
+        test_command(&server, "drop filter", MESSAGE_NO_EXIST);
+    }
+    
+    #[test]
+    fn test_ttl() {
+        let server : BloomServer = BloomServer::new(BloomConfig::default());
+
+        // A filter with a ttl reports it in info, one without does not
+        test_command(&server, "create ttl_filter ttl=3600", MESSAGE_DONE);
+        test_command(&server, "create ttl_forever", MESSAGE_DONE);
+        assert!(server.interpret_request("info ttl_filter").as_slice().contains("\r\nttl "));
+        assert!(!server.interpret_request("info ttl_forever").as_slice().contains("\r\nttl "));
+
+        // Every row of a list has a ttl column, - for filters without one
+        let listing : String = server.interpret_request("list ttl_");
+        assert!(listing.as_slice().lines().filter(|line| line.starts_with("ttl_")).all(|line| line.split(' ').count() == 6));
+        assert!(listing.as_slice().lines().any(|line| line.starts_with("ttl_forever ") && line.ends_with(" -")));
+
+        // A filter whose expiry has passed is dropped, the others are kept
+        test_command(&server, "create ttl_expired expires_at=1", MESSAGE_DONE);
+        assert_eq!(server.drop_expired_filters(now()), vec![String::from_str("ttl_expired")]);
+        test_command(&server, "info ttl_expired", MESSAGE_NO_EXIST);
+        test_command(&server, "create ttl_expired", MESSAGE_DONE);
+
+        test_command(&server, "drop ttl_filter", MESSAGE_DONE);
+        test_command(&server, "drop ttl_forever", MESSAGE_DONE);
+        test_command(&server, "drop ttl_expired", MESSAGE_DONE);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut sessions : FilterProfile = FilterProfile::new("sessions");
+        sessions.patterns = vec![String::from_str("session_*")];
+        sessions.capacity = Some(50000);
+        sessions.ttl = Some(3600);
+
+        let mut config : BloomConfig = BloomConfig::default();
+        config.profiles.push(sessions);
+
+        let server : BloomServer = BloomServer::new(config);
+
+        // Profiles apply by name or by pattern, explicit parameters win
+        test_command(&server, "create profile_named profile=sessions", MESSAGE_DONE);
+        test_command(&server, "create session_eu", MESSAGE_DONE);
+        test_command(&server, "create session_us capacity=20000", MESSAGE_DONE);
+        test_command(&server, "create profile_missing profile=campaigns", MESSAGE_BAD_ARGS);
+
+        assert!(server.interpret_request("info profile_named").as_slice().contains("\r\ncapacity 50000\r\n"));
+        assert!(server.interpret_request("info session_eu").as_slice().contains("\r\nttl "));
+        assert!(server.interpret_request("info session_us").as_slice().contains("\r\ncapacity 20000\r\n"));
+
+        test_command(&server, "drop profile_named", MESSAGE_DONE);
+        test_command(&server, "drop session_eu", MESSAGE_DONE);
+        test_command(&server, "drop session_us", MESSAGE_DONE);
+    }
+
+    #[test]
+    fn test_auth() {
+        let mut ini : IniFile = IniFile::new();
+        ini.read_string(vec![
+            String::from_str("[role:reader]"),
+            String::from_str("permissions=read"),
+            String::from_str("prefixes=auth_"),
+            String::from_str("[role:ops]"),
+            String::from_str("permissions=read,write,admin"),
+            String::from_str("[tokens]"),
+            String::from_str("reader_token=reader"),
+            String::from_str("ops_token=ops")]);
+
+        let mut config : BloomConfig = BloomConfig::default();
+        config.access_control = AccessControl::from_ini(&ini).unwrap();
+
+        let server : BloomServer = BloomServer::new(config);
+        let mut session : ClientSession = ClientSession::new();
+
+        // Nothing is allowed before authenticating
+        test_session_command(&server, &mut session, "list", MESSAGE_UNAUTHORIZED);
+        test_session_command(&server, &mut session, "auth", MESSAGE_BAD_ARGS);
+        test_session_command(&server, &mut session, "auth wrong_token", MESSAGE_UNAUTHORIZED);
+        test_session_command(&server, &mut session, "infor auth_filter", MESSAGE_NOT_IMPLEMENTED);
+
+        // Admins can do everything
+        test_session_command(&server, &mut session, "auth ops_token", MESSAGE_DONE);
+        test_session_command(&server, &mut session, "create auth_filter", MESSAGE_DONE);
+        test_session_command(&server, &mut session, "create other_filter", MESSAGE_DONE);
+        test_session_command(&server, &mut session, "set auth_filter first", "1");
+        test_session_command(&server, &mut session, "flush", MESSAGE_DONE);
+
+        // Readers can only read filters matching their prefixes
+        test_session_command(&server, &mut session, "auth reader_token", MESSAGE_DONE);
+        test_session_command(&server, &mut session, "check auth_filter first", "1");
+        test_session_command(&server, &mut session, "check other_filter first", MESSAGE_UNAUTHORIZED);
+        test_session_command(&server, &mut session, "set auth_filter first", MESSAGE_UNAUTHORIZED);
+        test_session_command(&server, &mut session, "drop auth_filter", MESSAGE_UNAUTHORIZED);
+        test_session_command(&server, &mut session, "list", MESSAGE_UNAUTHORIZED);
+        test_session_command(&server, &mut session, "list other_", MESSAGE_UNAUTHORIZED);
+        test_session_command(&server, &mut session, "list auth_", "START\r\nauth_filter 0.0001 239627 100000 1 -\r\nEND");
+
+        // A refused import is answered without reading the files sent along, and the connection is closed
+        {
+            let (mut acceptor, address) : (TcpAcceptor, String) = bind_ephemeral();
+            let mut client_stream : BufferedStream<TcpStream> = BufferedStream::new(TcpStream::connect(address.as_slice()).unwrap());
+            let mut server_stream : BufferedStream<TcpStream> = BufferedStream::new(acceptor.accept().unwrap());
+
+            client_stream.write_str("snapshot auth_filter 1\r\n").unwrap();
+            client_stream.flush().unwrap();
+
+            assert!(server.handle_import(&session, "import auth_filter", &mut server_stream).is_err());
+            assert_eq!(client_stream.read_line().unwrap(), format!("{}\r\n", MESSAGE_UNAUTHORIZED));
+        }
+
+        // The metrics page covers every filter, so it needs a token for an unscoped reader
+        assert!(!server.metrics_authorized(None));
+        assert!(!server.metrics_authorized(Some("wrong_token")));
+        assert!(!server.metrics_authorized(Some("reader_token")));
+        assert!(server.metrics_authorized(Some("ops_token")));
+
+        // Clean up
+        test_session_command(&server, &mut session, "auth ops_token", MESSAGE_DONE);
+        test_session_command(&server, &mut session, "drop auth_filter", MESSAGE_DONE);
+        test_session_command(&server, &mut session, "drop other_filter", MESSAGE_DONE);
+    }
+
+    #[test]
+    fn test_tls() {
+        let directory : TempDir = TempDir::new("bloomd-test-tls").unwrap();
+        let (cert_filename, key_filename) = tls::test::generate_self_signed(directory.path().join("server").as_str().unwrap());
+        let client = tls::test::generate_self_signed(directory.path().join("client").as_str().unwrap());
+
+        // Require clients to present the test client certificate
+        let mut config : BloomConfig = BloomConfig::default();
+        config.tls_cert_file = cert_filename.clone();
+        config.tls_key_file = key_filename;
+        config.tls_ca_file = client.0.clone();
+
+        let context : SslContext = tls::create_context(&config).unwrap();
+        let (acceptor, address) : (TcpAcceptor, String) = bind_ephemeral();
+        let server : Arc<BloomServer> = Arc::new(BloomServer::new(config.clone()));
+
+        Thread::spawn(move || {
+            accept_tls_clients(server, acceptor, Arc::new(context));
+        });
+
+        let client_context : SslContext = tls::test::create_client_context(cert_filename.as_slice(), Some(client));
+        let stream : TcpStream = TcpStream::connect(address.as_slice()).unwrap();
+        let mut stream = BufferedStream::new(SslStream::new(&client_context, stream).unwrap());
+
+        stream.write_str("list\r\n").unwrap();
+        stream.flush().unwrap();
+
+        assert_eq!(stream.read_line().unwrap().as_slice(), "START\r\n");
+        assert_eq!(stream.read_line().unwrap().as_slice(), "END\r\n");
+
+        // Clients without a certificate are turned away during the handshake
+        let anonymous_context : SslContext = tls::test::create_client_context(cert_filename.as_slice(), None);
+        let stream : TcpStream = TcpStream::connect(address.as_slice()).unwrap();
+        let handshake = SslStream::new(&anonymous_context, stream);
+        assert!(handshake.is_err() || BufferedStream::new(handshake.unwrap()).read_line().is_err());
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let directory : TempDir = TempDir::new("bloomd-test-unix").unwrap();
+        let path : Path = directory.path().join("bloomd.sock");
+
+        let mut config : BloomConfig = BloomConfig::default();
+        config.unix_socket = String::from_str(path.as_str().unwrap());
+
+        // A file that isn't a socket is left alone
+        File::create(&path).write_str("not a socket").unwrap();
+        assert!(unix_socket::bind(&config).is_err());
+        assert_eq!(File::open(&path).read_to_string().unwrap().as_slice(), "not a socket");
+        fs::unlink(&path).unwrap();
+
+        // A socket nothing listens on any more is replaced
+        drop(unix_socket::bind(&config).unwrap());
+        assert!(path.exists());
+
+        let acceptor = unix_socket::bind(&config).unwrap();
+        let guard : UnixSocketGuard = UnixSocketGuard::new(path.clone());
+
+        // Only the owner and group can connect
+        assert_eq!(fs::lstat(&path).unwrap().perm.bits() & 0o777, 0o770);
+
+        // A socket another server listens on isn't taken over
+        assert!(unix_socket::bind(&config).is_err());
+        let server : Arc<BloomServer> = Arc::new(BloomServer::new(config.clone()));
+
+        Thread::spawn(move || {
+            accept_clients(server, acceptor);
+        });
+
+        let mut stream = BufferedStream::new(UnixStream::connect(&Path::new(config.unix_socket.clone())).unwrap());
+
+        stream.write_str("check unix_filter first\r\n").unwrap();
+        stream.flush().unwrap();
+
+        assert_eq!(stream.read_line().unwrap().as_slice(), format!("{}\r\n", MESSAGE_NO_EXIST).as_slice());
+
+        // The socket file is removed once the guard goes away
+        drop(guard);
+        assert!(!Path::new(config.unix_socket.clone()).exists());
+    }
+
+    #[test]
+    fn test_client() {
+        let server : Arc<BloomServer> = Arc::new(BloomServer::new(BloomConfig::default()));
+        let (acceptor, address) : (TcpAcceptor, String) = bind_ephemeral();
+        let mut closer : TcpAcceptor = acceptor.clone();
+
+        let accept_thread = {
+            let server = server.clone();
+
+            Thread::scoped(move || {
+                accept_clients(server, acceptor);
+            })
+        };
+
+        let mut client : Client = Client::new(address.as_slice());
+        client.batch_size = 2;
+
+        assert_eq!(client.create("client_filter", &["capacity=20000"]).unwrap(), true);
+        assert_eq!(client.create("client_filter", &[]).unwrap(), false);
+
+        assert_eq!(client.set("client_filter", "first").unwrap(), 1);
+        assert_eq!(client.check("client_filter", "first").unwrap(), 1);
+        assert_eq!(client.check("client_filter", "second").unwrap(), 0);
+
+        // Batches are split into several commands, and the results joined back up
+        assert_eq!(client.bulk("client_filter", &["first", "second", "third"]).unwrap(), vec![2, 1, 1]);
+        assert_eq!(client.multi("client_filter", &["first", "second", "third", "fourth"]).unwrap(), vec![2, 1, 1, 0]);
+
+        let listing : Vec<FilterListing> = client.list(Some("client_")).unwrap();
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].name.as_slice(), "client_filter");
+        assert_eq!(listing[0].capacity, 20000);
+        assert_eq!(listing[0].size, 3);
+
+        let info : FilterInfo = client.info("client_filter").unwrap();
+        assert_eq!(info.capacity, 20000);
+        assert_eq!(info.in_memory, false);
+        assert_eq!(info.layers, 1);
+        assert!(info.k_num > 0);
+        assert_eq!(info.size, 3);
+        assert_eq!(info.ttl, None);
+        assert_eq!(info.partitions, None);
+        assert_eq!(info.counters.checks(), 6);
+        assert_eq!(info.counters.set_hits, 1);
+        assert_eq!(info.counters.set_misses, 3);
+
+        // Error responses come back as errors, and the connection stays usable
+        match client.check("missing_filter", "first") {
+            Err(ClientError::NoFilter(name)) => { assert_eq!(name.as_slice(), "missing_filter") },
+            _ => { panic!("expected NoFilter") }
+        }
+        match client.set("client_filter", "two words") {
+            Err(ClientError::InvalidKey(_)) => { },
+            _ => { panic!("expected InvalidKey") }
+        }
+
+        // An invalid key anywhere in a bulk stops every batch from being sent
+        match client.bulk("client_filter", &["fifth", "sixth", "two words"]) {
+            Err(ClientError::InvalidKey(_)) => { },
+            _ => { panic!("expected InvalidKey") }
+        }
+        assert_eq!(client.check("client_filter", "fifth").unwrap(), 0);
+        match client.create("client_filter_2", &["profile=no_such_profile"]) {
+            Err(ClientError::Client(_)) => { },
+            _ => { panic!("expected a Client Error") }
+        }
+        assert_eq!(client.idle_connections(), 1);
+
+        client.flush(Some("client_filter")).unwrap();
+        client.drop_filter("client_filter").unwrap();
+        assert!(client.list(None).unwrap().iter().all(|filter| filter.name.as_slice() != "client_filter"));
+
+        // Stop the accept loop the way start does
+        server.stop_serving();
+        closer.close_accept().unwrap();
+        accept_thread.join();
+    }
+
+    #[test]
+    fn test_client_reconnect() {
+        let (mut acceptor, address) : (TcpAcceptor, String) = bind_ephemeral();
+
+        // A server that hangs up on each connection after answering one request
+        let server_thread = Thread::scoped(move || {
+            for _ in (0..2) {
+                let mut stream = BufferedStream::new(acceptor.accept().unwrap());
+                stream.read_line().unwrap();
+                stream.write_str("Done\r\n").unwrap();
+                stream.flush().unwrap();
+            }
+
+            // a set is never sent again, so no third connection comes in
+            acceptor.set_timeout(Some(1000));
+            assert!(acceptor.accept().is_err());
+        });
+
+        let client : Client = Client::new(address.as_slice());
+        client.flush(None).unwrap();
+        assert_eq!(client.idle_connections(), 1);
+
+        // The pooled connection has been closed by the server, so the request goes over a new one
+        client.flush(None).unwrap();
+        assert_eq!(client.idle_connections(), 1);
+
+        // A set may already have been applied, so the closed connection is reported rather than retried
+        match client.set("client_filter", "key") {
+            Err(ClientError::Io(_)) => { },
+            _ => { panic!("expected an I/O error") }
+        }
+        assert_eq!(client.idle_connections(), 0);
+
+        server_thread.join();
+    }
+
+    #[test]
+    fn test_load() {
+        let server : BloomServer = BloomServer::new(BloomConfig::default());
+        let directory : TempDir = TempDir::new("bloomd-test-load").unwrap();
+        let key_file : Path = directory.path().join("keys.txt");
+        let bad_file : Path = directory.path().join("keys.bin");
+
+        // More keys than fit in one batch
+        {
+            let mut file : File = File::create(&key_file).unwrap();
+            for i in (0..25000) {
+                file.write_line(format!("key{}", i).as_slice()).unwrap();
+            }
+        }
+
+        // A length prefix promising more bytes than the file holds
+        File::create(&bad_file).unwrap().write(&[0, 0, 0, 16, 97, 98, 99]).unwrap();
+
+        let key_file : &str = key_file.as_str().unwrap();
+        let bad_file : &str = bad_file.as_str().unwrap();
+
+        test_command(&server, format!("load load_filter {}", key_file).as_slice(), MESSAGE_NO_EXIST);
+        test_command(&server, "create load_filter capacity=100000", MESSAGE_DONE);
+        test_command(&server, format!("load load_filter {} format=csv", key_file).as_slice(), MESSAGE_BAD_ARGS);
+        test_command(&server, "load load_filter cancel", MESSAGE_BAD_ARGS);
+        assert!(server.interpret_request("load load_filter /nonexistent/keys.txt").as_slice().starts_with(MESSAGE_SERVER_ERROR));
+
+        // The load is queued straight away, and only one runs at a time
+        test_command(&server, format!("load load_filter {}", key_file).as_slice(), MESSAGE_DONE);
+        test_command(&server, format!("load load_filter {}", key_file).as_slice(), MESSAGE_LOADING);
+        assert!(server.interpret_request("info load_filter").as_slice().contains("\r\nload_done 0\r\n"));
+
+        server.run_pending_loads();
+
+        test_command(&server, "check load_filter key0", "1");
+        test_command(&server, "check load_filter key24999", "1");
+        test_command(&server, "check load_filter key25000", "0");
+
+        // The load shows up in info, and didn't count as sets
+        let info : String = server.interpret_request("info load_filter");
+        assert!(info.as_slice().contains("\r\nsize 25000\r\n"));
+        assert!(info.as_slice().contains("\r\nsets 0\r\n"));
+        assert!(info.as_slice().contains(format!("\r\nload_done 1\r\nload_file {}\r\nload_keys 25000\r\nload_percent 100.0\r\n", key_file).as_slice()));
+
+        // A load that fails is over, and says why
+        test_command(&server, format!("load load_filter {} format=prefixed", bad_file).as_slice(), MESSAGE_DONE);
+        server.run_pending_loads();
+        assert!(server.interpret_request("info load_filter").as_slice().contains("\r\nload_done 1\r\nload_failed "));
+
+        // A cancelled load stops before its next batch
+        test_command(&server, "create load_filter_3 capacity=100000", MESSAGE_DONE);
+        test_command(&server, format!("load load_filter_3 {}", key_file).as_slice(), MESSAGE_DONE);
+        test_command(&server, "load load_filter_3 cancel", MESSAGE_DONE);
+        server.run_pending_loads();
+
+        let info : String = server.interpret_request("info load_filter_3");
+        assert!(info.as_slice().contains("\r\nload_done 1\r\nload_failed cancelled\r\n"));
+        assert!(info.as_slice().contains("\r\nload_keys 0\r\n"));
+
+        // A filter can be loaded as it is created, but not from a key file that can't be opened
+        test_command(&server, format!("create load_filter_2 load={}", key_file).as_slice(), MESSAGE_DONE);
+        server.run_pending_loads();
+        test_command(&server, "check load_filter_2 key100", "1");
+
+        assert!(server.interpret_request("create load_filter_4 load=/nonexistent/keys.txt").as_slice().starts_with(MESSAGE_SERVER_ERROR));
+        test_command(&server, "info load_filter_4", MESSAGE_NO_EXIST);
+
+        test_command(&server, "drop load_filter", MESSAGE_DONE);
+        test_command(&server, "drop load_filter_2", MESSAGE_DONE);
+        test_command(&server, "drop load_filter_3", MESSAGE_DONE);
+    }
+
+    #[test]
+    fn test_swap() {
+        let server : BloomServer = BloomServer::new(BloomConfig::default());
+        let staging : Path = Path::new("/tmp/bloomd-test-swap");
+
+        test_command(&server, "create swap_filter", MESSAGE_DONE);
+        test_command(&server, "set swap_filter old", "1");
+
+        // Build the replacement offline
+        {
+            let mut bloom_filter : BloomFilter = BloomFilter::create("swap_filter", 10000, 0.001, IoMode::Buffered, staging.clone()).unwrap();
+            assert_eq!(bloom_filter.set("new").unwrap(), 1);
+            bloom_filter.flush().unwrap();
+        }
+
+        // A swap that was cut off doesn't get in the way of the next one
+        fs::mkdir_recursive(&Path::new("/tmp/bloomd/swap.swap_filter"), io::USER_RWX).unwrap();
+
+        test_command(&server, "swap swap_filter /tmp/bloomd-test-swap", MESSAGE_DONE);
+        test_command(&server, "check swap_filter new", "1");
+        test_command(&server, "check swap_filter old", "0");
+        assert!(!staging.exists());
+        assert!(!Path::new("/tmp/bloomd/swap.swap_filter").exists());
+        assert!(!Path::new("/tmp/bloomd/retired.swap_filter").exists());
+        assert!(Path::new("/tmp/bloomd/filter.swap_filter/swap_filter.ini").exists());
+
+        // The staged directory has to exist
+        test_command(&server, "swap swap_filter /tmp/bloomd-test-swap", MESSAGE_BAD_ARGS);
+
+        test_command(&server, "drop swap_filter", MESSAGE_DONE);
+    }
+
+    #[test]
+    fn test_partitioned() {
+        let server : BloomServer = BloomServer::new(BloomConfig::default());
+        let directory : Path = Path::new("/tmp/bloomd/filter.partitioned_filter");
+
+        test_command(&server, "create partitioned_filter partitions=0", MESSAGE_BAD_ARGS);
+        test_command(&server, "create partitioned_filter capacity=100000 partitions=4", MESSAGE_DONE);
+
+        let keys : Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+
+        for key in keys.iter() {
+            test_command(&server, format!("set partitioned_filter {}", key).as_slice(), "1");
+        }
+
+        test_command(&server, "check partitioned_filter key0", "1");
+        test_command(&server, "check partitioned_filter key100", "0");
+        test_command(&server, "bulk partitioned_filter key0 key100", "2 1");
+        test_command(&server, "multi partitioned_filter key0 key100 key101", "2 1 0");
+
+        // The keys are spread across every partition, each with its own files
+        server.flush_filters().unwrap();
+        for index in (0..4) {
+            assert!(directory.join(format!("partition.{}.ini", index)).exists());
+            assert!(directory.join(format!("partition.{}.0.bmp", index)).exists());
+        }
+
+        let info : String = server.interpret_request("info partitioned_filter");
+        assert!(info.as_slice().contains("\r\nsize 101\r\n"));
+        assert!(info.as_slice().contains("\r\npartitions 4\r\nresident_partitions 4\r\n"));
+
+        // Only the partition a key belongs to is paged back in
+        test_command(&server, "close partitioned_filter", MESSAGE_DONE);
+        test_command(&server, "check partitioned_filter key1", "1");
+        assert!(server.interpret_request("info partitioned_filter").as_slice().contains("\r\nresident_partitions 1\r\n"));
+
+        // Partitions are read back in from disk along with the filter
+        {
+            let mut bloom_filter : BloomFilter = BloomFilter::from_directory(&directory, &String::from_str("partitioned_filter"), true).unwrap();
+            assert_eq!(bloom_filter.partitions.len(), 4);
+            assert_eq!(bloom_filter.resident_partitions(), 0);
+
+            for key in keys.iter() {
+                assert_eq!(bloom_filter.check(key.clone()).unwrap(), 1);
+            }
+        }
+
+        test_command(&server, "drop partitioned_filter", MESSAGE_DONE);
+        assert!(!directory.exists());
+    }
+
+    #[test]
+    fn test_io_mode() {
+        let mut config : BloomConfig = BloomConfig::default();
+        config.use_mmap = true;
+
+        let server : BloomServer = BloomServer::new(config);
+        let mmap_directory : Path = Path::new("/tmp/bloomd/filter.mmap_filter");
+        let buffered_directory : Path = Path::new("/tmp/bloomd/filter.buffered_filter");
+
+        // Filters take the server's use_mmap unless they set their own
+        test_command(&server, "create mmap_filter", MESSAGE_DONE);
+        test_command(&server, "create buffered_filter use_mmap=0", MESSAGE_DONE);
+
+        for filter_name in ["mmap_filter", "buffered_filter"].iter() {
+            test_command(&server, format!("set {} foo", filter_name).as_slice(), "1");
+            test_command(&server, format!("check {} foo", filter_name).as_slice(), "1");
+        }
+
+        // The mode is kept with the filter, and either way the keys make it to disk
+        server.flush_filters().unwrap();
+        test_command(&server, "close mmap_filter", MESSAGE_DONE);
+        test_command(&server, "close buffered_filter", MESSAGE_DONE);
+
+        {
+            let mut bloom_filter : BloomFilter = BloomFilter::from_directory(&mmap_directory, &String::from_str("mmap_filter"), false).unwrap();
+            assert_eq!(bloom_filter.config.io_mode, IoMode::Mmap);
+            assert_eq!(bloom_filter.check(String::from_str("foo")).unwrap(), 1);
+            assert_eq!(bloom_filter.bitmap_modes(), vec![bitmap_mode::SHARED as u32]);
+        }
+        {
+            let mut bloom_filter : BloomFilter = BloomFilter::from_directory(&buffered_directory, &String::from_str("buffered_filter"), false).unwrap();
+            assert_eq!(bloom_filter.config.io_mode, IoMode::Buffered);
+            assert_eq!(bloom_filter.check(String::from_str("foo")).unwrap(), 1);
+            assert_eq!(bloom_filter.bitmap_modes(), vec![bitmap_mode::PERSISTENT as u32]);
+        }
+
+        test_command(&server, "drop mmap_filter", MESSAGE_DONE);
+        test_command(&server, "drop buffered_filter", MESSAGE_DONE);
+    }
+
+    #[test]
+    fn test_incremental_flush() {
+        let server : BloomServer = BloomServer::new(BloomConfig::default());
+
+        test_command(&server, "create dirty_filter capacity=1000000", MESSAGE_DONE);
+        test_command(&server, "create clean_filter", MESSAGE_DONE);
+        server.flush_filters().unwrap();
+
+        // Nothing changed, so nothing is written
+        server.flush_filters().unwrap();
+        assert!(server.interpret_request("stats").as_slice().contains("\r\nlast_flush_bytes 0\r\n"));
+
+        // Only the pages the key landed in are written, along with the header page
+        test_command(&server, "set dirty_filter foo", "1");
+        test_command(&server, "check clean_filter foo", "0");
+
+        let (bytes, total) : (u64, u64) = server.use_filter(&String::from_str("dirty_filter"), |filter| {
+            assert!(filter.is_dirty());
+            (filter.dirty_bytes(), filter.config.bytes)
+        }).unwrap();
+
+        assert!(bytes > 0 && bytes < total);
+
+        // A check only changes the counters, so none of the bitmap is written
+        assert!(server.use_filter(&String::from_str("clean_filter"), |filter| filter.is_dirty() && filter.dirty_bytes() == 0).unwrap());
+
+        server.flush_filters().unwrap();
+        let stats : String = server.interpret_request("stats");
+        assert!(stats.as_slice().contains(format!("\r\nlast_flush_bytes {}\r\n", bytes).as_slice()));
+        assert!(stats.as_slice().contains("\r\nlast_flush_synced_bytes 0\r\n"));
+        assert!(!server.use_filter(&String::from_str("dirty_filter"), |filter| filter.is_dirty()).unwrap());
+        assert!(!server.use_filter(&String::from_str("clean_filter"), |filter| filter.is_dirty()).unwrap());
+
+        // The counters still made it to the ini file
+        let clean_filter : BloomFilter = BloomFilter::from_directory(&Path::new("/tmp/bloomd/filter.clean_filter"), &String::from_str("clean_filter"), false).unwrap();
+        assert_eq!(clean_filter.counters.check_misses, 1);
+
+        // A mapped bitmap is synced whole, and reported apart from the bytes written
+        test_command(&server, "create mapped_filter use_mmap=1", MESSAGE_DONE);
+        server.flush_filters().unwrap();
+        test_command(&server, "set mapped_filter foo", "1");
+
+        let mapped : u64 = server.use_filter(&String::from_str("mapped_filter"), |filter| {
+            assert_eq!(filter.dirty_bytes(), 0);
+            filter.mapped_bytes()
+        }).unwrap();
 
-    let duration : Duration = Duration::minutes(1);
+        server.flush_filters().unwrap();
+        let stats : String = server.interpret_request("stats");
+        assert!(mapped > 0);
+        assert!(stats.as_slice().contains("\r\nlast_flush_bytes 0\r\n"));
+        assert!(stats.as_slice().contains(format!("\r\nlast_flush_synced_bytes {}\r\n", mapped).as_slice()));
 
-    // create worker threads
-    if server.config.worker_threads <= 1 {
-        let mut worker : Worker = Worker::new(duration);
-        worker.add_task(flush_task);
-        worker.add_task(close_task);
+        test_command(&server, "drop dirty_filter", MESSAGE_DONE);
+        test_command(&server, "drop clean_filter", MESSAGE_DONE);
+        test_command(&server, "drop mapped_filter", MESSAGE_DONE);
+    }
 
-        Thread::spawn(worker);
-    } else {
-        let mut worker1 : Worker = Worker::new(duration);
-        worker1.add_task(flush_task);
+    #[test]
+    fn test_replication() {
+        let leader_dir : Path = Path::new("/tmp/bloomd-test-leader");
+        let follower_dir : Path = Path::new("/tmp/bloomd-test-follower");
 
-        let mut worker2 : Worker = Worker::new(duration);
-        worker2.add_task(close_task);
+        for data_dir in [&leader_dir, &follower_dir].iter() {
+            if data_dir.exists() {
+                fs::rmdir_recursive(*data_dir).unwrap();
+            }
 
-        Thread::spawn(worker1);
-        Thread::spawn(worker2);
+            fs::mkdir_recursive(*data_dir, io::USER_RWX).unwrap();
+        }
+
+        // Followers reach the leader over TCP, like any other client
+        let mut leader_config : BloomConfig = BloomConfig::default();
+        leader_config.data_dir = String::from_str(leader_dir.as_str().unwrap());
+
+        let leader : Arc<BloomServer> = Arc::new(BloomServer::new(leader_config));
+        let (acceptor, leader_address) : (TcpAcceptor, String) = bind_ephemeral();
+
+        {
+            let leader = leader.clone();
+
+            Thread::spawn(move || {
+                accept_clients(leader, acceptor);
+            });
+        }
+
+        test_command(&*leader, "create replicated", MESSAGE_DONE);
+        test_command(&*leader, "set replicated before", "1");
+
+        let mut follower_config : BloomConfig = BloomConfig::default();
+        follower_config.data_dir = String::from_str(follower_dir.as_str().unwrap());
+        follower_config.replicate_from = leader_address.clone();
+
+        let follower : Arc<BloomServer> = Arc::new(BloomServer::new(follower_config));
+
+        {
+            let follower = follower.clone();
+            let leader_address = leader_address.clone();
+
+            Thread::spawn(move || {
+                follow_leader(follower, leader_address);
+            });
+        }
+
+        // The follower starts from a copy of the leader's filters
+        wait_for_command(&*follower, "check replicated before", "1");
+        assert!(leader.interpret_request("stats").as_slice().contains("\r\nfollowers 1\r\n"));
+
+        // Then gets every change as it is made
+        test_command(&*leader, "set replicated after", "1");
+        test_command(&*leader, "bulk replicated one two", "1 1");
+        test_command(&*leader, "create streamed", MESSAGE_DONE);
+        wait_for_command(&*follower, "multi replicated after one two three", "1 1 1 0");
+        wait_for_command(&*follower, "check streamed anything", "0");
+
+        // Clients can only read from the follower
+        test_command(&*follower, "set replicated mine", MESSAGE_READ_ONLY);
+        test_command(&*follower, "create mine", MESSAGE_READ_ONLY);
+        test_command(&*follower, "drop replicated", MESSAGE_READ_ONLY);
+
+        test_command(&*leader, "clear streamed", MESSAGE_DONE);
+        wait_for_command(&*follower, "check streamed anything", MESSAGE_NO_EXIST);
+        assert!(follower_dir.join("filter.streamed").exists());
+
+        test_command(&*leader, "drop replicated", MESSAGE_DONE);
+        wait_for_command(&*follower, "check replicated before", MESSAGE_NO_EXIST);
     }
 
-    // handle connection
-    for stream in acceptor.incoming() {
-        match stream {
-            Ok(stream) => {
-                let server = server.clone();
+    #[test]
+    fn test_replication_during_sync() {
+        let leader_dir : TempDir = TempDir::new("bloomd-test-sync-leader").unwrap();
+        let follower_dir : TempDir = TempDir::new("bloomd-test-sync-follower").unwrap();
 
-                Thread::spawn(move || {
-                    server.handle_client(stream);
-                });
-            },
-            Err(e) => { println!("failed to connect to incoming client: {}", e) }
+        // Mapped bitmaps change on disk the moment a key is set, so a copy read in late would hold the key
+        let mut leader_config : BloomConfig = BloomConfig::default();
+        leader_config.data_dir = String::from_str(leader_dir.path().as_str().unwrap());
+        leader_config.use_mmap = true;
+
+        let leader : Arc<BloomServer> = Arc::new(BloomServer::new(leader_config));
+        let (acceptor, leader_address) : (TcpAcceptor, String) = bind_ephemeral();
+
+        {
+            let leader = leader.clone();
+
+            Thread::spawn(move || {
+                accept_clients(leader, acceptor);
+            });
+        }
+
+        for index in (0..4) {
+            test_command(&*leader, format!("create synced{} capacity=1000000", index).as_slice(), MESSAGE_DONE);
+        }
+
+        // Keys keep being set while the follower is sent its copy of every filter
+        let setter = {
+            let leader = leader.clone();
+
+            Thread::scoped(move || {
+                for key in (0..1000) {
+                    for index in (0..4) {
+                        test_command(&*leader, format!("set synced{} key{}", index, key).as_slice(), "1");
+                    }
+                }
+            })
         };
+
+        let mut follower_config : BloomConfig = BloomConfig::default();
+        follower_config.data_dir = String::from_str(follower_dir.path().as_str().unwrap());
+        follower_config.replicate_from = leader_address.clone();
+
+        let follower : Arc<BloomServer> = Arc::new(BloomServer::new(follower_config));
+
+        {
+            let follower = follower.clone();
+
+            Thread::spawn(move || {
+                follow_leader(follower, leader_address);
+            });
+        }
+
+        setter.join();
+
+        // Each key was set once, in the copy or replayed after it but never both, so the layers match the leader's
+        for index in (0..4) {
+            let filter_name : String = format!("synced{}", index);
+
+            wait_for_command(&*follower, format!("check {} key999", filter_name).as_slice(), "1");
+            test_command(&*follower, format!("multi {} key0 key500 key999", filter_name).as_slice(), "1 1 1");
+
+            let follower_sizes : Option<(u64, Vec<u64>)> = follower.use_filter(&filter_name, |filter| (filter.config.size, filter.config.filter_sizes.clone()));
+            let leader_sizes : Option<(u64, Vec<u64>)> = leader.use_filter(&filter_name, |filter| (filter.config.size, filter.config.filter_sizes.clone()));
+            assert_eq!(follower_sizes, leader_sizes);
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{BloomServer, MESSAGE_NO_EXIST, MESSAGE_EXISTS, MESSAGE_DONE, MESSAGE_BAD_ARGS, MESSAGE_NOT_IMPLEMENTED};
-    use config::{BloomConfig};
+    #[test]
+    fn test_replication_backlog() {
+        let replicas : Replicas = Replicas::new();
+        let (sender, receiver) : (SyncSender<ReplicationEvent>, Receiver<ReplicationEvent>) = sync_channel(1);
+
+        replicas.add(sender);
+        replicas.broadcast(ReplicationEvent::Command(String::from_str("set replicated one")));
+        assert_eq!(replicas.count(), 1);
+
+        // A follower whose queue is full is dropped, and sent every filter again when it reconnects
+        replicas.broadcast(ReplicationEvent::Command(String::from_str("set replicated two")));
+        assert_eq!(replicas.count(), 0);
+
+        // What was queued before it fell behind is still written out
+        assert_eq!(receiver.iter().count(), 1);
+    }
 
     #[test]
-    fn test_server () {
-        // Start by making a default server instance
+    fn test_sharding() {
+        // Each server is a process of its own, with its own port and data_dir, as if run on its own machine
+        let servers : Vec<ServerProcess> = (0..3).map(|index| {
+            let data_dir : Path = Path::new(format!("/tmp/bloomd-test-shard-{}", index));
+
+            if data_dir.exists() {
+                fs::rmdir_recursive(&data_dir).unwrap();
+            }
+
+            return ServerProcess::start(&data_dir);
+        }).collect();
+
+        let addresses : Vec<&str> = servers.iter().map(|server| server.address.as_slice()).collect();
+
+        match ShardedClient::new(&[]) {
+            Err(ClientError::NoServers) => { },
+            _ => { panic!("created a sharded client without any servers") }
+        }
+
+        let client : ShardedClient = ShardedClient::new(&addresses[..2]).unwrap();
+        let names : Vec<String> = (0..20).map(|i| format!("shard{}", i)).collect();
+        let memory_names : Vec<String> = (0..10).map(|i| format!("shard_memory{}", i)).collect();
+
+        for name in names.iter() {
+            assert!(client.create(name.as_slice(), &[]).unwrap());
+            assert_eq!(client.set(name.as_slice(), "key").unwrap(), 1);
+        }
+
+        // Each filter lives only on the server the ring maps it to
+        for name in names.iter() {
+            let node : String = client.node_for(name.as_slice());
+
+            for address in addresses[..2].iter() {
+                assert_eq!(Client::new(*address).check(name.as_slice(), "key").is_ok(), *address == node.as_slice());
+            }
+        }
+
+        // Listing gathers the filters from every server
+        let listed : Vec<String> = client.list(Some("shard")).unwrap().into_iter().map(|listing| listing.name).collect();
+        let mut sorted : Vec<String> = names.clone();
+        sorted.sort();
+        assert_eq!(listed, sorted);
+
+        // In-memory filters have no files to export
+        for name in memory_names.iter() {
+            assert!(client.create(name.as_slice(), &["in_memory=1", "partitions=2"]).unwrap());
+        }
+
+        let memory_node : String = client.node_for(memory_names[0].as_slice());
+        match Client::new(memory_node.as_slice()).export(memory_names[0].as_slice()) {
+            Err(ClientError::Client(_)) => { },
+            _ => { panic!("exported an in-memory filter") }
+        }
+
+        // Adding a server moves over just the filters that now map to it, keys and all
+        let moved : Vec<String> = client.add_node(addresses[2]).unwrap();
+        let moved_files : Vec<&String> = moved.iter().filter(|name| !memory_names.contains(*name)).collect();
+        assert!(!moved_files.is_empty() && moved_files.len() < names.len());
+
+        for name in names.iter() {
+            let node : String = client.node_for(name.as_slice());
+            assert_eq!(moved.contains(name), node.as_slice() == addresses[2]);
+
+            for address in addresses.iter() {
+                assert_eq!(Client::new(*address).check(name.as_slice(), "key").is_ok(), *address == node.as_slice());
+            }
+
+            assert_eq!(client.check(name.as_slice(), "key").unwrap(), 1);
+        }
+
+        // In-memory filters are created afresh wherever they now map to, partitions and all
+        for name in memory_names.iter() {
+            let info : FilterInfo = client.info(name.as_slice()).unwrap();
+            assert!(info.in_memory);
+            assert_eq!(info.partitions, Some(2));
+        }
+
+        let mut all_sorted : Vec<String> = names.clone();
+        all_sorted.extend(memory_names.iter().map(|name| name.clone()));
+        all_sorted.sort();
+
+        let listed : Vec<String> = client.list(Some("shard")).unwrap().into_iter().map(|listing| listing.name).collect();
+        assert_eq!(listed, all_sorted);
+
+        // Nothing is left to move the second time around
+        assert!(client.add_node(addresses[2]).unwrap().is_empty());
+        assert_eq!(client.nodes().len(), 3);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        // An in-flight request holds up the shutdown until the timeout
+        let server : Arc<BloomServer> = Arc::new(BloomServer::new(BloomConfig::default()));
+        assert!(server.begin_request());
+        assert!(!shutdown(server.clone(), Duration::milliseconds(100)));
+
+        // No new requests are served once shutting down
+        assert!(!server.begin_request());
+
+        // Once the request is done, filters get flushed
+        server.end_request();
+        assert!(shutdown(server.clone(), Duration::seconds(5)));
+
+        // An aborted flush doesn't start on any more filters
         let server : BloomServer = BloomServer::new(BloomConfig::default());
-        
-        // Test create, including when filter already exists
-        test_command(&server, "create filter", MESSAGE_DONE);
-        test_command(&server, "create filter", MESSAGE_EXISTS);
-        
-        // Test set (and check)
-        test_command(&server, "check filter first", "0");
-        test_command(&server, "set filter first", "1");
-        test_command(&server, "c filter first", "1");
-        test_command(&server, "s filter first", "2");
-        test_command(&server, "c filter first", "2");
-        test_command(&server, "s filter first", "3");
-        test_command(&server, "c filter first", "3");
-        
-        // Test bad entries for set and check
-        test_command(&server, "set filetr first", MESSAGE_NO_EXIST);
-        test_command(&server, "check filetr first", MESSAGE_NO_EXIST);
-        test_command(&server, "set filter first second", MESSAGE_BAD_ARGS);
-        test_command(&server, "set filter first second", MESSAGE_BAD_ARGS);
-        test_command(&server, "check filter", MESSAGE_BAD_ARGS);
-        test_command(&server, "set filter", MESSAGE_BAD_ARGS);
-        
-        // Test multi and bulk
-        test_command(&server, "multi filter first second third", "3 0 0");
-        test_command(&server, "bulk filter first second third", "4 1 1");
-        test_command(&server, "b filter first second third", "5 2 2");
-        test_command(&server, "m filter first second third", "5 2 2");
-        
-        // Test bad entries for multi and bulk
-        test_command(&server, "bulk filetr first second third", MESSAGE_NO_EXIST);
-        test_command(&server, "multi filetr first second third", MESSAGE_NO_EXIST);
-        test_command(&server, "check filter", MESSAGE_BAD_ARGS);
-        test_command(&server, "set filter", MESSAGE_BAD_ARGS);
-        
-        // Test list
-        test_command(&server, "list fake_prefix", "START\r\nEND");
-        test_command(&server, "list", "START\r\nfilter 0.0001 239627 100000 3\r\nEND");
-        
-        // Test info
-        let info_results : &str = "START\r\ncapacity 100000\r\nchecks 10\r\ncheck_hits 7\r\ncheck_misses 3\r\npage_ins 0\r\npage_outs 0\r\nprobability 0.0001\r\nsets 9\r\nset_hits 6\r\nset_misses 3\r\nsize 3\r\nstorage 239627\r\nEND";
-        test_command(&server, "info", MESSAGE_BAD_ARGS);
-        test_command(&server, "info filetr", MESSAGE_NO_EXIST);
-        test_command(&server, "info filter", info_results);
-        
-        // Test nonexistent commands
-        test_command(&server, "infor filter", MESSAGE_NOT_IMPLEMENTED);
-        test_command(&server, "sette filter first", MESSAGE_NOT_IMPLEMENTED);
-        
-        // Test flush, close, and clear
-        test_command(&server, "flush", MESSAGE_DONE);
-        test_command(&server, "flush filter", MESSAGE_DONE);
-        
-        test_command(&server, "close", MESSAGE_BAD_ARGS);
-        test_command(&server, "close filter", MESSAGE_DONE);
-        test_command(&server, "create filter", MESSAGE_EXISTS);
-        
-        test_command(&server, "clear filter", MESSAGE_DONE);
-        test_command(&server, "create filter", MESSAGE_DONE);
-        test_command(&server, "m filter first second third", "5 2 2");
-        
-        // Clean up to prevent persistence affecting future tests, and test drop
-        test_command(&server, "drop", MESSAGE_BAD_ARGS);
-        test_command(&server, "drop filter", MESSAGE_DONE);
-        test_command(&server, "drop filter", MESSAGE_NO_EXIST);
+        test_command(&server, "create shutdown_filter in_memory=1", MESSAGE_DONE);
+        test_command(&server, "set shutdown_filter foo", "1");
+
+        server.abort_flushes();
+        server.flush_filters().unwrap();
+        assert!(server.use_filter(&String::from_str("shutdown_filter"), |filter| filter.is_dirty()).unwrap());
+
+        test_command(&server, "drop shutdown_filter", MESSAGE_DONE);
     }
-    
+
+    #[test]
+    fn test_metrics() {
+        let server : Arc<BloomServer> = Arc::new(BloomServer::new(BloomConfig::default()));
+
+        test_command(&*server, "create metrics_filter", MESSAGE_DONE);
+        test_command(&*server, "set metrics_filter first", "1");
+        test_command(&*server, "check metrics_filter first", "1");
+        test_command(&*server, "check metrics_filter second", "0");
+        server.flush_filters().unwrap();
+
+        let (acceptor, address) : (TcpAcceptor, String) = bind_ephemeral();
+
+        {
+            let server = server.clone();
+
+            Thread::spawn(move || {
+                accept_metrics_clients(server, acceptor);
+            });
+        }
+
+        // The metrics page holds the per-filter counters and the server-wide measurements
+        let mut stream = BufferedStream::new(TcpStream::connect(address.as_slice()).unwrap());
+        stream.write_str("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        stream.flush().unwrap();
+
+        let response : String = stream.read_to_string().unwrap();
+
+        assert!(response.as_slice().starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.as_slice().contains("bloomd_filter_checks_total{filter=\"metrics_filter\"} 2\n"));
+        assert!(response.as_slice().contains("bloomd_filter_check_hits_total{filter=\"metrics_filter\"} 1\n"));
+        assert!(response.as_slice().contains("bloomd_filter_size{filter=\"metrics_filter\"} 1\n"));
+        assert!(response.as_slice().contains("bloomd_filter_resident{filter=\"metrics_filter\"} 1\n"));
+        assert!(response.as_slice().contains("bloomd_flush_duration_seconds_count 1\n"));
+        assert!(response.as_slice().contains("bloomd_connections_total 0\n"));
+
+        // Other pages don't exist
+        let mut stream = BufferedStream::new(TcpStream::connect(address.as_slice()).unwrap());
+        stream.write_str("GET / HTTP/1.1\r\n\r\n").unwrap();
+        stream.flush().unwrap();
+
+        assert!(stream.read_to_string().unwrap().as_slice().starts_with("HTTP/1.0 404 Not Found\r\n"));
+
+        test_command(&*server, "drop metrics_filter", MESSAGE_DONE);
+    }
+
+    #[test]
+    fn test_stats() {
+        let server : BloomServer = BloomServer::new(BloomConfig::default());
+        let mut session : ClientSession = ClientSession::new();
+
+        test_session_command(&server, &mut session, "create stats_filter", MESSAGE_DONE);
+        test_session_command(&server, &mut session, "c stats_filter first", "0");
+        test_session_command(&server, &mut session, "check stats_filter first", "0");
+        test_session_command(&server, &mut session, "check stats_missing first", MESSAGE_NO_EXIST);
+        test_session_command(&server, &mut session, "create stats_filter", MESSAGE_EXISTS);
+        test_session_command(&server, &mut session, "stats now", MESSAGE_BAD_ARGS);
+        server.flush_filters().unwrap();
+
+        let stats : String = server.handle_request(&mut session, "stats");
+
+        assert!(stats.as_slice().starts_with(MESSAGE_START));
+        assert!(stats.as_slice().contains("\r\nfilters 1\r\nresident_filters 1\r\n"));
+        assert!(!stats.as_slice().contains("\r\nlast_flush 0\r\n"));
+        assert!(stats.as_slice().contains("\r\ncmd_check 3\r\n"));
+        assert!(stats.as_slice().contains("\r\ncmd_create 2\r\n"));
+        assert!(stats.as_slice().contains("\r\nerrors_bad_args 1\r\nerrors_no_exist 1\r\n"));
+        assert!(!stats.as_slice().contains("errors_exists"));
+
+        test_session_command(&server, &mut session, "drop stats_filter", MESSAGE_DONE);
+    }
+
+    #[test]
+    fn test_slowlog() {
+        let mut config : BloomConfig = BloomConfig::default();
+        config.slowlog_threshold = 0;
+        config.slowlog_max_len = 3;
+
+        let server : BloomServer = BloomServer::new(config);
+        let mut session : ClientSession = ClientSession::from_address(String::from_str("127.0.0.1:50000"));
+
+        // With no threshold, every command is slow
+        test_session_command(&server, &mut session, "create slow_filter", MESSAGE_DONE);
+        test_session_command(&server, &mut session, "bulk slow_filter a b c", "1 1 1");
+        test_session_command(&server, &mut session, "list", "START\r\nslow_filter 0.0001 239627 100000 3 -\r\nEND");
+
+        let slowlog : String = server.handle_request(&mut session, "slowlog 2");
+        let lines : Vec<&str> = slowlog.as_slice().split_str("\r\n").collect();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("2 "));
+        assert!(lines[1].ends_with(" list - 0 127.0.0.1:50000"));
+        assert!(lines[2].starts_with("1 "));
+        assert!(lines[2].ends_with(" bulk slow_filter 4 127.0.0.1:50000"));
+
+        // Only the most recent entries are kept
+        assert_eq!(server.slowlog.lock().unwrap().len(), 3);
+
+        test_session_command(&server, &mut session, "slowlog ten", MESSAGE_BAD_ARGS);
+        test_session_command(&server, &mut session, "slowlog reset", MESSAGE_DONE);
+        assert!(server.handle_request(&mut session, "slowlog").as_slice().starts_with("START\r\n5 1"));
+
+        test_session_command(&server, &mut session, "drop slow_filter", MESSAGE_DONE);
+    }
+
+    #[test]
+    fn test_server_errors() {
+        let server : BloomServer = BloomServer::new(BloomConfig::default());
+
+        // Bad parameters are reported instead of taking the server down
+        test_command(&server, "create error_filter capacity=0", MESSAGE_BAD_ARGS);
+
+        // A filter whose directory went missing can't be flushed, but stays usable
+        test_command(&server, "create error_filter", MESSAGE_DONE);
+        test_command(&server, "set error_filter first", "1");
+        fs::rmdir_recursive(&Path::new("/tmp/bloomd/filter.error_filter")).unwrap();
+
+        assert!(server.interpret_request("flush error_filter").as_slice().starts_with(MESSAGE_SERVER_ERROR));
+        assert!(server.interpret_request("flush").as_slice().starts_with(MESSAGE_SERVER_ERROR));
+        test_command(&server, "check error_filter first", "1");
+
+        // Adding a layer fails too, without poisoning the filter's lock
+        assert!(server.interpret_request("set error_filter first").as_slice().starts_with(MESSAGE_SERVER_ERROR));
+        test_command(&server, "check error_filter first", "1");
+
+        test_command(&server, "drop error_filter", MESSAGE_DONE);
+    }
+
+    #[test]
+    fn test_reload() {
+        let filename : &str = "/tmp/bloomd-test-reload.ini";
+        File::create(&Path::new(filename)).write_str("[bloomd]\nflush_interval=60\ntcp_port=8673\n").unwrap();
+
+        let mut server : BloomServer = BloomServer::new(BloomConfig::from_filename(filename).unwrap());
+        test_command(&server, "reload", MESSAGE_NO_CONFIG_FILE);
+
+        server.config_filename = Some(String::from_str(filename));
+        test_command(&server, "reload", MESSAGE_DONE);
+        test_command(&server, "reload now", MESSAGE_BAD_ARGS);
+
+        // Live settings are applied, structural ones are reported
+        File::create(&Path::new(filename)).write_str("[bloomd]\nflush_interval=30\ntcp_port=9000\n").unwrap();
+        test_command(&server, "reload", format!("{} tcp_port", MESSAGE_RESTART_NEEDED).as_slice());
+        assert_eq!(server.config().flush_interval, 30);
+        assert_eq!(server.config().tcp_port, 8673);
+
+        // An invalid file leaves the running config alone
+        File::create(&Path::new(filename)).write_str("[bloomd]\nflush_interval=6o\n").unwrap();
+        test_command(&server, "reload", format!("{} line 2: flush_interval: expected an integer, got \"6o\"", MESSAGE_INVALID_CONFIG).as_slice());
+        assert_eq!(server.config().flush_interval, 30);
+
+        fs::unlink(&Path::new(filename)).unwrap();
+    }
+
+    // a bloomd server run as a process of its own; it's killed once dropped
+    struct ServerProcess {
+        process : Process,
+        address : String
+    }
+
+    impl ServerProcess {
+        // starts the bloomd binary built alongside the tests on a free port, and waits until it takes connections
+        // the port is found by binding to port 0 and handed over to the server, so another process can take it
+        // in between; the server then fails to start, and is started again on another port
+        fn start(data_dir : &Path) -> ServerProcess {
+            for _ in (0..5) {
+                match ServerProcess::try_start(data_dir) {
+                    Some(server) => { return server },
+                    None => { }
+                }
+            }
+
+            panic!("bloomd did not start listening after 5 attempts");
+        }
+
+        // starts the server on a port that was free a moment ago
+        // returns None if the server exits, or doesn't take connections in time
+        fn try_start(data_dir : &Path) -> Option<ServerProcess> {
+            let port : u16 = TcpListener::bind("127.0.0.1:0").unwrap().socket_name().unwrap().port;
+            let address : String = format!("127.0.0.1:{}", port);
+
+            let process : Process = Command::new(os::self_exe_path().unwrap().join("bloomd"))
+                .arg("--bind_address=127.0.0.1")
+                .arg(format!("--tcp_port={}", port))
+                .arg(format!("--data_dir={}", data_dir.display()))
+                .stdin(StdioContainer::Ignored)
+                .stdout(StdioContainer::Ignored)
+                .stderr(StdioContainer::Ignored)
+                .spawn()
+                .unwrap();
+
+            let mut server : ServerProcess = ServerProcess { process: process, address: address };
+
+            for _ in (0..100) {
+                if server.has_exited() {
+                    return None;
+                }
+
+                // whatever took the port takes connections too, so the server has to still be running after one
+                if TcpStream::connect(server.address.as_slice()).is_ok() && !server.has_exited() {
+                    return Some(server);
+                }
+
+                timer::sleep(Duration::milliseconds(50));
+            }
+
+            return None;
+        }
+
+        // returns true if the server process has exited, without waiting for it
+        fn has_exited(&mut self) -> bool {
+            self.process.set_timeout(Some(0));
+            let exited : bool = self.process.wait().is_ok();
+            self.process.set_timeout(None);
+
+            return exited;
+        }
+    }
+
+    impl Drop for ServerProcess {
+        fn drop(&mut self) {
+            let _ = self.process.signal_kill();
+        }
+    }
+
+    // binds a listener to a free port on localhost; returns the acceptor and the address it's bound to
+    fn bind_ephemeral() -> (TcpAcceptor, String) {
+        let mut listener : TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address : String = listener.socket_name().unwrap().to_string();
+
+        return (listener.listen().unwrap(), address);
+    }
+
+    fn test_session_command(server : &BloomServer, session : &mut ClientSession, command : &str, result : &str) {
+        assert_eq!(server.handle_request(session, command).as_slice(),
+                   result);
+    }
+
+    // retries the command until it gets the expected response, for changes a follower makes in the background
+    fn wait_for_command(server : &BloomServer, command : &str, result : &str) {
+        for _ in (0..100) {
+            if server.interpret_request(command).as_slice() == result {
+                return;
+            }
+
+            timer::sleep(Duration::milliseconds(20));
+        }
+
+        test_command(server, command, result);
+    }
+
     fn test_command(server : &BloomServer, command : &str, result : &str) {
         assert_eq!(server.interpret_request(command).as_slice(),
                    result);
@@ -0,0 +1,372 @@
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::io::{BufferedStream, IoResult, Stream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use bloomd::now;
+use bloomd::wrappers::BloomFilter;
+
+// constants -------------------------------------------------------------------
+const METRICS_PATH         : &'static str = "/metrics";
+const CONTENT_TYPE         : &'static str = "text/plain; version=0.0.4";
+const AUTHORIZATION        : &'static str = "authorization:";
+const BEARER               : &'static str = "Bearer ";
+
+// upper bounds of the latency histogram buckets, in seconds
+static LATENCY_BUCKETS : [f64; 12] = [0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5];
+
+// upper bounds of the flush duration histogram buckets, in seconds
+static FLUSH_BUCKETS : [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+
+// name, type and help text of each per-filter metric, in the order of FilterSample::values
+static FILTER_METRICS : [(&'static str, &'static str, &'static str); 13] = [
+    ("bloomd_filter_checks_total",       "counter", "Keys checked against the filter."),
+    ("bloomd_filter_check_hits_total",   "counter", "Checked keys found in the filter."),
+    ("bloomd_filter_check_misses_total", "counter", "Checked keys not found in the filter."),
+    ("bloomd_filter_sets_total",         "counter", "Keys set in the filter."),
+    ("bloomd_filter_set_hits_total",     "counter", "Set keys that were already in the filter."),
+    ("bloomd_filter_set_misses_total",   "counter", "Set keys that were new to the filter."),
+    ("bloomd_filter_page_ins_total",     "counter", "Times the filter was paged into memory."),
+    ("bloomd_filter_page_outs_total",    "counter", "Times the filter was paged out of memory."),
+    ("bloomd_filter_size",               "gauge",   "Keys in the filter."),
+    ("bloomd_filter_capacity",           "gauge",   "Initial capacity of the filter."),
+    ("bloomd_filter_bytes",              "gauge",   "Bytes configured for the whole filter, across all its layers."),
+    ("bloomd_filter_layers",             "gauge",   "Layers in the filter."),
+    ("bloomd_filter_resident",           "gauge",   "1 if the filter is loaded in memory, 0 if it is paged out.")
+];
+// -----------------------------------------------------------------------------
+
+// A cumulative histogram, as exposed to Prometheus
+#[derive(Clone)]
+pub struct Histogram {
+    bounds : &'static [f64], // Upper bounds of the buckets
+    counts : Vec<u64>,       // Observations falling into each bucket
+    sum    : f64,            // Sum of all observations
+    count  : u64             // Number of observations
+}
+
+impl Histogram {
+    // Returns an empty histogram with the given bucket upper bounds
+    pub fn new(bounds : &'static [f64]) -> Self {
+        return Histogram { bounds: bounds, counts: (0..bounds.len()).map(|_| 0).collect(), sum: 0.0, count: 0 };
+    }
+
+    // Records a single observation
+    pub fn observe(&mut self, value : f64) {
+        match self.bounds.iter().position(|bound| value <= *bound) {
+            Some(index) => { self.counts[index] += 1 },
+            None => { }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+
+    // Returns the number of observations
+    pub fn count(&self) -> u64 {
+        return self.count;
+    }
+
+    // Returns the sum of all observations
+    pub fn sum(&self) -> f64 {
+        return self.sum;
+    }
+
+    // Appends the _bucket, _sum and _count samples of the histogram to the output
+    pub fn write(&self, out : &mut String, name : &str, labels : &[(&str, &str)]) {
+        let mut cumulative : u64 = 0;
+
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            cumulative += *count;
+
+            let bound : String = bound.to_string();
+            let mut bucket_labels : Vec<(&str, &str)> = labels.to_vec();
+            bucket_labels.push(("le", bound.as_slice()));
+
+            write_sample(out, format!("{}_bucket", name).as_slice(), bucket_labels.as_slice(), cumulative as f64);
+        }
+
+        let mut bucket_labels : Vec<(&str, &str)> = labels.to_vec();
+        bucket_labels.push(("le", "+Inf"));
+
+        write_sample(out, format!("{}_bucket", name).as_slice(), bucket_labels.as_slice(), self.count as f64);
+        write_sample(out, format!("{}_sum", name).as_slice(), labels, self.sum);
+        write_sample(out, format!("{}_count", name).as_slice(), labels, self.count as f64);
+    }
+}
+
+// Server-wide measurements, updated as clients are served and filters are flushed
+pub struct ServerMetrics {
+    started_at          : u64,                                  // Unix time the server started at
+    connections_current : AtomicUsize,                          // Clients currently connected
+    connections_total   : AtomicUsize,                          // Clients connected since startup
+    command_latency     : Mutex<HashMap<String, Histogram>>,    // Time spent handling each command
+    errors              : Mutex<HashMap<String, u64>>,          // Error responses sent, by kind
+    flush_duration      : Mutex<Histogram>,                     // Time spent flushing all filters
+    flush_bytes_total   : AtomicUsize,                          // Buffered bitmap bytes written by flushes since startup
+    flush_synced_total  : AtomicUsize,                          // Mapped bitmap bytes synced by flushes since startup
+    last_flush          : Mutex<Option<(u64, f64, u64, u64)>>   // Unix time, duration, bytes written and bytes synced of the last flush
+}
+
+impl ServerMetrics {
+    // Returns a new instance, with nothing measured yet
+    pub fn new() -> Self {
+        return ServerMetrics {
+            started_at: now(),
+            connections_current: AtomicUsize::new(0),
+            connections_total: AtomicUsize::new(0),
+            command_latency: Mutex::new(HashMap::new()),
+            errors: Mutex::new(HashMap::new()),
+            flush_duration: Mutex::new(Histogram::new(&FLUSH_BUCKETS)),
+            flush_bytes_total: AtomicUsize::new(0),
+            flush_synced_total: AtomicUsize::new(0),
+            last_flush: Mutex::new(None)
+        };
+    }
+
+    // Returns the seconds since the server started
+    pub fn uptime(&self) -> u64 {
+        return now() - self.started_at;
+    }
+
+    // Records a newly connected client
+    pub fn connection_opened(&self) {
+        self.connections_current.fetch_add(1, Ordering::SeqCst);
+        self.connections_total.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Records a client disconnecting
+    pub fn connection_closed(&self) {
+        self.connections_current.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // Returns the number of clients currently connected
+    pub fn connections_current(&self) -> usize {
+        return self.connections_current.load(Ordering::SeqCst);
+    }
+
+    // Returns the number of clients connected since startup
+    pub fn connections_total(&self) -> usize {
+        return self.connections_total.load(Ordering::SeqCst);
+    }
+
+    // Records the time, in seconds, it took to handle a command
+    pub fn observe_command(&self, command : &str, seconds : f64) {
+        let mut command_latency = self.command_latency.lock().unwrap();
+
+        if !command_latency.contains_key(command) {
+            command_latency.insert(String::from_str(command), Histogram::new(&LATENCY_BUCKETS));
+        }
+
+        command_latency.get_mut(command).unwrap().observe(seconds);
+    }
+
+    // Returns the number of times each command was handled, sorted by command
+    pub fn command_counts(&self) -> Vec<(String, u64)> {
+        let command_latency = self.command_latency.lock().unwrap();
+
+        let mut counts : Vec<(String, u64)> = command_latency.iter().map(|(command, histogram)| (command.clone(), histogram.count())).collect();
+        counts.sort();
+
+        return counts;
+    }
+
+    // Records an error response of the given kind
+    pub fn record_error(&self, kind : &str) {
+        let mut errors = self.errors.lock().unwrap();
+
+        if !errors.contains_key(kind) {
+            errors.insert(String::from_str(kind), 0);
+        }
+
+        *errors.get_mut(kind).unwrap() += 1;
+    }
+
+    // Returns the number of error responses of each kind, sorted by kind
+    pub fn error_counts(&self) -> Vec<(String, u64)> {
+        let mut counts : Vec<(String, u64)> = self.errors.lock().unwrap().iter().map(|(kind, count)| (kind.clone(), *count)).collect();
+        counts.sort();
+
+        return counts;
+    }
+
+    // Records the time, in seconds, it took to flush all filters, the buffered bitmap bytes written
+    // and the mapped bitmap bytes synced
+    pub fn observe_flush(&self, seconds : f64, written : u64, synced : u64) {
+        self.flush_duration.lock().unwrap().observe(seconds);
+        self.flush_bytes_total.fetch_add(written as usize, Ordering::SeqCst);
+        self.flush_synced_total.fetch_add(synced as usize, Ordering::SeqCst);
+        *self.last_flush.lock().unwrap() = Some((now(), seconds, written, synced));
+    }
+
+    // Returns the unix time, duration in seconds, buffered bitmap bytes written and mapped bitmap bytes
+    // synced of the last flush, if there was one
+    pub fn last_flush(&self) -> Option<(u64, f64, u64, u64)> {
+        return *self.last_flush.lock().unwrap();
+    }
+
+    // Returns a copy of the flush duration histogram
+    pub fn flush_duration(&self) -> Histogram {
+        return self.flush_duration.lock().unwrap().clone();
+    }
+
+    // Appends the server-wide metrics to the output, in Prometheus exposition format
+    pub fn write(&self, out : &mut String) {
+        write_header(out, "bloomd_connections", "gauge", "Clients currently connected.");
+        write_sample(out, "bloomd_connections", &[], self.connections_current() as f64);
+
+        write_header(out, "bloomd_connections_total", "counter", "Clients connected since startup.");
+        write_sample(out, "bloomd_connections_total", &[], self.connections_total() as f64);
+
+        write_header(out, "bloomd_command_duration_seconds", "histogram", "Time spent handling commands.");
+        {
+            let command_latency = self.command_latency.lock().unwrap();
+
+            let mut commands : Vec<&String> = command_latency.keys().collect();
+            commands.sort();
+
+            for command in commands.iter() {
+                command_latency.get(*command).unwrap().write(out, "bloomd_command_duration_seconds", &[("command", command.as_slice())]);
+            }
+        }
+
+        write_header(out, "bloomd_flush_duration_seconds", "histogram", "Time spent flushing all filters to disk.");
+        self.flush_duration.lock().unwrap().write(out, "bloomd_flush_duration_seconds", &[]);
+
+        write_header(out, "bloomd_flush_bytes_total", "counter", "Buffered bitmap bytes written to disk by flushes.");
+        write_sample(out, "bloomd_flush_bytes_total", &[], self.flush_bytes_total.load(Ordering::SeqCst) as f64);
+
+        write_header(out, "bloomd_flush_synced_bytes_total", "counter", "Mapped bitmap bytes synced by flushes, an upper bound on the bytes written.");
+        write_sample(out, "bloomd_flush_synced_bytes_total", &[], self.flush_synced_total.load(Ordering::SeqCst) as f64);
+    }
+}
+
+// The per-filter values exposed as metrics, taken while the filter is locked
+pub struct FilterSample {
+    name   : String,  // Filter name
+    values : Vec<f64> // Values of the FILTER_METRICS, in order
+}
+
+impl FilterSample {
+    // Takes the current values from the given filter
+    pub fn new(name : &str, filter : &BloomFilter) -> Self {
+        return FilterSample {
+            name: String::from_str(name),
+            values: vec![
+                filter.counters.checks() as f64,
+                filter.counters.check_hits as f64,
+                filter.counters.check_misses as f64,
+                filter.counters.sets() as f64,
+                filter.counters.set_hits as f64,
+                filter.counters.set_misses as f64,
+                filter.counters.page_ins as f64,
+                filter.counters.page_outs as f64,
+                filter.config.size as f64,
+                filter.config.capacity as f64,
+                filter.config.bytes as f64,
+                filter.layer_count() as f64,
+                if filter.is_resident() { 1.0 } else { 0.0 }]
+        };
+    }
+}
+
+// Appends the per-filter metrics to the output, grouping the samples of each metric under one header
+pub fn write_filter_samples(out : &mut String, samples : &[FilterSample]) {
+    for (index, &(name, metric_type, help)) in FILTER_METRICS.iter().enumerate() {
+        write_header(out, name, metric_type, help);
+
+        for sample in samples.iter() {
+            write_sample(out, name, &[("filter", sample.name.as_slice())], sample.values[index]);
+        }
+    }
+}
+
+// Appends the HELP and TYPE lines of a metric to the output
+pub fn write_header(out : &mut String, name : &str, metric_type : &str, help : &str) {
+    out.push_str(format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, metric_type).as_slice());
+}
+
+// Appends a single sample line to the output
+pub fn write_sample(out : &mut String, name : &str, labels : &[(&str, &str)], value : f64) {
+    out.push_str(name);
+
+    if !labels.is_empty() {
+        let pairs : Vec<String> = labels.iter().map(|&(label, value)| format!("{}=\"{}\"", label, escape_label(value))).collect();
+        out.push_str(format!("{{{}}}", pairs.connect(",")).as_slice());
+    }
+
+    out.push_str(format!(" {}\n", value).as_slice());
+}
+
+// escapes a label value as required by the exposition format
+fn escape_label(value : &str) -> String {
+    return value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n");
+}
+
+// Serves a single HTTP request for the metrics page, rendering the body only for GET /metrics.
+// The bearer token from the Authorization header, if any, is passed to authorize first.
+pub fn serve_http<S : Stream, A : Fn(Option<&str>) -> bool, F : Fn() -> String>(stream : S, authorize : A, render : F) -> IoResult<()> {
+    let mut buf_stream : BufferedStream<S> = BufferedStream::new(stream);
+
+    // the request line and the Authorization header are all we care about; other headers are ignored
+    let request_line : String = try!(buf_stream.read_line());
+    let mut token : Option<String> = None;
+
+    loop {
+        let header : String = try!(buf_stream.read_line());
+        let header : &str = header.as_slice().trim();
+
+        if header.is_empty() {
+            break;
+        }
+
+        if header.to_ascii_lowercase().as_slice().starts_with(AUTHORIZATION) {
+            let value : &str = header.slice_from(AUTHORIZATION.len()).trim();
+
+            if value.starts_with(BEARER) {
+                token = Some(String::from_str(value.slice_from(BEARER.len()).trim()));
+            }
+        }
+    }
+
+    let parts : Vec<&str> = request_line.as_slice().split(' ').collect();
+
+    let (status, body) : (&str, String) = if parts.len() < 2 || parts[0] != "GET" {
+        ("405 Method Not Allowed", String::new())
+    } else if parts[1] != METRICS_PATH {
+        ("404 Not Found", String::new())
+    } else if !authorize(token.as_ref().map(|token| token.as_slice())) {
+        ("401 Unauthorized", String::new())
+    } else {
+        ("200 OK", render())
+    };
+
+    try!(buf_stream.write_str(format!("HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                                      status, CONTENT_TYPE, body.len()).as_slice()));
+    try!(buf_stream.write_str(body.as_slice()));
+
+    return buf_stream.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Histogram, LATENCY_BUCKETS};
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram : Histogram = Histogram::new(&LATENCY_BUCKETS);
+        histogram.observe(0.0002);
+        histogram.observe(0.002);
+        histogram.observe(2.0);
+
+        let mut out : String = String::new();
+        histogram.write(&mut out, "latency", &[("command", "check")]);
+
+        assert!(out.as_slice().contains("latency_bucket{command=\"check\",le=\"0.0001\"} 0\n"));
+        assert!(out.as_slice().contains("latency_bucket{command=\"check\",le=\"0.00025\"} 1\n"));
+        assert!(out.as_slice().contains("latency_bucket{command=\"check\",le=\"0.5\"} 2\n"));
+        assert!(out.as_slice().contains("latency_bucket{command=\"check\",le=\"+Inf\"} 3\n"));
+        assert!(out.as_slice().contains("latency_count{command=\"check\"} 3\n"));
+        assert_eq!(histogram.count(), 3);
+    }
+}
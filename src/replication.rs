@@ -0,0 +1,155 @@
+use bloomd::{FilterSnapshot, protocol_error, read_line};
+use std::io::{BufferedStream, IoResult, TcpStream};
+use std::sync::Mutex;
+use std::sync::mpsc::{SyncSender, TrySendError};
+
+// constants -------------------------------------------------------------------
+const REQUEST_AUTH      : &'static str = "auth";
+const REQUEST_REPLICATE : &'static str = "replicate";
+const RESPONSE_DONE     : &'static str = "Done";
+const SYNC_START        : &'static str = "START";
+const SYNC_END          : &'static str = "END";
+// -----------------------------------------------------------------------------
+
+// A change a leader streams to its followers
+#[derive(Clone)]
+pub enum ReplicationEvent {
+    Command(String),          // A request for the follower to replay, as a client would send it
+    Snapshot(FilterSnapshot), // A filter to replace as a whole, for changes that can't be replayed key by key
+    Snapshotted(String)       // Marks where the initial snapshot of the named filter was taken; never sent
+}
+
+impl ReplicationEvent {
+    // Returns the name of the filter the event changes
+    pub fn filter_name(&self) -> Option<&str> {
+        return match *self {
+            ReplicationEvent::Command(ref command) => command.as_slice().split(' ').nth(1),
+            ReplicationEvent::Snapshot(ref snapshot) => Some(snapshot.name.as_slice()),
+            ReplicationEvent::Snapshotted(ref filter_name) => Some(filter_name.as_slice())
+        };
+    }
+
+    // Writes the event to a follower's stream
+    pub fn write_to<W : Writer>(&self, writer : &mut W) -> IoResult<()> {
+        return match *self {
+            ReplicationEvent::Command(ref command) => writer.write_str(format!("{}\r\n", command).as_slice()),
+            ReplicationEvent::Snapshot(ref snapshot) => snapshot.write_to(writer),
+            ReplicationEvent::Snapshotted(_) => Ok(())
+        };
+    }
+}
+
+// A change as a follower reads it in from its leader
+pub enum LeaderEvent {
+    Command(String),         // A request to replay
+    Snapshot(FilterSnapshot) // A filter to put in place of the follower's copy
+}
+
+// The followers connected to a leader, each fed the changes through its own bounded channel
+pub struct Replicas {
+    senders : Mutex<Vec<SyncSender<ReplicationEvent>>>
+}
+
+impl Replicas {
+    // Returns an instance without any followers
+    pub fn new() -> Self {
+        return Replicas { senders: Mutex::new(Vec::new()) };
+    }
+
+    // Registers a follower, which is sent every change from now on
+    pub fn add(&self, sender : SyncSender<ReplicationEvent>) {
+        self.senders.lock().unwrap().push(sender);
+    }
+
+    // Returns the number of followers connected
+    pub fn count(&self) -> usize {
+        return self.senders.lock().unwrap().len();
+    }
+
+    // Returns true if there are any followers to send changes to
+    pub fn is_active(&self) -> bool {
+        return self.count() > 0;
+    }
+
+    // Sends the event to every follower, forgetting the ones that have gone away
+    // A follower whose channel is full has fallen too far behind to catch up, so it's forgotten too: its stream
+    // ends once the changes already queued are written, and it's sent a full copy again when it reconnects
+    pub fn broadcast(&self, event : ReplicationEvent) {
+        self.senders.lock().unwrap().retain(|sender| {
+            return match sender.try_send(event.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    log_warn!("Dropping a follower that fell behind; it is sent every filter again once it reconnects");
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false
+            };
+        });
+    }
+
+    // Forgets every follower; their streams end once the changes already sent are written
+    pub fn disconnect_all(&self) {
+        self.senders.lock().unwrap().clear();
+    }
+}
+
+// A follower's connection to its leader, carrying the initial snapshot and then the stream of changes
+pub struct LeaderConnection {
+    stream : BufferedStream<TcpStream>
+}
+
+impl LeaderConnection {
+    // Connects to the leader at the given <host>:<port>, authenticating with the token if there is one,
+    // and asks it to replicate to this server
+    pub fn connect(address : &str, token : &str) -> IoResult<Self> {
+        let stream : TcpStream = try!(TcpStream::connect(address));
+        let mut connection : LeaderConnection = LeaderConnection { stream: BufferedStream::new(stream) };
+
+        if !token.is_empty() {
+            let response : String = try!(connection.request(format!("{} {}", REQUEST_AUTH, token).as_slice()));
+
+            if response.as_slice() != RESPONSE_DONE {
+                return Err(protocol_error("leader rejected the replication token", response.as_slice()));
+            }
+        }
+
+        let response : String = try!(connection.request(REQUEST_REPLICATE));
+
+        if response.as_slice() != SYNC_START {
+            return Err(protocol_error("leader refused to replicate", response.as_slice()));
+        }
+
+        return Ok(connection);
+    }
+
+    // Returns the next filter of the initial snapshot, or None once every filter has been sent
+    pub fn next_snapshot(&mut self) -> IoResult<Option<FilterSnapshot>> {
+        let line : String = try!(read_line(&mut self.stream));
+
+        if line.as_slice() == SYNC_END {
+            return Ok(None);
+        }
+
+        return FilterSnapshot::read_from(&mut self.stream, line.as_slice()).map(|snapshot| Some(snapshot));
+    }
+
+    // Waits for the next change the leader streams
+    pub fn next_event(&mut self) -> IoResult<LeaderEvent> {
+        let line : String = try!(read_line(&mut self.stream));
+
+        if FilterSnapshot::is_header(line.as_slice()) {
+            return FilterSnapshot::read_from(&mut self.stream, line.as_slice()).map(|snapshot| LeaderEvent::Snapshot(snapshot));
+        }
+
+        return Ok(LeaderEvent::Command(line));
+    }
+
+    // sends a request and returns the single line response
+    fn request(&mut self, command : &str) -> IoResult<String> {
+        try!(self.stream.write_str(command));
+        try!(self.stream.write_str("\r\n"));
+        try!(self.stream.flush());
+
+        return read_line(&mut self.stream);
+    }
+}
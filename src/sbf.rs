@@ -5,10 +5,11 @@ use self::libc::{c_char, c_int, c_ulong, c_void, malloc, size_t};
 use std::{mem, ffi, ptr};
 use bitmap::bloom_bitmap;
 use bloom::bloom_bloomfilter;
+use error::{BloomError, BloomResult};
 use filter::IBloomFilter;
-use util;
 
 #[repr(C, packed)]
+#[allow(dead_code)]
 pub struct bloom_sbf_params {
     initial_capacity      : u64,
     fp_probability        : f64,
@@ -29,6 +30,7 @@ extern "C" fn default_callback(input : *mut c_void, bytes : c_ulong, out : *mut
 }
     
 #[repr(C)]
+#[allow(dead_code)]
 pub struct bloom_sbf {
     params         : bloom_sbf_params,
     callback       : bloom_sbf_callback,
@@ -69,7 +71,7 @@ impl bloom_sbf {
     pub fn from_filters_with_callback(params         : bloom_sbf_params,
                                       callback       : bloom_sbf_callback,
                                       callback_input : *mut c_void,
-                                      filters        : Vec<bloom_bloomfilter>) -> Result<Self, String> {
+                                      filters        : Vec<bloom_bloomfilter>) -> BloomResult<Self> {
         let mut dirty_filters : Vec<u8> = Vec::new();
         let mut capacities : Vec<u64> = Vec::new();
 
@@ -83,13 +85,13 @@ impl bloom_sbf {
         let value : i32 = unsafe { externals::sbf_from_filters(&mut sbf.params as *mut bloom_sbf_params, callback, callback_input, sbf.num_filters, sbf.filters.as_mut_slice() as *mut [bloom_bloomfilter], &mut sbf as *mut bloom_sbf) };
 
         if value < 0 {
-            return util::strerror(value);
+            return Err(BloomError::from_code(value, "building a scalable filter from its layers"));
         }
         
         return Ok(sbf);
     }
 
-    pub fn from_filters(params : bloom_sbf_params, filters : Vec<bloom_bloomfilter>) -> Result<Self, String> {
+    pub fn from_filters(params : bloom_sbf_params, filters : Vec<bloom_bloomfilter>) -> BloomResult<Self> {
         return bloom_sbf::from_filters_with_callback(params, default_callback, ptr::null_mut(), filters);
     }
 
@@ -103,25 +105,25 @@ impl bloom_sbf {
 }
 
 impl IBloomFilter<bool> for bloom_sbf {   
-    fn add(&mut self, key : String) -> Result<bool, String> {
+    fn add(&mut self, key : String) -> BloomResult<bool> {
         let key : ffi::CString = ffi::CString::from_slice(key.as_slice().as_bytes());
 
         let result : i32 = unsafe { externals::sbf_add(self as *mut bloom_sbf, key.as_ptr()) };
 
         if result < 0 {
-            return util::strerror(result);
+            return Err(BloomError::from_code(result, "adding a key to the scalable filter"));
         }
 
         return Ok(result > 0);
     }
 
-    fn contains(&self, key : &String) -> Result<bool, String> {
+    fn contains(&self, key : &String) -> BloomResult<bool> {
         let key : ffi::CString = ffi::CString::from_slice(key.as_slice().as_bytes());
 
         let result : i32 = unsafe { externals::sbf_contains(self as *const bloom_sbf, key.as_ptr()) };
 
         if result < 0 {
-            return util::strerror(result);
+            return Err(BloomError::from_code(result, "checking a key against the scalable filter"));
         }
 
         return Ok(result > 0);
@@ -131,11 +133,11 @@ impl IBloomFilter<bool> for bloom_sbf {
         return unsafe { externals::sbf_size(self as *const bloom_sbf) };
     }
 
-    fn flush(&mut self) -> Result<(), String> {
-        let value : u32 = unsafe { externals::sbf_flush(self as *mut bloom_sbf) };
+    fn flush(&mut self) -> BloomResult<()> {
+        let value : i32 = unsafe { externals::sbf_flush(self as *mut bloom_sbf) };
 
         if value < 0 {
-            return util::strerror(value);
+            return Err(BloomError::from_code(value, "flushing the layers of the scalable filter"));
         }
 
         return Ok(());
@@ -148,6 +150,8 @@ impl Drop for bloom_sbf {
     }
 }
 
+// the whole of the C interface, not all of which is called from Rust
+#[allow(dead_code)]
 mod externals {
     use super::libc::{c_char, c_double, c_int, c_uint, c_ulong, c_void};
     use bloom::bloom_bloomfilter;
@@ -178,6 +182,7 @@ mod externals {
 mod tests {
     use std::ptr;
     use super::{bloom_sbf, bloom_sbf_params};
+    use bitmap::IoMode;
     use bloom::{bloom_bloomfilter, bloom_filter_params, create_bloom_filter};
     use filter;
 
@@ -189,7 +194,7 @@ mod tests {
         let mut filters : Vec<bloom_bloomfilter> = Vec::new();
 
         for i in (0..3) {
-            filters.push(create_bloom_filter(&bf_params, format!("/tmp/sbf-map{}.bmp", i).as_slice()));
+            filters.push(create_bloom_filter(&bf_params, format!("/tmp/sbf-map{}.bmp", i).as_slice(), false, IoMode::Buffered).unwrap());
         }
 
         let filter : bloom_sbf = bloom_sbf::from_filters(sbf_params, filters);
@@ -0,0 +1,437 @@
+use client::{Client, ClientError, ClientResult, FilterInfo, FilterListing};
+use snapshot::FilterSnapshot;
+use util;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+// constants -------------------------------------------------------------------
+const POINTS_PER_NODE : usize = 160;
+// -----------------------------------------------------------------------------
+
+// A consistent hash ring mapping filter names to nodes; adding a node only moves the
+// filters that map to it, each node being placed at many points to even out the load
+#[derive(Clone)]
+pub struct HashRing {
+    nodes  : Vec<String>,      // Addresses of the nodes, in the order they were added
+    points : Vec<(u64, usize)> // Points on the ring, in order, with the index of the node each belongs to
+}
+
+impl HashRing {
+    // Returns a ring without any nodes
+    pub fn new() -> Self {
+        return HashRing { nodes: Vec::new(), points: Vec::new() };
+    }
+
+    // Places a node on the ring; returns false if it was already on it
+    pub fn add_node(&mut self, node : &str) -> bool {
+        if self.contains_node(node) {
+            return false;
+        }
+
+        let index : usize = self.nodes.len();
+        self.nodes.push(String::from_str(node));
+
+        for point in (0..POINTS_PER_NODE) {
+            self.points.push((util::stable_hash(format!("{}#{}", node, point).as_slice()), index));
+        }
+
+        self.points.sort();
+
+        return true;
+    }
+
+    // Returns true if the node is on the ring
+    pub fn contains_node(&self, node : &str) -> bool {
+        return self.nodes.iter().any(|existing| existing.as_slice() == node);
+    }
+
+    // Returns the addresses of the nodes on the ring, in the order they were added
+    pub fn nodes(&self) -> &[String] {
+        return self.nodes.as_slice();
+    }
+
+    // Returns the node a filter lives on: the first one at or after the filter's point on the ring,
+    // or None if the ring is empty
+    pub fn node_for(&self, filter : &str) -> Option<&str> {
+        if self.points.is_empty() {
+            return None;
+        }
+
+        let key : u64 = util::stable_hash(filter);
+
+        // binary search for the first point at or after the key
+        let mut low  : usize = 0;
+        let mut high : usize = self.points.len();
+
+        while low < high {
+            let middle : usize = (low + high) / 2;
+
+            if self.points[middle].0 < key {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        // past the last point, the ring wraps around to the first
+        let (_, index) : (u64, usize) = self.points[if low == self.points.len() { 0 } else { low }];
+
+        return Some(self.nodes[index].as_slice());
+    }
+}
+
+// A client for filters spread across several bloomd servers, each filter living on the
+// server the hash ring maps its name to; it can be shared between threads
+pub struct ShardedClient {
+    shards   : RwLock<Shards>, // The ring, and a pooled client for each server on it
+    fence    : RwLock<()>,     // Held by every write, and taken whole while filters move between servers
+    password : Option<String>
+}
+
+// the servers a ShardedClient sends requests to
+struct Shards {
+    ring    : HashRing,
+    clients : HashMap<String, Client> // A pooled client for each server, by address
+}
+
+impl ShardedClient {
+    // Returns a client for the servers at the given <host>:<port> addresses, of which there must be at least one
+    pub fn new(addresses : &[&str]) -> ClientResult<Self> {
+        return ShardedClient::create_client(addresses, None);
+    }
+
+    // Returns a client that authenticates to every server with the given password
+    pub fn with_password(addresses : &[&str], password : &str) -> ClientResult<Self> {
+        return ShardedClient::create_client(addresses, Some(String::from_str(password)));
+    }
+
+    // Returns the addresses of the servers, in the order they were added
+    pub fn nodes(&self) -> Vec<String> {
+        return self.shards.read().unwrap().ring.nodes().to_vec();
+    }
+
+    // Returns the address of the server the filter lives on
+    pub fn node_for(&self, filter : &str) -> String {
+        return String::from_str(self.shards.read().unwrap().node_for(filter));
+    }
+
+    // Creates a filter on its server, with options such as "capacity=100000"; returns false if it already exists
+    pub fn create(&self, filter : &str, options : &[&str]) -> ClientResult<bool> {
+        return self.write_to(filter, |client| client.create(filter, options));
+    }
+
+    // Drops a filter, deleting it from its server's disk
+    pub fn drop_filter(&self, filter : &str) -> ClientResult<()> {
+        return self.write_to(filter, |client| client.drop_filter(filter));
+    }
+
+    // Closes a filter, unloading it from its server's memory
+    pub fn close(&self, filter : &str) -> ClientResult<()> {
+        return self.read_from(filter, |client| client.close(filter));
+    }
+
+    // Clears a closed filter from its server's list, leaving it on disk
+    pub fn clear(&self, filter : &str) -> ClientResult<()> {
+        return self.write_to(filter, |client| client.clear(filter));
+    }
+
+    // Flushes a filter to disk, or every filter on every server if none is given
+    pub fn flush(&self, filter : Option<&str>) -> ClientResult<()> {
+        return match filter {
+            Some(filter) => self.read_from(filter, |client| client.flush(Some(filter))),
+            None => {
+                for client in self.shards.read().unwrap().clients.values() {
+                    try!(client.flush(None));
+                }
+
+                Ok(())
+            }
+        };
+    }
+
+    // Adds a key to a filter, returning the layer it was added to, or 0 if every layer had it
+    pub fn set(&self, filter : &str, key : &str) -> ClientResult<u32> {
+        return self.write_to(filter, |client| client.set(filter, key));
+    }
+
+    // Returns the number of layers of a filter that contain the key
+    pub fn check(&self, filter : &str, key : &str) -> ClientResult<u32> {
+        return self.read_from(filter, |client| client.check(filter, key));
+    }
+
+    // Adds the keys to a filter; returns the result of each set
+    pub fn bulk(&self, filter : &str, keys : &[&str]) -> ClientResult<Vec<u32>> {
+        return self.write_to(filter, |client| client.bulk(filter, keys));
+    }
+
+    // Checks the keys against a filter; returns the result of each check
+    pub fn multi(&self, filter : &str, keys : &[&str]) -> ClientResult<Vec<u32>> {
+        return self.read_from(filter, |client| client.multi(filter, keys));
+    }
+
+    // Returns the settings and counters of a filter
+    pub fn info(&self, filter : &str) -> ClientResult<FilterInfo> {
+        return self.read_from(filter, |client| client.info(filter));
+    }
+
+    // Lists the filters on every server whose names start with the prefix, or every filter if none is given,
+    // sorted by name
+    pub fn list(&self, prefix : Option<&str>) -> ClientResult<Vec<FilterListing>> {
+        let mut filters : Vec<FilterListing> = Vec::new();
+
+        for client in self.shards.read().unwrap().clients.values() {
+            filters.extend(try!(client.list(prefix)).into_iter());
+        }
+
+        filters.sort_by(|a, b| a.name.cmp(&b.name));
+
+        return Ok(filters);
+    }
+
+    // Adds a server, moving each filter that will map to it over from the server it is on
+    // Writes through this client wait until the move is over, while reads carry on against the old servers:
+    // every filter is copied to the new server first, and only once all of them are does the ring change,
+    // after which the old copies are dropped. In-memory filters have no files to copy, so they are created
+    // afresh on the new server, empty. If a copy fails, the copies made so far are dropped and the ring is
+    // left as it was; calling this again with an address already on the ring drops any old copies left over
+    // returns the names of the filters moved
+    pub fn add_node(&self, address : &str) -> ClientResult<Vec<String>> {
+        let _fence = self.fence.write().unwrap();
+
+        if self.shards.read().unwrap().ring.contains_node(address) {
+            return self.drop_moved(address);
+        }
+
+        let target : Client = self.new_client(address);
+        let mut ring : HashRing = self.shards.read().unwrap().ring.clone();
+        ring.add_node(address);
+
+        let mut moved : Vec<String> = Vec::new();
+        let mut failure : Option<ClientError> = None;
+
+        {
+            let shards = self.shards.read().unwrap();
+
+            'nodes: for (node, client) in shards.clients.iter() {
+                let listings : Vec<FilterListing> = match client.list(None) {
+                    Ok(listings) => listings,
+                    Err(e) => {
+                        failure = Some(e);
+                        break 'nodes;
+                    }
+                };
+
+                for listing in listings.iter() {
+                    if ring.node_for(listing.name.as_slice()) != Some(address) {
+                        continue;
+                    }
+
+                    match copy_filter(client, &target, listing.name.as_slice()) {
+                        Ok(_) => { moved.push(listing.name.clone()) },
+                        Err(e) => {
+                            log_warn!("Could not copy filter {} from {} to {}: {}", listing.name, node, address, e);
+                            failure = Some(e);
+                            break 'nodes;
+                        }
+                    }
+                }
+            }
+        }
+
+        match failure {
+            Some(e) => {
+                for filter in moved.iter() {
+                    match target.drop_filter(filter.as_slice()) {
+                        Err(e) => { log_warn!("Could not drop the copy of filter {} from {}: {}", filter, address, e) },
+                        Ok(_) => { }
+                    }
+                }
+
+                return Err(e);
+            },
+            None => { }
+        }
+
+        {
+            let mut shards = self.shards.write().unwrap();
+            shards.ring = ring;
+            shards.clients.insert(String::from_str(address), target);
+        }
+
+        try!(self.drop_moved(address));
+
+        moved.sort();
+
+        return Ok(moved);
+    }
+
+    // builds a client with a ring of the given servers
+    fn create_client(addresses : &[&str], password : Option<String>) -> ClientResult<Self> {
+        if addresses.is_empty() {
+            return Err(ClientError::NoServers);
+        }
+
+        let client : ShardedClient = ShardedClient {
+            shards: RwLock::new(Shards { ring: HashRing::new(), clients: HashMap::new() }),
+            fence: RwLock::new(()),
+            password: password
+        };
+
+        {
+            let mut shards = client.shards.write().unwrap();
+
+            for address in addresses.iter() {
+                if shards.ring.add_node(*address) {
+                    shards.clients.insert(String::from_str(*address), client.new_client(*address));
+                }
+            }
+        }
+
+        return Ok(client);
+    }
+
+    // returns a client for a server, authenticating with the password if there is one
+    fn new_client(&self, address : &str) -> Client {
+        return match self.password {
+            Some(ref password) => Client::with_password(address, password.as_slice()),
+            None => Client::new(address)
+        };
+    }
+
+    // sends a request that reads from a filter to the server it lives on
+    fn read_from<T, F : Fn(&Client) -> ClientResult<T>>(&self, filter : &str, request : F) -> ClientResult<T> {
+        let shards = self.shards.read().unwrap();
+
+        return request(shards.client_for(filter));
+    }
+
+    // sends a request that changes a filter to the server it lives on, once no filters are being moved
+    fn write_to<T, F : Fn(&Client) -> ClientResult<T>>(&self, filter : &str, request : F) -> ClientResult<T> {
+        let _fence = self.fence.read().unwrap();
+
+        return self.read_from(filter, request);
+    }
+
+    // drops the copies of the filters that now live on the given server from every other server
+    // carries on past filters that can't be dropped, and returns the names of those that were
+    fn drop_moved(&self, address : &str) -> ClientResult<Vec<String>> {
+        let shards = self.shards.read().unwrap();
+
+        let mut dropped : Vec<String> = Vec::new();
+        let mut failure : Option<ClientError> = None;
+
+        for (node, client) in shards.clients.iter() {
+            if node.as_slice() == address {
+                continue;
+            }
+
+            let listings : Vec<FilterListing> = match client.list(None) {
+                Ok(listings) => listings,
+                Err(e) => {
+                    failure = Some(e);
+                    continue;
+                }
+            };
+
+            for listing in listings.iter() {
+                if shards.node_for(listing.name.as_slice()) != address {
+                    continue;
+                }
+
+                match client.drop_filter(listing.name.as_slice()) {
+                    Ok(_) => { dropped.push(listing.name.clone()) },
+                    Err(e) => {
+                        log_warn!("Could not drop filter {} from {}, which it moved off: {}", listing.name, node, e);
+                        failure = Some(e);
+                    }
+                }
+            }
+        }
+
+        return match failure {
+            Some(e) => Err(e),
+            None => {
+                dropped.sort();
+                Ok(dropped)
+            }
+        };
+    }
+}
+
+impl Shards {
+    // returns the address of the server the filter lives on; there is always at least one server
+    fn node_for(&self, filter : &str) -> &str {
+        return self.ring.node_for(filter).expect("a ShardedClient always has at least one server");
+    }
+
+    // returns the client for the server the filter lives on
+    fn client_for(&self, filter : &str) -> &Client {
+        return self.clients.get(self.node_for(filter)).unwrap();
+    }
+}
+
+// copies a filter from one server to another: its files if it has any, or else a new, empty filter with the same settings
+fn copy_filter(source : &Client, target : &Client, filter : &str) -> ClientResult<()> {
+    let info : FilterInfo = try!(source.info(filter));
+
+    if info.in_memory {
+        let mut options : Vec<String> = vec![format!("capacity={}", info.capacity), format!("prob={}", info.probability), String::from_str("in_memory=1")];
+
+        match info.ttl {
+            Some(ttl) => { options.push(format!("ttl={}", ttl)) },
+            None => { }
+        }
+
+        match info.partitions {
+            Some(partitions) => { options.push(format!("partitions={}", partitions)) },
+            None => { }
+        }
+
+        let options : Vec<&str> = options.iter().map(|option| option.as_slice()).collect();
+        try!(target.create(filter, options.as_slice()));
+
+        return Ok(());
+    }
+
+    let snapshot : FilterSnapshot = try!(source.export(filter));
+
+    return target.import(&snapshot);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HashRing;
+
+    #[test]
+    fn test_node_for() {
+        let mut ring : HashRing = HashRing::new();
+        assert!(ring.node_for("foo").is_none());
+
+        assert!(ring.add_node("127.0.0.1:8673"));
+        assert!(ring.add_node("127.0.0.1:8674"));
+        assert!(!ring.add_node("127.0.0.1:8673"));
+
+        // every filter maps to a node, and always the same one
+        let names : Vec<String> = (0..1000).map(|i| format!("filter{}", i)).collect();
+        let first : Vec<String> = names.iter().map(|name| String::from_str(ring.node_for(name.as_slice()).unwrap())).collect();
+
+        assert!(first.iter().any(|node| node.as_slice() == "127.0.0.1:8673"));
+        assert!(first.iter().any(|node| node.as_slice() == "127.0.0.1:8674"));
+
+        // adding a node only moves filters onto it
+        ring.add_node("127.0.0.1:8675");
+
+        let mut moved : usize = 0;
+        for (name, node) in names.iter().zip(first.iter()) {
+            let now : &str = ring.node_for(name.as_slice()).unwrap();
+
+            if now != node.as_slice() {
+                assert_eq!(now, "127.0.0.1:8675");
+                moved += 1;
+            }
+        }
+
+        // about a third of the filters move
+        assert!(moved > 200 && moved < 500);
+    }
+}
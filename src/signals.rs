@@ -0,0 +1,48 @@
+extern crate libc;
+
+use self::libc::{c_int, size_t};
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+
+// constants -------------------------------------------------------------------
+const SIGHUP  : c_int = 1;
+const SIGINT  : c_int = 2;
+const SIGTERM : c_int = 15;
+// -----------------------------------------------------------------------------
+
+// Set by the signal handler; signal handlers can't do much more than store a flag
+static SHUTDOWN : AtomicBool = ATOMIC_BOOL_INIT;
+static HANGUP   : AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handle_signal(signum : c_int) {
+    match signum {
+        SIGHUP => { HANGUP.store(true, Ordering::SeqCst) },
+        _      => { SHUTDOWN.store(true, Ordering::SeqCst) }
+    }
+}
+
+// Installs the handlers for SIGTERM and SIGINT (shut down) and SIGHUP (reload the config and flush)
+pub fn install() {
+    unsafe {
+        externals::signal(SIGTERM, handle_signal as size_t);
+        externals::signal(SIGINT,  handle_signal as size_t);
+        externals::signal(SIGHUP,  handle_signal as size_t);
+    }
+}
+
+// Returns true once SIGTERM or SIGINT has been received
+pub fn shutdown_requested() -> bool {
+    return SHUTDOWN.load(Ordering::SeqCst);
+}
+
+// Returns true if SIGHUP has been received since the last call
+pub fn take_hangup() -> bool {
+    return HANGUP.swap(false, Ordering::SeqCst);
+}
+
+mod externals {
+    use super::libc::{c_int, size_t};
+
+    extern {
+        pub fn signal(signum : c_int, handler : size_t) -> size_t;
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::RingBuf;
+
+// A command that took longer than the slow log threshold
+#[derive(Clone, Debug)]
+pub struct SlowLogEntry {
+    pub id          : u64,            // Sequence number, increasing with every entry ever recorded
+    pub timestamp   : u64,            // Unix time the command finished at
+    pub duration_us : u64,            // Time spent handling the command, in microseconds
+    pub command     : String,         // Command name
+    pub filter      : Option<String>, // Filter the command was run against, if any
+    pub arg_count   : usize,          // Number of arguments after the command name
+    pub client      : String          // Address of the client that sent the command
+}
+
+impl SlowLogEntry {
+    // Returns the entry as a single line of a 'slowlog' response:
+    // <id> <timestamp> <duration_us> <command> <filter> <arg_count> <client>, with '-' for a missing filter or client
+    pub fn to_line(&self) -> String {
+        return format!("{} {} {} {} {} {} {}",
+                       self.id,
+                       self.timestamp,
+                       self.duration_us,
+                       self.command,
+                       self.filter.as_ref().map(|filter| filter.as_slice()).unwrap_or("-"),
+                       self.arg_count,
+                       if self.client.is_empty() { "-" } else { self.client.as_slice() });
+    }
+}
+
+// The most recent slow commands, oldest first; the oldest entries are dropped once it is full
+pub struct SlowLog {
+    entries : RingBuf<SlowLogEntry>,
+    next_id : u64
+}
+
+impl SlowLog {
+    // Returns an empty slow log
+    pub fn new() -> Self {
+        return SlowLog { entries: RingBuf::new(), next_id: 0 };
+    }
+
+    // Adds an entry, assigning its id, and drops the oldest entries beyond max_len
+    pub fn record(&mut self, mut entry : SlowLogEntry, max_len : usize) {
+        entry.id = self.next_id;
+        self.next_id += 1;
+
+        self.entries.push_back(entry);
+
+        while self.entries.len() > max_len {
+            self.entries.pop_front();
+        }
+    }
+
+    // Returns up to count of the most recent entries, newest first
+    pub fn latest(&self, count : usize) -> Vec<SlowLogEntry> {
+        return self.entries.iter().rev().take(count).map(|entry| entry.clone()).collect();
+    }
+
+    // Returns the number of entries held
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    // Removes every entry; ids keep increasing
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SlowLog, SlowLogEntry};
+
+    fn entry(command : &str) -> SlowLogEntry {
+        return SlowLogEntry {
+            id: 0,
+            timestamp: 1421712000,
+            duration_us: 25000,
+            command: String::from_str(command),
+            filter: None,
+            arg_count: 0,
+            client: String::new()
+        };
+    }
+
+    #[test]
+    fn test_slowlog() {
+        let mut slowlog : SlowLog = SlowLog::new();
+        slowlog.record(entry("bulk"), 2);
+        slowlog.record(entry("flush"), 2);
+        slowlog.record(entry("create"), 2);
+
+        // The oldest entry was dropped, the newest comes first
+        let latest : Vec<SlowLogEntry> = slowlog.latest(10);
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[0].to_line().as_slice(), "2 1421712000 25000 create - 0 -");
+        assert_eq!(latest[1].id, 1);
+
+        slowlog.reset();
+        assert_eq!(slowlog.len(), 0);
+
+        slowlog.record(entry("bulk"), 2);
+        assert_eq!(slowlog.latest(1)[0].id, 3);
+    }
+}
@@ -0,0 +1,354 @@
+use config::BloomFilterConfig;
+use error::BloomResult;
+use inifile::IniFile;
+use wrappers::BloomFilter;
+use std::cmp;
+use std::io;
+use std::io::{BufReader, File, IoError, IoErrorKind, IoResult};
+use std::io::fs;
+use std::iter::repeat;
+use std::str::FromStr;
+
+// constants -------------------------------------------------------------------
+const SNAPSHOT_HEADER  : &'static str = "snapshot";
+const CONFIG_EXTENSION : &'static str = "ini";
+const COPY_BLOCK_SIZE  : usize        = 65536;
+const MAX_FILES        : usize        = 65536;
+const MAX_CONFIG_BYTES : usize        = 65536;
+// -----------------------------------------------------------------------------
+
+// A copy of the files a filter keeps on disk, as read in by followers and by the server a filter is imported into
+#[derive(Clone)]
+pub struct FilterSnapshot {
+    pub name  : String,                 // Name of the filter
+    pub files : Vec<(String, Vec<u8>)>  // Name and contents of the ini files and of each bitmap
+}
+
+impl FilterSnapshot {
+    // Flushes the filter and copies its files into memory; returns None for a filter only kept in memory
+    // The copy holds exactly the changes made before it, for as long as it takes to send it on
+    pub fn from_filter(filter : &mut BloomFilter) -> BloomResult<Option<Self>> {
+        let source : SnapshotSource = match try!(SnapshotSource::from_filter(filter)) {
+            Some(source) => source,
+            None => { return Ok(None) }
+        };
+
+        let mut files : Vec<(String, Vec<u8>)> = source.configs;
+
+        for path in source.bitmaps.iter() {
+            files.push((String::from_str(path.filename_str().unwrap()), try!(File::open(path).read_to_end())));
+        }
+
+        return Ok(Some(FilterSnapshot { name: source.name, files: files }));
+    }
+
+    // Returns true if the line is the header a snapshot starts with
+    pub fn is_header(line : &str) -> bool {
+        return line.starts_with(format!("{} ", SNAPSHOT_HEADER).as_slice());
+    }
+
+    // Reads a snapshot, header line included
+    pub fn read<R : Buffer>(reader : &mut R) -> IoResult<Self> {
+        let header : String = try!(read_line(reader));
+
+        return FilterSnapshot::read_from(reader, header.as_slice());
+    }
+
+    // Reads a snapshot, given the header line it starts with:
+    // snapshot <filter> <file_count>, followed by <filename> <length> and the raw contents for each file
+    // Each ini file has to come before the bitmaps it lists, which can be no more, nor bigger, than it says,
+    // so a snapshot can't make the reader take in more than the filter it claims to be
+    pub fn read_from<R : Buffer>(reader : &mut R, header : &str) -> IoResult<Self> {
+        let parts : Vec<&str> = header.split(' ').collect();
+
+        if parts.len() != 3 || parts[0] != SNAPSHOT_HEADER || !is_safe_name(parts[1]) {
+            return Err(protocol_error("malformed snapshot header", header));
+        }
+
+        let file_count : usize = try!(parse_count(parts[2], header));
+
+        if file_count > MAX_FILES {
+            return Err(protocol_error("too many files in snapshot", header));
+        }
+
+        let mut files : Vec<(String, Vec<u8>)> = Vec::with_capacity(file_count);
+
+        // how many more bitmaps, and bytes of them, the ini files read so far allow for
+        let mut bitmaps_allowed : usize = 0;
+        let mut bytes_allowed : u64 = 0;
+
+        for _ in (0..file_count) {
+            let line : String = try!(read_line(reader));
+            let file_parts : Vec<&str> = line.as_slice().split(' ').collect();
+
+            if file_parts.len() != 2 || !is_safe_name(file_parts[0]) {
+                return Err(protocol_error("malformed snapshot file header", line.as_slice()));
+            }
+
+            let length : usize = try!(parse_count(file_parts[1], line.as_slice()));
+
+            if Path::new(file_parts[0]).extension_str() == Some(CONFIG_EXTENSION) {
+                if length > MAX_CONFIG_BYTES {
+                    return Err(protocol_error("filter config in snapshot is too big", line.as_slice()));
+                }
+
+                let contents : Vec<u8> = try!(reader.read_exact(length));
+                let (bitmaps, bytes) : (usize, u64) = try!(listed_bitmaps(contents.as_slice(), line.as_slice()));
+
+                bitmaps_allowed += bitmaps;
+                bytes_allowed += bytes * bitmaps as u64;
+
+                files.push((String::from_str(file_parts[0]), contents));
+            } else {
+                if bitmaps_allowed == 0 || length as u64 > bytes_allowed {
+                    return Err(protocol_error("bitmap in snapshot isn't listed by the filter's config", line.as_slice()));
+                }
+
+                bitmaps_allowed -= 1;
+                bytes_allowed -= length as u64;
+
+                files.push((String::from_str(file_parts[0]), try!(reader.read_exact(length))));
+            }
+        }
+
+        return Ok(FilterSnapshot { name: String::from_str(parts[1]), files: files });
+    }
+
+    // Writes the snapshot in the form read_from reads it in
+    pub fn write_to<W : Writer>(&self, writer : &mut W) -> IoResult<()> {
+        try!(writer.write_str(format!("{} {} {}\r\n", SNAPSHOT_HEADER, self.name, self.files.len()).as_slice()));
+
+        for &(ref filename, ref contents) in self.files.iter() {
+            try!(writer.write_str(format!("{} {}\r\n", filename, contents.len()).as_slice()));
+            try!(writer.write(contents.as_slice()));
+        }
+
+        return Ok(());
+    }
+
+    // Writes the files out to the given directory, which must not exist yet
+    pub fn write_to_directory(&self, directory : &Path) -> IoResult<()> {
+        try!(fs::mkdir(directory, io::USER_RWX));
+
+        for &(ref filename, ref contents) in self.files.iter() {
+            let mut path : Path = directory.clone();
+            path.push(filename.as_slice());
+
+            try!(File::create(&path).write(contents.as_slice()));
+        }
+
+        return Ok(());
+    }
+}
+
+// A filter's files as listed while it was locked, sent in the form FilterSnapshot::read_from reads them in
+// The ini files are small and rewritten on every flush, so they're copied right away; the bitmaps are only
+// read from disk, a block at a time, as they're written out, so the filter has to stay locked until then:
+// a set made in between would land in a bitmap the ini file doesn't count it in
+#[derive(Clone)]
+pub struct SnapshotSource {
+    pub name    : String,                 // Name of the filter
+    pub configs : Vec<(String, Vec<u8>)>, // Name and contents of each ini file
+    pub bitmaps : Vec<Path>               // Path to each bitmap
+}
+
+impl SnapshotSource {
+    // Flushes the filter and lists its files; returns None for a filter only kept in memory
+    pub fn from_filter(filter : &mut BloomFilter) -> BloomResult<Option<Self>> {
+        if filter.config.in_memory {
+            return Ok(None);
+        }
+
+        try!(filter.flush());
+
+        let mut configs : Vec<(String, Vec<u8>)> = Vec::new();
+        let mut bitmaps : Vec<Path> = Vec::new();
+
+        for path in filter.files().into_iter() {
+            if path.extension_str() == Some(CONFIG_EXTENSION) {
+                let contents : Vec<u8> = try!(File::open(&path).read_to_end());
+                configs.push((String::from_str(path.filename_str().unwrap()), contents));
+            } else {
+                bitmaps.push(path);
+            }
+        }
+
+        return Ok(Some(SnapshotSource { name: filter.config.filter_name.clone(), configs: configs, bitmaps: bitmaps }));
+    }
+
+    // Writes the snapshot, reading the bitmaps in from disk
+    // A bitmap that's gone or has shrunk since it was listed, e.g. because its filter was dropped, is an error
+    pub fn write_to<W : Writer>(&self, writer : &mut W) -> IoResult<()> {
+        try!(writer.write_str(format!("{} {} {}\r\n", SNAPSHOT_HEADER, self.name, self.configs.len() + self.bitmaps.len()).as_slice()));
+
+        for &(ref filename, ref contents) in self.configs.iter() {
+            try!(writer.write_str(format!("{} {}\r\n", filename, contents.len()).as_slice()));
+            try!(writer.write(contents.as_slice()));
+        }
+
+        let mut buffer : Vec<u8> = repeat(0u8).take(COPY_BLOCK_SIZE).collect();
+
+        for path in self.bitmaps.iter() {
+            let mut file : File = try!(File::open(path));
+            let length : u64 = try!(fs::stat(path)).size;
+
+            try!(writer.write_str(format!("{} {}\r\n", path.filename_str().unwrap(), length).as_slice()));
+
+            let mut remaining : u64 = length;
+
+            while remaining > 0 {
+                let wanted : usize = cmp::min(remaining, COPY_BLOCK_SIZE as u64) as usize;
+                let read : usize = try!(file.read(buffer.slice_to_mut(wanted)));
+
+                try!(writer.write(buffer.slice_to(read)));
+                remaining -= read as u64;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+// Reads a line, without its line ending
+pub fn read_line<R : Buffer>(reader : &mut R) -> IoResult<String> {
+    let line : String = try!(reader.read_line());
+    let chars_to_trim : &[char] = &['\n', '\r'];
+
+    return Ok(String::from_str(line.as_slice().trim_right_matches(chars_to_trim)));
+}
+
+// Returns the error for something the other side sent that doesn't follow the protocol
+pub fn protocol_error(desc : &'static str, line : &str) -> IoError {
+    return IoError { kind: IoErrorKind::InvalidInput, desc: desc, detail: Some(String::from_str(line)) };
+}
+
+// returns true if the filter or file name can't name anything outside the data_dir, as the files go straight into it
+fn is_safe_name(name : &str) -> bool {
+    return !name.is_empty() && !name.contains("/") && !name.starts_with(".") && !name.chars().any(|c| c.is_control());
+}
+
+// returns the number of bitmaps the ini file of a filter, or of one of its partitions, lists, and the size of each
+fn listed_bitmaps(contents : &[u8], line : &str) -> IoResult<(usize, u64)> {
+    let mut ini : IniFile = IniFile::new();
+    ini.read_string(BufReader::new(contents).lines().filter_map(|line| line.ok()).collect());
+
+    return match BloomFilterConfig::from_ini(&ini) {
+        Ok(config) => Ok((config.bitmap_filenames.len(), config.bytes)),
+        Err(_) => Err(protocol_error("malformed filter config in snapshot", line))
+    };
+}
+
+// parses a file count or length in a snapshot
+fn parse_count(value : &str, line : &str) -> IoResult<usize> {
+    return match FromStr::from_str(value) {
+        Some(count) => Ok(count),
+        None => Err(protocol_error("malformed number in snapshot", line))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FilterSnapshot, SnapshotSource};
+    use config::BloomFilterConfig;
+    use inifile::IniFile;
+    use std::io::{BufReader, File, MemWriter, TempDir};
+    use std::io::fs;
+    use std::iter::repeat;
+
+    #[test]
+    fn test_write_read() {
+        let snapshot : FilterSnapshot = FilterSnapshot {
+            name: String::from_str("foo"),
+            files: vec![(String::from_str("foo.ini"), config_ini("foo", &["foo.0.bmp"], 4)),
+                        (String::from_str("foo.0.bmp"), vec![0u8, 13, 10, 255])]
+        };
+
+        let mut writer : MemWriter = MemWriter::new();
+        snapshot.write_to(&mut writer).unwrap();
+
+        let bytes : Vec<u8> = writer.into_inner();
+        let read : FilterSnapshot = FilterSnapshot::read(&mut BufReader::new(bytes.as_slice())).unwrap();
+
+        assert_eq!(read.name, snapshot.name);
+        assert_eq!(read.files, snapshot.files);
+    }
+
+    #[test]
+    fn test_read_rejects_paths() {
+        let bytes : &[u8] = b"snapshot foo 1\r\n../config.ini 0\r\n";
+        assert!(FilterSnapshot::read(&mut BufReader::new(bytes)).is_err());
+
+        let bytes : &[u8] = b"snapshot ../foo 0\r\n";
+        assert!(FilterSnapshot::read(&mut BufReader::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn test_read_rejects_unlisted_bitmaps() {
+        // More files than any filter has
+        let bytes : &[u8] = b"snapshot foo 4294967295\r\n";
+        assert!(FilterSnapshot::read(&mut BufReader::new(bytes)).is_err());
+
+        // A bitmap before the config listing it
+        let bytes : &[u8] = b"snapshot foo 1\r\nfoo.0.bmp 4294967295\r\n";
+        assert!(FilterSnapshot::read(&mut BufReader::new(bytes)).is_err());
+
+        // A bitmap bigger than the config says, or one more than it lists
+        for bitmaps in [vec![(String::from_str("foo.0.bmp"), 5us)], vec![(String::from_str("foo.0.bmp"), 4), (String::from_str("foo.1.bmp"), 4)]].iter() {
+            let mut writer : MemWriter = MemWriter::new();
+            let config : Vec<u8> = config_ini("foo", &["foo.0.bmp"], 4);
+
+            writer.write_str(format!("snapshot foo {}\r\nfoo.ini {}\r\n", bitmaps.len() + 1, config.len()).as_slice()).unwrap();
+            writer.write(config.as_slice()).unwrap();
+
+            for &(ref filename, length) in bitmaps.iter() {
+                writer.write_str(format!("{} {}\r\n", filename, length).as_slice()).unwrap();
+                writer.write(repeat(0u8).take(length).collect::<Vec<u8>>().as_slice()).unwrap();
+            }
+
+            let bytes : Vec<u8> = writer.into_inner();
+            assert!(FilterSnapshot::read(&mut BufReader::new(bytes.as_slice())).is_err());
+        }
+    }
+
+    #[test]
+    fn test_source_write_read() {
+        let directory : TempDir = TempDir::new("bloomd-test-snapshot").unwrap();
+        let bitmap : Path = directory.path().join("foo.0.bmp");
+        let contents : Vec<u8> = (0..200000).map(|i| (i % 251) as u8).collect();
+        let config : Vec<u8> = config_ini("foo", &["foo.0.bmp"], 200000);
+
+        File::create(&bitmap).write(contents.as_slice()).unwrap();
+
+        let source : SnapshotSource = SnapshotSource {
+            name: String::from_str("foo"),
+            configs: vec![(String::from_str("foo.ini"), config.clone())],
+            bitmaps: vec![bitmap.clone()]
+        };
+
+        let mut writer : MemWriter = MemWriter::new();
+        source.write_to(&mut writer).unwrap();
+
+        let bytes : Vec<u8> = writer.into_inner();
+        let read : FilterSnapshot = FilterSnapshot::read(&mut BufReader::new(bytes.as_slice())).unwrap();
+
+        assert_eq!(read.name.as_slice(), "foo");
+        assert_eq!(read.files, vec![(String::from_str("foo.ini"), config),
+                                    (String::from_str("foo.0.bmp"), contents)]);
+
+        // A bitmap deleted after it was listed can't be sent
+        fs::unlink(&bitmap).unwrap();
+        assert!(source.write_to(&mut MemWriter::new()).is_err());
+    }
+
+    // returns the ini file of a filter with the given bitmaps, each of the given number of bytes
+    fn config_ini(filter_name : &str, bitmap_filenames : &[&str], bytes : u64) -> Vec<u8> {
+        let mut config : BloomFilterConfig = BloomFilterConfig::new(String::from_str(filter_name), 1000, 0.01, 7, false, bytes);
+        config.bitmap_filenames = bitmap_filenames.iter().map(|filename| String::from_str(*filename)).collect();
+        config.filter_sizes = bitmap_filenames.iter().map(|_| 0).collect();
+
+        let mut ini : IniFile = IniFile::new();
+        config.add_to_ini(&mut ini);
+
+        return format!("{}", ini).into_bytes();
+    }
+}
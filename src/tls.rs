@@ -0,0 +1,95 @@
+extern crate openssl;
+
+use bloomd::config::BloomConfig;
+use self::openssl::ssl::{SslContext, SslMethod, SslVerifyPeer, SslVerifyFailIfNoPeerCert, SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3};
+use self::openssl::x509::X509FileType;
+
+// Creates the SSL context used to accept TLS clients, from the certificate, key and
+// optional client CA in the config. A client CA turns on verification of client certificates.
+pub fn create_context(config : &BloomConfig) -> Result<SslContext, String> {
+    let mut context : SslContext = match new_context() {
+        Ok(context) => context,
+        Err(e) => { return Err(e) }
+    };
+
+    match context.set_certificate_file(&Path::new(config.tls_cert_file.as_slice()), X509FileType::PEM) {
+        Some(e) => { return Err(format!("could not load certificate {}: {}", config.tls_cert_file, e)) },
+        None => { }
+    }
+
+    match context.set_private_key_file(&Path::new(config.tls_key_file.as_slice()), X509FileType::PEM) {
+        Some(e) => { return Err(format!("could not load private key {}: {}", config.tls_key_file, e)) },
+        None => { }
+    }
+
+    if !config.tls_ca_file.is_empty() {
+        match context.set_CA_file(&Path::new(config.tls_ca_file.as_slice())) {
+            Some(e) => { return Err(format!("could not load client CA {}: {}", config.tls_ca_file, e)) },
+            None => { }
+        }
+
+        context.set_verify(SslVerifyPeer | SslVerifyFailIfNoPeerCert, None);
+    }
+
+    return Ok(context);
+}
+
+// returns a context that negotiates the newest TLS version both sides support, never falling back to SSL
+fn new_context() -> Result<SslContext, String> {
+    let mut context : SslContext = match SslContext::new(SslMethod::Sslv23) {
+        Ok(context) => context,
+        Err(e) => { return Err(e.to_string()) }
+    };
+
+    context.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3);
+
+    return Ok(context);
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::openssl::crypto::hash::HashType;
+    use super::new_context;
+    use super::openssl::ssl::{SslContext, SslVerifyPeer};
+    use super::openssl::x509::{X509Generator, X509FileType, KeyUsage};
+    use std::io::fs::File;
+
+    // Writes a freshly generated self-signed certificate and its key to <prefix>.crt and <prefix>.key
+    // returns the certificate and key filenames
+    pub fn generate_self_signed(prefix : &str) -> (String, String) {
+        let generator : X509Generator = X509Generator::new()
+                                                      .set_bitlength(2048)
+                                                      .set_valid_period(1)
+                                                      .set_CN("localhost")
+                                                      .set_sign_hash(HashType::SHA256)
+                                                      .set_usage(&[KeyUsage::DigitalSignature, KeyUsage::KeyEncipherment]);
+
+        let (cert, pkey) = generator.generate().unwrap();
+
+        let cert_filename : String = format!("{}.crt", prefix);
+        let key_filename : String = format!("{}.key", prefix);
+
+        cert.write_pem(&mut File::create(&Path::new(cert_filename.as_slice()))).unwrap();
+        pkey.write_pem(&mut File::create(&Path::new(key_filename.as_slice()))).unwrap();
+
+        return (cert_filename, key_filename);
+    }
+
+    // Returns a client context that trusts the given certificate, optionally presenting a client certificate
+    pub fn create_client_context(ca_filename : &str, client : Option<(String, String)>) -> SslContext {
+        let mut context : SslContext = new_context().unwrap();
+
+        assert!(context.set_CA_file(&Path::new(ca_filename)).is_none());
+        context.set_verify(SslVerifyPeer, None);
+
+        match client {
+            Some((cert_filename, key_filename)) => {
+                assert!(context.set_certificate_file(&Path::new(cert_filename.as_slice()), X509FileType::PEM).is_none());
+                assert!(context.set_private_key_file(&Path::new(key_filename.as_slice()), X509FileType::PEM).is_none());
+            },
+            None => { }
+        }
+
+        return context;
+    }
+}
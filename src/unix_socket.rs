@@ -0,0 +1,98 @@
+extern crate libc;
+
+use bloomd::config::BloomConfig;
+use self::libc::mode_t;
+use self::libc::types::os::arch::posix01::stat;
+use std::ffi;
+use std::mem;
+use std::io::{fs, IoError, IoErrorKind, IoResult};
+use std::io::fs::PathExtensions;
+use std::io::net::pipe::{UnixListener, UnixAcceptor, UnixStream};
+
+// constants -------------------------------------------------------------------
+const S_IFMT   : mode_t = 0o170000;
+const S_IFSOCK : mode_t = 0o140000;
+// -----------------------------------------------------------------------------
+
+// Binds the unix socket in the config, with the configured permissions and ownership. A socket left
+// behind by a server that's gone is replaced; anything else at the path, or a socket another server
+// is listening on, is an error
+pub fn bind(config : &BloomConfig) -> IoResult<UnixAcceptor> {
+    let path : Path = Path::new(config.unix_socket.clone());
+
+    match file_mode(&path) {
+        Some(mode) if mode & S_IFMT == S_IFSOCK => {
+            if UnixStream::connect(&path).is_ok() {
+                return Err(socket_error("another server is listening on the unix socket", &path));
+            }
+
+            try!(fs::unlink(&path));
+        },
+        Some(_) => { return Err(socket_error("unix_socket names a file that isn't a socket", &path)) },
+        None => { }
+    }
+
+    // the socket is created with the configured mode, rather than changed to it once clients could connect
+    let previous : mode_t = unsafe { externals::umask(!(config.unix_socket_mode as mode_t) & 0o777) };
+    let listener : IoResult<UnixListener> = UnixListener::bind(&path);
+    unsafe { externals::umask(previous) };
+
+    let acceptor : UnixAcceptor = try!(try!(listener).listen());
+
+    if config.unix_socket_uid >= 0 || config.unix_socket_gid >= 0 {
+        try!(fs::chown(&path, config.unix_socket_uid, config.unix_socket_gid));
+    }
+
+    return Ok(acceptor);
+}
+
+// Removes the unix socket file when dropped
+pub struct UnixSocketGuard {
+    path : Path
+}
+
+impl UnixSocketGuard {
+    pub fn new(path : Path) -> Self {
+        return UnixSocketGuard { path: path };
+    }
+}
+
+impl Drop for UnixSocketGuard {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            match fs::unlink(&self.path) {
+                Err(e) => { log_warn!("Could not remove unix socket {}: {}", self.path.display(), e) },
+                Ok(_) => { }
+            }
+        }
+    }
+}
+
+// returns the type and permission bits of the file at the path, without following symlinks,
+// or None if there is no such file
+fn file_mode(path : &Path) -> Option<mode_t> {
+    let c_path : ffi::CString = ffi::CString::from_slice(path.as_vec());
+    let mut buf : stat = unsafe { mem::zeroed() };
+
+    if unsafe { externals::lstat(c_path.as_ptr(), &mut buf) } < 0 {
+        return None;
+    }
+
+    return Some(buf.st_mode);
+}
+
+// returns the error for a unix socket path that can't be bound
+fn socket_error(desc : &'static str, path : &Path) -> IoError {
+    return IoError { kind: IoErrorKind::PathAlreadyExists, desc: desc, detail: Some(path.display().to_string()) };
+}
+
+mod externals {
+    use super::libc::{c_char, c_int, mode_t};
+    use super::libc::types::os::arch::posix01::stat;
+
+    extern {
+        pub fn lstat(path : *const c_char, buf : *mut stat) -> c_int;
+
+        pub fn umask(mask : mode_t) -> mode_t;
+    }
+}
@@ -1,21 +1,92 @@
-extern crate libc;
+extern crate time;
 
-use std::{ffi, mem};
+// constants -------------------------------------------------------------------
+const FNV_OFFSET_BASIS : u64 = 0xcbf29ce484222325;
+const FNV_PRIME        : u64 = 0x100000001b3;
+// -----------------------------------------------------------------------------
 
-// makes a call to strerror and returns an Err with the returned String value
-pub fn strerror<T>(errnum : i32) -> Result<T, String> {
-    let error : *const libc::c_char = unsafe { libc::funcs::c95::string::strerror(errnum) };
+// returns the current unix time, in seconds
+pub fn now() -> u64 {
+    return time::get_time().sec as u64;
+}
+
+// returns the seconds elapsed since the given time, as returned by precise_time_ns
+pub fn seconds_since(start_ns : u64) -> f64 {
+    return (time::precise_time_ns() - start_ns) as f64 / 1e9;
+}
+
+// returns a monotonic time in nanoseconds, for measuring durations
+pub fn precise_time_ns() -> u64 {
+    return time::precise_time_ns();
+}
+
+// returns the 64-bit FNV-1a hash of the key, which unlike the std hashers is the same in every process
+pub fn stable_hash(key : &str) -> u64 {
+    let mut hash : u64 = FNV_OFFSET_BASIS;
+
+    for byte in key.bytes() {
+        hash = (hash ^ byte as u64) * FNV_PRIME;
+    }
+
+    return hash;
+}
 
-    if !error.is_null() {
-        let buf : &[u8] = unsafe { ffi::c_str_to_bytes(&error) };
-        let error_string : String = String::from_utf8(buf.to_vec()).unwrap();
+// returns true if the name matches the glob pattern, where '*' matches any run of characters and '?' any one character
+pub fn glob_matches(pattern : &str, name : &str) -> bool {
+    let pattern : Vec<char> = pattern.chars().collect();
+    let name : Vec<char> = name.chars().collect();
 
-        unsafe {
-            libc::free(mem::transmute(error));
+    let mut p : usize = 0;
+    let mut n : usize = 0;
+
+    // the position of the last '*' seen, and of the character in the name it would match up to next;
+    // on a mismatch, that '*' takes one more character and matching carries on after it
+    let mut star : Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else {
+            match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                },
+                None => { return false }
+            }
         }
+    }
+
+    // whatever is left of the pattern can only match the empty rest of the name
+    while p < pattern.len() && pattern[p] == '*' {
+        p += 1;
+    }
+
+    return p == pattern.len();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_matches;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("session_*", "session_eu"));
+        assert!(glob_matches("session_*", "session_"));
+        assert!(glob_matches("sess?", "sess1"));
+        assert!(glob_matches("*_eu_*", "session_eu_2"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("session_*", "sessions_eu_2"));
+        assert!(!glob_matches("sess?", "sess"));
+        assert!(!glob_matches("sess?", "sess12"));
 
-        return Err(error_string);
-    } else {
-        return Err(String::new());
+        // Patterns with many stars don't take exponential time
+        let name : String = (0..200).map(|_| 'a').collect();
+        assert!(!glob_matches("*a*a*a*a*a*a*a*a*a*a*b", name.as_slice()));
     }
 }
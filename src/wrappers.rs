@@ -1,14 +1,21 @@
+use error::{BloomError, BloomResult};
 use inifile::IniFile;
-use config::BloomFilterConfig;
+use config::{BloomFilterConfig, FILTER_TYPE_PARTITIONED};
 use filter::IBloomFilter;
-use bloom::{bloom_filter_params, bloom_bloomfilter, create_bloom_filter, load_bloom_filter};
+use bitmap::IoMode;
+use bloom::{bloom_filter_params, bloom_bloomfilter, create_bloom_filter, create_bloom_filter_params, load_bloom_filter};
 use lbf::bloom_lbf;
+use util;
 use std::ops::{Deref, DerefMut};
 use std::io;
-use std::io::{fs, IoResult};
+use std::io::fs;
 use std::io::fs::PathExtensions;
 
 // constants -------------------------------------------------------------------
+pub const FILTER_FOLDER_PREFIX : &'static str = "filter.";
+
+const PARTITION_FILE_PREFIX : &'static str = "partition.";
+
 const INI_SECTION_COUNTERS : &'static str = "counters";
 const INI_OPTION_CHECK_HITS : &'static str = "check_hits";
 const INI_OPTION_CHECK_MISSES : &'static str = "check_misses";
@@ -19,6 +26,7 @@ const INI_OPTION_PAGE_OUTS : &'static str = "page_outs";
 // -----------------------------------------------------------------------------
 
 // Keeps track of statistics for filters; used by the info command
+#[derive(Clone, Debug)]
 pub struct BloomFilterCounters {
     pub check_hits   : u64,
     pub check_misses : u64,
@@ -35,41 +43,41 @@ impl BloomFilterCounters {
     }
 
     // Loads an instance from an ini file, returning an error if the ini file is missing information
-    pub fn from_ini(ini : &IniFile) -> Result<Self, String> {
+    pub fn from_ini(ini : &IniFile) -> BloomResult<Self> {
         let check_hits : u64;
         match ini.get::<u64>(INI_SECTION_COUNTERS, INI_OPTION_CHECK_HITS) {
             Some(value) => { check_hits = value },
-            None => { return Err(String::from_str("missing counters:check_hits")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing counters:check_hits"))) }
         };
 
         let check_misses : u64;
         match ini.get::<u64>(INI_SECTION_COUNTERS, INI_OPTION_CHECK_MISSES) {
             Some(value) => { check_misses = value },
-            None => { return Err(String::from_str("missing counters:check_misses")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing counters:check_misses"))) }
         };
 
         let set_hits : u64;
         match ini.get::<u64>(INI_SECTION_COUNTERS, INI_OPTION_SET_HITS) {
             Some(value) => { set_hits = value },
-            None => { return Err(String::from_str("missing counters:set_hits")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing counters:set_hits"))) }
         };
 
         let set_misses : u64;
         match ini.get::<u64>(INI_SECTION_COUNTERS, INI_OPTION_SET_MISSES) {
             Some(value) => { set_misses = value },
-            None => { return Err(String::from_str("missing counters:set_misses")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing counters:set_misses"))) }
         };
 
         let page_ins : u64;
         match ini.get::<u64>(INI_SECTION_COUNTERS, INI_OPTION_PAGE_INS) {
             Some(value) => { page_ins = value },
-            None => { return Err(String::from_str("missing counters:page_ins")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing counters:page_ins"))) }
         };
 
         let page_outs : u64;
         match ini.get::<u64>(INI_SECTION_COUNTERS, INI_OPTION_PAGE_OUTS) {
             Some(value) => { page_outs = value },
-            None => { return Err(String::from_str("missing counters:page_outs")) }
+            None => { return Err(BloomError::Corrupt(String::from_str("missing counters:page_outs"))) }
         };
 
         return Ok(BloomFilterCounters {
@@ -104,15 +112,63 @@ impl BloomFilterCounters {
     }
 }
 
+// How far a 'load' into a filter has got; reported by the info command
+#[derive(Clone)]
+pub struct LoadProgress {
+    pub path        : String,         // Key file being loaded
+    pub keys        : u64,            // Keys added so far
+    pub bytes_read  : u64,            // Bytes of the key file read so far
+    pub total_bytes : u64,            // Size of the key file
+    pub done        : bool,           // Set once the load is over, whether or not every key was added
+    pub cancelled   : bool,           // Set to stop the load before its next batch
+    pub error       : Option<String>  // Why the load stopped before the end of the key file, if it did
+}
+
+impl LoadProgress {
+    // Returns the progress of a load that hasn't added any keys yet
+    pub fn new(path : &str, total_bytes : u64) -> Self {
+        return LoadProgress {
+            path: String::from_str(path),
+            keys: 0,
+            bytes_read: 0,
+            total_bytes: total_bytes,
+            done: false,
+            cancelled: false,
+            error: None
+        };
+    }
+
+    // Returns true while the load is queued or running
+    pub fn is_running(&self) -> bool {
+        return !self.done;
+    }
+
+    // Returns how much of the key file has been read, as a percentage
+    pub fn percent(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 100.0;
+        }
+
+        return self.bytes_read as f64 * 100.0 / self.total_bytes as f64;
+    }
+}
+
 // The structure wrapping around bloom filters, used by bloomd.
-// Keeps the configuration, the filter, and the counters
+// Keeps the configuration, the filter, and the counters.
+// A partitioned filter has no layers of its own; each key goes to one of its
+// partitions by hash, and each partition is paged in and out on its own
 pub struct BloomFilter {
-    pub config      : BloomFilterConfig,   // Filter-specific config
-    lbf             : Option<bloom_lbf>,   // Layered bloom filter
-    pub counters    : BloomFilterCounters, // Counters
-    pub directory   : Path,                // File directory path,
-    pub config_file : Path,                // INI file path
-    pub cold_index  : u64                  // Used to determine how recently filter was used
+    pub config        : BloomFilterConfig,    // Filter-specific config
+    lbf               : Option<bloom_lbf>,    // Layered bloom filter
+    pub counters      : BloomFilterCounters,  // Counters
+    pub directory     : Path,                 // File directory path,
+    pub config_file   : Path,                 // INI file path
+    pub cold_index    : u64,                  // Used to determine how recently filter was used
+    pub load_progress : Option<LoadProgress>, // Progress of the last 'load' into the filter, if any
+    pub partitions    : Vec<BloomFilter>,     // Layered filters the keys are spread across, if partitioned
+    file_prefix       : String,               // Prefix of a partition's ini file and bitmaps, empty otherwise
+    dirty             : bool,                 // Set when keys, layers or settings change, until the next flush
+    counters_dirty    : bool                  // Set when only the counters change, until the next flush
 }
 
 impl BloomFilter {
@@ -123,74 +179,159 @@ impl BloomFilter {
         config_file.set_extension("ini");
 
         return BloomFilter {
-            config      : config,
-            lbf         : Some(lbf),
-            counters    : BloomFilterCounters::new(),
-            directory   : directory,
-            config_file : config_file,
-            cold_index  : 0
+            config         : config,
+            lbf            : Some(lbf),
+            counters       : BloomFilterCounters::new(),
+            directory      : directory,
+            config_file    : config_file,
+            cold_index     : 0,
+            load_progress  : None,
+            partitions     : Vec::new(),
+            file_prefix    : String::new(),
+            dirty          : true,
+            counters_dirty : false
         };
     }
 
+    // Returns a new instance of a partitioned BloomFilter, with config.partitions empty partitions
+    // that split the capacity between them; each is a layered filter with its own bitmaps in the directory
+    pub fn new_partitioned(mut config : BloomFilterConfig, directory : Path) -> BloomResult<Self> {
+        let partition_count : u64 = config.partitions as u64;
+
+        if partition_count == 0 {
+            return Err(BloomError::InvalidParams(String::from_str("a partitioned filter needs at least 1 partition")));
+        }
+
+        let capacity : u64 = (config.capacity + partition_count - 1) / partition_count;
+        let params : bloom_filter_params = try!(create_bloom_filter_params(capacity, config.probability));
+
+        let mut partitions : Vec<BloomFilter> = Vec::with_capacity(partition_count as usize);
+
+        for index in (0..config.partitions) {
+            let mut partition_config : BloomFilterConfig = BloomFilterConfig::new(config.filter_name.clone(), capacity, config.probability, params.k_num, config.in_memory, params.bytes);
+            partition_config.io_mode = config.io_mode;
+            let lbf : bloom_lbf = bloom_lbf::new(bloom_filter_params::new(params.bytes, params.k_num, capacity, config.probability), config.filter_name.clone(), Vec::new());
+
+            let mut partition : BloomFilter = BloomFilter::new(partition_config, lbf, directory.clone());
+            partition.set_partition_index(index);
+
+            partitions.push(partition);
+        }
+
+        // the filter's bytes are those of a layer in every partition
+        config.k_num = params.k_num;
+        config.bytes = params.bytes * partition_count;
+        config.filter_type = String::from_str(FILTER_TYPE_PARTITIONED);
+
+        let mut config_file : Path = directory.clone();
+        config_file.push(config.filter_name.as_slice());
+        config_file.set_extension("ini");
+
+        return Ok(BloomFilter {
+            config         : config,
+            lbf            : None,
+            counters       : BloomFilterCounters::new(),
+            directory      : directory,
+            config_file    : config_file,
+            cold_index     : 0,
+            load_progress  : None,
+            partitions     : partitions,
+            file_prefix    : String::new(),
+            dirty          : true,
+            counters_dirty : false
+        });
+    }
+
+    // Creates a new, empty filter in the given directory, which must not exist yet, with its bitmaps
+    // read in and written back the given way
+    pub fn create(filter_name : &str, capacity : u64, probability : f64, io_mode : IoMode, directory : Path) -> BloomResult<Self> {
+        let params : bloom_filter_params = try!(create_bloom_filter_params(capacity, probability));
+        let mut config : BloomFilterConfig = BloomFilterConfig::new(String::from_str(filter_name), capacity, probability, params.k_num, false, params.bytes);
+        config.io_mode = io_mode;
+        let lbf : bloom_lbf = bloom_lbf::new(params, String::from_str(filter_name), Vec::new());
+
+        let mut filter : BloomFilter = BloomFilter::new(config, lbf, directory);
+        try!(filter.init());
+        try!(filter.flush());
+
+        return Ok(filter);
+    }
+
     // Reads in a Bloom Filter from a given directory; returns an error if
     // the ini file is missing or lacks information
-    pub fn from_directory(directory : &Path, filter_name : &String, load_filter : bool) -> Result<Self, String> {
+    pub fn from_directory(directory : &Path, filter_name : &String, load_filter : bool) -> BloomResult<Self> {
         if directory.exists() {
             let mut config_file : Path = directory.clone();
             config_file.push(filter_name.as_slice());
             config_file.set_extension("ini");
 
-            return match IniFile::from_filename(config_file.as_str().unwrap()) {
-                Ok(ini) => {
-                    let config : BloomFilterConfig;
-                    match BloomFilterConfig::from_ini(&ini) {
-                        Ok(_config) => { config = _config },
-                        Err(e) => { return Err(e) }
-                    };
-
-                    let counters : BloomFilterCounters;
-                    match BloomFilterCounters::from_ini(&ini) {
-                        Ok(_counters) => { counters = _counters },
-                        Err(e) => { return return Err(e) }
-                    };
-
-                    let mut bloom_filter : BloomFilter = BloomFilter {
-                        config: config,
-                        lbf: None,
-                        counters: counters,
-                        directory: directory.clone(),
-                        config_file: config_file,
-                        cold_index: 0
-                    };
-
-                    if load_filter {
-                        bloom_filter.load_filter();
-                    }
-
-                    return Ok(bloom_filter);
-                },
-                Err(e) => { Err(e.to_string()) }
-            };
+            let mut bloom_filter : BloomFilter = try!(BloomFilter::from_config_file(directory, config_file));
+
+            // partitions are paged in as their keys are used, so only the ones in use take up memory
+            for index in (0..bloom_filter.config.partitions) {
+                let mut partition_file : Path = directory.clone();
+                partition_file.push(format!("{}{}.ini", PARTITION_FILE_PREFIX, index).as_slice());
+
+                let mut partition : BloomFilter = try!(BloomFilter::from_config_file(directory, partition_file));
+                partition.set_partition_index(index);
+
+                bloom_filter.partitions.push(partition);
+            }
+
+            if load_filter && !bloom_filter.config.is_partitioned() {
+                bloom_filter.load_filter();
+            }
+
+            return Ok(bloom_filter);
         }
 
-        return Err(format!("directory {} does not exist", directory.display()));
+        return Err(BloomError::NotFound(format!("directory {} does not exist", directory.display())));
+    }
+
+    // reads in the config and counters of a filter, or of a partition, from its ini file
+    fn from_config_file(directory : &Path, config_file : Path) -> BloomResult<Self> {
+        return match IniFile::from_filename(config_file.as_str().unwrap()) {
+            Ok(ini) => {
+                let config : BloomFilterConfig = try!(BloomFilterConfig::from_ini(&ini));
+                let counters : BloomFilterCounters = try!(BloomFilterCounters::from_ini(&ini));
+
+                Ok(BloomFilter {
+                    config: config,
+                    lbf: None,
+                    counters: counters,
+                    directory: directory.clone(),
+                    config_file: config_file,
+                    cold_index: 0,
+                    load_progress: None,
+                    partitions: Vec::new(),
+                    file_prefix: String::new(),
+                    dirty: false,
+                    counters_dirty: false
+                })
+            },
+            Err(e) => { Err(BloomError::Io(e)) }
+        };
+    }
+
+    // makes this filter the partition with the given index, naming its files after it
+    fn set_partition_index(&mut self, index : u32) {
+        self.file_prefix = format!("{}{}.", PARTITION_FILE_PREFIX, index);
+
+        self.config_file = self.directory.clone();
+        self.config_file.push(format!("{}ini", self.file_prefix).as_slice());
     }
 
     // Handles the creation of a bloom filter on the disk, including the corresponding bitmap
-    pub fn add_filter(&mut self, value : u32) -> Result<(), String> {
+    pub fn add_filter(&mut self, value : u32) -> BloomResult<()> {
         let mut path : Path = self.directory.clone();
-        path.push(value.to_string());
-        path.set_extension("bmp");
+        path.push(format!("{}{}.bmp", self.file_prefix, value).as_slice());
 
         let bitmap_filename : String = String::from_str(path.as_str().unwrap());
 
-        let bloom_filter : bloom_bloomfilter;
-        match create_bloom_filter(&(**self).params, bitmap_filename.as_slice(), self.config.in_memory) {
-            Ok(_bloom_filter) => { bloom_filter = _bloom_filter },
-            Err(e) => { return Err(e) }
-        }
+        let bloom_filter : bloom_bloomfilter = try!(create_bloom_filter(&(**self).params, bitmap_filename.as_slice(), self.config.in_memory, self.config.io_mode));
 
         (**self).add_filter(bloom_filter);
+        self.dirty = true;
 
         if !self.config.in_memory {
             self.config.bitmap_filenames.push(bitmap_filename);
@@ -200,19 +341,82 @@ impl BloomFilter {
         return Ok(());
     }
 
-    // Flushes the bloom filter back to the disk
-    pub fn flush(&mut self) -> Result<(), String> {
-        if !self.config.in_memory {
+    // Flushes the bloom filter back to the disk, if it changed since the last flush, writing only the
+    // pages of its buffered bitmaps that changed; a filter whose counters alone changed only has its
+    // ini file rewritten. Returns the number of buffered bitmap bytes written, and the number of
+    // mapped bitmap bytes synced, which is an upper bound on what the kernel writes
+    pub fn flush(&mut self) -> BloomResult<(u64, u64)> {
+        if (self.dirty || self.counters_dirty) && !self.config.in_memory {
             let mut ini : IniFile = IniFile::new();
             self.config.add_to_ini(&mut ini);
             self.counters.add_to_ini(&mut ini);
 
-            if ini.write_to_path(&self.config_file).is_err() {
-                println!("Could not write to config file: {}", self.config_file.as_str().unwrap());
-            } 
+            try!(ini.write_to_path(&self.config_file));
         }
 
-        return (**self).flush();
+        self.counters_dirty = false;
+
+        if self.config.is_partitioned() {
+            let bytes : (u64, u64) = try!(self.flush_partitions());
+            self.dirty = false;
+
+            return Ok(bytes);
+        }
+
+        // a filter that isn't loaded wrote its bitmaps out when it was unloaded
+        if !self.dirty || !self.is_resident() {
+            self.dirty = false;
+            return Ok((0, 0));
+        }
+
+        let written : u64 = (**self).dirty_bytes();
+        let synced : u64 = (**self).mapped_bytes();
+        try!((**self).flush());
+
+        self.dirty = false;
+
+        return Ok((written, synced));
+    }
+
+    // flushes every partition, carrying on past partitions that fail
+    // returns the bytes written and synced, or the last error if any partition failed
+    fn flush_partitions(&mut self) -> BloomResult<(u64, u64)> {
+        let mut result : BloomResult<(u64, u64)> = Ok((0, 0));
+        let (mut written, mut synced) : (u64, u64) = (0, 0);
+
+        for partition in self.partitions.iter_mut() {
+            match partition.flush() {
+                Err(e) => { result = Err(e) },
+                Ok((partition_written, partition_synced)) => {
+                    written += partition_written;
+                    synced += partition_synced;
+                }
+            }
+        }
+
+        return result.map(|_| (written, synced));
+    }
+
+    // Returns true if the filter, its counters, or any of its partitions changed since the last flush
+    pub fn is_dirty(&self) -> bool {
+        return self.dirty || self.counters_dirty || self.partitions.iter().any(|partition| partition.is_dirty());
+    }
+
+    // Marks the filter as changed, so the next flush writes it out; for changes made to its config directly
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    // Returns the paths of the ini file and bitmaps of the filter and of its partitions
+    pub fn files(&self) -> Vec<Path> {
+        let mut files : Vec<Path> = vec![self.config_file.clone()];
+        files.extend(self.config.bitmap_filenames.iter().map(|filename| Path::new(filename.as_slice())));
+
+        for partition in self.partitions.iter() {
+            files.extend(partition.files().into_iter());
+        }
+
+        return files;
     }
 
     // Loads a bloom filter from the disk back into the BloomFilter instance
@@ -223,57 +427,282 @@ impl BloomFilter {
         for bitmap_filename in self.config.bitmap_filenames.iter() {
             let index : usize = filters.len();
 
-            match load_bloom_filter(&params, self.config.filter_sizes[index], bitmap_filename.as_slice(), self.config.in_memory) {
+            match load_bloom_filter(&params, self.config.filter_sizes[index], bitmap_filename.as_slice(), self.config.in_memory, self.config.io_mode) {
                 Ok(filter) => { filters.push(filter) },
-                Err(e) => { println!("Could not load filter {} ({}): {}", index, bitmap_filename, e) }
+                Err(e) => { log_error!("Could not load layer {} of filter {} ({}): {}", index, self.config.filter_name, bitmap_filename, e) }
             }
         }
 
         let lbf : bloom_lbf = bloom_lbf::new(params, self.config.filter_name.clone(), filters);
 
         self.lbf = Some(lbf);
+
+        if self.file_prefix.is_empty() {
+            log_debug!("Paged in filter {}", self.config.filter_name);
+        } else {
+            log_debug!("Paged in {}ini of filter {}", self.file_prefix, self.config.filter_name);
+        }
+    }
+
+    // Points the filter at the directory its files were moved or copied to, keeping the
+    // names of its bitmaps; the ini file is written there on the next flush
+    pub fn relocate(&mut self, directory : Path) {
+        for bitmap_filename in self.config.bitmap_filenames.iter_mut() {
+            let mut path : Path = directory.clone();
+            path.push(Path::new(bitmap_filename.as_slice()).filename().unwrap());
+
+            *bitmap_filename = String::from_str(path.as_str().unwrap());
+        }
+
+        self.config_file = directory.clone();
+
+        // a partition's ini file is named after its index, and it shares the filter's name
+        if self.file_prefix.is_empty() {
+            self.config_file.push(self.config.filter_name.as_slice());
+            self.config_file.set_extension("ini");
+        } else {
+            self.config_file.push(format!("{}ini", self.file_prefix).as_slice());
+        }
+
+        for partition in self.partitions.iter_mut() {
+            partition.config.filter_name = self.config.filter_name.clone();
+            partition.relocate(directory.clone());
+        }
+
+        self.directory = directory;
+        self.dirty = true;
     }
 
-    // Removes the bloom filter from memory
+    // Removes the bloom filter from memory, with every partition
     pub fn unload_filter(&mut self) {
         self.lbf = None;
+
+        for partition in self.partitions.iter_mut() {
+            partition.unload_filter();
+        }
+    }
+
+    // Returns true if the bloom filter, or any of its partitions, is loaded in memory
+    pub fn is_resident(&self) -> bool {
+        return self.lbf.is_some() || self.partitions.iter().any(|partition| partition.is_resident());
+    }
+
+    // Returns the number of partitions loaded in memory
+    pub fn resident_partitions(&self) -> usize {
+        return self.partitions.iter().filter(|partition| partition.is_resident()).count();
+    }
+
+    // Returns the number of bytes of bitmaps loaded in memory
+    pub fn resident_bytes(&self) -> u64 {
+        if self.config.is_partitioned() {
+            return self.partitions.iter().fold(0, |total, partition| total + partition.resident_bytes());
+        }
+
+        if !self.is_resident() {
+            return 0;
+        }
+
+        return self.config.bytes * self.config.filter_sizes.len() as u64;
     }
 
-    // Resets the cold index for this filter
+    // Returns the bitmap_mode of each layer in memory, across every partition
+    pub fn bitmap_modes(&self) -> Vec<u32> {
+        let mut modes : Vec<u32> = match self.lbf {
+            Some(ref lbf) => lbf.bitmap_modes(),
+            None => Vec::new()
+        };
+
+        for partition in self.partitions.iter() {
+            modes.extend(partition.bitmap_modes().into_iter());
+        }
+
+        return modes;
+    }
+
+    // Returns the number of layers, across every partition
+    pub fn layer_count(&self) -> usize {
+        return self.partitions.iter().fold(self.config.filter_sizes.len(), |total, partition| total + partition.config.filter_sizes.len());
+    }
+
+    // Resets the cold index for this filter; partitions are paged in as their keys are used
     pub fn touch(&mut self) {
         self.cold_index = 0;
-        (**self).touch();
+
+        if !self.config.is_partitioned() {
+            (**self).touch();
+        }
+    }
+
+    // Does a check for the given key and returns the number of layers containing it
+    pub fn check(&mut self, key : String) -> BloomResult<u32> {
+        self.touch();
+
+        let value : u32 = if self.config.is_partitioned() {
+            let index : usize = self.partition_index(&key);
+            let partition : &mut BloomFilter = &mut self.partitions[index];
+
+            partition.touch();
+            try!(partition.contains(&key))
+        } else {
+            try!(self.contains(&key))
+        };
+
+        if value > 0 {
+            self.counters.check_hits += 1;
+        } else {
+            self.counters.check_misses += 1;
+        }
+        self.counters_dirty = true;
+
+        return Ok(value);
+    }
+
+    // Does a set for the given key, creating a new layer if every layer has it,
+    // and returns the layer it was added to
+    pub fn set(&mut self, key : String) -> BloomResult<u32> {
+        self.touch();
+
+        let (found, value) : (u32, u32) = if self.config.is_partitioned() {
+            let index : usize = self.partition_index(&key);
+            let size : u64 = self.partitions[index].config.size;
+
+            let added : (u32, u32) = try!(self.partitions[index].set_key(key));
+            self.config.size += self.partitions[index].config.size - size;
+            self.dirty = true;
+
+            added
+        } else {
+            try!(self.set_key(key))
+        };
+
+        // Increment the counters for the filter
+        if found > 0 {
+            self.counters.set_hits += 1;
+        } else {
+            self.counters.set_misses += 1;
+        }
+
+        return Ok(value);
+    }
+
+    // adds the key to a layered filter, updating its sizes
+    // returns the number of layers that had it, and the layer it was added to
+    fn set_key(&mut self, key : String) -> BloomResult<(u32, u32)> {
+        self.touch();
+
+        let (found, value) : (u32, u32) = try!(self.add_key(key));
+        self.dirty = true;
+
+        // Increment the size of the filter
+        self.config.size = self.size();
+
+        if value > 0 {
+            let index : usize = (value - 1) as usize;
+            self.config.filter_sizes[index] = self.get_filter_size(index);
+        }
+
+        return Ok((found, value));
+    }
+
+    // Adds a batch of keys the way set does, but leaves the set counters alone and
+    // updates the sizes once for the whole batch; returns the number of keys added
+    pub fn load_keys(&mut self, keys : &[String]) -> BloomResult<u64> {
+        self.touch();
+
+        if self.config.is_partitioned() {
+            let mut batches : Vec<Vec<String>> = self.partitions.iter().map(|_| Vec::new()).collect();
+
+            for key in keys.iter() {
+                batches[self.partition_index(key)].push(key.clone());
+            }
+
+            for (partition, batch) in self.partitions.iter_mut().zip(batches.iter()) {
+                if !batch.is_empty() {
+                    try!(partition.load_keys(batch.as_slice()));
+                }
+            }
+
+            self.config.size = self.partitions.iter().fold(0, |total, partition| total + partition.config.size);
+            self.dirty = true;
+
+            return Ok(keys.len() as u64);
+        }
+
+        self.dirty = true;
+
+        for key in keys.iter() {
+            try!(self.add_key(key.clone()));
+        }
+
+        self.config.size = self.size();
+
+        for index in (0..self.config.filter_sizes.len()) {
+            self.config.filter_sizes[index] = self.get_filter_size(index);
+        }
+
+        return Ok(keys.len() as u64);
+    }
+
+    // adds the key to the first layer that doesn't contain it, creating a new layer if
+    // every layer has it; returns the number of layers that had it, and the layer it was added to
+    fn add_key(&mut self, key : String) -> BloomResult<(u32, u32)> {
+        // Check and make sure that there is a layer that doesn't contain the key,
+        // creating a new layer if necessary
+        let found : u32 = try!(self.contains(&key));
+        if found == self.num_filters {
+            try!(self.add_filter(found));
+        }
+
+        let value : u32 = try!(self.add(key));
+
+        return Ok((found, value));
+    }
+
+    // returns the index of the partition the key belongs to
+    fn partition_index(&self, key : &String) -> usize {
+        return (util::stable_hash(key.as_slice()) % self.partitions.len() as u64) as usize;
     }
 
     // Initializes the bloom filter on disk
-    pub fn init(&self) -> IoResult<()>{
+    pub fn init(&self) -> BloomResult<()> {
         if !self.config.in_memory {
-            fs::mkdir(&self.directory, io::USER_RWX).unwrap();
+            try!(fs::mkdir(&self.directory, io::USER_RWX));
         }
 
         return Ok(());
     }
 
     // Deletes the bloom filter from the disk
-    pub fn delete(&mut self) -> IoResult<()> {
+    pub fn delete(&mut self) -> BloomResult<()> {
         if self.directory.exists() {
-            return fs::rmdir_recursive(&self.directory);
+            try!(fs::rmdir_recursive(&self.directory));
         }
 
         return Ok(());
     }
 }
 
+// Only a layered filter, or a partition, has layers of its own; a partitioned filter's layers are in its
+// partitions, so reaching for them is a bug, reported as such rather than as a bare unwrap
 impl Deref for BloomFilter {
     type Target = bloom_lbf;
 
     fn deref<'a>(&'a self) -> &'a bloom_lbf {
-        return self.lbf.as_ref().unwrap();
+        return match self.lbf {
+            Some(ref lbf) => lbf,
+            None if self.config.is_partitioned() => panic!("filter {} is partitioned, so its layers are in its partitions", self.config.filter_name),
+            None => panic!("filter {} isn't paged in", self.config.filter_name)
+        };
     }
 }
 
 impl DerefMut for BloomFilter {
     fn deref_mut<'a>(&'a mut self) -> &'a mut bloom_lbf {
+        // paging in a partitioned filter's own layers would make up an empty one from its totals
+        if self.config.is_partitioned() {
+            panic!("filter {} is partitioned, so its layers are in its partitions", self.config.filter_name);
+        }
+
         if self.lbf.is_none() {
             self.load_filter();
         }